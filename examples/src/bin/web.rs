@@ -0,0 +1,57 @@
+//! Small HTTP service storing notes in an s3qlite database.
+//!
+//!   curl -X POST localhost:3000/notes -d 'hello'
+//!   curl localhost:3000/notes
+//!
+//! Every note also enqueues a job for the `worker` example. With `ROLE=replica` the
+//! service reads from a local copy kept up to date from the store and redirects writes
+//! to the leader at `LEADER_URL`, see the library docs.
+
+use examples::{Replica, Role};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    let role = match std::env::var("ROLE").as_deref() {
+        Ok("replica") => {
+            let url = std::env::var("OBJECT_STORE_URL").expect("OBJECT_STORE_URL is not set");
+            let path = std::env::var("REPLICA_PATH").unwrap_or_else(|_| "replica.db".to_string());
+            let refresh_ms = std::env::var("REPLICA_REFRESH_MS")
+                .ok()
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(1000);
+            let replica = Arc::new(tokio::sync::Mutex::new(Replica::new(&url, path)));
+            examples::refresh_periodically(replica.clone(), Duration::from_millis(refresh_ms));
+            Role::Replica {
+                replica,
+                leader_url: std::env::var("LEADER_URL")
+                    .unwrap_or_else(|_| examples::DEFAULT_LEADER_URL.to_string()),
+            }
+        }
+        Ok("leader") | Err(_) => {
+            examples::init_leader()
+                .await
+                .expect("failed to initialize database");
+            Role::Leader
+        }
+        Ok(role) => panic!("unknown ROLE {role:?}, expected leader or replica"),
+    };
+    let leader = matches!(role, Role::Leader);
+
+    let addr = std::env::var("LISTEN_ADDR").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    println!("listening on {addr}");
+    axum::serve(listener, examples::router(role))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .unwrap();
+
+    if leader {
+        examples::flush_leader()
+            .await
+            .expect("failed to flush the database");
+    }
+}
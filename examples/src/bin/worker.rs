@@ -0,0 +1,33 @@
+//! Queue worker processing the jobs enqueued by the `web` example.
+//!
+//! Finds pending jobs on its own replica of the database and reports each one done to
+//! the leader at `LEADER_URL`, which is the only process that writes. A job is reported
+//! again if the worker stops before the leader's answer reaches it, so processing must
+//! be safe to repeat. Stops after the current pass on Ctrl-C.
+
+use examples::Replica;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    let url = std::env::var("OBJECT_STORE_URL").expect("OBJECT_STORE_URL is not set");
+    let path = std::env::var("REPLICA_PATH").unwrap_or_else(|_| "worker.db".to_string());
+    let leader_url =
+        std::env::var("LEADER_URL").unwrap_or_else(|_| examples::DEFAULT_LEADER_URL.to_string());
+    let mut replica = Replica::new(&url, path);
+    let client = reqwest::Client::new();
+
+    let stop = tokio::signal::ctrl_c();
+    tokio::pin!(stop);
+    loop {
+        match examples::work(&mut replica, &client, &leader_url).await {
+            Ok(0) => {}
+            Ok(done) => println!("finished {done} jobs"),
+            Err(e) => eprintln!("failed to process jobs: {e}"),
+        }
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Shared setup for the example binaries: one leader and any number of read replicas of
+//! one database.
+//!
+//! SlateDB allows a single writer per store, so one process is the leader: `web` with
+//! `ROLE=leader` (the default) opens `examples.db` through the VFS and takes every
+//! write. Replicas never open the store for writing. A `Replica` rebuilds a local copy
+//! of the database from what the leader has made durable, read with `s3qlite::reader`,
+//! whenever its commit generation moves, and queries the copy with plain SQLite. `web`
+//! with `ROLE=replica` serves reads that way and redirects writes to `LEADER_URL`; the
+//! `worker` finds pending jobs on a replica of its own and reports them done to the
+//! leader.
+//!
+//! Against MinIO, started with `docker compose up` in this directory:
+//!
+//!   export OBJECT_STORE_URL='s3://examples?endpoint=http://localhost:9000&allow_http=true'
+//!   export AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin AWS_REGION=us-east-1
+//!   cargo run -p examples --bin web
+//!   ROLE=replica LISTEN_ADDR=127.0.0.1:3001 cargo run -p examples --bin web
+//!   cargo run -p examples --bin worker
+//!
+//! `tests/leader_and_replicas.rs` runs the same flow against a `file://` store.
+
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use rusqlite::{Connection, OpenFlags};
+use s3qlite::reader::Reader;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DB_NAME: &str = "examples.db";
+
+/// Where writes go when `LEADER_URL` isn't set.
+pub const DEFAULT_LEADER_URL: &str = "http://127.0.0.1:3000";
+
+/// Start the VFS and make sure the example schema exists. Only the leader calls this.
+pub async fn init_leader() -> Result<(), String> {
+    s3qlite::initialize().await?;
+    with_leader(|connection| {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS notes (id INTEGER PRIMARY KEY, body TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS jobs (
+                 id INTEGER PRIMARY KEY,
+                 note_id INTEGER NOT NULL,
+                 state TEXT NOT NULL DEFAULT 'pending'
+             );",
+        )
+    })
+    .await
+}
+
+/// Run `f` on a connection to the leader's database. The VFS drives its own tokio
+/// runtime with block_on, so SQLite calls happen on blocking threads rather than on the
+/// async workers.
+pub async fn with_leader<T, F>(f: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let connection = Connection::open(DB_NAME)?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        f(&connection)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// Make everything the leader wrote durable, before it exits.
+pub async fn flush_leader() -> Result<(), String> {
+    with_leader(|connection| connection.execute_batch("PRAGMA s3qlite_flush")).await
+}
+
+/// Notes with the state of their job, one per line.
+pub fn list_notes(connection: &Connection) -> rusqlite::Result<String> {
+    let mut stmt = connection.prepare(
+        "SELECT n.id, coalesce(j.state, '-'), n.body FROM notes n
+         LEFT JOIN jobs j ON j.note_id = n.id ORDER BY n.id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(format!(
+            "{}\t{}\t{}\n",
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?
+        ))
+    })?;
+    rows.collect()
+}
+
+/// Ids of the jobs still pending, oldest first.
+pub fn pending_jobs(connection: &Connection) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = connection.prepare("SELECT id FROM jobs WHERE state = 'pending' ORDER BY id")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// A local copy of the leader's database, see the module docs.
+pub struct Replica {
+    url: String,
+    path: std::path::PathBuf,
+    generation: Option<u64>,
+}
+
+impl Replica {
+    /// A replica of the database in the store at `url`, copied to `path`.
+    pub fn new(url: &str, path: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            url: url.to_string(),
+            path: path.into(),
+            generation: None,
+        }
+    }
+
+    /// Copy the database again if it moved on since the last copy, returning whether it
+    /// did.
+    pub async fn refresh(&mut self) -> Result<bool, String> {
+        let reader = Reader::open(&self.url).await?;
+        let copied = self.copy(&reader).await;
+        reader.close().await?;
+        copied
+    }
+
+    async fn copy(&mut self, reader: &Reader) -> Result<bool, String> {
+        let Some(database) = reader.database(DB_NAME).await? else {
+            return Ok(false);
+        };
+        if self.generation == Some(database.generation) {
+            return Ok(false);
+        }
+        let mut file = vec![0; database.len as usize];
+        let mut pages = reader.pages(DB_NAME).await?;
+        while let Some(page) = pages.next().await? {
+            let end = (page.offset + page.data.len()).min(file.len());
+            if page.offset < end {
+                file[page.offset..end].copy_from_slice(&page.data[..end - page.offset]);
+            }
+        }
+        // renamed into place, so queries never see half a copy
+        let partial = self.path.with_extension("partial");
+        std::fs::write(&partial, &file).map_err(|e| e.to_string())?;
+        std::fs::rename(&partial, &self.path).map_err(|e| e.to_string())?;
+        self.generation = Some(database.generation);
+        Ok(true)
+    }
+
+    /// A read-only connection to the copy, through SQLite's own file VFS.
+    pub fn open(&self) -> rusqlite::Result<Connection> {
+        Connection::open_with_flags_and_vfs(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY, "unix")
+    }
+}
+
+/// Refresh `replica` every `interval`, for as long as the process runs.
+pub fn refresh_periodically(replica: Arc<tokio::sync::Mutex<Replica>>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = replica.lock().await.refresh().await {
+                eprintln!("failed to refresh the replica: {e}");
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// What a `web` process does with requests.
+#[derive(Clone)]
+pub enum Role {
+    Leader,
+    Replica {
+        replica: Arc<tokio::sync::Mutex<Replica>>,
+        leader_url: String,
+    },
+}
+
+pub fn router(role: Role) -> Router {
+    Router::new()
+        .route("/notes", get(list).post(create))
+        .route("/jobs/{id}/done", post(finish))
+        .with_state(role)
+}
+
+fn internal_error(e: String) -> Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+}
+
+/// Send a write on to the leader, with a redirect that keeps the method and body.
+fn to_leader(leader_url: &str, path: &str) -> Response {
+    let location = format!("{}{path}", leader_url.trim_end_matches('/'));
+    (
+        StatusCode::TEMPORARY_REDIRECT,
+        [(header::LOCATION, location)],
+    )
+        .into_response()
+}
+
+async fn list(State(role): State<Role>) -> Response {
+    let notes = match role {
+        Role::Leader => with_leader(list_notes).await,
+        Role::Replica { replica, .. } => {
+            let replica = replica.lock().await;
+            replica
+                .open()
+                .and_then(|connection| list_notes(&connection))
+                .map_err(|e| e.to_string())
+        }
+    };
+    notes.map_or_else(internal_error, IntoResponse::into_response)
+}
+
+async fn create(State(role): State<Role>, body: String) -> Response {
+    if let Role::Replica { leader_url, .. } = role {
+        return to_leader(&leader_url, "/notes");
+    }
+    let id = with_leader(move |connection| {
+        let tx = connection.unchecked_transaction()?;
+        tx.execute("INSERT INTO notes (body) VALUES (?1)", [&body])?;
+        let id = tx.last_insert_rowid();
+        tx.execute("INSERT INTO jobs (note_id) VALUES (?1)", [id])?;
+        tx.commit()?;
+        Ok(id)
+    })
+    .await;
+    match id {
+        Ok(id) => (StatusCode::CREATED, format!("{id}\n")).into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn finish(State(role): State<Role>, Path(id): Path<i64>) -> Response {
+    if let Role::Replica { leader_url, .. } = role {
+        return to_leader(&leader_url, &format!("/jobs/{id}/done"));
+    }
+    let finished = with_leader(move |connection| {
+        connection.execute(
+            "UPDATE jobs SET state = 'done' WHERE id = ?1 AND state = 'pending'",
+            [id],
+        )
+    })
+    .await;
+    match finished {
+        Ok(0) => (StatusCode::CONFLICT, "no such pending job\n").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Process the jobs pending on `replica` and report each done to the leader, returning
+/// how many the leader took. A job another worker finished first is skipped.
+pub async fn work(
+    replica: &mut Replica,
+    client: &reqwest::Client,
+    leader_url: &str,
+) -> Result<usize, String> {
+    replica.refresh().await?;
+    let jobs = replica
+        .open()
+        .and_then(|connection| pending_jobs(&connection))
+        .map_err(|e| e.to_string())?;
+    let mut done = 0;
+    for id in jobs {
+        println!("processing job {id}");
+        let url = format!("{}/jobs/{id}/done", leader_url.trim_end_matches('/'));
+        let response = client.post(url).send().await.map_err(|e| e.to_string())?;
+        match response.status() {
+            StatusCode::NO_CONTENT => done += 1,
+            StatusCode::CONFLICT => {}
+            status => return Err(format!("leader answered {status} for job {id}")),
+        }
+    }
+    Ok(done)
+}
@@ -0,0 +1,86 @@
+//! The leader, a replica and a worker from the examples, against a `file://` store.
+
+use examples::{Replica, Role};
+use std::sync::Arc;
+use std::time::Duration;
+
+async fn serve(role: Role) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, examples::router(role)).await });
+    url
+}
+
+/// Poll `url` until its body contains `expected`, or give up after a while.
+async fn wait_for(client: &reqwest::Client, url: &str, expected: &str) -> String {
+    let mut body = String::new();
+    for _ in 0..100 {
+        body = client.get(url).send().await.unwrap().text().await.unwrap();
+        if body.contains(expected) {
+            return body;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{url} never returned {expected:?}, last returned {body:?}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_go_to_the_leader_and_reach_the_replicas() {
+    let dir = std::env::temp_dir().join(format!("s3qlite-examples-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("store")).unwrap();
+    let store_url = format!("file://{}", dir.join("store").display());
+    // read once, when the VFS starts, and nothing else in this binary runs concurrently
+    unsafe { std::env::set_var("OBJECT_STORE_URL", &store_url) };
+
+    examples::init_leader().await.unwrap();
+    let leader_url = serve(Role::Leader).await;
+    let replica = Arc::new(tokio::sync::Mutex::new(Replica::new(
+        &store_url,
+        dir.join("replica.db"),
+    )));
+    examples::refresh_periodically(replica.clone(), Duration::from_millis(50));
+    let replica_url = serve(Role::Replica {
+        replica,
+        leader_url: leader_url.clone(),
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    // posted to the replica, which redirects it to the leader
+    let response = client
+        .post(format!("{replica_url}/notes"))
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(response.text().await.unwrap(), "1\n");
+
+    wait_for(
+        &client,
+        &format!("{replica_url}/notes"),
+        "1\tpending\thello",
+    )
+    .await;
+
+    let mut worker = Replica::new(&store_url, dir.join("worker.db"));
+    assert_eq!(
+        examples::work(&mut worker, &client, &leader_url).await,
+        Ok(1)
+    );
+    let response = client
+        .post(format!("{leader_url}/jobs/1/done"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+
+    assert_eq!(
+        wait_for(&client, &format!("{leader_url}/notes"), "done").await,
+        "1\tdone\thello\n"
+    );
+    wait_for(&client, &format!("{replica_url}/notes"), "1\tdone\thello").await;
+
+    examples::flush_leader().await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
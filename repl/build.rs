@@ -1,4 +1,15 @@
 fn main() {
+    #[cfg(feature = "bundled")]
+    bundled();
+
+    #[cfg(not(feature = "bundled"))]
+    prebuilt();
+}
+
+/// Link ./lib/libsqlite3.a (`make repl/lib/libsqlite3.a`), or a system libsqlite3
+/// without the `static` feature.
+#[cfg(not(feature = "bundled"))]
+fn prebuilt() {
     println!("cargo:rustc-link-search=native=./lib");
 
     #[cfg(feature = "static")]
@@ -18,3 +29,54 @@ fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=./lib/libsqlite3.a");
 }
+
+/// Compile the SQLite amalgamation the Makefile downloads, with the same options as
+/// `make static`, and link it with the s3qlite library from `cargo build` in the root.
+/// Nothing from the system SQLite or ./lib is used.
+#[cfg(feature = "bundled")]
+fn bundled() {
+    let source = std::path::Path::new("../sqlite/sqlite3.c");
+    if !source.exists() {
+        panic!(
+            "{} is missing, run `make sqlite/sqlite3.c`",
+            source.display()
+        );
+    }
+    cc::Build::new()
+        .file(source)
+        .include("../sqlite")
+        .define("SQLITE_ENABLE_COLUMN_METADATA", "1")
+        .define("SQLITE_ENABLE_LOAD_EXTENSION", "1")
+        .define("SQLITE_ENABLE_FTS5", "1")
+        .define("SQLITE_ENABLE_BATCH_ATOMIC_WRITE", "1")
+        .define("SQLITE_ENABLE_DBSTAT_VTAB", "1")
+        .define("SQLITE_ENABLE_NULL_TRIM", "1")
+        .define("SQLITE_ENABLE_RTREE", "1")
+        .define("HAVE_READLINE", "0")
+        .define("_GNU_SOURCE", None)
+        .opt_level(2)
+        .warnings(false)
+        .compile("sqlite3");
+
+    let s3qlite = std::path::Path::new("../target/debug/libs3qlite.a");
+    if !s3qlite.exists() {
+        panic!(
+            "{} is missing, run `cargo build` in the root",
+            s3qlite.display()
+        );
+    }
+    println!("cargo:rustc-link-search=native=../target/debug");
+    println!("cargo:rustc-link-lib=static=s3qlite");
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+        println!("cargo:rustc-link-lib=framework=Security");
+        println!("cargo:rustc-link-lib=framework=CoreFoundation");
+    } else {
+        println!("cargo:rustc-link-lib=pthread");
+        println!("cargo:rustc-link-lib=dl");
+        println!("cargo:rustc-link-lib=m");
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", source.display());
+    println!("cargo:rerun-if-changed={}", s3qlite.display());
+}
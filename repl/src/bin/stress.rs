@@ -0,0 +1,530 @@
+//! Multi-database stress harness for the grpsqlite VFS.
+//!
+//!   cargo run --bin stress -- --databases 32 --threads 8 --seconds 30
+//!
+//! Worker threads pick random databases and run a random mix of DDL, DML, row deletes,
+//! read transactions, blob churn (to push pages through the page cache), named snapshots
+//! opened as databases of their own, and deletes of whole database files, which are
+//! recreated empty. Every write transaction keeps a `ledger` row in step with the `items`
+//! table, so each snapshot, and at the end each database, is checked with
+//! `PRAGMA integrity_check` and `count(items) == ledger.n`.
+//!
+//! The in-process lock manager blocks instead of returning SQLITE_BUSY, so transactions on
+//! the same database are serialized by the harness; concurrency comes from many databases.
+//!
+//!   cargo run --bin stress -- --processes 4 --databases 16 --threads 4
+//!
+//! runs the workload in that many worker processes at once, each a copy of this binary
+//! with its own VFS. SlateDB takes one writer per store, so each worker gets a store of
+//! its own, `worker-N` under the `file://` directory in `OBJECT_STORE_URL` (or under a
+//! temporary directory). Once they've all exited, a fresh process per worker reopens its
+//! store and checks every database again, so what the checks see is what was durably
+//! stored rather than what one process had cached.
+//!
+//!   cargo run --release --bin stress -- --workload locks --databases 64 --threads 16
+//!
+//! measures lock manager throughput instead: every thread keeps a connection open to each
+//! database and runs short read transactions, which take and release a SHARED lock
+//! against cached pages, then reports transactions per second.
+
+use sqlite::{Connection, State};
+use std::ffi::CString;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+unsafe extern "C" {
+    fn initialize_grpsqlite() -> i32;
+    fn flush_traces();
+}
+
+#[derive(PartialEq)]
+enum Workload {
+    Mixed,
+    Locks,
+}
+
+#[derive(PartialEq)]
+enum Phase {
+    Run,
+    Verify,
+}
+
+struct Options {
+    workload: Workload,
+    databases: usize,
+    threads: usize,
+    seconds: u64,
+    seed: u64,
+    processes: usize,
+    // set in the worker processes started for --processes
+    worker: Option<usize>,
+    phase: Phase,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, String> {
+        let mut options = Options {
+            workload: Workload::Mixed,
+            databases: 24,
+            threads: 4,
+            seconds: 10,
+            seed: 1,
+            processes: 1,
+            worker: None,
+            phase: Phase::Run,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {flag}"))?;
+            let parse = |v: &str| v.parse::<u64>().map_err(|e| format!("{flag}: {e}"));
+            match flag.as_str() {
+                "--workload" => {
+                    options.workload = match value.as_str() {
+                        "mixed" => Workload::Mixed,
+                        "locks" => Workload::Locks,
+                        _ => return Err(format!("{flag}: expected mixed or locks")),
+                    }
+                }
+                "--databases" => options.databases = parse(&value)? as usize,
+                "--threads" => options.threads = parse(&value)? as usize,
+                "--seconds" => options.seconds = parse(&value)?,
+                "--seed" => options.seed = parse(&value)?,
+                "--processes" => options.processes = parse(&value)?.max(1) as usize,
+                "--worker" => options.worker = Some(parse(&value)? as usize),
+                "--phase" => {
+                    options.phase = match value.as_str() {
+                        "run" => Phase::Run,
+                        "verify" => Phase::Verify,
+                        _ => return Err(format!("{flag}: expected run or verify")),
+                    }
+                }
+                _ => return Err(format!("unknown flag {flag}")),
+            }
+        }
+        Ok(options)
+    }
+
+    /// The seed of thread `t`, distinct across worker processes.
+    fn thread_seed(&self, t: usize) -> u64 {
+        let worker = self.worker.unwrap_or(0) as u64;
+        self.seed.wrapping_mul(0x9e3779b97f4a7c15) ^ (worker << 32) ^ (t as u64 + 1)
+    }
+}
+
+/// xorshift64, good enough to pick operations without pulling in a rand dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+fn db_name(i: usize) -> String {
+    format!("stress_{i}.db")
+}
+
+fn setup(connection: &Connection) -> sqlite::Result<()> {
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS items (id INTEGER PRIMARY KEY, body BLOB);
+         CREATE TABLE IF NOT EXISTS ledger (id INTEGER PRIMARY KEY CHECK (id = 1), n INTEGER);
+         INSERT OR IGNORE INTO ledger (id, n) VALUES (1, 0);",
+    )
+}
+
+/// Operations on a random database, weighted by how often they're picked.
+const OPS: &[(&str, u64)] = &[
+    ("insert", 4),
+    ("update", 2),
+    ("delete", 1),
+    ("ddl", 1),
+    ("read", 2),
+    ("churn", 3),
+    ("snapshot", 1),
+    ("remove", 1),
+];
+
+fn pick_op(rng: &mut Rng) -> &'static str {
+    let mut n = rng.below(OPS.iter().map(|(_, weight)| weight).sum());
+    for &(op, weight) in OPS {
+        if n < weight {
+            return op;
+        }
+        n -= weight;
+    }
+    unreachable!()
+}
+
+fn invariant_error(message: String) -> sqlite::Error {
+    sqlite::Error {
+        code: None,
+        message: Some(message),
+    }
+}
+
+/// Whether `items` and `ledger` are in step as `connection` sees them.
+fn consistent(connection: &Connection) -> sqlite::Result<bool> {
+    let mut stmt =
+        connection.prepare("SELECT (SELECT count(*) FROM items) = (SELECT n FROM ledger)")?;
+    stmt.next()?;
+    Ok(stmt.read::<i64, _>(0)? == 1)
+}
+
+fn run_op(connection: &Connection, op: &str, i: usize, rng: &mut Rng) -> sqlite::Result<()> {
+    match op {
+        "insert" => {
+            let n = 1 + rng.below(20);
+            let size = 16 + rng.below(512);
+            connection.execute("BEGIN")?;
+            connection.execute(format!(
+                "WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < {n})
+                 INSERT INTO items (body) SELECT randomblob({size}) FROM s;
+                 UPDATE ledger SET n = n + {n};"
+            ))?;
+            connection.execute("COMMIT")
+        }
+        "update" => connection.execute(format!(
+            "UPDATE items SET body = randomblob({}) WHERE id % 7 = {}",
+            32 + rng.below(256),
+            rng.below(7)
+        )),
+        "delete" => {
+            connection.execute("BEGIN")?;
+            connection.execute(format!(
+                "DELETE FROM items WHERE id % 5 = {};
+                 UPDATE ledger SET n = (SELECT count(*) FROM items);",
+                rng.below(5)
+            ))?;
+            connection.execute("COMMIT")
+        }
+        // create and drop a scratch table and index
+        "ddl" => connection.execute(
+            "CREATE TABLE IF NOT EXISTS scratch (k INTEGER PRIMARY KEY, v TEXT);
+             CREATE INDEX IF NOT EXISTS scratch_v ON scratch (v);
+             INSERT INTO scratch (v) VALUES (hex(randomblob(32)));
+             DROP TABLE scratch;",
+        ),
+        // a read transaction must see items and ledger in step
+        "read" => {
+            connection.execute("BEGIN")?;
+            let consistent = consistent(connection)?;
+            connection.execute("COMMIT")?;
+            if !consistent {
+                return Err(invariant_error(
+                    "read transaction saw items and ledger out of step".into(),
+                ));
+            }
+            Ok(())
+        }
+        // a large blob that spans many pages, then remove it
+        "churn" => {
+            connection.execute("BEGIN")?;
+            connection.execute(format!(
+                "INSERT INTO items (body) VALUES (zeroblob({}));
+                 DELETE FROM items WHERE id = last_insert_rowid();",
+                64 * 1024 + rng.below(256 * 1024)
+            ))?;
+            connection.execute("COMMIT")
+        }
+        // name a snapshot, then open it as a database of its own and check it
+        "snapshot" => {
+            let name = format!("s{}", rng.below(3));
+            connection.execute(format!("PRAGMA s3qlite_snapshot='{name}'"))?;
+            let snapshot = Connection::open(format!("{}@{name}", db_name(i)))?;
+            if query_value(
+                &snapshot,
+                "SELECT integrity_check FROM pragma_integrity_check",
+            )? != sqlite::Value::String("ok".to_string())
+            {
+                return Err(invariant_error(format!(
+                    "snapshot {name} failed integrity_check"
+                )));
+            }
+            if !consistent(&snapshot)? {
+                return Err(invariant_error(format!(
+                    "snapshot {name} has items and ledger out of step"
+                )));
+            }
+            Ok(())
+        }
+        _ => unreachable!("{op}"),
+    }
+}
+
+fn query_value(connection: &Connection, sql: &str) -> sqlite::Result<sqlite::Value> {
+    let mut stmt = connection.prepare(sql)?;
+    match stmt.next()? {
+        State::Row => stmt.read::<sqlite::Value, _>(0),
+        State::Done => Err(invariant_error(format!("no rows for {sql}"))),
+    }
+}
+
+/// Delete database `i` through the VFS, as SQLite deletes a journal, and recreate it.
+/// Nothing may have it open.
+fn remove(i: usize) -> sqlite::Result<()> {
+    let name = CString::new(db_name(i)).unwrap();
+    let rc = unsafe {
+        let vfs = sqlite::ffi::sqlite3_vfs_find(std::ptr::null());
+        ((*vfs).xDelete.unwrap())(vfs, name.as_ptr(), 0)
+    };
+    if rc != sqlite::ffi::SQLITE_OK {
+        return Err(sqlite::Error {
+            code: Some(rc as isize),
+            message: Some(format!("deleting {}", db_name(i))),
+        });
+    }
+    let connection = Connection::open(db_name(i))?;
+    let tables = query_value(&connection, "SELECT count(*) FROM sqlite_schema")?;
+    if tables != sqlite::Value::Integer(0) {
+        return Err(invariant_error(format!(
+            "{} still had {tables:?} tables after its delete",
+            db_name(i)
+        )));
+    }
+    setup(&connection)
+}
+
+fn check(i: usize) -> Result<(), String> {
+    let connection = Connection::open(db_name(i)).map_err(|e| e.to_string())?;
+    let query = |sql: &str| query_value(&connection, sql).map_err(|e| e.to_string());
+    let integrity = query("PRAGMA integrity_check")?;
+    if integrity != sqlite::Value::String("ok".to_string()) {
+        return Err(format!("integrity_check: {integrity:?}"));
+    }
+    let items = query("SELECT count(*) FROM items")?;
+    let ledger = query("SELECT n FROM ledger")?;
+    if items != ledger {
+        return Err(format!("items={items:?} but ledger={ledger:?}"));
+    }
+    Ok(())
+}
+
+/// Read transactions on random databases from every thread at once. Readers don't block
+/// each other, so the rate is bounded by the VFS lock and file bookkeeping.
+fn lock_throughput(options: &Options) {
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(options.seconds);
+    let handles: Vec<_> = (0..options.threads)
+        .map(|t| {
+            let databases = options.databases;
+            let mut rng = Rng(options.thread_seed(t));
+            thread::spawn(move || {
+                let connections: Vec<Connection> = (0..databases)
+                    .map(|i| Connection::open(db_name(i)).unwrap())
+                    .collect();
+                let mut transactions = 0u64;
+                while Instant::now() < deadline {
+                    let connection = &connections[rng.below(databases as u64) as usize];
+                    let mut stmt = connection.prepare("SELECT n FROM ledger").unwrap();
+                    stmt.next().unwrap();
+                    transactions += 1;
+                }
+                transactions
+            })
+        })
+        .collect();
+    let transactions: u64 = handles
+        .into_iter()
+        .map(|handle| handle.join().expect("stress thread panicked"))
+        .sum();
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{transactions} read transactions in {elapsed:.1}s, {:.0}/s across {} threads and {} databases",
+        transactions as f64 / elapsed,
+        options.threads,
+        options.databases
+    );
+}
+
+/// Run the workload in `options.processes` worker processes, then check each worker's
+/// store from a fresh process.
+fn run_processes(options: &Options) {
+    let (base, temporary) = match std::env::var("OBJECT_STORE_URL") {
+        Ok(url) if url.starts_with("file://") => (PathBuf::from(&url["file://".len()..]), false),
+        Ok(url) => {
+            eprintln!("--processes needs a file:// OBJECT_STORE_URL, not {url}");
+            std::process::exit(2);
+        }
+        Err(_) => (
+            std::env::temp_dir().join(format!("s3qlite-stress-{}", std::process::id())),
+            true,
+        ),
+    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let exe = std::env::current_exe().expect("no path to the stress binary");
+    let phases: &[Phase] = match options.workload {
+        Workload::Mixed => &[Phase::Run, Phase::Verify],
+        Workload::Locks => &[Phase::Run],
+    };
+    let mut failed = false;
+    for phase in phases {
+        let phase_arg = match phase {
+            Phase::Run => "run",
+            Phase::Verify => "verify",
+        };
+        let children: Vec<_> = (0..options.processes)
+            .map(|worker| {
+                let store = base.join(format!("worker-{worker}"));
+                Command::new(&exe)
+                    .args(&args)
+                    .args(["--worker", &worker.to_string(), "--phase", phase_arg])
+                    .env("OBJECT_STORE_URL", format!("file://{}", store.display()))
+                    .spawn()
+                    .expect("failed to start a stress worker")
+            })
+            .collect();
+        for (worker, mut child) in children.into_iter().enumerate() {
+            let status = child.wait().expect("failed to wait for a stress worker");
+            if !status.success() {
+                eprintln!("worker {worker} {phase_arg} failed: {status}");
+                failed = true;
+            }
+        }
+        if failed {
+            break;
+        }
+    }
+    if failed {
+        eprintln!("stores kept in {}", base.display());
+        std::process::exit(1);
+    }
+    if temporary {
+        let _ = std::fs::remove_dir_all(&base);
+    }
+    println!("{} worker processes passed", options.processes);
+}
+
+/// Check every database, as a worker process does once all workers have exited.
+fn verify(options: &Options) {
+    let failed = (0..options.databases)
+        .filter(|&i| match check(i) {
+            Ok(()) => false,
+            Err(e) => {
+                eprintln!("{}: invariant violated after reopening: {e}", db_name(i));
+                true
+            }
+        })
+        .count();
+    unsafe { flush_traces() };
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!(
+                "usage: stress [--workload mixed|locks] [--databases N] [--threads N] \
+                 [--seconds N] [--seed N] [--processes N]"
+            );
+            std::process::exit(2);
+        }
+    };
+    if options.processes > 1 && options.worker.is_none() {
+        run_processes(&options);
+        return;
+    }
+    unsafe { initialize_grpsqlite() };
+    if options.phase == Phase::Verify {
+        verify(&options);
+        return;
+    }
+
+    let locks: Arc<Vec<Mutex<()>>> =
+        Arc::new((0..options.databases).map(|_| Mutex::new(())).collect());
+    for i in 0..options.databases {
+        setup(&Connection::open(db_name(i)).unwrap()).unwrap();
+    }
+
+    if options.workload == Workload::Locks {
+        lock_throughput(&options);
+        unsafe { flush_traces() };
+        return;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(options.seconds);
+    let handles: Vec<_> = (0..options.threads)
+        .map(|t| {
+            let locks = locks.clone();
+            let mut rng = Rng(options.thread_seed(t));
+            thread::spawn(move || {
+                let mut ops = 0u64;
+                let mut errors = 0u64;
+                while Instant::now() < deadline {
+                    let i = rng.below(locks.len() as u64) as usize;
+                    let op = pick_op(&mut rng);
+                    let _guard = locks[i].lock().unwrap();
+                    if op == "remove" {
+                        match remove(i) {
+                            Ok(()) => ops += 1,
+                            Err(e) => {
+                                eprintln!("thread {t}: remove {}: {e}", db_name(i));
+                                errors += 1;
+                            }
+                        }
+                        continue;
+                    }
+                    let connection = match Connection::open(db_name(i)) {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            eprintln!("thread {t}: open {}: {e}", db_name(i));
+                            errors += 1;
+                            continue;
+                        }
+                    };
+                    match run_op(&connection, op, i, &mut rng) {
+                        Ok(()) => ops += 1,
+                        Err(e) => {
+                            eprintln!("thread {t}: {op} {}: {e}", db_name(i));
+                            let _ = connection.execute("ROLLBACK");
+                            errors += 1;
+                        }
+                    }
+                }
+                (ops, errors)
+            })
+        })
+        .collect();
+
+    let (mut ops, mut errors) = (0, 0);
+    for handle in handles {
+        let (o, e) = handle.join().expect("stress thread panicked");
+        ops += o;
+        errors += e;
+    }
+
+    let mut failed = 0;
+    for i in 0..options.databases {
+        if let Err(e) = check(i) {
+            eprintln!("{}: invariant violated: {e}", db_name(i));
+            failed += 1;
+        }
+    }
+    unsafe { flush_traces() };
+
+    let worker = options
+        .worker
+        .map_or(String::new(), |worker| format!("worker {worker}: "));
+    println!(
+        "{worker}{ops} operations, {errors} errors, {failed}/{} databases failed checks",
+        options.databases
+    );
+    if errors > 0 || failed > 0 {
+        std::process::exit(1);
+    }
+}
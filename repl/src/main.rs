@@ -7,6 +7,22 @@ mod main_test;
 
 unsafe extern "C" {
     fn initialize_grpsqlite() -> i32;
+    fn s3qlite_state_dir(buf: *mut std::ffi::c_char, len: usize) -> i32;
+}
+
+/// Where the REPL keeps its history, in the s3qlite state directory. None if
+/// `S3QLITE_REPL_HISTORY=false`.
+fn history_path() -> Option<std::path::PathBuf> {
+    if std::env::var("S3QLITE_REPL_HISTORY").is_ok_and(|v| v == "false") {
+        return None;
+    }
+    let mut buf = vec![0u8; 4096];
+    if unsafe { s3qlite_state_dir(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let dir = std::ffi::CStr::from_bytes_until_nul(&buf).ok()?.to_str().ok()?;
+    std::fs::create_dir_all(dir).ok()?;
+    Some(std::path::Path::new(dir).join("repl_history.txt"))
 }
 
 struct SqliteRepl {
@@ -63,6 +79,9 @@ impl SqliteRepl {
                 println!("  .open <file>    Open a database file");
                 println!("  .tables         List all tables");
                 println!("  .schema [table] Show table schema");
+                println!("  .explain <sql>  Show the query plan with page counts and cache residency");
+                println!("  .pagesize <n>   Rewrite the database with n byte pages");
+                println!("  .heatmap <db> [csv] Export page reads and writes, by table");
                 println!("\nEnter SQL statements to execute them.");
                 println!("Use semicolon (;) to end statements.");
             }
@@ -73,6 +92,29 @@ impl SqliteRepl {
             ".tables" => {
                 self.list_tables();
             }
+            cmd if cmd.starts_with(".explain") => {
+                // the SQL keeps its case, string literals may depend on it
+                let sql = command.trim()[".explain".len()..].trim();
+                if sql.is_empty() {
+                    println!("Usage: .explain <sql>");
+                } else {
+                    self.explain(sql);
+                }
+            }
+            cmd if cmd.starts_with(".pagesize") => {
+                match cmd[".pagesize".len()..].trim().parse::<usize>() {
+                    Ok(page_size) => self.migrate_page_size(page_size),
+                    Err(_) => println!("Usage: .pagesize <bytes>"),
+                }
+            }
+            cmd if cmd.starts_with(".heatmap") => {
+                // file names keep their case
+                let parts: Vec<&str> = command.split_whitespace().collect();
+                match parts.get(1) {
+                    Some(db) => self.export_heatmap(db, parts.get(2).copied()),
+                    None => println!("Usage: .heatmap <db> [out.csv]"),
+                }
+            }
             cmd if cmd.starts_with(".open") => {
                 let parts: Vec<&str> = cmd.split_whitespace().collect();
                 if parts.len() > 1 {
@@ -201,6 +243,117 @@ impl SqliteRepl {
         }
     }
 
+    fn explain(&self, sql: &str) {
+        let Some(connection) = self.connection.as_ref() else {
+            println!("No database opened");
+            return;
+        };
+        let mut plan = Vec::new();
+        let explained = connection
+            .prepare(format!("EXPLAIN QUERY PLAN {sql}"))
+            .and_then(|mut stmt| {
+                while let State::Row = stmt.next()? {
+                    plan.push((
+                        stmt.read::<i64, _>(0)?,
+                        stmt.read::<i64, _>(1)?,
+                        stmt.read::<String, _>(3)?,
+                    ));
+                }
+                Ok(())
+            });
+        if let Err(e) = explained {
+            println!("SQL Error: {e}");
+            return;
+        }
+
+        let page_size = query_string(connection, "PRAGMA page_size")
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(4096);
+        let cached = query_string(connection, "PRAGMA s3qlite_cached_ranges")
+            .map(|ranges| parse_ranges(&ranges))
+            .unwrap_or_default();
+
+        println!("QUERY PLAN");
+        self.print_plan(connection, &plan, 0, "", page_size, &cached);
+    }
+
+    fn migrate_page_size(&self, page_size: usize) {
+        let Some(connection) = self.connection.as_ref() else {
+            println!("No database opened");
+            return;
+        };
+        let pages = query_string(connection, "PRAGMA page_count").unwrap_or_default();
+        println!(
+            "Rewriting {pages} pages of {} with {page_size} byte pages",
+            self.current_db
+        );
+        let result = change_page_size(connection, page_size, |elapsed| {
+            println!("  still rewriting after {}s", elapsed.as_secs());
+        });
+        match result {
+            Ok(pages) => println!("Done, {} is now {pages} pages", self.current_db),
+            Err(e) => println!("Page size not changed: {e}"),
+        }
+    }
+
+    fn export_heatmap(&self, db: &str, out: Option<&str>) {
+        // counts are kept by the VFS, any connection to the database can report them
+        let other;
+        let connection = match &self.connection {
+            Some(connection) if db == self.current_db => connection,
+            _ => match Connection::open(db) {
+                Ok(connection) => {
+                    other = connection;
+                    &other
+                }
+                Err(e) => {
+                    println!("Error opening database: {e}");
+                    return;
+                }
+            },
+        };
+        let csv = match heatmap_csv(connection) {
+            Ok(csv) => csv,
+            Err(e) => {
+                println!("Heatmap not available: {e}");
+                return;
+            }
+        };
+        match out {
+            Some(out) => match std::fs::write(out, &csv) {
+                Ok(()) => println!("Wrote {} pages to {out}", csv.lines().count() - 1),
+                Err(e) => println!("Error writing {out}: {e}"),
+            },
+            None => print!("{csv}"),
+        }
+    }
+
+    fn print_plan(
+        &self,
+        connection: &Connection,
+        plan: &[(i64, i64, String)],
+        parent: i64,
+        indent: &str,
+        page_size: usize,
+        cached: &[(usize, usize)],
+    ) {
+        let children: Vec<_> = plan.iter().filter(|(_, p, _)| *p == parent).collect();
+        for (i, (id, _, detail)) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let mut line = format!("{indent}{}{detail}", if last { "`--" } else { "|--" });
+            for object in plan_objects(detail) {
+                if let Some((pages, resident)) =
+                    object_residency(connection, &object, page_size, cached)
+                {
+                    line.push_str(&format!("  [{object}: {pages} pages, {resident} cached]"));
+                }
+            }
+            println!("{line}");
+            let indent = format!("{indent}{}", if last { "   " } else { "|  " });
+            self.print_plan(connection, plan, *id, &indent, page_size, cached);
+        }
+    }
+
     fn execute_sql(&self, sql: &str) -> bool {
         let sql = sql.trim();
         if sql.is_empty() {
@@ -338,7 +491,10 @@ impl SqliteRepl {
         let mut rl = DefaultEditor::new()?;
 
         // Try to load history
-        let _ = rl.load_history("repl_history.txt");
+        let history = history_path();
+        if let Some(history) = &history {
+            let _ = rl.load_history(history);
+        }
 
         println!("\nWelcome to grpsqlite REPL!");
         println!("Type .help for commands or enter SQL statements.");
@@ -401,11 +557,165 @@ impl SqliteRepl {
         }
 
         // Save history
-        let _ = rl.save_history("repl_history.txt");
+        if let Some(history) = &history {
+            let _ = rl.save_history(history);
+        }
         Ok(())
     }
 }
 
+fn query_string(connection: &Connection, sql: &str) -> Option<String> {
+    let mut stmt = connection.prepare(sql).ok()?;
+    match stmt.next().ok()? {
+        State::Row => stmt.read::<String, _>(0).ok(),
+        State::Done => None,
+    }
+}
+
+/// The tables and indexes a query plan step reads, e.g. `orders` and `orders_by_date`
+/// for `SEARCH orders USING INDEX orders_by_date (date>?)`.
+fn plan_objects(detail: &str) -> Vec<String> {
+    let words: Vec<&str> = detail.split_whitespace().collect();
+    let mut objects = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        let next = match *word {
+            // older SQLite versions say `SCAN TABLE orders`
+            "SCAN" | "SEARCH" => words
+                .get(i + 1)
+                .filter(|w| **w != "TABLE")
+                .or_else(|| words.get(i + 2)),
+            "INDEX" => words.get(i + 1),
+            _ => None,
+        };
+        if let Some(name) = next {
+            objects.push(name.to_string());
+        }
+    }
+    objects
+}
+
+/// `start-end` pairs separated by commas, as returned by `PRAGMA s3qlite_cached_ranges`.
+fn parse_ranges(ranges: &str) -> Vec<(usize, usize)> {
+    ranges
+        .split(',')
+        .filter_map(|range| {
+            let (start, end) = range.split_once('-')?;
+            Some((start.parse().ok()?, end.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Pages of a table or index and how many of them are in the VFS page cache. None for
+/// names that aren't btrees, like subquery aliases.
+fn object_residency(
+    connection: &Connection,
+    name: &str,
+    page_size: usize,
+    cached: &[(usize, usize)],
+) -> Option<(usize, usize)> {
+    let mut stmt = connection
+        .prepare("SELECT pageno FROM dbstat WHERE name = ?")
+        .ok()?;
+    stmt.bind((1, name)).ok()?;
+    let mut pages = 0;
+    let mut resident = 0;
+    while let Ok(State::Row) = stmt.next() {
+        let pageno = stmt.read::<i64, _>(0).ok()? as usize;
+        let start = (pageno - 1) * page_size;
+        pages += 1;
+        if cached
+            .iter()
+            .any(|&(s, e)| s <= start && start + page_size <= e)
+        {
+            resident += 1;
+        }
+    }
+    (pages > 0).then_some((pages, resident))
+}
+
+/// `PRAGMA s3qlite_heatmap` with a column naming the table or index owning each page,
+/// empty for free pages or when SQLite was built without `dbstat`.
+fn heatmap_csv(connection: &Connection) -> Result<String, String> {
+    let counts = query_string(connection, "PRAGMA s3qlite_heatmap")
+        .ok_or_else(|| "the VFS doesn't record page accesses".to_string())?;
+    let mut owners = std::collections::HashMap::new();
+    if let Ok(mut stmt) = connection.prepare("SELECT pageno, name FROM dbstat") {
+        while let Ok(State::Row) = stmt.next() {
+            if let (Ok(pageno), Ok(name)) = (stmt.read::<i64, _>(0), stmt.read::<String, _>(1)) {
+                owners.insert(pageno.to_string(), name);
+            }
+        }
+    }
+    let mut lines = counts.lines();
+    let mut csv = format!("{},object\n", lines.next().unwrap_or("page,reads,writes"));
+    for line in lines {
+        let page = line.split(',').next().unwrap_or_default();
+        let owner = owners.get(page).map(String::as_str).unwrap_or_default();
+        csv.push_str(&format!("{line},{owner}\n"));
+    }
+    Ok(csv)
+}
+
+/// Rewrite the database with `page_size` byte pages: `PRAGMA page_size` then `VACUUM`.
+/// The VACUUM holds an exclusive lock while it rewrites, so it fails with "database is
+/// locked" rather than racing a writer. `progress` is called about once a second with
+/// the time spent so far. Returns the new page count.
+fn change_page_size(
+    connection: &Connection,
+    page_size: usize,
+    mut progress: impl FnMut(std::time::Duration),
+) -> Result<usize, String> {
+    if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+        return Err(format!(
+            "{page_size} isn't a power of two from 512 to 65536"
+        ));
+    }
+    connection
+        .execute(format!("PRAGMA page_size={page_size}"))
+        .map_err(|e| e.to_string())?;
+
+    struct Progress<'a> {
+        start: std::time::Instant,
+        reported: std::time::Instant,
+        callback: &'a mut dyn FnMut(std::time::Duration),
+    }
+    unsafe extern "C" fn on_progress(arg: *mut std::ffi::c_void) -> std::ffi::c_int {
+        let progress = unsafe { &mut *arg.cast::<Progress>() };
+        if progress.reported.elapsed() >= std::time::Duration::from_secs(1) {
+            progress.reported = std::time::Instant::now();
+            (progress.callback)(progress.start.elapsed());
+        }
+        0
+    }
+    let mut state = Progress {
+        start: std::time::Instant::now(),
+        reported: std::time::Instant::now(),
+        callback: &mut progress,
+    };
+    unsafe {
+        sqlite::ffi::sqlite3_progress_handler(
+            connection.as_raw(),
+            10_000,
+            Some(on_progress),
+            (&raw mut state).cast(),
+        );
+    }
+    let vacuumed = connection.execute("VACUUM");
+    unsafe {
+        sqlite::ffi::sqlite3_progress_handler(connection.as_raw(), 0, None, std::ptr::null_mut());
+    }
+    vacuumed.map_err(|e| e.to_string())?;
+
+    let current = query_string(connection, "PRAGMA page_size").unwrap_or_default();
+    if current != page_size.to_string() {
+        // e.g. WAL mode, where the page size is fixed
+        return Err(format!("page size is still {current}"));
+    }
+    query_string(connection, "PRAGMA page_count")
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| "failed to count pages".to_string())
+}
+
 fn main() {
     match SqliteRepl::new() {
         Ok(mut repl) => {
@@ -5,6 +5,33 @@ mod tests {
     unsafe extern "C" {
         fn initialize_grpsqlite() -> i32;
         fn flush_traces();
+        fn s3qlite_healthcheck(deadline_ms: u32, buf: *mut std::ffi::c_char, len: usize) -> i32;
+        fn s3qlite_progress_handler(
+            callback: Option<
+                unsafe extern "C" fn(
+                    *mut std::ffi::c_void,
+                    *const std::ffi::c_char,
+                    *const std::ffi::c_char,
+                    u64,
+                ) -> i32,
+            >,
+            arg: *mut std::ffi::c_void,
+        );
+        fn s3qlite_interrupt(handle_id: i64) -> i32;
+        fn s3qlite_unlock_notify(
+            callback: Option<
+                unsafe extern "C" fn(*mut std::ffi::c_void, *const std::ffi::c_char, u64),
+            >,
+            arg: *mut std::ffi::c_void,
+        );
+        fn s3qlite_app_background() -> i32;
+        fn s3qlite_config_set(name: *const std::ffi::c_char, value: *const std::ffi::c_char)
+        -> i32;
+        fn sqlite3_s3qlite_init(
+            db: *mut std::ffi::c_void,
+            pz_err_msg: *mut *mut std::ffi::c_char,
+            p_api: *mut std::ffi::c_void,
+        ) -> i32;
     }
 
     fn init_vfs() {
@@ -384,6 +411,76 @@ mod tests {
         unsafe { flush_traces() };
     }
 
+    #[test]
+    fn test_plan_objects() {
+        assert_eq!(
+            crate::plan_objects("SEARCH orders USING INDEX orders_by_date (date>?)"),
+            ["orders", "orders_by_date"]
+        );
+        assert_eq!(crate::plan_objects("SCAN TABLE items"), ["items"]);
+        assert!(crate::plan_objects("USE TEMP B-TREE FOR ORDER BY").is_empty());
+    }
+
+    #[test]
+    fn test_cached_ranges_pragma() {
+        init_vfs();
+        let connection = Connection::open("test_cached_ranges_pragma.db").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS ranges (id INTEGER PRIMARY KEY, body TEXT)")
+            .unwrap();
+        let mut stmt = connection.prepare("PRAGMA s3qlite_cached_ranges").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let ranges = crate::parse_ranges(&stmt.read::<String, _>(0).unwrap());
+        // page 1 and the table root were just written
+        assert_eq!(ranges.first(), Some(&(0, 8192)));
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_health() {
+        init_vfs();
+        let connection = Connection::open("test_health.db").unwrap();
+        let mut stmt = connection.prepare("PRAGMA s3qlite_health=5000").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let status: String = stmt.read(0).unwrap();
+        assert!(status.starts_with("{\"healthy\":true,"), "{status}");
+        assert!(status.contains("\"pending_bytes\":0"), "{status}");
+
+        let mut buf = [0u8; 512];
+        let rc = unsafe { s3qlite_healthcheck(5000, buf.as_mut_ptr().cast(), buf.len()) };
+        let status = std::ffi::CStr::from_bytes_until_nul(&buf).unwrap();
+        assert_eq!(rc, 0, "{status:?}");
+        assert!(status.to_str().unwrap().contains("\"store\":{\"ok\":true"));
+        // the result code is enough for a liveness probe
+        assert_eq!(
+            unsafe { s3qlite_healthcheck(5000, std::ptr::null_mut(), 0) },
+            0
+        );
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_open_handles_pragma() {
+        init_vfs();
+        let open_handles = |connection: &Connection| -> usize {
+            let mut stmt = connection.prepare("PRAGMA s3qlite_open_handles").unwrap();
+            assert_eq!(stmt.next().unwrap(), State::Row);
+            let handles: String = stmt.read(0).unwrap();
+            assert!(handles.starts_with('['), "{handles}");
+            handles.matches("\"path\":\"test_open_handles.db\"").count()
+        };
+        let connection = Connection::open("test_open_handles.db").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS t (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        let other = Connection::open("test_open_handles.db").unwrap();
+        other.execute("SELECT count(*) FROM t").unwrap();
+        assert_eq!(open_handles(&connection), 2);
+        drop(other);
+        assert_eq!(open_handles(&connection), 1);
+        unsafe { flush_traces() };
+    }
+
     #[test]
     fn test_custom_vfs_pragma() {
         init_vfs();
@@ -399,4 +496,3074 @@ mod tests {
         }
         unsafe { flush_traces() };
     }
+
+    #[test]
+    fn test_unknown_pragmas_reach_sqlite() {
+        init_vfs();
+        let connection = Connection::open("test_unknown_pragmas_reach_sqlite.db").unwrap();
+        connection.execute("PRAGMA user_version = 7").unwrap();
+
+        let mut stmt = connection.prepare("PRAGMA user_version").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 7);
+        let mut stmt = connection.prepare("PRAGMA page_size").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 4096);
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_pin_pragma() {
+        init_vfs();
+        let connection = Connection::open("test_pin_pragma.db").unwrap();
+        connection
+            .execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT)")
+            .unwrap();
+        connection
+            .execute("CREATE INDEX users_name ON users (name)")
+            .unwrap();
+
+        // page 1 plus the table and index root pages
+        let mut stmt = connection.prepare("PRAGMA s3qlite_pin='users'").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let pinned: String = stmt.read(0).unwrap();
+        assert_eq!(pinned, "3");
+
+        assert!(connection.execute("PRAGMA s3qlite_pin='missing'").is_err());
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_trace_id_pragma() {
+        init_vfs();
+        let connection = Connection::open("test_trace_id_pragma.db").unwrap();
+        let read_trace_id = || {
+            let mut stmt = connection.prepare("PRAGMA s3qlite_trace_id").unwrap();
+            assert_eq!(stmt.next().unwrap(), State::Row);
+            stmt.read::<String, _>(0).unwrap()
+        };
+        assert_eq!(read_trace_id(), "");
+
+        connection
+            .execute("PRAGMA s3qlite_trace_id='req-1234'")
+            .unwrap();
+        assert_eq!(read_trace_id(), "req-1234");
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS traced (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        assert_eq!(read_trace_id(), "req-1234");
+
+        connection.execute("PRAGMA s3qlite_trace_id=''").unwrap();
+        assert_eq!(read_trace_id(), "");
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_cost_estimate_pragma() {
+        init_vfs();
+        let connection = Connection::open("test_cost_estimate_pragma.db").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS events (id INTEGER PRIMARY KEY, body TEXT)")
+            .unwrap();
+        connection
+            .execute("INSERT INTO events (body) VALUES ('a'), ('b')")
+            .unwrap();
+
+        let mut stmt = connection.prepare("PRAGMA s3qlite_cost_estimate").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let estimate: String = stmt.read(0).unwrap();
+        assert!(estimate.starts_with('$'), "{estimate}");
+        assert!(estimate.contains("puts"), "{estimate}");
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_unchanged_writes_skipped() {
+        init_vfs();
+        let connection = Connection::open("test_unchanged_writes_skipped.db").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS notes (id INTEGER PRIMARY KEY, body TEXT)")
+            .unwrap();
+        connection
+            .execute("INSERT INTO notes (body) VALUES ('a'), ('b')")
+            .unwrap();
+        // dirties the table page but leaves it as it was
+        connection
+            .execute(
+                "BEGIN; UPDATE notes SET body = 'x' WHERE id = 1; \
+                 UPDATE notes SET body = 'a' WHERE id = 1; COMMIT",
+            )
+            .unwrap();
+
+        let mut stmt = connection.prepare("PRAGMA s3qlite_cost_estimate").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let estimate: String = stmt.read(0).unwrap();
+        let skipped: u64 = estimate
+            .rsplit_once(", ")
+            .and_then(|(_, tail)| tail.split(' ').next())
+            .and_then(|n| n.parse().ok())
+            .unwrap();
+        assert!(skipped > 0, "{estimate}");
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_vacuum_truncates() {
+        init_vfs();
+        let connection = Connection::open("test_vacuum_truncates.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS blobs (id INTEGER PRIMARY KEY, body BLOB); \
+                 DELETE FROM blobs; \
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50) \
+                 INSERT INTO blobs (body) SELECT zeroblob(2000) FROM n",
+            )
+            .unwrap();
+        let page_count = |connection: &Connection| {
+            let mut stmt = connection.prepare("PRAGMA page_count").unwrap();
+            assert_eq!(stmt.next().unwrap(), State::Row);
+            stmt.read::<i64, _>(0).unwrap()
+        };
+        let before = page_count(&connection);
+        connection.execute("DELETE FROM blobs; VACUUM").unwrap();
+        assert!(page_count(&connection) < before);
+
+        let mut stmt = connection.prepare("PRAGMA integrity_check").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<String, _>(0).unwrap(), "ok");
+        unsafe { flush_traces() };
+    }
+
+    /// Serve `file` over HTTP on a local port, answering ranged GETs like S3 or a CDN.
+    /// Returns the base URL.
+    fn serve_file(file: std::path::PathBuf) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let data = std::fs::read(&file).unwrap();
+                let mut range = None;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(spec) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = spec.trim().split_once('-').unwrap();
+                        let start: usize = start.parse().unwrap();
+                        let end = end.parse::<usize>().map_or(data.len(), |e| e + 1);
+                        range = Some((start, end.min(data.len())));
+                    }
+                }
+                let (status, body, content_range) = match range {
+                    Some((start, end)) => (
+                        "206 Partial Content",
+                        &data[start..end],
+                        format!(
+                            "Content-Range: bytes {start}-{}/{}\r\n",
+                            end - 1,
+                            data.len()
+                        ),
+                    ),
+                    None => ("200 OK", &data[..], String::new()),
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n{content_range}\
+                     ETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream
+                    .write_all(header.as_bytes())
+                    .and_then(|_| stream.write_all(body));
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[test]
+    fn test_remote_database() {
+        init_vfs();
+        // publish a database written by SQLite's own file VFS, with 8KiB pages so reads
+        // span the VFS's 4KiB chunks
+        let file =
+            std::env::temp_dir().join(format!("s3qlite_published_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        let local = Connection::open_with_flags(
+            format!("file:{}?vfs=unix", file.display()),
+            sqlite::OpenFlags::new()
+                .with_create()
+                .with_read_write()
+                .with_uri(),
+        )
+        .unwrap();
+        local
+            .execute(
+                "PRAGMA page_size=8192; \
+                 CREATE TABLE cities (id INTEGER PRIMARY KEY, name TEXT); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 5000) \
+                 INSERT INTO cities (name) SELECT 'city ' || x FROM s",
+            )
+            .unwrap();
+        drop(local);
+        let url = format!("{}/cities.db", serve_file(file.clone()));
+
+        let remote =
+            Connection::open_with_flags(&url, sqlite::OpenFlags::new().with_read_only()).unwrap();
+        let mut stmt = remote
+            .prepare("SELECT count(*), max(name) FROM cities WHERE id > 100")
+            .unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 4900);
+        assert_eq!(stmt.read::<String, _>(1).unwrap(), "city 999");
+        drop(stmt);
+        assert!(remote.execute("DELETE FROM cities").is_err());
+        drop(remote);
+        let _ = std::fs::remove_file(&file);
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_attach_snapshot() {
+        init_vfs();
+        let connection = Connection::open("test_attach_snapshot.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS orders (id INTEGER PRIMARY KEY, item TEXT); \
+                 DELETE FROM orders; \
+                 INSERT INTO orders (item) VALUES ('apple'), ('pear')",
+            )
+            .unwrap();
+        connection
+            .execute("PRAGMA s3qlite_snapshot='before'")
+            .unwrap();
+        connection
+            .execute("INSERT INTO orders (item) VALUES ('plum'); DELETE FROM orders WHERE id = 1")
+            .unwrap();
+
+        connection
+            .execute("ATTACH 'test_attach_snapshot.db@before' AS old")
+            .unwrap();
+        let mut stmt = connection
+            .prepare(
+                "SELECT item FROM orders EXCEPT SELECT item FROM old.orders \
+                 UNION ALL \
+                 SELECT '-' || item FROM old.orders EXCEPT SELECT '-' || item FROM orders",
+            )
+            .unwrap();
+        let mut diff = Vec::new();
+        while let State::Row = stmt.next().unwrap() {
+            diff.push(stmt.read::<String, _>(0).unwrap());
+        }
+        diff.sort();
+        assert_eq!(diff, ["-apple", "plum"]);
+
+        // snapshots are read-only
+        assert!(
+            connection
+                .execute("INSERT INTO old.orders (item) VALUES ('fig')")
+                .is_err()
+        );
+        connection.execute("DETACH old").unwrap();
+        // without such a snapshot the name is an ordinary database
+        connection
+            .execute("ATTACH 'test_attach_snapshot.db@missing' AS gone")
+            .unwrap();
+        assert_eq!(
+            crate::query_string(&connection, "SELECT count(*) FROM gone.sqlite_schema").unwrap(),
+            "0"
+        );
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_at_sign_in_name() {
+        init_vfs();
+        let connection = Connection::open("user@example.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (body TEXT); \
+                 BEGIN; INSERT INTO t VALUES ('a'), ('b'); COMMIT",
+            )
+            .unwrap();
+        drop(connection);
+        let connection = Connection::open("user@example.db").unwrap();
+        assert_eq!(integrity_and_count(&connection, "t"), ("ok".to_string(), 2));
+    }
+
+    #[test]
+    fn test_flush_pragma() {
+        init_vfs();
+        let connection = Connection::open("test_flush_pragma.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (body BLOB); \
+                 INSERT INTO t SELECT randomblob(3000) FROM (SELECT 1 UNION ALL SELECT 2)",
+            )
+            .unwrap();
+        let report = crate::query_string(&connection, "PRAGMA s3qlite_flush").unwrap();
+        let count = |name: &str| -> u64 {
+            let (_, rest) = report.split_once(&format!("\"{name}\":")).unwrap();
+            rest.split([',', '}']).next().unwrap().parse().unwrap()
+        };
+        // other tests write concurrently, so only lower bounds hold
+        assert!(count("batches") >= 1, "{report}");
+        assert!(count("pages") >= 3, "{report}");
+        assert!(count("bytes") >= 6000, "{report}");
+        count("elapsed_ms");
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_verify_cache_pragma() {
+        init_vfs();
+        let connection = Connection::open("test_verify_cache_pragma.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS checked (id INTEGER PRIMARY KEY, body TEXT); \
+                 INSERT INTO checked (body) VALUES ('a'), ('b')",
+            )
+            .unwrap();
+        let pragma = |sql: &str| {
+            let mut stmt = connection.prepare(sql).unwrap();
+            assert_eq!(stmt.next().unwrap(), State::Row);
+            stmt.read::<String, _>(0).unwrap()
+        };
+        // other tests write concurrently, so a racing write may count as a mismatch
+        pragma("PRAGMA s3qlite_verify_cache")
+            .parse::<usize>()
+            .unwrap();
+        let stats = pragma("PRAGMA s3qlite_stats");
+        assert!(stats.contains("\"cache_verified_pages\":"), "{stats}");
+        assert!(!stats.contains("\"cache_verified_pages\":0,"), "{stats}");
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_new_database_bootstrap() {
+        init_vfs();
+        let connection = Connection::open("test_new_database_bootstrap.db").unwrap();
+        connection
+            .execute("CREATE TABLE fresh (id INTEGER PRIMARY KEY, body TEXT)")
+            .unwrap();
+        let mut stmt = connection.prepare("PRAGMA s3qlite_cost_estimate").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let estimate: String = stmt.read(0).unwrap();
+        let (gets, puts) = estimate
+            .split_once(" from ")
+            .and_then(|(_, tail)| {
+                let words: Vec<&str> = tail.split(' ').collect();
+                Some((
+                    words.first()?.parse::<u64>().ok()?,
+                    words.get(6)?.parse::<u64>().ok()?,
+                ))
+            })
+            .unwrap();
+        // the marker and both initial pages go in a single batch
+        assert_eq!(puts, 1, "{estimate}");
+        assert!(gets <= 2, "{estimate}");
+        unsafe { flush_traces() };
+    }
+
+    // Runs in a child process started by test_silent_mode, a no-op otherwise.
+    #[test]
+    fn silent_mode_workload() {
+        if std::env::var("S3QLITE_SILENT_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("test_silent_mode.db").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS quiet (id INTEGER PRIMARY KEY, body TEXT)")
+            .unwrap();
+        connection
+            .execute("INSERT INTO quiet (body) VALUES ('a'), ('b')")
+            .unwrap();
+        let mut stmt = connection.prepare("SELECT count(*) FROM quiet").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        // registering a second time hits the logger-already-set path
+        init_vfs();
+        unsafe { flush_traces() };
+    }
+
+    #[test]
+    fn test_silent_mode() {
+        let output = run_child("silent_mode_workload", &[("S3QLITE_SILENT_CHILD", "1")]);
+        assert!(output.status.success(), "{output:?}");
+        assert!(output.stderr.is_empty(), "{output:?}");
+        // only the test harness' own summary
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        for line in stdout.lines() {
+            assert!(
+                line.is_empty()
+                    || line.starts_with("running ")
+                    || line.starts_with("test result: ")
+                    || line.chars().all(|c| c == '.'),
+                "unexpected output {line:?} in {stdout:?}"
+            );
+        }
+    }
+
+    // Runs in a child process started by test_pending_writes_limit, a no-op otherwise.
+    #[test]
+    fn pending_writes_limit_workload() {
+        let Ok(mode) = std::env::var("S3QLITE_LIMIT_CHILD") else {
+            return;
+        };
+        init_vfs();
+        let connection = Connection::open("test_pending_writes_limit.db").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS bulk (id INTEGER PRIMARY KEY, body BLOB)")
+            .unwrap();
+        let insert = if mode == "commit" {
+            // fewer bytes written than the limit, in more blocks than it allows
+            "INSERT INTO bulk (body) VALUES (randomblob(70000))"
+        } else {
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 20) \
+             INSERT INTO bulk (body) SELECT randomblob(2000) FROM n"
+        };
+        let err = connection.execute(insert).unwrap_err();
+        assert_eq!(err.code, Some(13), "{err:?}"); // SQLITE_FULL
+        assert_eq!(
+            integrity_and_count(&connection, "bulk"),
+            ("ok".to_string(), 0)
+        );
+
+        let mut stmt = connection.prepare("PRAGMA s3qlite_stats").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let stats: String = stmt.read(0).unwrap();
+        assert!(!stats.contains("\"pending_writes_full\":0"), "{stats}");
+    }
+
+    #[test]
+    fn test_pending_writes_limit() {
+        for envs in [
+            &[
+                ("PENDING_WRITES_MAX_BYTES", "16384"),
+                ("S3QLITE_LIMIT_CHILD", "write"),
+            ][..],
+            // by default a third of the in-flight budget, what its commit can take
+            &[
+                ("COMMIT_INFLIGHT_BYTES", "49152"),
+                ("S3QLITE_LIMIT_CHILD", "write"),
+            ],
+            // checked again at commit against the blocks the writes land in
+            &[
+                ("BLOCK_SIZE", "65536"),
+                ("PENDING_WRITES_MAX_BYTES", "100000"),
+                ("S3QLITE_LIMIT_CHILD", "commit"),
+            ],
+        ] {
+            let output = run_child("pending_writes_limit_workload", envs);
+            assert!(output.status.success(), "{envs:?}: {output:?}");
+        }
+    }
+
+    // Runs in a child process started by test_path_key, a no-op otherwise.
+    #[test]
+    fn path_key_workload() {
+        if std::env::var("S3QLITE_PATH_KEY_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("tenant_acme/orders.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE orders (id INTEGER PRIMARY KEY, item TEXT); \
+                 INSERT INTO orders (item) VALUES ('apple'), ('pear')",
+            )
+            .unwrap();
+        drop(connection);
+
+        let connection = Connection::open("tenant_acme/orders.db").unwrap();
+        let mut stmt = connection.prepare("SELECT count(*) FROM orders").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 2);
+        drop(stmt);
+
+        let mut stmt = connection.prepare("PRAGMA s3qlite_names").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        let names: String = stmt.read(0).unwrap();
+        // one entry, `{"<hmac>/<hmac>":"tenant_acme/orders.db"}`, journals aren't listed
+        let (stored, path) = names.trim_matches(['{', '}']).split_once(':').unwrap();
+        assert_eq!(path, "\"tenant_acme/orders.db\"", "{names}");
+        assert!(!stored.contains("acme") && stored.contains('/'), "{names}");
+    }
+
+    #[test]
+    fn test_path_key() {
+        let output = run_child(
+            "path_key_workload",
+            &[
+                (
+                    "PATH_KEY",
+                    "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                ),
+                ("S3QLITE_PATH_KEY_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    /// Run the test `name` in a child process of this test binary with `envs` set, for
+    /// workloads that need their own VFS configuration. The VFS is silent in it.
+    fn run_child(name: &str, envs: &[(&str, &str)]) -> std::process::Output {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                &format!("main_test::tests::{name}"),
+                "--nocapture",
+                "-q",
+            ])
+            .env("S3QLITE_SILENT", "true")
+            .envs(envs.iter().copied())
+            .output()
+            .unwrap()
+    }
+
+    fn integrity_and_count(connection: &Connection, table: &str) -> (String, i64) {
+        let mut stmt = connection
+            .prepare(format!(
+                "SELECT (SELECT integrity_check FROM pragma_integrity_check), \
+                 (SELECT count(*) FROM {table})"
+            ))
+            .unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        (stmt.read(0).unwrap(), stmt.read(1).unwrap())
+    }
+
+    #[test]
+    fn test_journal_mode_matrix() {
+        init_vfs();
+        for journal_mode in ["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "OFF"] {
+            for locking_mode in ["NORMAL", "EXCLUSIVE"] {
+                for synchronous in ["OFF", "NORMAL", "FULL", "EXTRA"] {
+                    let combination = format!("{journal_mode}/{locking_mode}/{synchronous}");
+                    let path = format!(
+                        "matrix_{}_{}_{}.db",
+                        journal_mode.to_lowercase(),
+                        locking_mode.to_lowercase(),
+                        synchronous.to_lowercase()
+                    );
+                    let connection = Connection::open(&path).unwrap();
+                    // a tiny cache makes SQLite spill to the journal mid-transaction
+                    connection
+                        .execute(format!(
+                            "PRAGMA journal_mode={journal_mode}; \
+                             PRAGMA locking_mode={locking_mode}; \
+                             PRAGMA synchronous={synchronous}; \
+                             PRAGMA cache_size=4; \
+                             CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB)"
+                        ))
+                        .unwrap();
+                    for _ in 0..2 {
+                        connection
+                            .execute(
+                                "WITH RECURSIVE s(x) AS \
+                                 (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 50) \
+                                 INSERT INTO t (body) SELECT randomblob(300) FROM s",
+                            )
+                            .unwrap();
+                    }
+                    // without a journal a rollback can't restore the pages it spilled
+                    if journal_mode != "OFF" {
+                        connection
+                            .execute(
+                                "BEGIN; \
+                                 UPDATE t SET body = randomblob(500); \
+                                 DELETE FROM t WHERE id % 3 = 0; \
+                                 ROLLBACK",
+                            )
+                            .unwrap();
+                    }
+                    assert_eq!(
+                        integrity_and_count(&connection, "t"),
+                        ("ok".to_string(), 100),
+                        "{combination}"
+                    );
+                    drop(connection);
+
+                    let connection = Connection::open(&path).unwrap();
+                    assert_eq!(
+                        integrity_and_count(&connection, "t"),
+                        ("ok".to_string(), 100),
+                        "{combination} after reopening"
+                    );
+                }
+            }
+        }
+
+        // WAL needs shared memory, so it's refused instead of bricking the database
+        for locking_mode in ["NORMAL", "EXCLUSIVE"] {
+            let path = format!("matrix_wal_{}.db", locking_mode.to_lowercase());
+            let connection = Connection::open(&path).unwrap();
+            connection
+                .execute(format!(
+                    "PRAGMA locking_mode={locking_mode}; CREATE TABLE t (id INTEGER PRIMARY KEY)"
+                ))
+                .unwrap();
+            let err = connection.execute("PRAGMA journal_mode=WAL").unwrap_err();
+            assert!(
+                err.to_string().contains("WAL is not supported"),
+                "{locking_mode}: {err}"
+            );
+            connection.execute("INSERT INTO t DEFAULT VALUES").unwrap();
+            drop(connection);
+            let connection = Connection::open(&path).unwrap();
+            assert_eq!(
+                integrity_and_count(&connection, "t"),
+                ("ok".to_string(), 1),
+                "{locking_mode}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_large_page_size() {
+        init_vfs();
+        let connection = Connection::open("large_pages.db").unwrap();
+        connection
+            .execute(
+                "PRAGMA page_size=65536; \
+                 CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 100) \
+                 INSERT INTO t (body) SELECT randomblob(2000) FROM s",
+            )
+            .unwrap();
+        drop(connection);
+
+        let connection = Connection::open("large_pages.db").unwrap();
+        let mut stmt = connection.prepare("PRAGMA page_size").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 65536);
+        drop(stmt);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 100)
+        );
+    }
+
+    fn size_limit(connection: &Connection, arg: Option<&str>) -> String {
+        let sql = match arg {
+            Some(arg) => format!("PRAGMA s3qlite_size_limit={arg}"),
+            None => "PRAGMA s3qlite_size_limit".to_string(),
+        };
+        let mut stmt = connection.prepare(sql).unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        stmt.read(0).unwrap()
+    }
+
+    #[test]
+    fn test_size_limit() {
+        init_vfs();
+        let connection = Connection::open("size_limit.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB)")
+            .unwrap();
+        assert_eq!(
+            size_limit(&connection, None),
+            r#"{"limit":null,"size":8192,"headroom":null}"#
+        );
+        // the limit can't be below the current size
+        assert!(
+            connection
+                .execute("PRAGMA s3qlite_size_limit=4096")
+                .is_err()
+        );
+        assert_eq!(
+            size_limit(&connection, Some("65536")),
+            r#"{"limit":65536,"size":8192,"headroom":57344}"#
+        );
+
+        // growing past the limit fails, and the transaction is rolled back
+        let err = connection
+            .execute(
+                "WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 100) \
+                 INSERT INTO t (body) SELECT randomblob(1000) FROM s",
+            )
+            .unwrap_err();
+        assert_eq!(err.code, Some(13), "{err}"); // SQLITE_FULL
+        connection
+            .execute("INSERT INTO t (body) VALUES (randomblob(1000))")
+            .unwrap();
+        drop(connection);
+
+        // the limit is kept with the database
+        let connection = Connection::open("size_limit.db").unwrap();
+        assert_eq!(integrity_and_count(&connection, "t"), ("ok".to_string(), 1));
+        assert!(size_limit(&connection, None).starts_with(r#"{"limit":65536,"#));
+
+        // 0 removes it
+        assert!(size_limit(&connection, Some("0")).starts_with(r#"{"limit":null,"#));
+        connection
+            .execute(
+                "WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 100) \
+                 INSERT INTO t (body) SELECT randomblob(1000) FROM s",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_scan_mode() {
+        init_vfs();
+        let connection = Connection::open("scan.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 500) \
+                 INSERT INTO t (body) SELECT randomblob(1000) FROM s",
+            )
+            .unwrap();
+        drop(connection);
+
+        let connection = Connection::open_with_flags(
+            "file:scan.db?scan=true",
+            sqlite::OpenFlags::new().with_read_write().with_uri(),
+        )
+        .unwrap();
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 500)
+        );
+        let mut stmt = connection
+            .prepare("SELECT sum(length(body)) FROM t")
+            .unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<i64, _>(0).unwrap(), 500 * 1000);
+        drop(stmt);
+
+        // scan handles are read-only
+        let err = connection
+            .execute("INSERT INTO t (body) VALUES (x'00')")
+            .unwrap_err();
+        assert!(err.to_string().contains("readonly"), "{err}");
+    }
+
+    #[test]
+    fn test_change_page_size() {
+        init_vfs();
+        let connection = Connection::open("change_page_size.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                 CREATE INDEX t_body ON t (body); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 300) \
+                 INSERT INTO t (body) SELECT randomblob(500) FROM s",
+            )
+            .unwrap();
+        assert!(crate::change_page_size(&connection, 3000, |_| {}).is_err());
+
+        let pages = crate::change_page_size(&connection, 65536, |_| {}).unwrap();
+        assert!(pages < 10, "{pages}");
+        drop(connection);
+
+        let connection = Connection::open("change_page_size.db").unwrap();
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA page_size").as_deref(),
+            Some("65536")
+        );
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 300)
+        );
+    }
+
+    #[test]
+    fn test_progress_handler() {
+        use std::ffi::{CStr, c_char, c_void};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static REPORTS: AtomicU64 = AtomicU64::new(0);
+        static CANCEL: AtomicU64 = AtomicU64::new(1);
+
+        // the handler is shared by every test in the process, so only count and cancel
+        // this test's file
+        unsafe extern "C" fn on_progress(
+            _arg: *mut c_void,
+            operation: *const c_char,
+            path: *const c_char,
+            _pages_done: u64,
+        ) -> i32 {
+            let (operation, path) = unsafe { (CStr::from_ptr(operation), CStr::from_ptr(path)) };
+            if operation.to_bytes() != b"truncate"
+                || !path.to_bytes().ends_with(b"progress_handler.db")
+            {
+                return 0;
+            }
+            REPORTS.fetch_add(1, Ordering::Relaxed);
+            CANCEL.load(Ordering::Relaxed) as i32
+        }
+
+        init_vfs();
+        let connection = Connection::open("progress_handler.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 800) \
+                 INSERT INTO t (body) SELECT randomblob(3000) FROM s; \
+                 DELETE FROM t WHERE id > 10",
+            )
+            .unwrap();
+
+        unsafe { s3qlite_progress_handler(Some(on_progress), std::ptr::null_mut()) };
+        // shrinking the file drops hundreds of pages, cancelled at the first report
+        assert!(connection.execute("VACUUM").is_err());
+        assert_eq!(REPORTS.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 10)
+        );
+
+        CANCEL.store(0, Ordering::Relaxed);
+        connection.execute("VACUUM").unwrap();
+        unsafe { s3qlite_progress_handler(None, std::ptr::null_mut()) };
+        assert!(REPORTS.load(Ordering::Relaxed) > 1);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 10)
+        );
+    }
+
+    #[test]
+    fn test_interrupt() {
+        use std::ffi::{CStr, c_char, c_void};
+        use std::sync::atomic::{AtomicI64, Ordering};
+
+        const FCNTL_INTERRUPT_ID: i32 = 0x5333_0001;
+        static HANDLE_ID: AtomicI64 = AtomicI64::new(-1);
+
+        // interrupts the handle from inside its own truncate, as another thread would
+        unsafe extern "C" fn on_progress(
+            _arg: *mut c_void,
+            operation: *const c_char,
+            path: *const c_char,
+            _pages_done: u64,
+        ) -> i32 {
+            let (operation, path) = unsafe { (CStr::from_ptr(operation), CStr::from_ptr(path)) };
+            if operation.to_bytes() == b"truncate" && path.to_bytes().ends_with(b"interrupt.db") {
+                unsafe { s3qlite_interrupt(HANDLE_ID.load(Ordering::Relaxed)) };
+            }
+            0
+        }
+
+        init_vfs();
+        let connection = Connection::open("interrupt.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 800) \
+                 INSERT INTO t (body) SELECT randomblob(3000) FROM s; \
+                 DELETE FROM t WHERE id > 10",
+            )
+            .unwrap();
+
+        let mut handle_id = -1i64;
+        let rc = unsafe {
+            sqlite::ffi::sqlite3_file_control(
+                connection.as_raw(),
+                c"main".as_ptr(),
+                FCNTL_INTERRUPT_ID,
+                (&raw mut handle_id).cast(),
+            )
+        };
+        assert_eq!(rc, 0);
+        HANDLE_ID.store(handle_id, Ordering::Relaxed);
+        assert_eq!(unsafe { s3qlite_interrupt(i64::MAX) }, 12); // SQLITE_NOTFOUND
+
+        // an interrupt with nothing running doesn't affect later operations
+        assert_eq!(unsafe { s3qlite_interrupt(handle_id) }, 0);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 10)
+        );
+
+        unsafe { s3qlite_progress_handler(Some(on_progress), std::ptr::null_mut()) };
+        let err = connection.execute("VACUUM").unwrap_err();
+        unsafe { s3qlite_progress_handler(None, std::ptr::null_mut()) };
+        assert!(err.to_string().contains("interrupt"), "{err}");
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 10)
+        );
+        connection.execute("VACUUM").unwrap();
+    }
+
+    #[test]
+    fn test_dump_stats() {
+        init_vfs();
+        let connection = Connection::open("dump_stats.db").unwrap();
+        let mut dumps = Vec::new();
+        for _ in 0..50 {
+            let path = crate::query_string(&connection, "PRAGMA s3qlite_dump_stats").unwrap();
+            dumps.push(std::path::PathBuf::from(path));
+        }
+
+        let latest = std::fs::read_to_string(dumps.last().unwrap()).unwrap();
+        let lines: Vec<&str> = latest.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("timestamp_ms,cache_verified_pages,"));
+        // the runtime's scheduler gauges ride along with the counters
+        assert!(lines[0].contains(",runtime_workers,"), "{latest}");
+        assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+        let stats = crate::query_string(&connection, "PRAGMA s3qlite_stats").unwrap();
+        assert!(
+            stats.contains("\"runtime_worker_utilization_pct\":"),
+            "{stats}"
+        );
+        // the in-memory store never throttles, so the window stays at THROTTLE_MAX_WINDOW
+        assert!(stats.contains("\"throttled_requests\":0,"), "{stats}");
+        assert!(stats.contains("\"throttle_window\":64"), "{stats}");
+
+        // only the newest STATS_DUMP_KEEP (48) are kept
+        assert!(!dumps[0].exists());
+        let kept = std::fs::read_dir(dumps[0].parent().unwrap())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("stats-")
+            })
+            .count();
+        assert_eq!(kept, 48);
+    }
+
+    // Runs in a child process started by test_memory_budget, a no-op otherwise.
+    #[test]
+    fn memory_budget_workload() {
+        if std::env::var("S3QLITE_MEMORY_BUDGET_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("test_memory_budget.db").unwrap();
+        let gauge = |name: &str| -> u64 {
+            let stats = crate::query_string(&connection, "PRAGMA s3qlite_stats").unwrap();
+            let (_, rest) = stats.split_once(&format!("\"{name}\":")).unwrap();
+            rest.split([',', '}']).next().unwrap().parse().unwrap()
+        };
+        assert_eq!(gauge("memory_budget_bytes"), 1_000_000);
+        connection
+            .execute(
+                "PRAGMA cache_size = 10; \
+                 CREATE TABLE t (body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 400) \
+                 INSERT INTO t SELECT randomblob(3000) FROM s",
+            )
+            .unwrap();
+        connection
+            .execute("SELECT sum(length(body)) FROM t")
+            .unwrap();
+
+        // the cache passed the budget, and was shrunk back under it
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+        assert_eq!(gauge("memory_degradations"), 1);
+        assert_eq!(gauge("memory_degraded"), 0);
+        assert!(gauge("memory_bytes") < 700_000);
+
+        // a cache that can't outgrow the budget again, and writes that still go through
+        connection
+            .execute(
+                "SELECT sum(length(body)) FROM t; \
+                 BEGIN; \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 100) \
+                 INSERT INTO t SELECT randomblob(3000) FROM s; \
+                 COMMIT",
+            )
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(gauge("memory_degradations"), 1);
+        assert!(gauge("memory_bytes") <= 700_000);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 500)
+        );
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let output = run_child(
+            "memory_budget_workload",
+            &[
+                ("MEMORY_BUDGET_BYTES", "1000000"),
+                ("S3QLITE_MEMORY_BUDGET_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_cache_policy, a no-op otherwise.
+    #[test]
+    fn cache_policy_workload() {
+        if std::env::var("S3QLITE_CACHE_POLICY_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("test_cache_policy.db").unwrap();
+        connection
+            .execute(
+                "PRAGMA cache_size = 10; \
+                 CREATE TABLE hot (body BLOB); \
+                 CREATE TABLE cold (body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 40) \
+                 INSERT INTO hot SELECT randomblob(3000) FROM s; \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 600) \
+                 INSERT INTO cold SELECT randomblob(3000) FROM s",
+            )
+            .unwrap();
+        // a hot working set, interrupted by full scans of a table bigger than the cache
+        for _ in 0..5 {
+            for _ in 0..3 {
+                connection
+                    .execute("SELECT sum(length(body)) FROM hot")
+                    .unwrap();
+            }
+            connection
+                .execute("SELECT sum(length(body)) FROM cold")
+                .unwrap();
+        }
+
+        let policy = crate::query_string(&connection, "PRAGMA s3qlite_cache_policy").unwrap();
+        let hits = |name: &str| -> u64 {
+            let (_, rest) = policy
+                .split_once(&format!("\"{name}\":{{\"hits\":"))
+                .unwrap();
+            rest.split(',').next().unwrap().parse().unwrap()
+        };
+        assert!(
+            policy.starts_with("{\"policy\":\"slru\",\"hits\":"),
+            "{policy}"
+        );
+        // the simulated slru cache saw exactly what the real one did
+        let (_, real) = policy.split_once("\"hits\":").unwrap();
+        assert_eq!(
+            real.split(',').next().unwrap().parse::<u64>().unwrap(),
+            hits("slru")
+        );
+        assert!(hits("slru") > hits("lru"), "{policy}");
+        assert!(hits("tinylfu") > hits("lru"), "{policy}");
+    }
+
+    #[test]
+    fn test_cache_policy() {
+        let output = run_child(
+            "cache_policy_workload",
+            &[
+                ("CACHE_POLICY", "slru"),
+                ("CACHE_SIMULATE", "true"),
+                ("MAX_CACHE_BYTES", "409600"),
+                ("S3QLITE_CACHE_POLICY_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in child processes started by test_super_journal_crash, a no-op otherwise.
+    // `crash:<needle>` commits a transaction over two databases and crashes at the first
+    // delete of a file containing `needle`, `recover` prints what each database holds.
+    #[test]
+    fn super_journal_workload() {
+        use std::ffi::{CStr, c_char, c_int, c_void};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static ARMED: AtomicBool = AtomicBool::new(false);
+        static NEEDLE: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+        // every VFS log line reaches SQLite's logger, so crash on the chosen delete with
+        // everything before it durable
+        unsafe extern "C" fn on_log(_arg: *mut c_void, _code: c_int, msg: *const c_char) {
+            let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy();
+            if ARMED.load(Ordering::Relaxed)
+                && msg.starts_with("delete: path=")
+                && msg.contains(NEEDLE.get().unwrap().as_str())
+            {
+                unsafe { s3qlite_app_background() };
+                std::process::abort();
+            }
+        }
+
+        let Ok(mode) = std::env::var("S3QLITE_SUPER_JOURNAL_CHILD") else {
+            return;
+        };
+        if let Some(needle) = mode.strip_prefix("crash:") {
+            NEEDLE.set(needle.to_string()).unwrap();
+            let log: unsafe extern "C" fn(*mut c_void, c_int, *const c_char) = on_log;
+            unsafe {
+                sqlite::ffi::sqlite3_config(
+                    sqlite::ffi::SQLITE_CONFIG_LOG,
+                    log,
+                    std::ptr::null_mut::<c_void>(),
+                )
+            };
+        }
+        init_vfs();
+        let connection = Connection::open("super_a.db").unwrap();
+        connection.execute("ATTACH 'super_b.db' AS b").unwrap();
+        if mode == "recover" {
+            let a = crate::query_string(&connection, "SELECT v FROM main.t").unwrap();
+            let b = crate::query_string(&connection, "SELECT v FROM b.t").unwrap();
+            let check = crate::query_string(&connection, "PRAGMA integrity_check").unwrap();
+            println!("recovered {a} {b} {check}");
+            let recoveries = crate::query_string(&connection, "PRAGMA s3qlite_recoveries").unwrap();
+            println!("recoveries {recoveries}");
+            return;
+        }
+        connection
+            .execute(
+                "CREATE TABLE main.t (v INTEGER); INSERT INTO main.t VALUES (0); \
+                 CREATE TABLE b.t (v INTEGER); INSERT INTO b.t VALUES (0)",
+            )
+            .unwrap();
+        ARMED.store(true, Ordering::Relaxed);
+        connection
+            .execute("BEGIN; UPDATE main.t SET v = 1; UPDATE b.t SET v = 1; COMMIT")
+            .unwrap();
+        panic!("the commit should have crashed");
+    }
+
+    #[test]
+    fn test_super_journal_crash() {
+        // crashing before the super-journal is deleted rolls both databases back, after
+        // it both keep the commit and their journals are discarded
+        for (needle, expected, outcome) in [
+            ("-mj", "0 0", "rolled_back"),
+            ("-journal", "1 1", "discarded"),
+        ] {
+            let dir = std::env::temp_dir().join(format!(
+                "s3qlite-super-journal-{}-{}",
+                std::process::id(),
+                needle.trim_start_matches('-')
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let url = format!("file://{}", dir.display());
+            let child = |mode: &str| {
+                run_child(
+                    "super_journal_workload",
+                    &[
+                        ("OBJECT_STORE_URL", &url),
+                        ("S3QLITE_SUPER_JOURNAL_CHILD", mode),
+                    ],
+                )
+            };
+
+            let output = child(&format!("crash:{needle}"));
+            assert!(!output.status.success(), "{output:?}");
+            let output = child("recover");
+            assert!(output.status.success(), "{output:?}");
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            assert!(
+                stdout.contains(&format!("recovered {expected} ok")),
+                "{needle}: {stdout}"
+            );
+            // one recovery report per database
+            let recoveries = stdout
+                .lines()
+                .find_map(|line| line.strip_prefix("recoveries "))
+                .unwrap();
+            for database in ["super_a.db", "super_b.db"] {
+                let report = format!(
+                    "\"database\":\"{database}\",\"found\":\"hot_journal\",\"outcome\":\"{outcome}\""
+                );
+                assert_eq!(recoveries.matches(&report).count(), 1, "{recoveries}");
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    // Runs in a child process started by test_mobile_lifecycle, a no-op otherwise.
+    #[test]
+    fn mobile_lifecycle_workload() {
+        let Ok(mode) = std::env::var("S3QLITE_LIFECYCLE_CHILD") else {
+            return;
+        };
+        let url = std::ffi::CString::new(std::env::var("S3QLITE_LIFECYCLE_URL").unwrap()).unwrap();
+        // registering starts nothing, so there is nothing to flush yet and configuration
+        // is still open
+        init_vfs();
+        assert_eq!(unsafe { s3qlite_app_background() }, 0);
+        let rc = unsafe { s3qlite_config_set(c"OBJECT_STORE_URL".as_ptr(), url.as_ptr()) };
+        assert_eq!(rc, 0);
+
+        let connection = Connection::open("lifecycle.db").unwrap();
+        if mode == "read" {
+            let count = crate::query_string(&connection, "SELECT count(*) FROM t").unwrap();
+            println!("count {count}");
+            return;
+        }
+        connection
+            .execute("CREATE TABLE t (v INTEGER); INSERT INTO t VALUES (1), (2)")
+            .unwrap();
+        // the store is built now, so it's too late to change it
+        let rc = unsafe { s3qlite_config_set(c"OBJECT_STORE_URL".as_ptr(), c"memory://".as_ptr()) };
+        assert_eq!(rc, 21, "expected SQLITE_MISUSE");
+
+        // the app is suspended and killed right after being backgrounded
+        assert_eq!(unsafe { s3qlite_app_background() }, 0);
+        std::process::abort();
+    }
+
+    #[test]
+    fn test_mobile_lifecycle() {
+        let dir = std::env::temp_dir().join(format!("s3qlite-lifecycle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = format!("file://{}", dir.display());
+        // the override wins over the environment
+        let child = |mode: &str| {
+            run_child(
+                "mobile_lifecycle_workload",
+                &[
+                    ("OBJECT_STORE_URL", "memory://"),
+                    ("S3QLITE_LIFECYCLE_URL", &url),
+                    ("S3QLITE_LIFECYCLE_CHILD", mode),
+                ],
+            )
+        };
+
+        let output = child("write");
+        // aborted after flushing, rather than failing an assertion first
+        assert!(!output.status.success(), "{output:?}");
+        assert!(
+            !String::from_utf8_lossy(&output.stderr).contains("panicked"),
+            "{output:?}"
+        );
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_some());
+
+        let output = child("read");
+        assert!(output.status.success(), "{output:?}");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("count 2"), "{stdout}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extension_entry_point() {
+        use std::ffi::{CStr, c_char, c_void};
+        use std::sync::Mutex;
+
+        static LOADED: Mutex<Option<(i32, String)>> = Mutex::new(None);
+
+        // stands in for a host calling the entry point itself, as SQLite's own loader
+        // throws the message away
+        unsafe extern "C" fn capture(
+            db: *mut c_void,
+            pz_err_msg: *mut *mut c_char,
+            p_api: *mut c_void,
+        ) -> i32 {
+            let rc = unsafe { sqlite3_s3qlite_init(db, pz_err_msg, p_api) };
+            let msg = unsafe { *pz_err_msg };
+            if !msg.is_null() {
+                let json = unsafe { CStr::from_ptr(msg) }
+                    .to_string_lossy()
+                    .into_owned();
+                unsafe {
+                    sqlite::ffi::sqlite3_free(msg.cast());
+                    *pz_err_msg = std::ptr::null_mut();
+                }
+                LOADED.lock().unwrap().get_or_insert((rc, json));
+            }
+            0
+        }
+
+        type EntryPoint = unsafe extern "C" fn(*mut c_void, *mut *mut c_char, *mut c_void) -> i32;
+        let entry_point: unsafe extern "C" fn() =
+            unsafe { std::mem::transmute(capture as EntryPoint) };
+        unsafe { sqlite::ffi::sqlite3_auto_extension(Some(entry_point)) };
+        let connection = sqlite::open(":memory:");
+        unsafe { sqlite::ffi::sqlite3_cancel_auto_extension(Some(entry_point)) };
+        drop(connection.unwrap());
+
+        let (rc, json) = LOADED.lock().unwrap().clone().unwrap();
+        assert_eq!(rc, 256, "SQLITE_OK_LOAD_PERMANENTLY");
+        assert!(json.contains("\"vfs\":\"s3qlite\""), "{json}");
+        assert!(json.contains("\"default\":false"), "{json}");
+        assert!(!json.contains("://"), "{json}");
+
+        let connection = Connection::open_with_flags(
+            "file:extension_entry_point.db?vfs=s3qlite",
+            sqlite::OpenFlags::new()
+                .with_create()
+                .with_read_write()
+                .with_uri(),
+        )
+        .unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1)")
+            .unwrap();
+        let mut stmt = connection.prepare("PRAGMA s3qlite_capabilities").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_eq!(stmt.read::<String, _>(0).unwrap(), json);
+    }
+
+    #[test]
+    fn test_open_without_create() {
+        init_vfs();
+        // a missing database isn't created without SQLITE_OPEN_CREATE
+        let read_write = sqlite::OpenFlags::new().with_read_write();
+        assert!(Connection::open_with_flags("open_without_create.db", read_write).is_err());
+
+        let connection = Connection::open("open_without_create.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1)")
+            .unwrap();
+        drop(connection);
+
+        let connection = Connection::open_with_flags(
+            "open_without_create.db",
+            sqlite::OpenFlags::new().with_read_write(),
+        )
+        .unwrap();
+        assert_eq!(integrity_and_count(&connection, "t"), ("ok".to_string(), 1));
+    }
+
+    // Runs in a child process started by test_set_readonly, a no-op otherwise.
+    #[test]
+    fn set_readonly_workload() {
+        if std::env::var("S3QLITE_SET_READONLY_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("set_readonly.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1)")
+            .unwrap();
+
+        // changing the mark takes the admin token
+        let err = connection
+            .execute("PRAGMA s3qlite_set_readonly=true")
+            .unwrap_err();
+        assert!(err.to_string().contains("admin_token"), "{err}");
+        assert!(
+            connection
+                .execute("PRAGMA s3qlite_admin_token='wrong'")
+                .is_err()
+        );
+        connection
+            .execute("PRAGMA s3qlite_admin_token='secret'")
+            .unwrap();
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA s3qlite_set_readonly=true").unwrap(),
+            "true"
+        );
+
+        // the connection that was open read-write can't commit any more
+        let err = connection.execute("INSERT INTO t VALUES (2)").unwrap_err();
+        assert!(err.to_string().contains("readonly"), "{err}");
+
+        // nor can a new one, but it can read
+        let other = Connection::open("set_readonly.db").unwrap();
+        assert_eq!(integrity_and_count(&other, "t"), ("ok".to_string(), 1));
+        assert!(other.execute("INSERT INTO t VALUES (3)").is_err());
+        drop(other);
+
+        connection
+            .execute("PRAGMA s3qlite_set_readonly=false")
+            .unwrap();
+        let other = Connection::open("set_readonly.db").unwrap();
+        other.execute("INSERT INTO t VALUES (4)").unwrap();
+        assert_eq!(integrity_and_count(&other, "t"), ("ok".to_string(), 2));
+    }
+
+    #[test]
+    fn test_set_readonly() {
+        let output = run_child(
+            "set_readonly_workload",
+            &[
+                ("ADMIN_TOKEN", "secret"),
+                ("S3QLITE_SET_READONLY_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test]
+    fn test_kv() {
+        init_vfs();
+        let connection = Connection::open("kv.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        // an unset key has no row, and no column for prepare() to name
+        let get = |connection: &Connection, key: &str| {
+            let mut value = None;
+            connection
+                .iterate(format!("PRAGMA s3qlite_get='{key}'"), |row| {
+                    value = row[0].1.map(str::to_string);
+                    true
+                })
+                .unwrap();
+            value
+        };
+
+        // outside a transaction a put is stored right away
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA s3qlite_put='schema_version=1'").unwrap(),
+            "stored"
+        );
+        assert_eq!(get(&connection, "schema_version").unwrap(), "1");
+        assert_eq!(get(&connection, "missing"), None);
+        assert!(connection.execute("PRAGMA s3qlite_put='no_value'").is_err());
+
+        // inside one it commits with the transaction's pages
+        let other = Connection::open("kv.db").unwrap();
+        connection
+            .execute("BEGIN; INSERT INTO t VALUES (1)")
+            .unwrap();
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA s3qlite_put='schema_version=2'").unwrap(),
+            "staged"
+        );
+        assert_eq!(get(&connection, "schema_version").unwrap(), "2");
+        connection.execute("COMMIT").unwrap();
+        assert_eq!(get(&other, "schema_version").unwrap(), "2");
+
+        // or not at all
+        connection
+            .execute("BEGIN; INSERT INTO t VALUES (2)")
+            .unwrap();
+        connection
+            .execute("PRAGMA s3qlite_put='schema_version=3'")
+            .unwrap();
+        connection.execute("ROLLBACK").unwrap();
+        assert_eq!(get(&other, "schema_version").unwrap(), "2");
+
+        // a transaction that writes no pages still commits its puts
+        connection.execute("BEGIN IMMEDIATE").unwrap();
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA s3qlite_put='schema_version=4'").unwrap(),
+            "staged"
+        );
+        connection.execute("COMMIT").unwrap();
+        assert_eq!(get(&other, "schema_version").unwrap(), "4");
+        connection
+            .execute("BEGIN IMMEDIATE; PRAGMA s3qlite_put='schema_version=5'; ROLLBACK")
+            .unwrap();
+        assert_eq!(get(&other, "schema_version").unwrap(), "4");
+
+        // nor does one that spilled pages to the file before rolling back
+        connection
+            .execute(
+                "PRAGMA cache_size = 2; BEGIN; \
+                 WITH RECURSIVE s(x) AS (SELECT 100 UNION ALL SELECT x + 1 FROM s WHERE x < 5000) \
+                 INSERT INTO t SELECT x FROM s; \
+                 PRAGMA s3qlite_put='schema_version=6'",
+            )
+            .unwrap();
+        connection
+            .execute("ROLLBACK; PRAGMA cache_size = -2000")
+            .unwrap();
+        assert_eq!(get(&other, "schema_version").unwrap(), "4");
+        assert_eq!(
+            crate::query_string(&other, "SELECT count(*) FROM t").unwrap(),
+            "1"
+        );
+
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA s3qlite_delete='schema_version'").unwrap(),
+            "stored"
+        );
+        assert_eq!(get(&other, "schema_version"), None);
+    }
+
+    // Runs in a child process started by test_heatmap, a no-op otherwise.
+    #[test]
+    fn heatmap_workload() {
+        if std::env::var("S3QLITE_HEATMAP_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("heatmap.db").unwrap();
+        connection
+            .execute(
+                "PRAGMA cache_size = 10; \
+                 CREATE TABLE hot (body BLOB); \
+                 CREATE TABLE cold (body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 40) \
+                 INSERT INTO hot SELECT randomblob(3000) FROM s; \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 40) \
+                 INSERT INTO cold SELECT randomblob(3000) FROM s",
+            )
+            .unwrap();
+        for _ in 0..10 {
+            connection
+                .execute("SELECT sum(length(body)) FROM hot")
+                .unwrap();
+        }
+
+        let csv = crate::heatmap_csv(&connection).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("page,reads,writes,object"));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        // busiest first: page 1, read by every transaction, then the scanned table
+        assert_eq!(rows[0][0], "1", "{csv}");
+        assert_eq!(rows[1][3], "hot", "{csv}");
+        let reads = |object: &str| -> u64 {
+            rows.iter()
+                .filter(|row| row[3] == object)
+                .map(|row| row[1].parse::<u64>().unwrap())
+                .sum()
+        };
+        assert!(reads("hot") > 10 * reads("cold"), "{csv}");
+        assert!(
+            rows.iter().any(|row| row[3] == "cold" && row[2] != "0"),
+            "{csv}"
+        );
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let output = run_child(
+            "heatmap_workload",
+            &[
+                ("HEATMAP_SAMPLE_EVERY", "1"),
+                ("S3QLITE_HEATMAP_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test]
+    fn test_features() {
+        init_vfs();
+        let connection = Connection::open("features.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1)")
+            .unwrap();
+        let features = crate::query_string(&connection, "PRAGMA s3qlite_features").unwrap();
+        assert!(
+            features.starts_with("{\"batch_atomic\":true,\"wal\":false,\"snapshots\":true,"),
+            "{features}"
+        );
+        assert!(features.ends_with("\"readonly\":false}"), "{features}");
+
+        // a snapshot can't be written
+        connection
+            .execute("PRAGMA s3qlite_snapshot='features'")
+            .unwrap();
+        connection
+            .execute("ATTACH 'features.db@features' AS old")
+            .unwrap();
+        let features = crate::query_string(&connection, "PRAGMA old.s3qlite_features").unwrap();
+        assert!(
+            features.starts_with("{\"batch_atomic\":false,\"wal\":false,\"snapshots\":false,"),
+            "{features}"
+        );
+        assert!(features.ends_with("\"readonly\":true}"), "{features}");
+    }
+
+    // Runs in a child process started by test_shadow_writes, a no-op otherwise.
+    #[test]
+    fn shadow_writes_workload() {
+        if std::env::var("S3QLITE_SHADOW_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("shadow_writes.db").unwrap();
+        // new pages, rewritten pages, a journaled transaction and a truncating VACUUM
+        connection
+            .execute(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 200) \
+                 INSERT INTO t (body) SELECT randomblob(3000) FROM s; \
+                 UPDATE t SET body = randomblob(100) WHERE id % 3 = 0; \
+                 PRAGMA cache_size = 10; \
+                 BEGIN; UPDATE t SET body = randomblob(2000) WHERE id > 20; COMMIT; \
+                 DELETE FROM t WHERE id > 50; \
+                 VACUUM",
+            )
+            .unwrap();
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 50)
+        );
+
+        let report = crate::query_string(&connection, "PRAGMA s3qlite_shadow_verify").unwrap();
+        assert!(report.ends_with(",\"mismatched\":[]}"), "{report}");
+        let checked: usize = report["{\"checked\":".len()..]
+            .split(',')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(checked > 200, "{report}");
+        let stats = crate::query_string(&connection, "PRAGMA s3qlite_stats").unwrap();
+        assert!(stats.contains("\"shadow_mismatches\":0,"), "{stats}");
+    }
+
+    #[test]
+    fn test_shadow_writes() {
+        let output = run_child(
+            "shadow_writes_workload",
+            &[("SHADOW_WRITES", "true"), ("S3QLITE_SHADOW_CHILD", "1")],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_generation, a no-op otherwise.
+    #[test]
+    fn generation_workload() {
+        if std::env::var("S3QLITE_GENERATION_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let writer = Connection::open("generation.db").unwrap();
+        writer
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        let generation = |connection: &Connection| -> u64 {
+            crate::query_string(connection, "PRAGMA s3qlite_generation")
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        let before = generation(&writer);
+        assert!(before > 0);
+        writer.execute("INSERT INTO t VALUES (1)").unwrap();
+        let after = generation(&writer);
+        assert_eq!(after, before + 1);
+
+        // reads don't move it
+        let reader = Connection::open("generation.db").unwrap();
+        assert_eq!(integrity_and_count(&reader, "t"), ("ok".to_string(), 1));
+        assert_eq!(generation(&reader), after);
+        let caught_up =
+            crate::query_string(&reader, &format!("PRAGMA s3qlite_min_generation={after}"));
+        assert_eq!(caught_up, Some(after.to_string()));
+
+        // a reader waits for a commit still in progress
+        let commit = std::thread::spawn(move || {
+            writer
+                .execute("BEGIN IMMEDIATE; INSERT INTO t VALUES (2)")
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            writer.execute("COMMIT").unwrap();
+        });
+        let next = after + 1;
+        let caught_up =
+            crate::query_string(&reader, &format!("PRAGMA s3qlite_min_generation={next}"));
+        assert_eq!(caught_up, Some(next.to_string()));
+        assert_eq!(integrity_and_count(&reader, "t"), ("ok".to_string(), 2));
+        commit.join().unwrap();
+
+        // and gives up on one that never comes
+        let err = reader
+            .execute(format!("PRAGMA s3qlite_min_generation={}", next + 1))
+            .unwrap_err();
+        assert!(err.to_string().contains("generation"), "{err}");
+    }
+
+    #[test]
+    fn test_generation() {
+        let output = run_child(
+            "generation_workload",
+            &[
+                ("MIN_GENERATION_WAIT_MS", "300"),
+                ("S3QLITE_GENERATION_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_journal_existence_cache, a no-op otherwise.
+    #[test]
+    fn journal_existence_cache_workload() {
+        if std::env::var("S3QLITE_JOURNAL_CACHE_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let absent_journal_hits = |connection: &Connection| -> u64 {
+            let stats = crate::query_string(connection, "PRAGMA s3qlite_stats").unwrap();
+            stats
+                .split("\"absent_journal_hits\":")
+                .nth(1)
+                .and_then(|rest| rest.split([',', '}']).next())
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        let connection = Connection::open("journal_cache.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        let before = absent_journal_hits(&connection);
+        for i in 0..20 {
+            connection
+                .execute(format!("INSERT INTO t VALUES ({i})"))
+                .unwrap();
+        }
+        // steady-state transactions find their journals missing without asking the store
+        assert!(absent_journal_hits(&connection) >= before + 20);
+
+        // a journal created again is seen again
+        connection
+            .execute("BEGIN; INSERT INTO t VALUES (100); ROLLBACK")
+            .unwrap();
+        connection
+            .execute("BEGIN; DELETE FROM t WHERE id < 10; COMMIT")
+            .unwrap();
+        let reader = Connection::open("journal_cache.db").unwrap();
+        assert_eq!(integrity_and_count(&reader, "t"), ("ok".to_string(), 10));
+    }
+
+    #[test]
+    fn test_journal_existence_cache() {
+        // a PATH_KEY stores journals under names without their suffix
+        let path_key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        for path_key in [None, Some(path_key)] {
+            let mut envs = vec![
+                ("JOURNAL_EXISTENCE_CACHE", "true"),
+                ("S3QLITE_JOURNAL_CACHE_CHILD", "1"),
+            ];
+            envs.extend(path_key.map(|path_key| ("PATH_KEY", path_key)));
+            let output = run_child("journal_existence_cache_workload", &envs);
+            assert!(output.status.success(), "{output:?}");
+        }
+    }
+
+    // Runs in a child process started by test_extent, a no-op otherwise.
+    #[test]
+    fn extent_workload() {
+        use sqlite::ffi::{SQLITE_FCNTL_FILE_POINTER, sqlite3_file};
+
+        if std::env::var("S3QLITE_EXTENT_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("extent.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1)")
+            .unwrap();
+
+        // SQLite doesn't leave gaps on its own, so the file is driven directly
+        let mut file: *mut sqlite3_file = std::ptr::null_mut();
+        let rc = unsafe {
+            sqlite::ffi::sqlite3_file_control(
+                connection.as_raw(),
+                c"main".as_ptr(),
+                SQLITE_FCNTL_FILE_POINTER,
+                (&raw mut file).cast(),
+            )
+        };
+        assert_eq!(rc, 0);
+        let methods = unsafe { &*(*file).pMethods };
+        let size = || {
+            let mut size = 0;
+            assert_eq!(unsafe { methods.xFileSize.unwrap()(file, &mut size) }, 0);
+            size
+        };
+        let write = |offset: i64, data: &[u8]| unsafe {
+            methods.xWrite.unwrap()(file, data.as_ptr().cast(), data.len() as i32, offset)
+        };
+        let read = |offset: i64, len: usize| {
+            let mut data = vec![0xffu8; len];
+            let rc = unsafe {
+                methods.xRead.unwrap()(file, data.as_mut_ptr().cast(), len as i32, offset)
+            };
+            (rc, data)
+        };
+        let truncate = |size: i64| unsafe { methods.xTruncate.unwrap()(file, size) };
+
+        // a write past a gap extends the file, and the gap reads as zeros
+        let original = size();
+        let end = original + 3 * 4096 + 110;
+        assert_eq!(write(end - 100, &[7; 100]), 0);
+        assert_eq!(size(), end);
+        assert_eq!(read(original, 3 * 4096), (0, vec![0; 3 * 4096]));
+        let (rc, data) = read(end - 200, 200);
+        assert_eq!(rc, 0);
+        assert_eq!(data[..100], [0; 100]);
+        assert_eq!(data[100..], [7; 100]);
+
+        // growing by truncate or a zero-length write only moves the size
+        assert_eq!(truncate(end + 5 * 4096), 0);
+        assert_eq!(size(), end + 5 * 4096);
+        assert_eq!(read(end, 4096), (0, vec![0; 4096]));
+        assert_eq!(write(end + 8 * 4096, &[]), 0);
+        assert_eq!(size(), end + 8 * 4096);
+
+        // shrinking drops the pages past the gap with it
+        assert_eq!(truncate(original), 0);
+        assert_eq!(size(), original);
+        assert_eq!(truncate(end), 0);
+        assert_eq!(read(end - 100, 100), (0, vec![0; 100]));
+        assert_eq!(truncate(original), 0);
+
+        assert_eq!(integrity_and_count(&connection, "t"), ("ok".to_string(), 1));
+        connection.execute("INSERT INTO t VALUES (2)").unwrap();
+        let other = Connection::open("extent.db").unwrap();
+        assert_eq!(integrity_and_count(&other, "t"), ("ok".to_string(), 2));
+    }
+
+    #[test]
+    fn test_extent() {
+        let output = run_child(
+            "extent_workload",
+            &[
+                ("ZERO_LENGTH_WRITES", "extend"),
+                ("S3QLITE_EXTENT_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test]
+    fn test_file_size() {
+        use sqlite::ffi::{SQLITE_FCNTL_FILE_POINTER, sqlite3_file};
+
+        init_vfs();
+        let file_size = |connection: &Connection| -> i64 {
+            let mut file: *mut sqlite3_file = std::ptr::null_mut();
+            let rc = unsafe {
+                sqlite::ffi::sqlite3_file_control(
+                    connection.as_raw(),
+                    c"main".as_ptr(),
+                    SQLITE_FCNTL_FILE_POINTER,
+                    (&raw mut file).cast(),
+                )
+            };
+            assert_eq!(rc, 0);
+            let methods = unsafe { &*(*file).pMethods };
+            let mut size = 0;
+            assert_eq!(unsafe { methods.xFileSize.unwrap()(file, &mut size) }, 0);
+            size
+        };
+        let pages = |connection: &Connection| -> i64 {
+            crate::query_string(connection, "PRAGMA page_count")
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        // the stored length follows the file as it grows and shrinks
+        let connection = Connection::open("file_size.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (body BLOB); \
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 50) \
+                 INSERT INTO t SELECT randomblob(3000) FROM s",
+            )
+            .unwrap();
+        let grown = pages(&connection);
+        assert_eq!(file_size(&connection), grown * 4096);
+        connection
+            .execute("DELETE FROM t WHERE rowid > 10; VACUUM")
+            .unwrap();
+        assert!(pages(&connection) < grown);
+        assert_eq!(file_size(&connection), pages(&connection) * 4096);
+        drop(connection);
+
+        let connection = Connection::open("file_size.db").unwrap();
+        assert_eq!(file_size(&connection), pages(&connection) * 4096);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 10)
+        );
+    }
+
+    // Runs in a child process started by test_block_size, a no-op otherwise.
+    #[test]
+    fn block_size_workload() {
+        if std::env::var("S3QLITE_BLOCK_SIZE_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let pragma =
+            |connection: &Connection, sql: &str| crate::query_string(connection, sql).unwrap();
+        let fill = |connection: &Connection| {
+            connection
+                .execute(
+                    "CREATE TABLE t (body BLOB); \
+                     WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 50) \
+                     INSERT INTO t SELECT randomblob(3000) FROM s",
+                )
+                .unwrap();
+        };
+
+        // BLOCK_SIZE applies to new files
+        let connection = Connection::open("block_size_default.db").unwrap();
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_block_size"), "8192");
+        fill(&connection);
+        connection
+            .execute("PRAGMA s3qlite_snapshot='filled'")
+            .unwrap();
+        connection
+            .execute("DELETE FROM t WHERE rowid > 10")
+            .unwrap();
+        // a file with data keeps its block size
+        assert!(
+            connection
+                .execute("PRAGMA s3qlite_block_size='4096'")
+                .is_err()
+        );
+        drop(connection);
+
+        // the open option picks one for a new file, and is ignored for one with data
+        let open = |uri: &str| {
+            Connection::open_with_flags(
+                uri,
+                sqlite::OpenFlags::new()
+                    .with_read_write()
+                    .with_create()
+                    .with_uri(),
+            )
+            .unwrap()
+        };
+        let connection = open("file:block_size_uri.db?block_size=65536");
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_block_size"), "65536");
+        fill(&connection);
+        drop(connection);
+        let connection = open("file:block_size_uri.db?block_size=4096");
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_block_size"), "65536");
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 50)
+        );
+        drop(connection);
+        assert!(
+            Connection::open_with_flags(
+                "file:block_size_bad.db?block_size=1000",
+                sqlite::OpenFlags::new()
+                    .with_read_write()
+                    .with_create()
+                    .with_uri(),
+            )
+            .is_err()
+        );
+
+        // the pragma sets one while the file is empty
+        let connection = Connection::open("block_size_pragma.db").unwrap();
+        assert!(
+            connection
+                .execute("PRAGMA s3qlite_block_size='1000'")
+                .is_err()
+        );
+        assert_eq!(
+            pragma(&connection, "PRAGMA s3qlite_block_size='16384'"),
+            "16384"
+        );
+        fill(&connection);
+        drop(connection);
+
+        // files with different block sizes are read side by side, snapshots included
+        let connection = Connection::open("block_size_pragma.db").unwrap();
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_block_size"), "16384");
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 50)
+        );
+        connection
+            .execute(
+                "ATTACH 'block_size_default.db' AS d; ATTACH 'block_size_default.db@filled' AS old",
+            )
+            .unwrap();
+        assert_eq!(pragma(&connection, "SELECT count(*) FROM d.t"), "10");
+        assert_eq!(pragma(&connection, "SELECT count(*) FROM old.t"), "50");
+        assert_eq!(
+            pragma(
+                &connection,
+                "SELECT integrity_check FROM old.pragma_integrity_check"
+            ),
+            "ok"
+        );
+    }
+
+    #[test]
+    fn test_block_size() {
+        let output = run_child(
+            "block_size_workload",
+            &[("BLOCK_SIZE", "8192"), ("S3QLITE_BLOCK_SIZE_CHILD", "1")],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_database_config, a no-op otherwise.
+    #[test]
+    fn database_config_workload() {
+        if std::env::var("S3QLITE_DATABASE_CONFIG_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let pragma =
+            |connection: &Connection, sql: &str| crate::query_string(connection, sql).unwrap();
+        let create = |connection: &Connection| {
+            connection
+                .execute(
+                    "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT); \
+                     CREATE INDEX users_name ON users (name); \
+                     INSERT INTO users (name) VALUES ('a'), ('b')",
+                )
+                .unwrap();
+        };
+
+        // the configured database takes its own settings
+        let connection = Connection::open("configured.db").unwrap();
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_block_size"), "65536");
+        create(&connection);
+        assert!(
+            pragma(&connection, "PRAGMA s3qlite_size_limit").starts_with("{\"limit\":1048576,")
+        );
+        // each commit was flushed before the lock was released
+        connection
+            .execute("INSERT INTO users (name) VALUES ('c')")
+            .unwrap();
+        assert!(pragma(&connection, "PRAGMA s3qlite_flush").starts_with("{\"batches\":0,"));
+        drop(connection);
+
+        // tables are pinned once they exist: page 1, the table and its index
+        let connection = Connection::open("pinned.db").unwrap();
+        create(&connection);
+        drop(connection);
+        let connection = Connection::open("pinned.db").unwrap();
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_pin"), "3");
+        drop(connection);
+
+        // every other database keeps the environment's
+        let connection = Connection::open("unconfigured.db").unwrap();
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_block_size"), "4096");
+        create(&connection);
+        assert!(pragma(&connection, "PRAGMA s3qlite_size_limit").starts_with("{\"limit\":null,"));
+        connection
+            .execute("INSERT INTO users (name) VALUES ('c')")
+            .unwrap();
+        assert!(!pragma(&connection, "PRAGMA s3qlite_flush").starts_with("{\"batches\":0,"));
+        drop(connection);
+        let connection = Connection::open("unconfigured.db").unwrap();
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_pin"), "1");
+    }
+
+    #[test]
+    fn test_database_config() {
+        let run = |config: &str| {
+            let path = std::env::temp_dir().join(format!(
+                "s3qlite-database-config-{}-{}.toml",
+                std::process::id(),
+                config.len()
+            ));
+            std::fs::write(&path, config).unwrap();
+            let output = run_child(
+                "database_config_workload",
+                &[
+                    ("CONFIG_FILE", path.to_str().unwrap()),
+                    ("S3QLITE_DATABASE_CONFIG_CHILD", "1"),
+                ],
+            );
+            std::fs::remove_file(&path).unwrap();
+            output
+        };
+        let output = run(r#"
+[databases."configured.db"]
+block_size = 65536
+max_db_bytes = 1048576
+durable_unlock = true
+
+[databases."pinned.db"]
+pin = ["users"]
+"#);
+        assert!(output.status.success(), "{output:?}");
+        // a setting that can't be per database fails the VFS rather than being ignored
+        let output = run(r#"
+[databases."configured.db"]
+compression = "zstd"
+"#);
+        assert!(!output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_durable_unlock, a no-op otherwise.
+    #[test]
+    fn durable_unlock_workload() {
+        let Ok(mode) = std::env::var("S3QLITE_DURABLE_UNLOCK_CHILD") else {
+            return;
+        };
+        init_vfs();
+        let connection = Connection::open("durable_unlock.db").unwrap();
+        if mode == "read" {
+            let count = crate::query_string(&connection, "SELECT count(*) FROM t").unwrap();
+            println!("count {count}");
+            return;
+        }
+        connection
+            .execute("CREATE TABLE t (v INTEGER); INSERT INTO t VALUES (1), (2)")
+            .unwrap();
+        // killed the moment the lock is released, so nothing after the unlock can make
+        // the commit durable
+        std::process::abort();
+    }
+
+    #[test]
+    fn test_durable_unlock() {
+        let dir =
+            std::env::temp_dir().join(format!("s3qlite-durable-unlock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = format!("file://{}", dir.display());
+        let child = |mode: &str| {
+            run_child(
+                "durable_unlock_workload",
+                &[
+                    ("OBJECT_STORE_URL", &url),
+                    ("DURABLE_UNLOCK", "true"),
+                    ("S3QLITE_DURABLE_UNLOCK_CHILD", mode),
+                ],
+            )
+        };
+
+        let output = child("write");
+        assert!(!output.status.success(), "{output:?}");
+        assert!(
+            !String::from_utf8_lossy(&output.stderr).contains("panicked"),
+            "{output:?}"
+        );
+
+        // the next process to open the database sees the commit
+        let output = child("read");
+        assert!(output.status.success(), "{output:?}");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("count 2"), "{stdout}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_connection() {
+        init_vfs();
+        let read_only = |path: &str| {
+            Connection::open_with_flags(path, sqlite::OpenFlags::new().with_read_only())
+        };
+        let count = |connection: &Connection| {
+            crate::query_string(connection, "SELECT count(*) FROM t").unwrap()
+        };
+        let writer = Connection::open("read_only_connection.db").unwrap();
+        writer
+            .execute(
+                "CREATE TABLE t (body TEXT); \
+                 INSERT INTO t VALUES ('a'), ('b'), ('c')",
+            )
+            .unwrap();
+
+        // a read-only connection reads the database as it was when opened, writers going
+        // on committing while it's in a read transaction
+        let reader = read_only("read_only_connection.db").unwrap();
+        reader.execute("BEGIN").unwrap();
+        assert_eq!(count(&reader), "3");
+        writer.execute("INSERT INTO t VALUES ('d'), ('e')").unwrap();
+        assert_eq!(count(&reader), "3");
+        reader.execute("COMMIT").unwrap();
+        assert_eq!(count(&reader), "3");
+        assert!(reader.execute("DELETE FROM t").is_err());
+        assert_eq!(count(&writer), "5");
+
+        // reopening moves it on
+        drop(reader);
+        let reader = read_only("read_only_connection.db").unwrap();
+        assert_eq!(count(&reader), "5");
+        assert_eq!(
+            crate::query_string(&reader, "PRAGMA integrity_check").unwrap(),
+            "ok"
+        );
+
+        // there's nothing to read in a database that doesn't exist
+        assert!(read_only("read_only_missing.db").is_err());
+    }
+
+    // Runs in a child process started by test_as_of, a no-op otherwise: the checkpoints
+    // of other tests would be found too.
+    #[test]
+    fn as_of_workload() {
+        if std::env::var("S3QLITE_AS_OF_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let query = |connection: &Connection, sql: &str| crate::query_string(connection, sql);
+        let count = |connection: &Connection| query(connection, "SELECT count(*) FROM t").unwrap();
+        let open = |uri: &str| {
+            Connection::open_with_flags(
+                uri,
+                sqlite::OpenFlags::new()
+                    .with_read_write()
+                    .with_create()
+                    .with_uri(),
+            )
+        };
+        // checkpoint times are kept to the second
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        let writer = Connection::open("as_of.db").unwrap();
+        writer
+            .execute(
+                "CREATE TABLE t (body TEXT); \
+                 INSERT INTO t VALUES ('a'), ('b'), ('c'); \
+                 PRAGMA s3qlite_snapshot='three'",
+            )
+            .unwrap();
+        pause();
+        let between = query(&writer, "SELECT strftime('%Y-%m-%dT%H:%M:%fZ', 'now')").unwrap();
+        pause();
+        writer
+            .execute(
+                "INSERT INTO t VALUES ('d'), ('e'); \
+                 PRAGMA s3qlite_snapshot='five'; \
+                 INSERT INTO t VALUES ('f')",
+            )
+            .unwrap();
+
+        // the database as of a time is the newest checkpoint retained from then or before,
+        // opened read-only
+        let then = open(&format!("file:as_of.db?as_of={between}")).unwrap();
+        assert_eq!(count(&then), "3");
+        assert!(then.execute("DELETE FROM t").is_err());
+        let later = open("file:as_of.db?as_of=2999-01-01T00:00:00Z").unwrap();
+        assert_eq!(count(&later), "5");
+        assert!(open("file:as_of.db?as_of=2000-01-01").is_err());
+        assert!(open("file:as_of.db?as_of=yesterday").is_err());
+
+        // a read-only connection moves between transactions
+        let reader =
+            Connection::open_with_flags("as_of.db", sqlite::OpenFlags::new().with_read_only())
+                .unwrap();
+        assert_eq!(count(&reader), "6");
+        let moved = query(&reader, &format!("PRAGMA s3qlite_as_of='{between}'")).unwrap();
+        assert!(moved.starts_with("{\"checkpoint\":"), "{moved}");
+        assert_eq!(count(&reader), "3");
+        reader.execute("BEGIN").unwrap();
+        assert_eq!(count(&reader), "3");
+        assert!(query(&reader, "PRAGMA s3qlite_as_of='2999-01-01'").is_none());
+        reader.execute("COMMIT").unwrap();
+        assert_eq!(query(&reader, "PRAGMA s3qlite_as_of").unwrap(), moved);
+
+        // one that writes reads the live database
+        assert!(query(&writer, "PRAGMA s3qlite_as_of").is_none());
+        assert_eq!(count(&writer), "6");
+    }
+
+    #[test]
+    fn test_as_of() {
+        let output = run_child("as_of_workload", &[("S3QLITE_AS_OF_CHILD", "1")]);
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_stall_bundle, a no-op otherwise.
+    #[test]
+    fn stall_bundle_workload() {
+        if std::env::var("S3QLITE_STALL_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("stall_bundle.db").unwrap();
+        // commits of megabytes take longer than STALL_THRESHOLD_MS
+        for _ in 0..3 {
+            connection
+                .execute(
+                    "CREATE TABLE IF NOT EXISTS t (body BLOB); \
+                     WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 500) \
+                     INSERT INTO t SELECT randomblob(8000) FROM s",
+                )
+                .unwrap();
+        }
+        let stats = crate::query_string(&connection, "PRAGMA s3qlite_stats").unwrap();
+        let (_, stalls) = stats.split_once("\"stalls\":").unwrap();
+        let stalls: u64 = stalls.split([',', '}']).next().unwrap().parse().unwrap();
+        assert!(stalls > 0, "{stats}");
+
+        // a run of stalls writes one bundle
+        let dir =
+            std::path::PathBuf::from(std::env::var("S3QLITE_STATE_DIR").unwrap()).join("stalls");
+        let bundles: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(bundles.len(), 1);
+        let bundle = std::fs::read_to_string(bundles[0].as_ref().unwrap().path()).unwrap();
+        assert!(bundle.starts_with("{\"stall\":{\"op\":"), "{bundle}");
+        assert!(bundle.contains("\"path\":\"stall_bundle.db\""), "{bundle}");
+        assert!(bundle.contains("\"threshold_ms\":1,"), "{bundle}");
+        // with what ran before it and what was open
+        assert!(bundle.contains("\"recent\":[{\"op\":"), "{bundle}");
+        assert!(bundle.contains("\"handles\":[{\"handle_id\":"), "{bundle}");
+        assert!(
+            bundle.contains("\"stats\":{\"cache_verified_pages\":"),
+            "{bundle}"
+        );
+    }
+
+    #[test]
+    fn test_stall_bundle() {
+        let dir = std::env::temp_dir().join(format!("s3qlite_stalls_{}", std::process::id()));
+        let output = run_child(
+            "stall_bundle_workload",
+            &[
+                ("STALL_THRESHOLD_MS", "1"),
+                ("S3QLITE_STATE_DIR", dir.to_str().unwrap()),
+                ("S3QLITE_STALL_CHILD", "1"),
+            ],
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_preload_cache, a no-op otherwise.
+    #[test]
+    fn preload_cache_workload() {
+        use std::ffi::{CStr, c_char, c_void};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static REPORTS: AtomicU64 = AtomicU64::new(0);
+        static PAGES_DONE: AtomicU64 = AtomicU64::new(0);
+
+        unsafe extern "C" fn on_progress(
+            _arg: *mut c_void,
+            operation: *const c_char,
+            path: *const c_char,
+            pages_done: u64,
+        ) -> i32 {
+            let (operation, path) = unsafe { (CStr::from_ptr(operation), CStr::from_ptr(path)) };
+            if operation.to_bytes() == b"preload" && path.to_bytes().ends_with(b"preload.db") {
+                REPORTS.fetch_add(1, Ordering::Relaxed);
+                PAGES_DONE.fetch_max(pages_done, Ordering::Relaxed);
+            }
+            0
+        }
+
+        let Ok(phase) = std::env::var("S3QLITE_PRELOAD_CHILD") else {
+            return;
+        };
+        init_vfs();
+        unsafe { s3qlite_progress_handler(Some(on_progress), std::ptr::null_mut()) };
+        if phase == "create" {
+            // creating the database doesn't preload it, there's nothing stored to read
+            let connection = Connection::open("preload.db").unwrap();
+            connection
+                .execute(
+                    "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                     WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 800) \
+                     INSERT INTO t (body) SELECT randomblob(3000) FROM s",
+                )
+                .unwrap();
+            // durable for the next process
+            crate::query_string(&connection, "PRAGMA s3qlite_flush").unwrap();
+            drop(connection);
+            assert_eq!(REPORTS.load(Ordering::Relaxed), 0);
+            return;
+        }
+
+        // the first open in a fresh process reads every page in the background, a chunk
+        // at a time, while commits rewrite them: what it read before a commit mustn't end
+        // up cached over the commit
+        let connection = Connection::open("preload.db").unwrap();
+        let pages: u64 = crate::query_string(&connection, "PRAGMA page_count")
+            .unwrap()
+            .parse()
+            .unwrap();
+        for round in 0..8 {
+            connection
+                .execute(format!(
+                    "UPDATE t SET body = zeroblob(3000) || {round} WHERE id % 8 = {round}"
+                ))
+                .unwrap();
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while PAGES_DONE.load(Ordering::Relaxed) < pages && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(PAGES_DONE.load(Ordering::Relaxed), pages);
+        assert!(REPORTS.load(Ordering::Relaxed) > 1);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 800)
+        );
+        let rewritten = crate::query_string(
+            &connection,
+            "SELECT count(*) FROM t WHERE body = zeroblob(3000) || (id % 8)",
+        );
+        assert_eq!(rewritten.as_deref(), Some("800"));
+        drop(connection);
+
+        // later opens don't preload again
+        let reports = REPORTS.load(Ordering::Relaxed);
+        let connection = Connection::open("preload.db").unwrap();
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 800)
+        );
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        unsafe { s3qlite_progress_handler(None, std::ptr::null_mut()) };
+        assert_eq!(REPORTS.load(Ordering::Relaxed), reports);
+    }
+
+    #[test]
+    fn test_preload_cache() {
+        let dir = std::env::temp_dir().join(format!("s3qlite_preload_{}", std::process::id()));
+        let url = format!("file://{}", dir.display());
+        for phase in ["create", "preload"] {
+            let output = run_child(
+                "preload_cache_workload",
+                &[
+                    ("OBJECT_STORE_URL", &url),
+                    ("PRELOAD_CACHE", "true"),
+                    ("PRELOAD_CACHE_CONCURRENCY", "2"),
+                    ("S3QLITE_PRELOAD_CHILD", phase),
+                ],
+            );
+            assert!(output.status.success(), "{phase}: {output:?}");
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Runs in a child process started by test_cache_manifest, a no-op otherwise.
+    #[test]
+    fn cache_manifest_workload() {
+        use std::ffi::{CStr, c_char, c_void};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static WARMED: AtomicU64 = AtomicU64::new(0);
+
+        unsafe extern "C" fn on_progress(
+            _arg: *mut c_void,
+            operation: *const c_char,
+            path: *const c_char,
+            pages_done: u64,
+        ) -> i32 {
+            let (operation, path) = unsafe { (CStr::from_ptr(operation), CStr::from_ptr(path)) };
+            if operation.to_bytes() == b"warm" && path.to_bytes().ends_with(b"cache_manifest.db") {
+                WARMED.fetch_max(pages_done, Ordering::Relaxed);
+            }
+            0
+        }
+
+        let Ok(phase) = std::env::var("S3QLITE_CACHE_MANIFEST_CHILD") else {
+            return;
+        };
+        let published = |connection: &Connection| -> u64 {
+            let stats = crate::query_string(connection, "PRAGMA s3qlite_stats").unwrap();
+            stats
+                .split("\"cache_manifests_published\":")
+                .nth(1)
+                .and_then(|rest| rest.split([',', '}']).next())
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        init_vfs();
+        if phase == "publish" {
+            let connection = Connection::open("cache_manifest.db").unwrap();
+            connection
+                .execute(
+                    "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB); \
+                     WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 800) \
+                     INSERT INTO t (body) SELECT randomblob(3000) FROM s",
+                )
+                .unwrap();
+            // CACHE_MANIFEST_INTERVAL_SECS is 1
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            while published(&connection) == 0 && std::time::Instant::now() < deadline {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            let count = published(&connection);
+            assert!(count > 0);
+            // an idle process doesn't write the same hot set again
+            std::thread::sleep(std::time::Duration::from_millis(2500));
+            assert_eq!(published(&connection), count);
+            // durable for the next process
+            crate::query_string(&connection, "PRAGMA s3qlite_flush").unwrap();
+            return;
+        }
+
+        // a fresh process warms the pages the last one had hot, in the background
+        unsafe { s3qlite_progress_handler(Some(on_progress), std::ptr::null_mut()) };
+        let connection = Connection::open("cache_manifest.db").unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while WARMED.load(Ordering::Relaxed) < 256 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        unsafe { s3qlite_progress_handler(None, std::ptr::null_mut()) };
+        assert!(WARMED.load(Ordering::Relaxed) >= 256);
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 800)
+        );
+    }
+
+    #[test]
+    fn test_cache_manifest() {
+        let dir = std::env::temp_dir().join(format!("s3qlite_manifest_{}", std::process::id()));
+        let url = format!("file://{}", dir.display());
+        for phase in ["publish", "warm"] {
+            let output = run_child(
+                "cache_manifest_workload",
+                &[
+                    ("OBJECT_STORE_URL", &url),
+                    ("CACHE_MANIFEST_INTERVAL_SECS", "1"),
+                    ("S3QLITE_CACHE_MANIFEST_CHILD", phase),
+                ],
+            );
+            assert!(output.status.success(), "{phase}: {output:?}");
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_write_across_pages() {
+        use sqlite::ffi::{SQLITE_FCNTL_FILE_POINTER, sqlite3_file};
+
+        init_vfs();
+        let connection = Connection::open("write_across_pages.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        let mut file: *mut sqlite3_file = std::ptr::null_mut();
+        let rc = unsafe {
+            sqlite::ffi::sqlite3_file_control(
+                connection.as_raw(),
+                c"main".as_ptr(),
+                SQLITE_FCNTL_FILE_POINTER,
+                (&raw mut file).cast(),
+            )
+        };
+        assert_eq!(rc, 0);
+        let methods = unsafe { &*(*file).pMethods };
+
+        // a write from the middle of one page to the middle of the next lands on both
+        let data: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+        let offset = 2 * 4096 - 3000;
+        assert_eq!(
+            unsafe { methods.xWrite.unwrap()(file, data.as_ptr().cast(), 6000, offset) },
+            0
+        );
+        let mut read = vec![0u8; 6000];
+        assert_eq!(
+            unsafe { methods.xRead.unwrap()(file, read.as_mut_ptr().cast(), 6000, offset) },
+            0
+        );
+        assert_eq!(read, data);
+        let mut size = 0;
+        assert_eq!(unsafe { methods.xFileSize.unwrap()(file, &mut size) }, 0);
+        assert!(size >= offset + 6000);
+    }
+
+    #[test]
+    fn test_read_repair() {
+        use sqlite::ffi::{SQLITE_FCNTL_FILE_POINTER, sqlite3_file};
+
+        init_vfs();
+        // punch a hole in a database the way a lost page would, and read it
+        let lose_pages = |name: &str, snapshot: bool| -> Connection {
+            let connection = Connection::open(name).unwrap();
+            connection
+                .execute(
+                    "CREATE TABLE t (body BLOB); \
+                     WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 50) \
+                     INSERT INTO t SELECT randomblob(3000) FROM s",
+                )
+                .unwrap();
+            if snapshot {
+                connection
+                    .execute("PRAGMA s3qlite_snapshot='before'")
+                    .unwrap();
+            }
+            let mut file: *mut sqlite3_file = std::ptr::null_mut();
+            let rc = unsafe {
+                sqlite::ffi::sqlite3_file_control(
+                    connection.as_raw(),
+                    c"main".as_ptr(),
+                    SQLITE_FCNTL_FILE_POINTER,
+                    (&raw mut file).cast(),
+                )
+            };
+            assert_eq!(rc, 0);
+            let methods = unsafe { &*(*file).pMethods };
+            let mut size = 0;
+            assert_eq!(unsafe { methods.xFileSize.unwrap()(file, &mut size) }, 0);
+            let mut last = vec![0u8; 4096];
+            unsafe {
+                assert_eq!(
+                    methods.xRead.unwrap()(file, last.as_mut_ptr().cast(), 4096, size - 4096),
+                    0
+                );
+                assert_eq!(methods.xTruncate.unwrap()(file, size - 3 * 4096), 0);
+                assert_eq!(
+                    methods.xWrite.unwrap()(file, last.as_ptr().cast(), 4096, size - 4096),
+                    0
+                );
+            }
+            drop(connection);
+            Connection::open(name).unwrap()
+        };
+        let stat = |connection: &Connection, name: &str| -> u64 {
+            let stats = crate::query_string(connection, "PRAGMA s3qlite_stats").unwrap();
+            let (_, rest) = stats.split_once(&format!("\"{name}\":")).unwrap();
+            rest.split([',', '}']).next().unwrap().parse().unwrap()
+        };
+
+        // the two lost pages are served from the snapshot
+        let connection = lose_pages("read_repair.db", true);
+        let repaired = stat(&connection, "pages_repaired");
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 50)
+        );
+        assert_eq!(stat(&connection, "pages_repaired"), repaired + 2);
+
+        // without a snapshot there's nothing to repair them from
+        let connection = lose_pages("read_repair_unrepairable.db", false);
+        let failed = stat(&connection, "page_repairs_failed");
+        let mut stmt = connection.prepare("PRAGMA integrity_check").unwrap();
+        assert_eq!(stmt.next().unwrap(), State::Row);
+        assert_ne!(stmt.read::<String, _>(0).unwrap(), "ok");
+        assert!(stat(&connection, "page_repairs_failed") > failed);
+    }
+
+    // Runs in a child process started by test_deterministic_clock, a no-op otherwise.
+    #[test]
+    fn deterministic_clock_workload() {
+        if std::env::var("S3QLITE_DETERMINISTIC_CLOCK_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("deterministic_clock.db").unwrap();
+        connection.execute("CREATE TABLE t (id INTEGER)").unwrap();
+        let handles = || crate::query_string(&connection, "PRAGMA s3qlite_open_handles").unwrap();
+
+        // the first handle gets the first id, and no time passes on its own
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(
+            handles().starts_with("[{\"handle_id\":1,\"path\":\"deterministic_clock.db\""),
+            "{}",
+            handles()
+        );
+        assert!(handles().contains("\"open_ms\":0,"), "{}", handles());
+
+        assert_eq!(
+            crate::query_string(&connection, "PRAGMA s3qlite_advance_clock='90000'").unwrap(),
+            "90000"
+        );
+        assert!(handles().contains("\"open_ms\":90000,"), "{}", handles());
+        assert!(
+            connection
+                .execute("PRAGMA s3qlite_advance_clock='soon'")
+                .is_err()
+        );
+
+        // SQLite's time is the clock's, from the Unix epoch
+        let now = || crate::query_string(&connection, "SELECT datetime('now')").unwrap();
+        assert_eq!(now(), "1970-01-01 00:01:30");
+        // and sleeping moves it rather than waiting
+        let start = std::time::Instant::now();
+        assert_eq!(unsafe { sqlite::ffi::sqlite3_sleep(60_000) }, 60_000);
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(now(), "1970-01-01 00:02:30");
+    }
+
+    #[test]
+    fn test_deterministic_clock() {
+        let output = run_child(
+            "deterministic_clock_workload",
+            &[
+                ("DETERMINISTIC_SEED", "1"),
+                ("S3QLITE_DETERMINISTIC_CLOCK_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_ttl, a no-op otherwise.
+    #[test]
+    fn ttl_workload() {
+        if std::env::var("S3QLITE_TTL_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let connection = Connection::open("ttl_scratch.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER); INSERT INTO t VALUES (1)")
+            .unwrap();
+        let pragma =
+            |connection: &Connection, sql: &str| crate::query_string(connection, sql).unwrap();
+        assert_eq!(
+            pragma(&connection, "PRAGMA s3qlite_ttl"),
+            r#"{"expires_at_ms":null}"#
+        );
+        let set = pragma(&connection, "PRAGMA s3qlite_ttl='60'");
+        assert!(set.ends_with(r#""remaining_ms":60000}"#), "{set}");
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_ttl"), set);
+        assert!(connection.execute("PRAGMA s3qlite_ttl='soon'").is_err());
+
+        // nothing has expired yet, and an open database is never deleted
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_expire"), "[]");
+        pragma(&connection, "PRAGMA s3qlite_advance_clock='61000'");
+        assert_eq!(pragma(&connection, "PRAGMA s3qlite_expire"), "[]");
+        drop(connection);
+
+        let other = Connection::open("ttl_other.db").unwrap();
+        assert_eq!(
+            pragma(&other, "PRAGMA s3qlite_expire"),
+            r#"["ttl_scratch.db"]"#
+        );
+        assert_eq!(pragma(&other, "PRAGMA s3qlite_expire"), "[]");
+
+        let connection = Connection::open("ttl_scratch.db").unwrap();
+        assert_eq!(
+            pragma(&connection, "SELECT count(*) FROM sqlite_schema"),
+            "0"
+        );
+        assert_eq!(
+            pragma(&connection, "PRAGMA s3qlite_ttl"),
+            r#"{"expires_at_ms":null}"#
+        );
+    }
+
+    #[test]
+    fn test_ttl() {
+        let output = run_child(
+            "ttl_workload",
+            &[
+                ("DETERMINISTIC_SEED", "1"),
+                ("EXPIRE_INTERVAL_SECS", "0"),
+                ("S3QLITE_TTL_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test]
+    fn test_custom_file_controls() {
+        const FCNTL_FLUSH: i32 = 0x5333_0002;
+        const FCNTL_SNAPSHOT: i32 = 0x5333_0003;
+        const FCNTL_STATS: i32 = 0x5333_0004;
+
+        init_vfs();
+        let connection = Connection::open("custom_file_controls.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER); INSERT INTO t VALUES (1), (2)")
+            .unwrap();
+        let file_control = |op: i32, arg: *mut std::ffi::c_void| unsafe {
+            sqlite::ffi::sqlite3_file_control(connection.as_raw(), c"main".as_ptr(), op, arg)
+        };
+        // a string op, the result replacing the argument
+        let text = |op: i32, arg: Option<&std::ffi::CStr>| -> Result<String, i32> {
+            let mut text = arg.map_or(std::ptr::null_mut(), |arg| arg.as_ptr().cast_mut());
+            match file_control(op, (&raw mut text).cast()) {
+                0 => {}
+                rc => return Err(rc),
+            }
+            let result = unsafe { std::ffi::CStr::from_ptr(text) };
+            let result = result.to_str().unwrap().to_string();
+            unsafe { sqlite::ffi::sqlite3_free(text.cast()) };
+            Ok(result)
+        };
+
+        assert_eq!(file_control(FCNTL_FLUSH, std::ptr::null_mut()), 0);
+        let stats = text(FCNTL_STATS, None).unwrap();
+        assert!(stats.contains("\"pages_repaired\":"), "{stats}");
+
+        let id = text(FCNTL_SNAPSHOT, Some(c"fcntl")).unwrap();
+        assert_eq!(id.len(), 36, "{id}");
+        connection
+            .execute("INSERT INTO t VALUES (3); ATTACH 'custom_file_controls.db@fcntl' AS old")
+            .unwrap();
+        assert_eq!(
+            integrity_and_count(&connection, "old.t"),
+            ("ok".to_string(), 2)
+        );
+        assert_eq!(text(FCNTL_SNAPSHOT, Some(c"a@b")), Err(1)); // SQLITE_ERROR
+        assert_eq!(text(FCNTL_SNAPSHOT, None), Err(1));
+        // string ops need somewhere to put their result
+        assert_eq!(file_control(FCNTL_STATS, std::ptr::null_mut()), 21); // SQLITE_MISUSE
+    }
+
+    // Runs in a child process started by test_object_store_url_settings, a no-op otherwise.
+    #[test]
+    fn object_store_url_settings_workload() {
+        use std::ffi::{CStr, c_char, c_int, c_void};
+
+        static LOGGED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+        unsafe extern "C" fn on_log(_arg: *mut c_void, _code: c_int, msg: *const c_char) {
+            let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy();
+            LOGGED.lock().unwrap().push(msg.into_owned());
+        }
+
+        // the child is told the error to expect
+        let Ok(expected) = std::env::var("S3QLITE_OBJECT_STORE_URL_CHILD") else {
+            return;
+        };
+        let log: unsafe extern "C" fn(*mut c_void, c_int, *const c_char) = on_log;
+        unsafe {
+            sqlite::ffi::sqlite3_config(
+                sqlite::ffi::SQLITE_CONFIG_LOG,
+                log,
+                std::ptr::null_mut::<c_void>(),
+            )
+        };
+        init_vfs();
+        // the VFS fails to start on the bad url before any request is made, so nothing
+        // opens
+        assert!(
+            Connection::open("object_store_url.db")
+                .and_then(|connection| connection.execute("CREATE TABLE t (id INTEGER)"))
+                .is_err()
+        );
+        let logged = LOGGED.lock().unwrap();
+        assert!(
+            logged.iter().any(|msg| msg.contains(&expected)),
+            "{logged:?}"
+        );
+    }
+
+    #[test]
+    fn test_object_store_url_settings() {
+        for (url, expected) in [
+            (
+                "s3://bucket/prefix?region=us-east-1&endpoint=http%3A%2F%2F127.0.0.1%3A1&bucket_size=9",
+                "unknown s3 setting bucket_size in object store url",
+            ),
+            // a list of endpoints to spread requests over can't have a gap in it
+            (
+                "s3://bucket?region=us-east-1&endpoint=http%3A%2F%2F127.0.0.1%3A1,,http%3A%2F%2F127.0.0.1%3A2",
+                "empty endpoint in object store url",
+            ),
+        ] {
+            let output = run_child(
+                "object_store_url_settings_workload",
+                &[
+                    ("OBJECT_STORE_URL", url),
+                    ("S3QLITE_OBJECT_STORE_URL_CHILD", expected),
+                ],
+            );
+            assert!(output.status.success(), "{url}: {output:?}");
+        }
+    }
+
+    #[test]
+    fn test_database_url() {
+        init_vfs();
+        // OBJECT_STORE_URL isn't set in tests, so databases are in the memory:// store
+        let connection = Connection::open("memory://database_url.db").unwrap();
+        connection
+            .execute("CREATE TABLE t (id INTEGER); INSERT INTO t VALUES (1), (2);")
+            .unwrap();
+        drop(connection);
+
+        let connection = Connection::open("database_url.db").unwrap();
+        let mut statement = connection.prepare("SELECT count(*) FROM t").unwrap();
+        assert_eq!(statement.next().unwrap(), sqlite::State::Row);
+        assert_eq!(statement.read::<i64, _>(0).unwrap(), 2);
+
+        // a database can't be opened out of another store
+        assert!(Connection::open("s3://elsewhere/database_url.db").is_err());
+    }
+
+    #[test]
+    fn test_analyze_advice() {
+        init_vfs();
+        let connection = Connection::open("analyze_advice.db").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT); CREATE INDEX t_v ON t (v);
+                 CREATE TABLE quiet (id INTEGER);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+                 INSERT INTO t (v) SELECT hex(randomblob(64)) FROM n;",
+            )
+            .unwrap();
+        let advice = || crate::query_string(&connection, "PRAGMA s3qlite_analyze_advice").unwrap();
+
+        // never analyzed and every page new
+        let fresh = advice();
+        assert!(
+            fresh.starts_with("{\"analyze\":true,\"has_stats\":false,"),
+            "{fresh}"
+        );
+        assert!(
+            fresh.contains("\"tables\":[{\"table\":\"t\",\"root_writes\":"),
+            "{fresh}"
+        );
+        assert!(
+            fresh.ends_with("{\"table\":\"quiet\",\"root_writes\":1}]}"),
+            "{fresh}"
+        );
+
+        // ANALYZE writing sqlite_stat1 starts the count again
+        connection.execute("ANALYZE").unwrap();
+        let analyzed = advice();
+        assert!(
+            analyzed.starts_with("{\"analyze\":false,\"has_stats\":true,"),
+            "{analyzed}"
+        );
+
+        connection
+            .execute("UPDATE t SET v = hex(randomblob(64))")
+            .unwrap();
+        assert!(advice().starts_with("{\"analyze\":true,"), "{}", advice());
+        assert!(
+            crate::query_string(&connection, "PRAGMA s3qlite_analyze_advice='reset'")
+                .unwrap()
+                .contains("\"pages_written\":0,")
+        );
+    }
+
+    // Runs in a child process started by test_lock_wait, a no-op otherwise.
+    #[test]
+    fn lock_wait_workload() {
+        use std::ffi::{CStr, c_char, c_void};
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static NOTIFIED: AtomicU64 = AtomicU64::new(0);
+
+        unsafe extern "C" fn on_unlock(_arg: *mut c_void, path: *const c_char, _released_by: u64) {
+            let path = unsafe { CStr::from_ptr(path) };
+            if path.to_bytes().ends_with(b"lock_wait.db") {
+                NOTIFIED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        if std::env::var("S3QLITE_LOCK_WAIT_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        unsafe { s3qlite_unlock_notify(Some(on_unlock), std::ptr::null_mut()) };
+        let writer = Connection::open("lock_wait.db").unwrap();
+        writer
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY)")
+            .unwrap();
+
+        // a wait longer than LOCK_TIMEOUT_MS fails with SQLITE_BUSY
+        writer
+            .execute("BEGIN EXCLUSIVE; INSERT INTO t VALUES (1)")
+            .unwrap();
+        let reader = Connection::open("lock_wait.db").unwrap();
+        let err = reader.execute("SELECT count(*) FROM t").unwrap_err();
+        assert_eq!(err.code, Some(5), "{err}"); // SQLITE_BUSY
+        assert_eq!(NOTIFIED.load(Ordering::Relaxed), 0);
+
+        // a shorter one gets the lock, and the release is reported
+        let waiter = std::thread::spawn(move || integrity_and_count(&reader, "t"));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        writer.execute("COMMIT").unwrap();
+        assert_eq!(waiter.join().unwrap(), ("ok".to_string(), 1));
+        assert!(NOTIFIED.load(Ordering::Relaxed) > 0);
+        unsafe { s3qlite_unlock_notify(None, std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn test_lock_wait() {
+        let output = run_child(
+            "lock_wait_workload",
+            &[
+                ("LOCK_TIMEOUT_MS", "1000"),
+                ("S3QLITE_LOCK_WAIT_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_commit_budget, a no-op otherwise.
+    #[test]
+    fn commit_budget_workload() {
+        if std::env::var("S3QLITE_COMMIT_BUDGET_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let budget_waits = |connection: &Connection| -> u64 {
+            let stats = crate::query_string(connection, "PRAGMA s3qlite_stats").unwrap();
+            stats
+                .split("\"commit_budget_waits\":")
+                .nth(1)
+                .and_then(|rest| rest.split([',', '}']).next())
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        let connection = Connection::open("commit_budget_a.db").unwrap();
+        assert_eq!(budget_waits(&connection), 0);
+
+        // with a budget of a byte every commit takes all of it, so commits to two
+        // databases at once wait for each other
+        let commits = |name: &'static str| {
+            std::thread::spawn(move || {
+                let connection = Connection::open(name).unwrap();
+                connection
+                    .execute("CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB)")
+                    .unwrap();
+                for _ in 0..200 {
+                    connection
+                        .execute("INSERT INTO t (body) VALUES (randomblob(3000))")
+                        .unwrap();
+                }
+                integrity_and_count(&connection, "t")
+            })
+        };
+        let (a, b) = (commits("commit_budget_a.db"), commits("commit_budget_b.db"));
+        assert_eq!(a.join().unwrap(), ("ok".to_string(), 200));
+        assert_eq!(b.join().unwrap(), ("ok".to_string(), 200));
+        assert!(budget_waits(&connection) > 0);
+
+        // a transaction's pages are loaded by several requests at once
+        connection
+            .execute(
+                "UPDATE t SET body = randomblob(3000); \
+                 DELETE FROM t WHERE id % 3 = 0",
+            )
+            .unwrap();
+        assert_eq!(
+            integrity_and_count(&connection, "t"),
+            ("ok".to_string(), 134)
+        );
+    }
+
+    #[test]
+    fn test_commit_budget() {
+        let output = run_child(
+            "commit_budget_workload",
+            &[
+                ("COMMIT_INFLIGHT_BYTES", "1"),
+                // no transaction fits in a byte, so let them run over the budget
+                ("PENDING_WRITES_MAX_BYTES", "0"),
+                ("COMMIT_MAX_CONCURRENCY", "8"),
+                ("S3QLITE_COMMIT_BUDGET_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Runs in a child process started by test_large_transaction_spills, a no-op otherwise.
+    #[test]
+    fn large_transaction_spills_workload() {
+        if std::env::var("S3QLITE_SPILL_CHILD").is_err() {
+            return;
+        }
+        init_vfs();
+        let spilled_bytes = |connection: &Connection| -> u64 {
+            let stats = crate::query_string(connection, "PRAGMA s3qlite_stats").unwrap();
+            stats
+                .split("\"spilled_bytes\":")
+                .nth(1)
+                .and_then(|rest| rest.split([',', '}']).next())
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        let connection = Connection::open("large_transaction.db").unwrap();
+        // SQLite's own cache has to hold the transaction, or it journals it instead
+        connection
+            .execute(
+                "PRAGMA cache_size = -65536; \
+                 CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB)",
+            )
+            .unwrap();
+        assert_eq!(spilled_bytes(&connection), 0);
+
+        // 2MB in one transaction, well past SPILL_THRESHOLD_BYTES
+        connection
+            .execute(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500) \
+                 INSERT INTO t (body) SELECT randomblob(4000) FROM n",
+            )
+            .unwrap();
+        let spilled = spilled_bytes(&connection);
+        assert!(spilled > 1_000_000, "{spilled}");
+        let reader = Connection::open("large_transaction.db").unwrap();
+        assert_eq!(integrity_and_count(&reader, "t"), ("ok".to_string(), 500));
+    }
+
+    #[test]
+    fn test_large_transaction_spills() {
+        let output = run_child(
+            "large_transaction_spills_workload",
+            &[
+                ("SPILL_THRESHOLD_BYTES", "65536"),
+                ("S3QLITE_SPILL_CHILD", "1"),
+            ],
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
 }
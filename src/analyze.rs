@@ -0,0 +1,261 @@
+//! When to run ANALYZE.
+//!
+//! SQLite plans queries from the statistics ANALYZE leaves in `sqlite_stat1`. Once the
+//! data has moved on from them a plan can scan where it should seek, which on pages
+//! fetched from object storage is the difference between a few GETs and thousands. The
+//! VFS can't run ANALYZE itself, but it sees every write, so it tracks how much each
+//! database has changed since ANALYZE last ran:
+//!
+//! - commits, from the change counter on page 1,
+//! - pages written, and how often each b-tree root was, which approximates the churn of
+//!   each table and its indexes (roots are rewritten as rows come and go, and on every
+//!   change to a small table).
+//!
+//! `PRAGMA s3qlite_analyze_advice` reads the schema, attributes the root writes to tables
+//! and returns them as JSON with `"analyze":true` once the pages written since reach
+//! `ANALYZE_CHURN_PCT` of the database. A write to `sqlite_stat1`'s root means ANALYZE
+//! ran, and starts the count again, as does `PRAGMA s3qlite_analyze_advice='reset'`.
+//! Only the first `MAX_TRACKED_PAGES` distinct pages written are counted per page, past
+//! that root writes are undercounted but pages written still add up.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+pub const MAX_TRACKED_PAGES: usize = 1 << 16;
+
+const STAT1: &str = "sqlite_stat1";
+
+#[derive(Debug, Default)]
+pub struct Churn {
+    inner: Mutex<ChurnInner>,
+}
+
+#[derive(Debug, Default)]
+struct ChurnInner {
+    commits: u64,
+    change_counter: Option<u32>,
+    pages_written: u64,
+    // writes by page number, 1 based like SQLite's
+    page_writes: HashMap<u32, u64>,
+}
+
+impl Churn {
+    /// Record SQLite writing `page` at `offset` of the database.
+    pub fn record_write(&self, offset: usize, page: &[u8]) {
+        if page.is_empty() {
+            return;
+        }
+        let page_no = (offset / page.len() + 1) as u32;
+        let mut inner = self.inner.lock();
+        inner.pages_written += 1;
+        if page_no == 1 && page.len() >= 100 {
+            let counter = u32::from_be_bytes(page[24..28].try_into().unwrap());
+            if inner.change_counter.replace(counter) != Some(counter) {
+                inner.commits += 1;
+            }
+        }
+        if inner.page_writes.len() < MAX_TRACKED_PAGES || inner.page_writes.contains_key(&page_no) {
+            *inner.page_writes.entry(page_no).or_default() += 1;
+        }
+    }
+
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock();
+        let change_counter = inner.change_counter;
+        *inner = ChurnInner {
+            change_counter,
+            ..Default::default()
+        };
+    }
+
+    /// The advice for a database of `page_count` pages with `schema`, as JSON.
+    pub fn advice(&self, schema: &[SchemaEntry], page_count: u64, churn_pct: u64) -> String {
+        let stat1 = schema.iter().find(|entry| entry.name == STAT1);
+        if stat1.is_some_and(|stat1| self.inner.lock().page_writes.contains_key(&stat1.root)) {
+            self.reset();
+        }
+        let inner = self.inner.lock();
+        let mut tables: Vec<(&str, u64)> = Vec::new();
+        for entry in schema.iter().filter(|entry| entry.kind == "table") {
+            tables.push((&entry.name, 0));
+        }
+        for entry in schema {
+            let writes = inner.page_writes.get(&entry.root).copied().unwrap_or(0);
+            if let Some((_, root_writes)) = tables.iter_mut().find(|(t, _)| *t == entry.table) {
+                *root_writes += writes;
+            }
+        }
+        tables.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let pct = (inner.pages_written * 100)
+            .checked_div(page_count)
+            .unwrap_or(0);
+        let analyze = inner.commits > 0 && page_count > 0 && pct >= churn_pct;
+        let tables = tables
+            .iter()
+            .map(|(table, root_writes)| {
+                format!(
+                    "{{\"table\":\"{}\",\"root_writes\":{root_writes}}}",
+                    escape(table)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"analyze\":{analyze},\"has_stats\":{},\"commits\":{},\"pages_written\":{},\"page_count\":{page_count},\"churn_pct\":{pct},\"tables\":[{tables}]}}",
+            stat1.is_some(),
+            inner.commits,
+            inner.pages_written,
+        )
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// A row of `sqlite_schema`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaEntry {
+    /// `table`, `index`, ...
+    pub kind: String,
+    pub name: String,
+    /// The table an index belongs to, or the table's own name
+    pub table: String,
+    pub root: u32,
+}
+
+/// A page of the `sqlite_schema` b-tree.
+#[derive(Debug)]
+pub enum SchemaPage {
+    Leaf(Vec<SchemaEntry>),
+    Interior(Vec<u32>),
+}
+
+/// The page size and usable size of each page from the database header on page 1.
+pub fn page_sizes(header: &[u8]) -> Option<(usize, usize)> {
+    if header.len() < 100 {
+        return None;
+    }
+    let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+        1 => 65536,
+        n => n as usize,
+    };
+    let usable = page_size.checked_sub(header[20] as usize)?;
+    // SQLite's own limits, which the payload arithmetic relies on
+    (page_size.is_power_of_two() && page_size >= 512 && usable >= 480)
+        .then_some((page_size, usable))
+}
+
+/// Parse page `page_no` of the `sqlite_schema` b-tree. Entries whose name or root page
+/// overflow the page are left out.
+pub fn parse_schema_page(page: &[u8], page_no: u32, usable: usize) -> Option<SchemaPage> {
+    let header = if page_no == 1 { 100 } else { 0 };
+    let kind = *page.get(header)?;
+    let cells = u16::from_be_bytes([*page.get(header + 3)?, *page.get(header + 4)?]) as usize;
+    let cell_pointers = header + if kind == 0x05 { 12 } else { 8 };
+    let cell = |i: usize| -> Option<usize> {
+        let at = cell_pointers + i * 2;
+        Some(u16::from_be_bytes([*page.get(at)?, *page.get(at + 1)?]) as usize)
+    };
+    match kind {
+        0x05 => {
+            let mut children = Vec::with_capacity(cells + 1);
+            for i in 0..cells {
+                let at = cell(i)?;
+                children.push(u32::from_be_bytes(page.get(at..at + 4)?.try_into().ok()?));
+            }
+            children.push(u32::from_be_bytes(
+                page.get(header + 8..header + 12)?.try_into().ok()?,
+            ));
+            Some(SchemaPage::Interior(children))
+        }
+        0x0D => {
+            let mut entries = Vec::with_capacity(cells);
+            for i in 0..cells {
+                let mut at = cell(i)?;
+                let (payload, n) = varint(page.get(at..)?)?;
+                at += n;
+                let (_rowid, n) = varint(page.get(at..)?)?;
+                at += n;
+                let local = local_payload(payload as usize, usable);
+                let Some(record) = page.get(at..at + local) else {
+                    continue;
+                };
+                if let Some(entry) = schema_entry(record) {
+                    entries.push(entry);
+                }
+            }
+            Some(SchemaPage::Leaf(entries))
+        }
+        _ => None,
+    }
+}
+
+/// How much of a table leaf cell's payload is stored on the page itself.
+fn local_payload(payload: usize, usable: usize) -> usize {
+    let max_local = usable - 35;
+    if payload <= max_local {
+        return payload;
+    }
+    let min_local = (usable - 12) * 32 / 255 - 23;
+    let local = min_local + (payload - min_local) % (usable - 4);
+    if local <= max_local { local } else { min_local }
+}
+
+/// `type`, `name`, `tbl_name` and `rootpage` from a `sqlite_schema` record.
+fn schema_entry(record: &[u8]) -> Option<SchemaEntry> {
+    let (header_len, mut at) = varint(record)?;
+    let mut serial_types = Vec::new();
+    while at < header_len as usize && serial_types.len() < 4 {
+        let (serial_type, n) = varint(record.get(at..)?)?;
+        serial_types.push(serial_type);
+        at += n;
+    }
+    let mut body = header_len as usize;
+    let mut values = Vec::new();
+    for serial_type in serial_types {
+        let len = match serial_type {
+            0 | 8 | 9 => 0,
+            1..=4 => serial_type as usize,
+            5 => 6,
+            6 | 7 => 8,
+            n if n >= 12 => (n as usize - 12) / 2,
+            _ => return None,
+        };
+        values.push((serial_type, record.get(body..body + len)?));
+        body += len;
+    }
+    let text = |i: usize| -> Option<String> {
+        let (serial_type, value) = values.get(i)?;
+        (*serial_type >= 13 && serial_type % 2 == 1)
+            .then(|| String::from_utf8_lossy(value).into_owned())
+    };
+    let root = match values.get(3)? {
+        (8, _) => 0,
+        (9, _) => 1,
+        (1..=4, value) => value.iter().fold(0u32, |n, &b| n << 8 | b as u32),
+        _ => return None,
+    };
+    Some(SchemaEntry {
+        kind: text(0)?,
+        name: text(1)?,
+        table: text(2)?,
+        root,
+    })
+}
+
+/// A SQLite varint and its length in bytes.
+fn varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *bytes.get(i)?;
+        if i == 8 {
+            return Some((value << 8 | byte as u64, 9));
+        }
+        value = value << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
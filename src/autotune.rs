@@ -0,0 +1,112 @@
+//! Adaptive tuning of the prefetch window and page cache size.
+//!
+//! Every interval the controller looks at the cache hit rate, the latency of cache
+//! misses and how many prefetched pages were actually read, then nudges the prefetch
+//! window and cache capacity within the configured bounds. Every change is logged.
+
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Below this hit rate a full cache is grown.
+const GROW_CACHE_BELOW_HIT_RATE: f64 = 0.90;
+/// Above this hit rate a mostly empty cache is shrunk.
+const SHRINK_CACHE_ABOVE_HIT_RATE: f64 = 0.99;
+/// Misses slower than this make prefetching worth its extra requests.
+const SLOW_MISS: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone)]
+pub struct Bounds {
+    pub min_cache_bytes: u64,
+    pub max_cache_bytes: u64,
+    pub max_prefetch_pages: usize,
+}
+
+/// What the VFS reports to the controller between ticks.
+#[derive(Default)]
+pub struct Signals {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    miss_nanos: AtomicU64,
+    prefetch_used: AtomicU64,
+    // prefetched keys not read yet, whatever is left at a tick was wasted
+    prefetched: Mutex<HashSet<Vec<u8>>>,
+    /// Pages to read ahead after a cache miss
+    pub prefetch_window: AtomicUsize,
+}
+
+impl Signals {
+    pub fn record_hit(&self, key: &[u8]) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if self.prefetched.lock().remove(key) {
+            self.prefetch_used.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_miss(&self, latency: Duration) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.miss_nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_prefetch(&self, key: &[u8]) {
+        self.prefetched.lock().insert(key.to_vec());
+    }
+}
+
+/// The current cache state the controller decides on.
+pub struct CacheState {
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// One controller step: drain the signals and return the new cache capacity, updating
+/// the prefetch window in place.
+pub fn tick(signals: &Signals, cache: CacheState, bounds: &Bounds) -> u64 {
+    let hits = signals.hits.swap(0, Ordering::Relaxed);
+    let misses = signals.misses.swap(0, Ordering::Relaxed);
+    let miss_nanos = signals.miss_nanos.swap(0, Ordering::Relaxed);
+    let used = signals.prefetch_used.swap(0, Ordering::Relaxed);
+    let wasted = {
+        let mut prefetched = signals.prefetched.lock();
+        let wasted = prefetched.len() as u64;
+        prefetched.clear();
+        wasted
+    };
+    if hits + misses == 0 {
+        return cache.max_bytes;
+    }
+    let hit_rate = hits as f64 / (hits + misses) as f64;
+    let avg_miss = Duration::from_nanos(miss_nanos.checked_div(misses).unwrap_or(0));
+
+    let mut max_bytes = cache.max_bytes;
+    if hit_rate < GROW_CACHE_BELOW_HIT_RATE && cache.bytes * 10 >= max_bytes * 9 {
+        max_bytes = (max_bytes + max_bytes / 4).min(bounds.max_cache_bytes);
+    } else if hit_rate > SHRINK_CACHE_ABOVE_HIT_RATE && cache.bytes * 2 < max_bytes {
+        max_bytes = (max_bytes - max_bytes / 5).max(bounds.min_cache_bytes);
+    }
+    if max_bytes != cache.max_bytes {
+        log::info!(
+            "autotune: cache {} -> {max_bytes} bytes (hit rate {hit_rate:.3}, {} bytes used)",
+            cache.max_bytes,
+            cache.bytes
+        );
+    }
+
+    let window = signals.prefetch_window.load(Ordering::Relaxed);
+    let useful = used + wasted == 0 || used >= wasted;
+    let new_window = if avg_miss >= SLOW_MISS && misses > 0 && useful {
+        (window * 2).max(1).min(bounds.max_prefetch_pages)
+    } else {
+        window / 2
+    };
+    if new_window != window {
+        signals.prefetch_window.store(new_window, Ordering::Relaxed);
+        log::info!(
+            "autotune: prefetch window {window} -> {new_window} pages \
+             (avg miss {avg_miss:?}, prefetched pages used {used}, wasted {wasted})"
+        );
+    }
+    max_bytes
+}
@@ -0,0 +1,84 @@
+//! Files created by this process that haven't been synced yet.
+//!
+//! A new database used to cost a marker PUT, probing GETs and a PUT per page before the
+//! first CREATE TABLE returned. Until its first sync a freshly created file now lives in
+//! memory: reads and size checks need no round trips, and the sync stores the marker and
+//! every page in one batch. A journal deleted before it is ever synced never reaches the
+//! store at all. Opening a file that exists writes nothing, and one that doesn't only
+//! becomes fresh when the open asks for `SQLITE_OPEN_CREATE`.
+//!
+//! The VFS promises `SQLITE_IOCAP_SEQUENTIAL`, so SQLite doesn't sync a journal before
+//! overwriting the pages it protects. A fresh journal that has been written to is
+//! therefore stored before a write to any other file reaches the store, super-journals
+//! before the journals naming them. A crash can then never leave changed pages without
+//! the hot journal that undoes them.
+
+use slatedb::bytes::Bytes;
+use std::collections::BTreeMap;
+
+pub struct FreshFile {
+    // page offset -> page image
+    pages: BTreeMap<usize, Vec<u8>>,
+    /// Bytes per page, see `meta`
+    pub block_size: usize,
+    /// Name table entry stored with the first sync, see `names`
+    pub name_entry: Option<Vec<u8>>,
+    // the size set by the last truncate, which may leave a gap, see `extent`
+    truncated_to: usize,
+}
+
+impl FreshFile {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            block_size,
+            name_entry: None,
+            truncated_to: 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        let block_size = self.block_size;
+        for (page_offset, offset_in_page, range) in
+            crate::page_spans(block_size, offset, data.len())
+        {
+            let data = &data[range];
+            let page = self.pages.entry(page_offset).or_default();
+            if offset_in_page + data.len() > page.len() {
+                page.resize(offset_in_page + data.len(), 0);
+            }
+            page[offset_in_page..offset_in_page + data.len()].copy_from_slice(data);
+        }
+    }
+
+    pub fn page(&self, page_offset: usize) -> Option<Bytes> {
+        self.pages
+            .get(&page_offset)
+            .map(|page| Bytes::copy_from_slice(page))
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.pages.values().map(Vec::len).sum()
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages
+            .last_key_value()
+            .map_or(0, |(offset, page)| offset + page.len())
+            .max(self.truncated_to)
+    }
+
+    pub fn truncate(&mut self, size: usize) {
+        self.pages.retain(|&offset, _| offset < size);
+        for (offset, page) in &mut self.pages {
+            page.truncate(size - offset);
+        }
+        self.truncated_to = size;
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.pages
+            .iter()
+            .map(|(offset, page)| (*offset, page.as_slice()))
+    }
+}
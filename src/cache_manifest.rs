@@ -0,0 +1,45 @@
+//! Cache manifests let a freshly started process warm its page cache from the pages
+//! another process found hot, instead of warming purely on demand.
+//!
+//! A manifest is stored under `{path}:cache_manifest` as newline separated page offsets,
+//! hottest first. Each `CACHE_MANIFEST_INTERVAL_SECS` the hot set of every cached
+//! database is taken again, and its manifest is only written if that changed, so an idle
+//! process doesn't write one a minute.
+
+use std::collections::BTreeMap;
+
+pub fn manifest_key(path: &str) -> String {
+    format!("{path}:cache_manifest")
+}
+
+/// Group cached page keys (`{path}:page:{offset}`) by database path, keeping their order.
+pub fn group_page_keys(keys: &[Vec<u8>]) -> BTreeMap<String, Vec<usize>> {
+    let mut by_path: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for key in keys {
+        let Ok(key) = std::str::from_utf8(key) else {
+            continue;
+        };
+        let Some((path, offset)) = key.rsplit_once(":page:") else {
+            continue;
+        };
+        if let Ok(offset) = offset.parse::<usize>() {
+            by_path.entry(path.to_string()).or_default().push(offset);
+        }
+    }
+    by_path
+}
+
+pub fn encode(offsets: &[usize]) -> String {
+    offsets
+        .iter()
+        .map(|o| o.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn decode(manifest: &[u8]) -> Vec<usize> {
+    String::from_utf8_lossy(manifest)
+        .lines()
+        .filter_map(|line| line.trim().parse::<usize>().ok())
+        .collect()
+}
@@ -0,0 +1,146 @@
+//! Time, randomness and ids, behind traits so tests can pin them down.
+//!
+//! Durations the VFS reports or acts on (how long a handle has been open or idle, how
+//! long a lock wait took, when the throttle window was last cut, when a recovery ran)
+//! are measured with a `Clock`, handle ids come from `Ids` and SlateDB's random number
+//! generators, which name its checkpoints, are seeded from `Random`.
+//!
+//! SQLite asks the VFS for the time too, for `'now'`, and to sleep between retries while
+//! a `busy_timeout` runs, so both follow the clock.
+//!
+//! Normally these are the system's. With `DETERMINISTIC_SEED` set the VFS uses
+//! `ManualClock`, which only moves when `PRAGMA s3qlite_advance_clock='<ms>'` moves it,
+//! and a `SeededRandom`, so tests of expiry and backoff can step time instead of
+//! sleeping through it. Timeouts waited on with the OS (lock waits, background task
+//! intervals) still take real time.
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// For measuring durations.
+    fn now(&self) -> Instant;
+
+    /// For timestamps.
+    fn system_now(&self) -> SystemTime;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock stopped at its creation until `advance` moves it.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_system: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_system: SystemTime::UNIX_EPOCH,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move the clock forward, returning how far it is from its start.
+    pub fn advance(&self, by: Duration) -> Duration {
+        let mut elapsed = self.elapsed.lock();
+        *elapsed += by;
+        *elapsed
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.start_system + *self.elapsed.lock()
+    }
+}
+
+/// Milliseconds from the Unix epoch to the Julian one, noon in Greenwich on November 24,
+/// 4714 B.C., which SQLite counts time from.
+const UNIX_EPOCH_JULIAN_MS: i64 = 210_866_760_000_000;
+
+/// `time` in milliseconds since the Julian epoch.
+pub fn julian_ms(time: SystemTime) -> i64 {
+    let unix_ms = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    };
+    UNIX_EPOCH_JULIAN_MS + unix_ms
+}
+
+pub trait Random: Send + Sync + std::fmt::Debug {
+    fn next_u64(&self) -> u64;
+}
+
+#[derive(Debug)]
+pub struct SystemRandom;
+
+impl Random for SystemRandom {
+    fn next_u64(&self) -> u64 {
+        let mut bytes = [0; 8];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes)
+            .expect("the system random source failed");
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// splitmix64, the same sequence for the same seed.
+#[derive(Debug)]
+pub struct SeededRandom(AtomicU64);
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self(AtomicU64::new(seed))
+    }
+}
+
+impl Random for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        let mut z = self
+            .0
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+pub trait Ids: Send + Sync + std::fmt::Debug {
+    fn next_id(&self) -> u64;
+}
+
+/// 1, 2, 3, ...
+#[derive(Debug)]
+pub struct SequentialIds(AtomicU64);
+
+impl Default for SequentialIds {
+    fn default() -> Self {
+        Self(AtomicU64::new(1))
+    }
+}
+
+impl Ids for SequentialIds {
+    fn next_id(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,114 @@
+//! Per-database storage traffic, and a rough projection of what it costs on S3.
+//!
+//! Counts are taken at the VFS boundary: every read that misses the page cache is
+//! counted as a GET and every SlateDB write as a PUT. SlateDB's own block cache and WAL
+//! batching mean the real request counts are lower, so the estimate is an upper bound.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
+
+const SECONDS_PER_MONTH: f64 = 30.0 * 24.0 * 60.0 * 60.0;
+const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// S3 prices in USD. Defaults are S3 Standard in us-east-1.
+#[derive(Debug, Clone)]
+pub struct Pricing {
+    pub per_1k_gets: f64,
+    pub per_1k_puts: f64,
+    pub per_gb_month: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    gets: u64,
+    get_bytes: u64,
+    puts: u64,
+    put_bytes: u64,
+    // page writes skipped because the contents were unchanged
+    skipped_puts: u64,
+}
+
+struct DbTraffic {
+    since: Instant,
+    counts: Counts,
+}
+
+#[derive(Default)]
+pub struct Traffic {
+    by_db: Mutex<HashMap<String, DbTraffic>>,
+}
+
+impl Traffic {
+    pub fn record_get(&self, key: &[u8], bytes: usize) {
+        self.record(key, |c| {
+            c.gets += 1;
+            c.get_bytes += bytes as u64;
+        });
+    }
+
+    pub fn record_put(&self, key: &[u8], bytes: usize) {
+        self.record(key, |c| {
+            c.puts += 1;
+            c.put_bytes += bytes as u64;
+        });
+    }
+
+    pub fn record_skipped_put(&self, key: &[u8]) {
+        self.record(key, |c| c.skipped_puts += 1);
+    }
+
+    fn record(&self, key: &[u8], f: impl FnOnce(&mut Counts)) {
+        let Some(path) = db_path(key) else {
+            return;
+        };
+        let mut by_db = self.by_db.lock();
+        let traffic = by_db.entry(path.to_string()).or_insert_with(|| DbTraffic {
+            since: Instant::now(),
+            counts: Counts::default(),
+        });
+        f(&mut traffic.counts);
+    }
+
+    /// Project the monthly cost of `path` at its request rate so far, storing
+    /// `stored_bytes`.
+    pub fn estimate(&self, path: &str, stored_bytes: usize, pricing: &Pricing) -> String {
+        let (counts, elapsed) = match self.by_db.lock().get(path) {
+            Some(t) => (t.counts, t.since.elapsed().as_secs_f64()),
+            None => return "no traffic recorded".to_string(),
+        };
+        // avoid projecting a month from a few milliseconds of traffic
+        let scale = SECONDS_PER_MONTH / elapsed.max(1.0);
+        let gets = counts.gets as f64 * scale;
+        let puts = counts.puts as f64 * scale;
+        let get_cost = gets / 1000.0 * pricing.per_1k_gets;
+        let put_cost = puts / 1000.0 * pricing.per_1k_puts;
+        let storage_cost = stored_bytes as f64 / BYTES_PER_GB * pricing.per_gb_month;
+        format!(
+            "${:.2}/month (gets {:.0} ${get_cost:.2}, puts {:.0} ${put_cost:.2}, storage {stored_bytes} bytes ${storage_cost:.2}) \
+             from {} gets / {} bytes and {} puts / {} bytes in {elapsed:.0}s, \
+             {} unchanged page writes skipped",
+            get_cost + put_cost + storage_cost,
+            gets,
+            puts,
+            counts.gets,
+            counts.get_bytes,
+            counts.puts,
+            counts.put_bytes,
+            counts.skipped_puts,
+        )
+    }
+}
+
+/// The database a storage key belongs to: `{path}`, `{path}:page:{offset}`,
+/// `{path}:snapshot:{name}` or `{path}:cache_manifest`.
+fn db_path(key: &[u8]) -> Option<&str> {
+    let key = std::str::from_utf8(key).ok()?;
+    if let Some((path, _)) = key.rsplit_once(":page:") {
+        return Some(path);
+    }
+    if let Some((path, _)) = key.rsplit_once(":snapshot:") {
+        return Some(path);
+    }
+    Some(key.strip_suffix(":cache_manifest").unwrap_or(key))
+}
@@ -0,0 +1,375 @@
+//! Credentials for the S3 object store.
+//!
+//! `S3QLITE_CREDENTIALS` picks the source:
+//! - `env`: `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+//! - `profile`: the `AWS_PROFILE` (or `default`) section of the shared credentials file
+//! - `instance`: object_store's own chain (web identity, ECS and IMDS), which refreshes
+//!   its credentials itself
+//!
+//! A provider registered with `set_credentials_provider` or
+//! `s3qlite_set_credentials_callback` before the VFS is initialized takes precedence.
+
+use parking_lot::Mutex;
+use slatedb::object_store::{self, CredentialProvider, aws::AwsCredential};
+use std::ffi::{CStr, c_char, c_int, c_void};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Refresh credentials this long before they expire, so requests signed just before
+/// expiry still land in time.
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+/// How often profile credentials are re-read, tools like `aws sso login` rewrite the file.
+const PROFILE_REREAD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    /// None for credentials that never expire
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A source of S3 credentials. `credentials` is called again whenever the previous
+/// credentials are about to expire, and may block.
+pub trait CredentialsProvider: Send + Sync {
+    fn credentials(&self) -> Result<Credentials, String>;
+}
+
+static REGISTERED_PROVIDER: Mutex<Option<Arc<dyn CredentialsProvider>>> = Mutex::new(None);
+
+/// Use `provider` for S3 credentials instead of `S3QLITE_CREDENTIALS`. Only takes effect
+/// if called before the VFS is initialized.
+pub fn set_credentials_provider(provider: Arc<dyn CredentialsProvider>) {
+    *REGISTERED_PROVIDER.lock() = Some(provider);
+}
+
+/// The credentials provider for the object store, or None to let object_store find
+/// credentials on its own.
+pub fn provider_from_config(
+    source: Option<&str>,
+) -> Result<Option<object_store::aws::AwsCredentialProvider>, String> {
+    let provider: Arc<dyn CredentialsProvider> = match REGISTERED_PROVIDER.lock().clone() {
+        Some(provider) => provider,
+        None => match source {
+            None | Some("instance") => return Ok(None),
+            Some("env") => Arc::new(EnvProvider),
+            Some("profile") => Arc::new(ProfileProvider::from_env()),
+            Some(other) => return Err(format!("unknown S3QLITE_CREDENTIALS source: {other}")),
+        },
+    };
+    Ok(Some(Arc::new(RefreshingProvider {
+        inner: provider,
+        cached: Mutex::new(None),
+    })))
+}
+
+pub struct EnvProvider;
+
+impl CredentialsProvider for EnvProvider {
+    fn credentials(&self) -> Result<Credentials, String> {
+        let var = |name: &str| std::env::var(name).map_err(|_| format!("{name} is not set"));
+        Ok(Credentials {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            expires_at: None,
+        })
+    }
+}
+
+pub struct ProfileProvider {
+    path: std::path::PathBuf,
+    profile: String,
+}
+
+impl ProfileProvider {
+    pub fn from_env() -> Self {
+        let path = std::env::var("AWS_SHARED_CREDENTIALS_FILE")
+            .map(Into::into)
+            .unwrap_or_else(|_| {
+                let home = std::env::var("HOME").unwrap_or_default();
+                std::path::Path::new(&home).join(".aws").join("credentials")
+            });
+        let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        Self { path, profile }
+    }
+}
+
+impl CredentialsProvider for ProfileProvider {
+    fn credentials(&self) -> Result<Credentials, String> {
+        let contents = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("failed to read {}: {e}", self.path.display()))?;
+        let mut section = None;
+        let mut values = std::collections::HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim().to_string());
+            } else if section.as_deref() == Some(self.profile.as_str())
+                && let Some((key, value)) = line.split_once('=')
+            {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        let value = |key: &str| {
+            values.get(key).cloned().ok_or_else(|| {
+                format!(
+                    "profile {} in {} has no {key}",
+                    self.profile,
+                    self.path.display()
+                )
+            })
+        };
+        Ok(Credentials {
+            access_key_id: value("aws_access_key_id")?,
+            secret_access_key: value("aws_secret_access_key")?,
+            session_token: value("aws_session_token").ok(),
+            expires_at: Some(SystemTime::now() + PROFILE_REREAD_INTERVAL),
+        })
+    }
+}
+
+/// Credentials filled in by `s3qlite_set_credentials_callback` callbacks. The strings only
+/// need to stay valid until the callback returns.
+#[repr(C)]
+pub struct S3qliteCredentials {
+    pub access_key_id: *const c_char,
+    pub secret_access_key: *const c_char,
+    /// May be null
+    pub session_token: *const c_char,
+    /// Unix seconds, 0 if the credentials don't expire
+    pub expires_at: i64,
+}
+
+pub type CredentialsCallback =
+    unsafe extern "C" fn(ctx: *mut c_void, out: *mut S3qliteCredentials) -> c_int;
+
+struct CallbackProvider {
+    callback: CredentialsCallback,
+    ctx: *mut c_void,
+}
+
+// The C side promises the context can be used from any thread, see
+// `s3qlite_set_credentials_callback`.
+unsafe impl Send for CallbackProvider {}
+unsafe impl Sync for CallbackProvider {}
+
+impl CredentialsProvider for CallbackProvider {
+    fn credentials(&self) -> Result<Credentials, String> {
+        let mut out = S3qliteCredentials {
+            access_key_id: std::ptr::null(),
+            secret_access_key: std::ptr::null(),
+            session_token: std::ptr::null(),
+            expires_at: 0,
+        };
+        let rc = unsafe { (self.callback)(self.ctx, &mut out) };
+        if rc != sqlite_plugin::vars::SQLITE_OK {
+            return Err(format!("credentials callback failed with {rc}"));
+        }
+        let string = |p: *const c_char| {
+            (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned())
+        };
+        Ok(Credentials {
+            access_key_id: string(out.access_key_id).ok_or("callback returned no key id")?,
+            secret_access_key: string(out.secret_access_key)
+                .ok_or("callback returned no secret key")?,
+            session_token: string(out.session_token),
+            expires_at: (out.expires_at > 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(out.expires_at as u64)),
+        })
+    }
+}
+
+/// Register a C callback that supplies S3 credentials. Must be called before the VFS is
+/// initialized; returns `SQLITE_MISUSE` afterwards.
+///
+/// # Safety
+/// `callback` is called from arbitrary threads with `ctx`, which must stay valid for the
+/// life of the process.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_set_credentials_callback(
+    callback: CredentialsCallback,
+    ctx: *mut c_void,
+) -> c_int {
+    if crate::vfs_initialized() {
+        return sqlite_plugin::vars::SQLITE_MISUSE;
+    }
+    set_credentials_provider(Arc::new(CallbackProvider { callback, ctx }));
+    sqlite_plugin::vars::SQLITE_OK
+}
+
+/// Caches the provider's credentials and fetches new ones shortly before they expire.
+struct RefreshingProvider {
+    inner: Arc<dyn CredentialsProvider>,
+    cached: Mutex<Option<(Arc<AwsCredential>, Option<SystemTime>)>>,
+}
+
+impl std::fmt::Debug for RefreshingProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RefreshingProvider").finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialProvider for RefreshingProvider {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        if let Some((credential, expires_at)) = &*self.cached.lock()
+            && expires_at.is_none_or(|t| SystemTime::now() + REFRESH_BEFORE_EXPIRY < t)
+        {
+            return Ok(credential.clone());
+        }
+        let inner = self.inner.clone();
+        let fresh = tokio::task::spawn_blocking(move || inner.credentials())
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r)
+            .map_err(|e| {
+                log::error!("failed to refresh credentials: {e}");
+                object_store::Error::Generic {
+                    store: "S3",
+                    source: e.into(),
+                }
+            })?;
+        let credential = Arc::new(AwsCredential {
+            key_id: fresh.access_key_id,
+            secret_key: fresh.secret_access_key,
+            token: fresh.session_token,
+        });
+        *self.cached.lock() = Some((credential.clone(), fresh.expires_at));
+        Ok(credential)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Numbered credentials, expiring `expires_in` after they're fetched.
+    struct Counting {
+        calls: AtomicUsize,
+        expires_in: Option<Duration>,
+    }
+
+    impl Counting {
+        fn new(expires_in: Option<Duration>) -> Arc<Self> {
+            Arc::new(Self {
+                calls: AtomicUsize::new(0),
+                expires_in,
+            })
+        }
+    }
+
+    impl CredentialsProvider for Counting {
+        fn credentials(&self) -> Result<Credentials, String> {
+            let n = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+            Ok(Credentials {
+                access_key_id: format!("key-{n}"),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+                expires_at: self.expires_in.map(|d| SystemTime::now() + d),
+            })
+        }
+    }
+
+    struct Failing;
+
+    impl CredentialsProvider for Failing {
+        fn credentials(&self) -> Result<Credentials, String> {
+            Err("no credentials here".to_string())
+        }
+    }
+
+    fn refreshing(inner: Arc<dyn CredentialsProvider>) -> RefreshingProvider {
+        RefreshingProvider {
+            inner,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn key_id(provider: &dyn CredentialProvider<Credential = AwsCredential>) -> String {
+        provider.get_credential().await.unwrap().key_id.clone()
+    }
+
+    #[tokio::test]
+    async fn credentials_are_refreshed_shortly_before_they_expire() {
+        // inside REFRESH_BEFORE_EXPIRY, so every request fetches them again
+        let expiring = Counting::new(Some(Duration::from_secs(60)));
+        let provider = refreshing(expiring.clone());
+        assert_eq!(key_id(&provider).await, "key-1");
+        assert_eq!(key_id(&provider).await, "key-2");
+
+        let lasting = Counting::new(Some(Duration::from_secs(3600)));
+        let provider = refreshing(lasting.clone());
+        assert_eq!(key_id(&provider).await, "key-1");
+        assert_eq!(key_id(&provider).await, "key-1");
+        assert_eq!(lasting.calls.load(Ordering::Relaxed), 1);
+
+        let permanent = Counting::new(None);
+        let provider = refreshing(permanent.clone());
+        assert_eq!(key_id(&provider).await, "key-1");
+        assert_eq!(key_id(&provider).await, "key-1");
+        assert_eq!(permanent.calls.load(Ordering::Relaxed), 1);
+
+        assert!(
+            refreshing(Arc::new(Failing))
+                .get_credential()
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn profiles_are_read_from_their_own_section() {
+        let path = std::env::temp_dir().join(format!("s3qlite_credentials_{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "[default]\naws_access_key_id = default-key\naws_secret_access_key = default-secret\n\n\
+             [work]\naws_access_key_id=work-key\naws_secret_access_key=work-secret\n\
+             aws_session_token=work-token\n\n[partial]\naws_access_key_id = partial-key\n",
+        )
+        .unwrap();
+        let profile = |profile: &str| ProfileProvider {
+            path: path.clone(),
+            profile: profile.to_string(),
+        };
+        let default = profile("default").credentials().unwrap();
+        assert_eq!(default.access_key_id, "default-key");
+        assert_eq!(default.session_token, None);
+        let work = profile("work").credentials().unwrap();
+        assert_eq!(work.access_key_id, "work-key");
+        assert_eq!(work.secret_access_key, "work-secret");
+        assert_eq!(work.session_token.as_deref(), Some("work-token"));
+        assert!(work.expires_at.is_some(), "profiles are re-read");
+        assert!(profile("partial").credentials().is_err());
+        assert!(profile("missing").credentials().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_registered_provider_takes_precedence() {
+        assert!(provider_from_config(None).unwrap().is_none());
+        assert!(provider_from_config(Some("instance")).unwrap().is_none());
+        assert!(provider_from_config(Some("env")).unwrap().is_some());
+        assert!(provider_from_config(Some("profile")).unwrap().is_some());
+        assert!(provider_from_config(Some("vault")).is_err());
+
+        set_credentials_provider(Counting::new(None));
+        // each source's provider fetches from the registered one
+        for (n, source) in [None, Some("instance"), Some("env"), Some("vault")]
+            .into_iter()
+            .enumerate()
+        {
+            let provider = provider_from_config(source).unwrap().unwrap();
+            assert_eq!(
+                key_id(provider.as_ref()).await,
+                format!("key-{}", n + 1),
+                "{source:?}"
+            );
+        }
+        *REGISTERED_PROVIDER.lock() = None;
+    }
+}
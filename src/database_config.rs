@@ -0,0 +1,103 @@
+//! Settings for one database.
+//!
+//! The environment configures every database in the process alike, and one setting
+//! rarely fits them all: an analytics database wants large blocks and its fact tables
+//! pinned, a ledger wants each commit durable before the lock is released. `CONFIG_FILE`
+//! names a TOML file with a section per database, keyed by the name it's opened with:
+//!
+//! ```toml
+//! [databases."analytics.db"]
+//! block_size = 65536
+//! pin = ["events", "sessions"]
+//!
+//! [databases."ledger.db"]
+//! durable_unlock = true
+//! max_db_bytes = 1073741824
+//! ```
+//!
+//! Each key overrides the setting of the same name in the environment for that database
+//! alone, and is applied when it's opened: `block_size` when it's created, see `meta`,
+//! `max_db_bytes` unless it has a limit of its own, see `size_limit`, and `pin` once it
+//! has the tables. Compression is SlateDB's, set for the whole store, so it isn't among
+//! them. An unknown key or a value of the wrong type fails the VFS at startup rather than
+//! being ignored.
+
+use crate::meta;
+use std::collections::HashMap;
+
+/// The settings a database overrides, `None` where it takes the environment's.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatabaseConfig {
+    pub block_size: Option<usize>,
+    pub max_db_bytes: Option<u64>,
+    pub durable_unlock: Option<bool>,
+    /// Tables whose pages are pinned in the page cache, see `s3qlite_pin`.
+    pub pin: Vec<String>,
+}
+
+/// Read `CONFIG_FILE`, returning the settings of each database by name.
+pub fn load(path: &str) -> Result<HashMap<String, DatabaseConfig>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read {path}: {e}"))?;
+    parse(&contents).map_err(|e| format!("{path}: {e}"))
+}
+
+pub fn parse(contents: &str) -> Result<HashMap<String, DatabaseConfig>, String> {
+    let table: toml::Table = contents.parse().map_err(|e| format!("{e}"))?;
+    let mut databases = HashMap::new();
+    for (key, value) in table {
+        if key != "databases" {
+            return Err(format!("unknown section {key}"));
+        }
+        let toml::Value::Table(sections) = value else {
+            return Err("databases is not a table".to_string());
+        };
+        for (name, section) in sections {
+            let toml::Value::Table(section) = section else {
+                return Err(format!("databases.\"{name}\" is not a table"));
+            };
+            let config =
+                parse_section(&section).map_err(|e| format!("[databases.\"{name}\"] {e}"))?;
+            databases.insert(name, config);
+        }
+    }
+    Ok(databases)
+}
+
+fn parse_section(section: &toml::Table) -> Result<DatabaseConfig, String> {
+    let mut config = DatabaseConfig::default();
+    for (key, value) in section {
+        match (key.as_str(), value) {
+            ("block_size", toml::Value::Integer(block_size)) => {
+                let block_size = usize::try_from(*block_size)
+                    .ok()
+                    .filter(|&block_size| meta::valid_block_size(block_size))
+                    .ok_or_else(|| format!("invalid block_size {block_size}"))?;
+                config.block_size = Some(block_size);
+            }
+            ("max_db_bytes", toml::Value::Integer(limit)) => {
+                let limit =
+                    u64::try_from(*limit).map_err(|_| format!("invalid max_db_bytes {limit}"))?;
+                config.max_db_bytes = Some(limit);
+            }
+            ("durable_unlock", toml::Value::Boolean(durable)) => {
+                config.durable_unlock = Some(*durable);
+            }
+            ("pin", toml::Value::Array(tables)) => {
+                config.pin = tables
+                    .iter()
+                    .map(|table| table.as_str().map(str::to_string))
+                    .collect::<Option<_>>()
+                    .ok_or("pin is not a list of table names")?;
+            }
+            ("compression", _) => {
+                return Err("compression is set for the whole store, not per database".into());
+            }
+            ("block_size" | "max_db_bytes" | "durable_unlock" | "pin", value) => {
+                return Err(format!("{key} can't be a {}", value.type_str()));
+            }
+            _ => return Err(format!("unknown key {key}")),
+        }
+    }
+    Ok(config)
+}
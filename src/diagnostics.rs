@@ -0,0 +1,47 @@
+//! The chrome trace of VFS operations, `s3qlite_trace.cpuprofile` in the state directory.
+//!
+//! Behind the `diagnostics` feature, on by default. Building with
+//! `--no-default-features` leaves out tracing-chrome and tracing-subscriber for a smaller
+//! extension: no trace is written, and with no subscriber installed the `tracing` spans
+//! around VFS calls are close to free.
+
+#[cfg(feature = "diagnostics")]
+pub use tracing_chrome::FlushGuard;
+
+#[cfg(not(feature = "diagnostics"))]
+pub struct FlushGuard;
+
+#[cfg(not(feature = "diagnostics"))]
+impl FlushGuard {
+    pub fn flush(&self) {}
+}
+
+#[cfg(feature = "diagnostics")]
+pub fn setup_tracing(state_dir: &std::path::Path) -> Result<FlushGuard, String> {
+    use std::fs::File;
+    use std::io::BufWriter;
+    use tracing_chrome::ChromeLayerBuilder;
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    let path = state_dir.join("s3qlite_trace.cpuprofile");
+    let file = std::fs::create_dir_all(state_dir)
+        .and_then(|_| File::create(&path))
+        .map_err(|e| format!("failed to create {}: {e}", path.display()))?;
+    let (chrome_layer, guard) = ChromeLayerBuilder::new()
+        .writer(BufWriter::new(file))
+        .build();
+
+    // Don't call init() which would take over global logging
+    // Instead, just set up tracing without interfering with existing log setup
+    let subscriber = Registry::default().with(chrome_layer);
+
+    // Only set the global default if there isn't one already
+    let _ = tracing::subscriber::set_global_default(subscriber);
+
+    Ok(guard)
+}
+
+#[cfg(not(feature = "diagnostics"))]
+pub fn setup_tracing(_state_dir: &std::path::Path) -> Result<FlushGuard, String> {
+    Err("s3qlite was built without the diagnostics feature".to_string())
+}
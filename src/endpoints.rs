@@ -0,0 +1,459 @@
+//! Spreading requests over several endpoints of one S3-compatible cluster.
+//!
+//! A MinIO or Ceph RGW cluster serves the same buckets from every node, and one gateway
+//! node caps the throughput of everything behind it. `s3://bucket?endpoint=http://a:9000,
+//! http://b:9000` builds a store per endpoint and `RoundRobinStore` hands each request to
+//! the next of them in turn.
+//!
+//! An endpoint whose request fails with anything but an answer about the object, not
+//! found, precondition failed and the like, is marked down and skipped for `DOWN_FOR`.
+//! The first request after that is its health check: it goes back into the rotation if
+//! the request succeeds and is marked down again if not. With every endpoint down
+//! requests go to them anyway rather than failing outright. A request that can be
+//! repeated safely, a read, a delete or an overwriting put or copy, is retried on the
+//! next endpoint up when one fails. A conditional put or copy isn't, because the failed
+//! endpoint may have applied it before failing, and repeating it would report a
+//! conflict with itself, nor is a rename, whose repeat would find its source gone. A
+//! multipart upload stays on the endpoint it was started on.
+
+use crate::clock::Clock;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use parking_lot::Mutex;
+use slatedb::bytes::Bytes;
+use slatedb::object_store::path::Path;
+use slatedb::object_store::{
+    self, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore, PutMode,
+    PutMultipartOptions, PutOptions, PutPayload, PutResult,
+};
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DOWN_FOR: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    store: Arc<dyn ObjectStore>,
+    // set while the endpoint is marked down, until it's due a health check
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Endpoint {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.lock().is_none_or(|until| now >= until)
+    }
+
+    fn observe<T>(&self, result: &object_store::Result<T>, now: Instant) {
+        match result {
+            Err(e) if is_endpoint_failure(e) => {
+                let mut down_until = self.down_until.lock();
+                if down_until.is_none() {
+                    log::warn!(
+                        "object store endpoint {} failed, skipping it for {DOWN_FOR:?}: {e}",
+                        self.url
+                    );
+                }
+                *down_until = Some(now + DOWN_FOR);
+            }
+            Err(_) => {}
+            Ok(_) => {
+                if self.down_until.lock().take().is_some() {
+                    log::info!("object store endpoint {} is back up", self.url);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `e` is the endpoint failing rather than an answer about the object. The
+/// errors object_store gives for statuses it has no variant for, and for connections
+/// that failed, are all `Generic`.
+fn is_endpoint_failure(e: &object_store::Error) -> bool {
+    matches!(e, object_store::Error::Generic { .. })
+}
+
+/// An object store that sends each request to the next of several stores, each an
+/// endpoint of the same cluster.
+pub struct RoundRobinStore {
+    endpoints: Vec<Arc<Endpoint>>,
+    next: AtomicUsize,
+    clock: Arc<dyn Clock>,
+}
+
+impl RoundRobinStore {
+    /// `endpoints` pairs each endpoint's URL with the store reaching the bucket through
+    /// it. There must be at least one. `clock` times how long one is down for.
+    pub fn new(endpoints: Vec<(String, Arc<dyn ObjectStore>)>, clock: Arc<dyn Clock>) -> Self {
+        assert!(
+            !endpoints.is_empty(),
+            "a round robin store needs an endpoint"
+        );
+        let endpoints = endpoints
+            .into_iter()
+            .map(|(url, store)| {
+                Arc::new(Endpoint {
+                    url,
+                    store,
+                    down_until: Mutex::new(None),
+                })
+            })
+            .collect();
+        Self {
+            endpoints,
+            next: AtomicUsize::new(0),
+            clock,
+        }
+    }
+
+    /// The endpoints to try a request on in order: those up from the next in turn, or all
+    /// of them if none is.
+    fn order(&self) -> Vec<&Arc<Endpoint>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = self.endpoints.len();
+        let turn = (0..n).map(|i| &self.endpoints[(start + i) % n]);
+        let now = self.clock.now();
+        let up: Vec<_> = turn
+            .clone()
+            .filter(|endpoint| endpoint.is_up(now))
+            .collect();
+        if up.is_empty() { turn.collect() } else { up }
+    }
+
+    /// Run `request` on the next endpoint, and on the ones after it while they fail if
+    /// `retry`.
+    async fn run<'a, T>(
+        &self,
+        retry: bool,
+        request: impl Fn(Arc<dyn ObjectStore>) -> BoxFuture<'a, object_store::Result<T>>,
+    ) -> object_store::Result<T> {
+        let order = self.order();
+        let last = order.len() - 1;
+        for (i, endpoint) in order.into_iter().enumerate() {
+            let result = request(endpoint.store.clone()).await;
+            endpoint.observe(&result, self.clock.now());
+            match result {
+                Err(e) if retry && i < last && is_endpoint_failure(&e) => continue,
+                result => return result,
+            }
+        }
+        unreachable!("there's always an endpoint to try")
+    }
+}
+
+impl std::fmt::Debug for RoundRobinStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoundRobinStore")
+            .field("endpoints", &self.endpoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Display for RoundRobinStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let urls: Vec<&str> = self.endpoints.iter().map(|e| e.url.as_str()).collect();
+        write!(f, "RoundRobinStore({})", urls.join(", "))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for RoundRobinStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> object_store::Result<PutResult> {
+        let retry = matches!(opts.mode, PutMode::Overwrite);
+        self.run(retry, |store| {
+            let (payload, opts) = (payload.clone(), opts.clone());
+            async move { store.put_opts(location, payload, opts).await }.boxed()
+        })
+        .await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> object_store::Result<Box<dyn MultipartUpload>> {
+        self.run(true, |store| {
+            let opts = opts.clone();
+            async move { store.put_multipart_opts(location, opts).await }.boxed()
+        })
+        .await
+    }
+
+    async fn get_opts(
+        &self,
+        location: &Path,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        self.run(true, |store| {
+            let options = options.clone();
+            async move { store.get_opts(location, options).await }.boxed()
+        })
+        .await
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<u64>) -> object_store::Result<Bytes> {
+        self.run(true, |store| {
+            let range = range.clone();
+            async move { store.get_range(location, range).await }.boxed()
+        })
+        .await
+    }
+
+    async fn get_ranges(
+        &self,
+        location: &Path,
+        ranges: &[Range<u64>],
+    ) -> object_store::Result<Vec<Bytes>> {
+        self.run(true, |store| {
+            async move { store.get_ranges(location, ranges).await }.boxed()
+        })
+        .await
+    }
+
+    async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+        self.run(true, |store| {
+            async move { store.head(location).await }.boxed()
+        })
+        .await
+    }
+
+    async fn delete(&self, location: &Path) -> object_store::Result<()> {
+        self.run(true, |store| {
+            async move { store.delete(location).await }.boxed()
+        })
+        .await
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        // a listing can't move endpoints halfway through, so it isn't retried
+        let endpoint = self.order()[0].clone();
+        let clock = self.clock.clone();
+        endpoint
+            .store
+            .list(prefix)
+            .inspect(move |result| endpoint.observe(result, clock.now()))
+            .boxed()
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        self.run(true, |store| {
+            async move { store.list_with_delimiter(prefix).await }.boxed()
+        })
+        .await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.run(true, |store| {
+            async move { store.copy(from, to).await }.boxed()
+        })
+        .await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.run(false, |store| {
+            async move { store.rename(from, to).await }.boxed()
+        })
+        .await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.run(false, |store| {
+            async move { store.copy_if_not_exists(from, to).await }.boxed()
+        })
+        .await
+    }
+
+    async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.run(false, |store| {
+            async move { store.rename_if_not_exists(from, to).await }.boxed()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use slatedb::object_store::memory::InMemory;
+    use std::sync::atomic::AtomicBool;
+
+    /// An endpoint onto a shared store that fails every request while `failing`.
+    #[derive(Debug)]
+    struct Flaky {
+        inner: Arc<InMemory>,
+        failing: AtomicBool,
+        requests: AtomicUsize,
+    }
+
+    impl Flaky {
+        fn new(inner: &Arc<InMemory>) -> Arc<Self> {
+            Arc::new(Self {
+                inner: inner.clone(),
+                failing: AtomicBool::new(false),
+                requests: AtomicUsize::new(0),
+            })
+        }
+
+        fn check(&self) -> object_store::Result<()> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            if self.failing.load(Ordering::Relaxed) {
+                return Err(object_store::Error::Generic {
+                    store: "flaky",
+                    source: "connection refused".into(),
+                });
+            }
+            Ok(())
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::Relaxed)
+        }
+    }
+
+    impl std::fmt::Display for Flaky {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Flaky")
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for Flaky {
+        async fn put_opts(
+            &self,
+            location: &Path,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            self.check()?;
+            self.inner.put_opts(location, payload, opts).await
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &Path,
+            opts: PutMultipartOptions,
+        ) -> object_store::Result<Box<dyn MultipartUpload>> {
+            self.check()?;
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &Path,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.check()?;
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &Path) -> object_store::Result<()> {
+            self.check()?;
+            self.inner.delete(location).await
+        }
+
+        fn list(
+            &self,
+            prefix: Option<&Path>,
+        ) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&Path>,
+        ) -> object_store::Result<ListResult> {
+            self.check()?;
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.check()?;
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.check()?;
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    /// A round robin store over endpoints `a` and `b` of `bucket`.
+    fn two_endpoints() -> (
+        Arc<InMemory>,
+        Arc<Flaky>,
+        Arc<Flaky>,
+        Arc<ManualClock>,
+        RoundRobinStore,
+    ) {
+        let bucket = Arc::new(InMemory::new());
+        let (a, b) = (Flaky::new(&bucket), Flaky::new(&bucket));
+        let clock = Arc::new(ManualClock::new());
+        let store = RoundRobinStore::new(
+            vec![
+                ("http://a".to_string(), a.clone() as Arc<dyn ObjectStore>),
+                ("http://b".to_string(), b.clone() as Arc<dyn ObjectStore>),
+            ],
+            clock.clone(),
+        );
+        (bucket, a, b, clock, store)
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_skips_a_failed_endpoint() {
+        let (_, a, b, clock, store) = two_endpoints();
+        let key = Path::from("key");
+        store.put(&key, "v".into()).await.unwrap();
+        assert_eq!((a.requests(), b.requests()), (1, 0));
+
+        // the put is b's turn and the get a's, which fails there and moves on to b
+        a.failing.store(true, Ordering::Relaxed);
+        store.put(&key, "v2".into()).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap().bytes().await.unwrap(), "v2");
+        assert_eq!((a.requests(), b.requests()), (2, 2));
+
+        // a is skipped while down, turn or not, copies included
+        let copied = Path::from("copied");
+        for _ in 0..4 {
+            store.head(&key).await.unwrap();
+        }
+        store.copy(&key, &copied).await.unwrap();
+        assert_eq!((a.requests(), b.requests()), (2, 7));
+
+        // once DOWN_FOR is up a's next turn checks on it, and it's back after a success
+        a.failing.store(false, Ordering::Relaxed);
+        clock.advance(DOWN_FOR);
+        for _ in 0..4 {
+            store.head(&copied).await.unwrap();
+        }
+        assert_eq!((a.requests(), b.requests()), (4, 9));
+    }
+
+    #[tokio::test]
+    async fn copies_are_retried_and_conditional_writes_are_not() {
+        let (bucket, a, b, clock, store) = two_endpoints();
+        let (key, copied) = (Path::from("key"), Path::from("copied"));
+        bucket.put(&key, "v".into()).await.unwrap();
+
+        // a conditional copy failing on a isn't repeated on b
+        a.failing.store(true, Ordering::Relaxed);
+        assert!(store.copy_if_not_exists(&key, &copied).await.is_err());
+        assert_eq!((a.requests(), b.requests()), (1, 0));
+
+        // a plain copy is, once a is due its check and it's a's turn again
+        clock.advance(DOWN_FOR);
+        store.head(&key).await.unwrap();
+        store.copy(&key, &copied).await.unwrap();
+        assert_eq!((a.requests(), b.requests()), (2, 2));
+        bucket.head(&copied).await.unwrap();
+    }
+}
@@ -1,41 +1,435 @@
+use crate::{autotune, cost, eviction, extent, meta, page_cache, pending_writes, stats};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// Values set through `s3qlite_config_set`, for hosts that can't set environment
+// variables (mobile apps). They take precedence over the environment.
+static OVERRIDES: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+pub fn set_override(name: &str, value: &str) {
+    OVERRIDES
+        .lock()
+        .get_or_insert_with(HashMap::new)
+        .insert(name.to_string(), value.to_string());
+}
+
+fn var(name: &str) -> Result<String, std::env::VarError> {
+    if let Some(value) = OVERRIDES.lock().as_ref().and_then(|o| o.get(name)) {
+        return Ok(value.clone());
+    }
+    std::env::var(name)
+}
+
+/// Where diagnostic artifacts (the chrome trace, REPL history) are written:
+/// `S3QLITE_STATE_DIR`, or an `s3qlite` directory in the platform cache directory.
+pub fn state_dir() -> PathBuf {
+    if let Ok(dir) = var("S3QLITE_STATE_DIR") {
+        return dir.into();
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let cache_dir = if cfg!(any(target_os = "macos", target_os = "ios")) {
+        home.map(|home| home.join("Library").join("Caches"))
+    } else if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.map(|home| home.join(".cache")))
+    };
+    cache_dir.unwrap_or_else(std::env::temp_dir).join("s3qlite")
+}
+
+/// `S3QLITE_SILENT=true` guarantees the crate writes nothing to stdout or stderr, for GUI
+/// hosts that treat any console output as an error. Logs still reach SQLite's log
+/// callback. Read on every write rather than cached, so it can be set at any time.
+pub fn silent() -> bool {
+    var("S3QLITE_SILENT")
+        .ok()
+        .and_then(|s| s.parse::<bool>().ok())
+        .unwrap_or(false)
+}
+
 #[derive(Debug, Clone)]
+#[allow(dead_code)] // not every option is wired up yet
 pub struct EnvConfig {
     pub grpc_vfs_url: String,
     pub grpc_vfs_connect_timeout_secs: u64,
+    /// Where pages are stored: `memory://` (default, for tests), `file:///path/to/dir`,
+    /// `s3://bucket/prefix?region=...&endpoint=...`, `gs://bucket/prefix` or
+    /// `azure://container/prefix`, see `store`. Databases can also be opened by URL within
+    /// it, `s3://bucket/prefix/app` for `app`.
+    pub object_store_url: Option<String>,
+    /// Where S3 credentials come from: `env`, `profile` or `instance` (default).
+    pub credentials_source: Option<String>,
+    /// Hex encoded 32 byte key. When set, database paths are stored under opaque names,
+    /// see `names`. Changing it makes existing databases unreachable.
+    pub path_key: Option<String>,
+    /// A TOML file of settings for single databases, see `database_config`.
+    pub config_file: Option<String>,
+    /// Token that lets a connection mark databases read-only, see `readonly`. Unset,
+    /// nobody can.
+    pub admin_token: Option<String>,
     pub local_cache_dir: Option<String>,
     pub max_cache_bytes: Option<u64>,
+    /// Page cache eviction policy: `lru` (default), `slru` or `tinylfu`, see `eviction`.
+    pub cache_policy: eviction::PolicyKind,
+    /// Simulate every eviction policy alongside the cache, for comparing them.
+    pub cache_simulate: bool,
     /// Locally read values instead of going to the server. Risks stale data.
     pub local_reads: bool,
-    /// Preload the cache on startup. Does not block reads. Will start from the DB head and download up to the max cache size.
+    /// Read each database into the cache in the background on its first open, from its
+    /// first page until the cache is full. Reads don't wait for it.
     pub preload_cache: bool,
+    /// Concurrent page reads of a preload.
     pub preload_cache_concurrency: u32,
+    /// See `state_dir`.
+    pub state_dir: PathBuf,
+    /// Write a chrome trace of VFS operations to `s3qlite_trace.cpuprofile` in the state
+    /// directory. Off by default without the `diagnostics` feature, see `diagnostics`.
+    pub trace_file: bool,
+    /// How often the chrome trace is flushed to disk in the background. 0 disables periodic
+    /// flushing, leaving only the final flush on shutdown.
+    pub trace_flush_interval_ms: u64,
+    /// How often the hot page list of each cached database is published for other
+    /// processes to warm from. 0 disables publishing.
+    pub cache_manifest_interval_secs: u64,
+    /// Prefetch the pages listed in a database's cache manifest on first open.
+    pub warm_from_manifest: bool,
+    /// Buffered transaction bytes kept in memory before the rest spill to a temp file
+    /// in `local_cache_dir` (or the system temp dir). The commit still holds every page
+    /// it changes in memory, see `pending_writes`.
+    pub spill_threshold_bytes: usize,
+    /// Bytes of memory the VFS degrades to stay under, 0 for no budget, see
+    /// `memory_budget`.
+    pub memory_budget_bytes: u64,
+    /// Page reads a single commit keeps in flight while loading the pages it modifies.
+    pub commit_max_concurrency: usize,
+    /// Most concurrent requests a fan-out may make, cut while the object store throttles.
+    /// 0 turns the backoff off, see `throttle`.
+    pub throttle_max_window: usize,
+    /// Bytes of page data all in-progress commits may hold at once, counting every copy
+    /// of a page a commit makes. Commits wait for budget instead of exhausting memory. A
+    /// single commit larger than the budget, possible only with a raised
+    /// `pending_writes_max_bytes`, waits for all of it and runs alone, over budget.
+    pub commit_inflight_bytes: usize,
+    /// Bytes a single transaction may buffer, in memory and spilled, before its writes
+    /// fail with `SQLITE_FULL`. Defaults to what keeps its commit within
+    /// `commit_inflight_bytes` and the memory budget, see `pending_writes`. 0 means no
+    /// limit, and no bound on the memory a commit takes.
+    pub pending_writes_max_bytes: u64,
+    /// Bytes any database may grow to before its writes fail with `SQLITE_FULL`, unless
+    /// it has a cap of its own, see `size_limit`. 0 means no limit.
+    pub max_db_bytes: u64,
+    /// Whether a zero-length write past the end of a file extends it, see `extent`.
+    pub zero_length_writes: extent::ZeroLengthWrites,
+    /// Bytes a `?scan=true` handle fetches per page miss, in aligned ranges. Clamped to
+    /// 1-4MB.
+    pub scan_fetch_bytes: usize,
+    /// Pages to read ahead after a cache miss. 0 disables prefetching until autotune
+    /// turns it on.
+    pub prefetch_pages: usize,
+    /// Make a commit durable before its EXCLUSIVE lock is released, so another process
+    /// that takes the lock next is guaranteed to see it. Adds a flush to every commit.
+    pub durable_unlock: bool,
+    /// How often a sample of cached pages is re-fetched from the store and compared
+    /// against the cache. 0 disables the background verifier.
+    pub cache_verify_interval_secs: u64,
+    /// Pages re-fetched per verification pass.
+    pub cache_verify_sample_pages: usize,
+    /// How long `PRAGMA s3qlite_min_generation` waits to catch up, see `generation`.
+    pub min_generation_wait_ms: u64,
+    /// How often the generation of a watched database is re-read, see `generation`. 0
+    /// stops it, leaving watchers to commits made in this process.
+    pub generation_poll_ms: u64,
+    /// Bytes stored per page key of new files, see `meta`.
+    pub block_size: usize,
+    /// How often expired databases are deleted, see `ttl`. 0 leaves them to
+    /// `PRAGMA s3qlite_expire`.
+    pub expire_interval_secs: u64,
+    /// Run on a manual clock and seeded randomness, for tests, see `clock`.
+    pub deterministic_seed: Option<u64>,
+    /// Serve pages missing below a database's size from its snapshots, see
+    /// `read_repair`.
+    pub read_repair: bool,
+    /// Mirror every stored page in memory and compare it with the store, see `shadow`.
+    pub shadow_writes: bool,
+    pub shadow_verify_interval_secs: u64,
+    /// Remember which journals don't exist, so `access` stops asking the store on every
+    /// transaction. Only safe while no other process writes the same databases: a hot
+    /// journal it leaves behind would go unnoticed.
+    pub journal_existence_cache: bool,
+    /// Log a warning for each handle still open after this many seconds. 0 disables
+    /// the check.
+    pub handle_warn_after_secs: u64,
+    /// Write a diagnostic bundle for each write, commit or sync that takes longer, see
+    /// `stall`. 0 disables it.
+    pub stall_threshold_ms: u64,
+    /// Stall bundles kept in the state directory.
+    pub stall_bundles_keep: usize,
+    /// How long a lock request waits for other handles before failing with
+    /// `SQLITE_BUSY`, see `lock_wait`. 0 waits forever.
+    pub lock_timeout_ms: u64,
+    /// How often the counters are written to `stats/` in the state directory, see
+    /// `stats`. 0 disables the dumps.
+    pub stats_dump_interval_secs: u64,
+    pub stats_dump_format: stats::DumpFormat,
+    /// Stats dumps kept before the oldest are deleted.
+    pub stats_dump_keep: usize,
+    /// Hot journal recoveries kept for `PRAGMA s3qlite_recoveries`, see `recovery`.
+    pub recovery_reports_keep: usize,
+    /// Record one in this many page reads and writes for `PRAGMA s3qlite_heatmap`, see
+    /// `heatmap`. 0 disables the heatmap.
+    pub heatmap_sample_every: u64,
+    /// Percent of a database's pages written since ANALYZE last ran at which
+    /// `PRAGMA s3qlite_analyze_advice` recommends running it again, see `analyze`.
+    pub analyze_churn_pct: u64,
+    /// Let the autotune controller adjust the prefetch window and cache size.
+    pub autotune: bool,
+    pub autotune_interval_secs: u64,
+    pub autotune_bounds: autotune::Bounds,
+    /// Prices used by `PRAGMA s3qlite_cost_estimate`.
+    pub pricing: cost::Pricing,
 }
 
 impl EnvConfig {
+    /// The commit in-flight budget in bytes, one permit each: at least one, and no more
+    /// than a single acquire can take.
+    pub fn commit_inflight_permits(&self) -> u32 {
+        self.commit_inflight_bytes.clamp(1, u32::MAX as usize) as u32
+    }
+
     pub fn new() -> Self {
+        let memory_budget_bytes = var("MEMORY_BUDGET_BYTES")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+        let commit_inflight_bytes = var("COMMIT_INFLIGHT_BYTES")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(256 * 1024 * 1024);
+        // the largest transaction whose commit fits in the in-flight budget, and in the
+        // memory budget if there's one
+        let bounded = match memory_budget_bytes {
+            0 => commit_inflight_bytes as u64,
+            budget => budget.min(commit_inflight_bytes as u64),
+        };
+        let default_pending_writes_max_bytes =
+            (bounded / pending_writes::COMMIT_COPIES as u64).max(1);
         Self {
-            grpc_vfs_url: std::env::var("GRPC_VFS_URL")
+            grpc_vfs_url: var("GRPC_VFS_URL")
                 .unwrap_or_else(|_| "http://localhost:50051".to_string()),
-            grpc_vfs_connect_timeout_secs: std::env::var("GRPC_VFS_CONNECT_TIMEOUT_SECS")
+            grpc_vfs_connect_timeout_secs: var("GRPC_VFS_CONNECT_TIMEOUT_SECS")
                 .unwrap_or_else(|_| "10".to_string())
                 .parse::<u64>()
                 .unwrap_or(10),
-            local_cache_dir: std::env::var("LOCAL_CACHE_DIR").ok(),
-            max_cache_bytes: std::env::var("MAX_CACHE_BYTES")
+            object_store_url: var("OBJECT_STORE_URL").ok(),
+            credentials_source: var("S3QLITE_CREDENTIALS").ok(),
+            path_key: var("PATH_KEY").ok(),
+            config_file: var("CONFIG_FILE").ok(),
+            admin_token: var("ADMIN_TOKEN").ok(),
+            local_cache_dir: var("LOCAL_CACHE_DIR").ok(),
+            max_cache_bytes: var("MAX_CACHE_BYTES")
                 .ok()
                 .and_then(|s| s.parse::<u64>().ok()),
-            local_reads: std::env::var("LOCAL_READS")
+            cache_policy: var("CACHE_POLICY")
+                .ok()
+                .and_then(|s| eviction::PolicyKind::parse(&s))
+                .unwrap_or(eviction::PolicyKind::Lru),
+            cache_simulate: var("CACHE_SIMULATE")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            local_reads: var("LOCAL_READS")
                 .ok()
                 .and_then(|s| s.parse::<bool>().ok())
                 .unwrap_or(false),
-            preload_cache: std::env::var("PRELOAD_CACHE")
+            preload_cache: var("PRELOAD_CACHE")
                 .ok()
                 .and_then(|s| s.parse::<bool>().ok())
                 .unwrap_or(false),
-            preload_cache_concurrency: std::env::var("PRELOAD_CACHE_CONCURRENCY")
+            preload_cache_concurrency: var("PRELOAD_CACHE_CONCURRENCY")
                 .ok()
                 .and_then(|s| s.parse::<u32>().ok())
                 .unwrap_or(4),
+            state_dir: state_dir(),
+            trace_file: var("S3QLITE_TRACE_FILE")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(cfg!(feature = "diagnostics")),
+            trace_flush_interval_ms: var("TRACE_FLUSH_INTERVAL_MS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(1000),
+            cache_manifest_interval_secs: var("CACHE_MANIFEST_INTERVAL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(60),
+            warm_from_manifest: var("WARM_FROM_MANIFEST")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(true),
+            spill_threshold_bytes: var("SPILL_THRESHOLD_BYTES")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(64 * 1024 * 1024),
+            memory_budget_bytes,
+            commit_max_concurrency: var("COMMIT_MAX_CONCURRENCY")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(16),
+            throttle_max_window: var("THROTTLE_MAX_WINDOW")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(64),
+            commit_inflight_bytes,
+            pending_writes_max_bytes: var("PENDING_WRITES_MAX_BYTES")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(default_pending_writes_max_bytes),
+            max_db_bytes: var("MAX_DB_BYTES")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+            zero_length_writes: var("ZERO_LENGTH_WRITES")
+                .ok()
+                .and_then(|s| extent::ZeroLengthWrites::parse(&s))
+                .unwrap_or(extent::ZeroLengthWrites::Ignore),
+            scan_fetch_bytes: var("SCAN_FETCH_BYTES")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(1024 * 1024)
+                .clamp(1024 * 1024, 4 * 1024 * 1024)
+                / crate::PAGE_SIZE
+                * crate::PAGE_SIZE,
+            prefetch_pages: var("PREFETCH_PAGES")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(0),
+            durable_unlock: var("DURABLE_UNLOCK")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            cache_verify_interval_secs: var("CACHE_VERIFY_INTERVAL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+            cache_verify_sample_pages: var("CACHE_VERIFY_SAMPLE_PAGES")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(16),
+            min_generation_wait_ms: var("MIN_GENERATION_WAIT_MS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(5000),
+            generation_poll_ms: var("GENERATION_POLL_MS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(1000),
+            block_size: var("BLOCK_SIZE")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .filter(|&block_size| meta::valid_block_size(block_size))
+                .unwrap_or(meta::DEFAULT_BLOCK_SIZE),
+            expire_interval_secs: var("EXPIRE_INTERVAL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(60),
+            deterministic_seed: var("DETERMINISTIC_SEED")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok()),
+            read_repair: var("READ_REPAIR")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(true),
+            shadow_writes: var("SHADOW_WRITES")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            shadow_verify_interval_secs: var("SHADOW_VERIFY_INTERVAL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(60),
+            journal_existence_cache: var("JOURNAL_EXISTENCE_CACHE")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            handle_warn_after_secs: var("HANDLE_WARN_AFTER_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+            stall_threshold_ms: var("STALL_THRESHOLD_MS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+            stall_bundles_keep: var("STALL_BUNDLES_KEEP")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(8),
+            lock_timeout_ms: var("LOCK_TIMEOUT_MS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+            stats_dump_interval_secs: var("STATS_DUMP_INTERVAL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+            stats_dump_format: var("STATS_DUMP_FORMAT")
+                .ok()
+                .and_then(|s| stats::DumpFormat::parse(&s))
+                .unwrap_or(stats::DumpFormat::Csv),
+            stats_dump_keep: var("STATS_DUMP_KEEP")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(48),
+            recovery_reports_keep: var("RECOVERY_REPORTS_KEEP")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(16),
+            heatmap_sample_every: var("HEATMAP_SAMPLE_EVERY")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(16),
+            analyze_churn_pct: var("ANALYZE_CHURN_PCT")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(10),
+            autotune: var("AUTOTUNE")
+                .ok()
+                .and_then(|s| s.parse::<bool>().ok())
+                .unwrap_or(false),
+            autotune_interval_secs: var("AUTOTUNE_INTERVAL_SECS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(10),
+            autotune_bounds: autotune::Bounds {
+                min_cache_bytes: var("AUTOTUNE_MIN_CACHE_BYTES")
+                    .ok()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or(page_cache::DEFAULT_MAX_CACHE_BYTES / 8),
+                max_cache_bytes: var("AUTOTUNE_MAX_CACHE_BYTES")
+                    .ok()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or(page_cache::DEFAULT_MAX_CACHE_BYTES * 8),
+                max_prefetch_pages: var("AUTOTUNE_MAX_PREFETCH_PAGES")
+                    .ok()
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or(32),
+            },
+            pricing: cost::Pricing {
+                per_1k_gets: var("S3_PRICE_PER_1K_GETS")
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .unwrap_or(0.0004),
+                per_1k_puts: var("S3_PRICE_PER_1K_PUTS")
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .unwrap_or(0.005),
+                per_gb_month: var("S3_PRICE_PER_GB_MONTH")
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .unwrap_or(0.023),
+            },
         }
     }
 }
@@ -0,0 +1,314 @@
+//! Eviction policies for the page cache, chosen with `CACHE_POLICY`.
+//!
+//! - `lru` evicts the least recently used page. A full table scan pushes out the whole
+//!   working set.
+//! - `slru` keeps pages read at least twice in a protected segment (80% of the cache)
+//!   and evicts from the probation segment first, so a scan only churns probation.
+//! - `tinylfu` admits new pages into a small window (1% of the cache). A page leaving
+//!   the window only enters the SLRU main cache if it has been read more often than
+//!   the page it would evict, judged by a frequency sketch of recent reads.
+//!
+//! A `Policy` only tracks keys and sizes, the page cache holds the data. With
+//! `CACHE_SIMULATE` the cache also drives a data-less policy of each kind over the same
+//! reads, so `PRAGMA s3qlite_cache_policy` can show how each would have done.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    Lru,
+    Slru,
+    TinyLfu,
+}
+
+impl PolicyKind {
+    pub const ALL: [PolicyKind; 3] = [PolicyKind::Lru, PolicyKind::Slru, PolicyKind::TinyLfu];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "lru" => Some(Self::Lru),
+            "slru" => Some(Self::Slru),
+            "tinylfu" => Some(Self::TinyLfu),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lru => "lru",
+            Self::Slru => "slru",
+            Self::TinyLfu => "tinylfu",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Window,
+    Probation,
+    Protected,
+}
+
+struct Tracked {
+    len: u64,
+    tick: u64,
+    segment: Segment,
+}
+
+pub struct Policy {
+    kind: PolicyKind,
+    tracked: HashMap<Vec<u8>, Tracked>,
+    // access tick -> key, oldest first. LRU only uses probation.
+    window: BTreeMap<u64, Vec<u8>>,
+    probation: BTreeMap<u64, Vec<u8>>,
+    protected: BTreeMap<u64, Vec<u8>>,
+    window_bytes: u64,
+    protected_bytes: u64,
+    bytes: u64,
+    tick: u64,
+    sketch: Option<Sketch>,
+}
+
+impl Policy {
+    /// A policy for a cache of about `max_bytes`, which sizes the frequency sketch.
+    pub fn new(kind: PolicyKind, max_bytes: u64) -> Self {
+        Self {
+            kind,
+            tracked: HashMap::new(),
+            window: BTreeMap::new(),
+            probation: BTreeMap::new(),
+            protected: BTreeMap::new(),
+            window_bytes: 0,
+            protected_bytes: 0,
+            bytes: 0,
+            tick: 0,
+            sketch: (kind == PolicyKind::TinyLfu)
+                .then(|| Sketch::new(max_bytes as usize / crate::PAGE_SIZE)),
+        }
+    }
+
+    pub fn kind(&self) -> PolicyKind {
+        self.kind
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Record a read of `key`, returning whether it is tracked.
+    pub fn access(&mut self, key: &[u8], max_bytes: u64) -> bool {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(key);
+        }
+        let tick = self.next_tick();
+        let Some(tracked) = self.tracked.get_mut(key) else {
+            return false;
+        };
+        let old_tick = std::mem::replace(&mut tracked.tick, tick);
+        let segment = tracked.segment;
+        let len = tracked.len;
+        let key = self
+            .segment_mut(segment)
+            .remove(&old_tick)
+            .unwrap_or_default();
+        if segment == Segment::Probation && self.kind != PolicyKind::Lru {
+            // a second read promotes the page
+            self.protected.insert(tick, key.clone());
+            self.protected_bytes += len;
+            if let Some(tracked) = self.tracked.get_mut(&key) {
+                tracked.segment = Segment::Protected;
+            }
+            self.demote(max_bytes);
+        } else {
+            self.segment_mut(segment).insert(tick, key);
+        }
+        true
+    }
+
+    /// Track `key`, returning the keys evicted to make room. May include `key` itself
+    /// when TinyLFU doesn't admit it. A key already tracked keeps its place.
+    pub fn insert(&mut self, key: &[u8], len: u64, max_bytes: u64) -> Vec<Vec<u8>> {
+        if let Some(tracked) = self.tracked.get_mut(key) {
+            let old_len = std::mem::replace(&mut tracked.len, len);
+            match tracked.segment {
+                Segment::Window => self.window_bytes = self.window_bytes - old_len + len,
+                Segment::Protected => self.protected_bytes = self.protected_bytes - old_len + len,
+                Segment::Probation => {}
+            }
+            self.bytes = self.bytes - old_len + len;
+            return self.evict(max_bytes);
+        }
+        let tick = self.next_tick();
+        let segment = match self.kind {
+            PolicyKind::TinyLfu => Segment::Window,
+            _ => Segment::Probation,
+        };
+        self.segment_mut(segment).insert(tick, key.to_vec());
+        self.tracked
+            .insert(key.to_vec(), Tracked { len, tick, segment });
+        self.bytes += len;
+        if segment == Segment::Window {
+            self.window_bytes += len;
+        }
+        self.evict(max_bytes)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        let Some(tracked) = self.tracked.remove(key) else {
+            return;
+        };
+        self.segment_mut(tracked.segment).remove(&tracked.tick);
+        self.bytes -= tracked.len;
+        match tracked.segment {
+            Segment::Window => self.window_bytes -= tracked.len,
+            Segment::Protected => self.protected_bytes -= tracked.len,
+            Segment::Probation => {}
+        }
+    }
+
+    /// Evict until at most `max_bytes` are tracked, returning the evicted keys.
+    pub fn evict(&mut self, max_bytes: u64) -> Vec<Vec<u8>> {
+        let mut evicted = Vec::new();
+        if self.kind == PolicyKind::TinyLfu {
+            let window_max = (max_bytes / 100).max(1);
+            while self.window_bytes > window_max {
+                let Some((_, candidate)) = self.window.pop_first() else {
+                    break;
+                };
+                let len = self.tracked[&candidate].len;
+                self.window_bytes -= len;
+                let victim = self
+                    .probation
+                    .first_key_value()
+                    .or_else(|| self.protected.first_key_value())
+                    .map(|(_, key)| key.clone());
+                let admit = match (&victim, &self.sketch) {
+                    _ if self.bytes <= max_bytes => true,
+                    (Some(victim), Some(sketch)) => {
+                        sketch.frequency(&candidate) > sketch.frequency(victim)
+                    }
+                    _ => true,
+                };
+                if !admit {
+                    self.tracked.remove(&candidate);
+                    self.bytes -= len;
+                    evicted.push(candidate);
+                    continue;
+                }
+                if let Some(victim) = victim.filter(|_| self.bytes > max_bytes) {
+                    self.remove(&victim);
+                    evicted.push(victim);
+                }
+                let tick = self.next_tick();
+                self.probation.insert(tick, candidate.clone());
+                if let Some(tracked) = self.tracked.get_mut(&candidate) {
+                    tracked.tick = tick;
+                    tracked.segment = Segment::Probation;
+                }
+            }
+        }
+        while self.bytes > max_bytes {
+            let Some(key) = [&self.probation, &self.protected, &self.window]
+                .into_iter()
+                .find_map(|segment| segment.first_key_value())
+                .map(|(_, key)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    /// Tracked keys, the ones most worth keeping first.
+    pub fn hot_keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.protected
+            .values()
+            .rev()
+            .chain(self.window.values().rev())
+            .chain(self.probation.values().rev())
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn segment_mut(&mut self, segment: Segment) -> &mut BTreeMap<u64, Vec<u8>> {
+        match segment {
+            Segment::Window => &mut self.window,
+            Segment::Probation => &mut self.probation,
+            Segment::Protected => &mut self.protected,
+        }
+    }
+
+    /// Move the oldest protected pages back to probation while protected holds more
+    /// than 80% of the cache.
+    fn demote(&mut self, max_bytes: u64) {
+        while self.protected_bytes > max_bytes / 5 * 4 {
+            let Some((_, key)) = self.protected.pop_first() else {
+                break;
+            };
+            let tick = self.next_tick();
+            if let Some(tracked) = self.tracked.get_mut(&key) {
+                tracked.tick = tick;
+                tracked.segment = Segment::Probation;
+                self.protected_bytes -= tracked.len;
+            }
+            self.probation.insert(tick, key);
+        }
+    }
+}
+
+/// Count-min sketch of recent reads, 4 bit counters that are halved every `10 * width`
+/// reads so old popularity fades.
+struct Sketch {
+    counters: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    reset_at: usize,
+}
+
+impl Sketch {
+    fn new(pages: usize) -> Self {
+        let width = pages.clamp(1024, 1 << 24).next_power_of_two();
+        Self {
+            counters: vec![0; width],
+            mask: width - 1,
+            additions: 0,
+            reset_at: width * 10,
+        }
+    }
+
+    fn indexes(&self, key: &[u8]) -> [usize; 4] {
+        std::array::from_fn(|seed| {
+            let mut hasher = std::hash::DefaultHasher::new();
+            seed.hash(&mut hasher);
+            key.hash(&mut hasher);
+            hasher.finish() as usize & self.mask
+        })
+    }
+
+    fn increment(&mut self, key: &[u8]) {
+        for i in self.indexes(key) {
+            self.counters[i] = (self.counters[i] + 1).min(15);
+        }
+        self.additions += 1;
+        if self.additions >= self.reset_at {
+            for counter in &mut self.counters {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, key: &[u8]) -> u8 {
+        self.indexes(key)
+            .into_iter()
+            .map(|i| self.counters[i])
+            .min()
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,40 @@
+//! File sizes past the last stored page.
+//!
+//! A file's size is where its pages run out, and SQLite grows a file by writing past its
+//! end, or by truncating it to a larger size when it preallocates (`SQLITE_FCNTL_CHUNK_SIZE`).
+//! Neither fills the range in between, so storing only the written pages left a gap the
+//! size scan stopped at: the file looked shorter than SQLite had made it, and reads
+//! stopped short of pages that were stored.
+//!
+//! A file's length is stored under `{path}:meta` (see `meta`), which is set to the end
+//! of every write and to the size given on truncate, so it covers a file that goes on
+//! past a gap. Nothing is materialized for the range in between: reads of it return
+//! zeros. Files stored before `{path}:meta` kept their size past a gap under
+//! `{path}:extent` instead, which is still read for them until their next change writes
+//! the record, and deleted with them.
+//!
+//! A zero-length write changes nothing by default, as with `pwrite`.
+//! `ZERO_LENGTH_WRITES=extend` makes one past the end extend the file to its offset,
+//! for callers that grow files that way.
+
+/// Where files stored before `{path}:meta` kept their size past a gap, a decimal byte
+/// count read with `meta::parse`.
+pub fn legacy_key(path: &str) -> String {
+    format!("{path}:extent")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroLengthWrites {
+    Ignore,
+    Extend,
+}
+
+impl ZeroLengthWrites {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ignore" => Some(Self::Ignore),
+            "extend" => Some(Self::Extend),
+            _ => None,
+        }
+    }
+}
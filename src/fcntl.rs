@@ -0,0 +1,41 @@
+//! s3qlite's own `sqlite3_file_control` opcodes.
+//!
+//! Hosts driving the VFS from C can reach it with `sqlite3_file_control` instead of
+//! building pragma statements. Each opcode takes a typed argument, see
+//! `sqlite_plugin::vfs::CustomFcntlArg`:
+//!
+//! - `FCNTL_INTERRUPT_ID`, a `sqlite3_int64*`: the handle's id, see `interrupt`.
+//! - `FCNTL_FLUSH`, no argument: store the file if it's new and make everything written
+//!   so far durable, as `s3qlite_app_background` does.
+//! - `FCNTL_SNAPSHOT`, a `char**` naming the snapshot: as `PRAGMA s3qlite_snapshot`,
+//!   replaced with the checkpoint id.
+//! - `FCNTL_STATS`, a `char**` set to null: replaced with `PRAGMA s3qlite_stats`.
+//!
+//! Strings passed back are allocated with `sqlite3_mprintf`, for the caller to
+//! `sqlite3_free`.
+
+use sqlite_plugin::vfs::CustomFcntlArg;
+
+pub use crate::interrupt::FCNTL_INTERRUPT_ID;
+
+pub const FCNTL_FLUSH: i32 = 0x5333_0002;
+pub const FCNTL_SNAPSHOT: i32 = 0x5333_0003;
+pub const FCNTL_STATS: i32 = 0x5333_0004;
+
+pub fn arg(op: i32) -> Option<CustomFcntlArg> {
+    match op {
+        FCNTL_INTERRUPT_ID => Some(CustomFcntlArg::Int64),
+        FCNTL_FLUSH => Some(CustomFcntlArg::None),
+        FCNTL_SNAPSHOT | FCNTL_STATS => Some(CustomFcntlArg::Text),
+        _ => None,
+    }
+}
+
+/// The pragma an opcode stands in for.
+pub fn pragma_name(op: i32) -> Option<&'static str> {
+    match op {
+        FCNTL_SNAPSHOT => Some("s3qlite_snapshot"),
+        FCNTL_STATS => Some("s3qlite_stats"),
+        _ => None,
+    }
+}
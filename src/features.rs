@@ -0,0 +1,33 @@
+//! What the VFS supports for one database, returned by `PRAGMA s3qlite_features` so
+//! ORMs and frameworks can adapt, e.g. pick a journal mode, without trial and error.
+//!
+//! `PRAGMA s3qlite_capabilities` describes the VFS registration as a whole, this
+//! describes the connection's database: a snapshot or remote database can't be written,
+//! so it has no batch atomic writes and takes no snapshots of its own.
+
+pub struct Features {
+    /// Single database transactions commit as one batch, without writing a journal
+    pub batch_atomic: bool,
+    /// Whether `PRAGMA s3qlite_snapshot=name` works here
+    pub snapshots: bool,
+    /// Database paths are stored under opaque names, see `names`
+    pub path_encryption: bool,
+    /// SSTs are compressed by SlateDB
+    pub compression: bool,
+    pub readonly: bool,
+}
+
+impl Features {
+    /// WAL, branches and page encryption aren't supported by any database, they are
+    /// listed so clients don't have to know that.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"batch_atomic\":{},\"wal\":false,\"snapshots\":{},\"branches\":false,\"encryption\":{{\"pages\":false,\"paths\":{}}},\"compression\":{},\"readonly\":{}}}",
+            self.batch_atomic,
+            self.snapshots,
+            self.path_encryption,
+            self.compression,
+            self.readonly,
+        )
+    }
+}
@@ -0,0 +1,71 @@
+//! Explicit durability barriers.
+//!
+//! Writes go to SlateDB without waiting for them to be durable, and SlateDB makes them
+//! durable in the background on its own interval. `PRAGMA s3qlite_flush` is a barrier
+//! an application can call when it needs to know: it stores the new files still held in
+//! memory, see `bootstrap`, flushes SlateDB, and returns what was written since the last
+//! explicit flush and how long the flush took, as JSON. The counts are what the VFS
+//! wrote, some of which SlateDB may already have made durable in the background.
+//!
+//! `FCNTL_FLUSH`, `DURABLE_UNLOCK` and `s3qlite_app_background` flush the same way
+//! and start the counts again.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Writes since the last explicit flush.
+#[derive(Debug, Default)]
+pub struct Unflushed {
+    batches: AtomicU64,
+    pages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Unflushed {
+    /// Record one write to SlateDB, a put or a batch.
+    pub fn record_batch(&self) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_pages(&self, pages: usize, bytes: usize) {
+        self.pages.fetch_add(pages as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn take(&self) -> Flushed {
+        Flushed {
+            batches: self.batches.swap(0, Ordering::Relaxed),
+            pages: self.pages.swap(0, Ordering::Relaxed),
+            bytes: self.bytes.swap(0, Ordering::Relaxed),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Count `flushed` again, after the flush that took it failed.
+    pub fn restore(&self, flushed: &Flushed) {
+        self.batches.fetch_add(flushed.batches, Ordering::Relaxed);
+        self.pages.fetch_add(flushed.pages, Ordering::Relaxed);
+        self.bytes.fetch_add(flushed.bytes, Ordering::Relaxed);
+    }
+}
+
+/// What one explicit flush made durable.
+#[derive(Debug)]
+pub struct Flushed {
+    pub batches: u64,
+    pub pages: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Flushed {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"batches\":{},\"pages\":{},\"bytes\":{},\"elapsed_ms\":{}}}",
+            self.batches,
+            self.pages,
+            self.bytes,
+            self.elapsed.as_millis()
+        )
+    }
+}
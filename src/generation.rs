@@ -0,0 +1,77 @@
+//! Commit generations, a read consistency token for application-level caching.
+//!
+//! Every write transaction on a database bumps its generation when it releases its
+//! EXCLUSIVE lock, stored under `{path}:generation` behind the pages it committed.
+//! `PRAGMA s3qlite_generation` returns the current one. Handing it to another
+//! connection as `PRAGMA s3qlite_min_generation=N` makes that connection wait until
+//! the database has caught up, re-reading the stored generation and dropping cached
+//! pages if it moved on elsewhere, so an application gets read-your-writes across
+//! connections without comparing data. Waiting gives up with `SQLITE_BUSY` after
+//! `MIN_GENERATION_WAIT_MS`.
+//!
+//! Host applications can follow the generation instead, see `watch`. While anything
+//! watches a database its stored generation is re-read every `GENERATION_POLL_MS`, so
+//! commits from other processes reach watchers, and waiters, without a query.
+//!
+//! Deleting a database keeps its generation, so tokens handed out before never look
+//! caught up by a database recreated in its place.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub fn generation_key(path: &str) -> String {
+    format!("{path}:generation")
+}
+
+/// A stored generation, a decimal count.
+pub fn parse(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+#[derive(Debug, Default)]
+pub struct Generation {
+    current: tokio::sync::watch::Sender<u64>,
+    // written to since the generation was last bumped
+    dirty: AtomicBool,
+}
+
+impl Generation {
+    pub fn get(&self) -> u64 {
+        *self.current.borrow()
+    }
+
+    /// Move the generation forward to `generation`, never back.
+    pub fn advance_to(&self, generation: u64) {
+        self.current.send_if_modified(|current| {
+            let advanced = generation > *current;
+            *current = (*current).max(generation);
+            advanced
+        });
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Whether the database was written to since the last call.
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<u64> {
+        self.current.subscribe()
+    }
+
+    /// Whether anything is watching or waiting on the generation.
+    pub fn watched(&self) -> bool {
+        self.current.receiver_count() > 0
+    }
+
+    /// Wait up to `timeout` for the generation to reach `min`.
+    pub async fn wait_for(&self, min: u64, timeout: Duration) -> bool {
+        let mut receiver = self.current.subscribe();
+        tokio::time::timeout(timeout, receiver.wait_for(|&generation| generation >= min))
+            .await
+            .is_ok_and(|result| result.is_ok())
+    }
+}
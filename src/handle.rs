@@ -3,17 +3,61 @@ pub struct GrpcVfsHandle {
     pub path: String,
     readonly: bool,
     pub handle_id: u64,
+    /// Correlation ID from `PRAGMA s3qlite_trace_id`
+    pub trace_id: Option<String>,
+    /// Set when the file was opened at a snapshot, `path@name`
+    pub snapshot: Option<crate::snapshot::Snapshot>,
+    /// Set for read-only opens, which read at a checkpoint of their own, see
+    /// `point_in_time`
+    pub pinned: Option<std::sync::Arc<crate::point_in_time::Pinned>>,
+    /// Set when the file is a database served over HTTP(S), see `remote`
+    pub remote: Option<crate::remote::RemoteFile>,
+    /// Set for read-only `?scan=true` handles: a page miss fetches this many bytes
+    pub scan_fetch_bytes: Option<usize>,
+    pub activity: Option<crate::handle_registry::Activity>,
+    /// Interrupts the operations running on this handle, see `interrupt`
+    pub interrupt: crate::interrupt::Interrupt,
+    /// Set by `PRAGMA s3qlite_admin_token`, see `readonly`
+    pub admin: bool,
+    /// Set when SQLite opened the file as a main database rather than a journal, which
+    /// the stored path can't tell under `PATH_KEY`
+    pub main_db: bool,
 }
 
 impl GrpcVfsHandle {
     pub fn new(path: String, readonly: bool, handle_id: u64) -> Self {
-        Self { path, readonly, handle_id }
+        Self {
+            path,
+            readonly,
+            handle_id,
+            trace_id: None,
+            snapshot: None,
+            pinned: None,
+            remote: None,
+            scan_fetch_bytes: None,
+            activity: None,
+            interrupt: Default::default(),
+            admin: false,
+            main_db: false,
+        }
+    }
+
+    /// Snapshots, remote databases and scan handles can't be written.
+    pub fn immutable(&self) -> bool {
+        self.snapshot.is_some() || self.remote.is_some() || self.scan_fetch_bytes.is_some()
+    }
+
+    /// Record that the handle was just used.
+    pub fn touch(&self) {
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
     }
 }
 
 impl sqlite_plugin::vfs::VfsHandle for GrpcVfsHandle {
     fn readonly(&self) -> bool {
-        self.readonly
+        self.readonly || self.scan_fetch_bytes.is_some()
     }
 
     fn in_memory(&self) -> bool {
@@ -0,0 +1,150 @@
+//! Every open handle with when it was opened and when it was last used, listed by
+//! `PRAGMA s3qlite_open_handles`.
+//!
+//! "database is locked" in a long-running server usually means some connection was
+//! never closed. With `HANDLE_WARN_AFTER_SECS` set, handles open longer than that are
+//! logged once each, with their path and how long they have been idle.
+//!
+//! `s3qlite_interrupt` finds the handle to interrupt here by its id.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::interrupt::Interrupt;
+
+/// Last use of a handle, in milliseconds since the registry was created. Shared with
+/// the handle so recording activity doesn't take the registry lock.
+#[derive(Debug, Clone)]
+pub struct Activity {
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+    last_ms: Arc<AtomicU64>,
+}
+
+impl Activity {
+    fn since_epoch(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.epoch)
+    }
+
+    pub fn touch(&self) {
+        self.last_ms
+            .store(self.since_epoch().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.since_epoch()
+            .saturating_sub(Duration::from_millis(self.last_ms.load(Ordering::Relaxed)))
+    }
+}
+
+struct Entry {
+    path: String,
+    opened_at: Instant,
+    activity: Activity,
+    interrupt: Interrupt,
+    warned: bool,
+}
+
+pub struct OpenHandle {
+    pub handle_id: u64,
+    pub path: String,
+    pub open_for: Duration,
+    pub idle_for: Duration,
+}
+
+pub struct Registry {
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+    handles: Mutex<HashMap<u64, Entry>>,
+}
+
+impl Registry {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            epoch: clock.now(),
+            clock,
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, handle_id: u64, path: &str, interrupt: &Interrupt) -> Activity {
+        let activity = Activity {
+            clock: self.clock.clone(),
+            epoch: self.epoch,
+            last_ms: Arc::new(AtomicU64::new(0)),
+        };
+        activity.touch();
+        self.handles.lock().insert(
+            handle_id,
+            Entry {
+                path: path.to_string(),
+                opened_at: self.clock.now(),
+                activity: activity.clone(),
+                interrupt: interrupt.clone(),
+                warned: false,
+            },
+        );
+        activity
+    }
+
+    fn since(&self, at: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(at)
+    }
+
+    pub fn remove(&self, handle_id: u64) {
+        self.handles.lock().remove(&handle_id);
+    }
+
+    /// Interrupt the operations running on a handle, false if it isn't open.
+    pub fn interrupt(&self, handle_id: u64) -> bool {
+        let Some(interrupt) = self
+            .handles
+            .lock()
+            .get(&handle_id)
+            .map(|entry| entry.interrupt.clone())
+        else {
+            return false;
+        };
+        interrupt.interrupt();
+        true
+    }
+
+    /// Open handles, oldest first.
+    pub fn list(&self) -> Vec<OpenHandle> {
+        let mut handles: Vec<OpenHandle> = self
+            .handles
+            .lock()
+            .iter()
+            .map(|(&handle_id, entry)| OpenHandle {
+                handle_id,
+                path: entry.path.clone(),
+                open_for: self.since(entry.opened_at),
+                idle_for: entry.activity.idle(),
+            })
+            .collect();
+        handles.sort_by_key(|h| std::cmp::Reverse(h.open_for));
+        handles
+    }
+
+    /// Handles open for longer than `threshold` that haven't been reported yet.
+    pub fn take_overdue(&self, threshold: Duration) -> Vec<OpenHandle> {
+        let mut handles = self.handles.lock();
+        handles
+            .iter_mut()
+            .filter(|(_, entry)| !entry.warned && self.since(entry.opened_at) > threshold)
+            .map(|(&handle_id, entry)| {
+                entry.warned = true;
+                OpenHandle {
+                    handle_id,
+                    path: entry.path.clone(),
+                    open_for: self.since(entry.opened_at),
+                    idle_for: entry.activity.idle(),
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,59 @@
+//! Readiness probe for processes embedding the VFS, returned by `s3qlite_healthcheck`
+//! and `PRAGMA s3qlite_health`.
+
+use std::time::Duration;
+
+pub struct Health {
+    /// Round trip to the object store, or why it failed
+    pub store: Result<Duration, String>,
+    /// Whether spill files can be created in the cache directory
+    pub cache_dir: Result<(), String>,
+    /// Bytes written by open transactions and new files that aren't stored yet
+    pub pending_bytes: u64,
+}
+
+impl Health {
+    pub fn healthy(&self) -> bool {
+        self.store.is_ok() && self.cache_dir.is_ok()
+    }
+
+    pub fn to_json(&self) -> String {
+        let store = match &self.store {
+            Ok(latency) => format!("{{\"ok\":true,\"latency_ms\":{}}}", latency.as_millis()),
+            Err(e) => format!("{{\"ok\":false,\"error\":{}}}", json_string(e)),
+        };
+        let cache_dir = match &self.cache_dir {
+            Ok(()) => "{\"ok\":true}".to_string(),
+            Err(e) => format!("{{\"ok\":false,\"error\":{}}}", json_string(e)),
+        };
+        format!(
+            "{{\"healthy\":{},\"store\":{store},\"cache_dir\":{cache_dir},\"pending_bytes\":{}}}",
+            self.healthy(),
+            self.pending_bytes
+        )
+    }
+}
+
+/// Check that files can be created in `dir`.
+pub fn check_dir_writable(dir: &std::path::Path) -> Result<(), String> {
+    let probe = dir.join(format!("s3qlite-health-{}", std::process::id()));
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {e}", dir.display()))
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
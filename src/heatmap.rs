@@ -0,0 +1,97 @@
+//! Sampled per-page access counts, for deciding what to pin, index or partition.
+//!
+//! One in `HEATMAP_SAMPLE_EVERY` reads and writes reaching the VFS is recorded against
+//! the SQLite page it touches, and counts are scaled back up when reported, so they are
+//! estimates. Reads served by SQLite's own page cache never get here. `PRAGMA
+//! s3qlite_heatmap` returns a database's counts as CSV, and the REPL's `.heatmap`
+//! annotates them with the table or index owning each page.
+//!
+//! Counts are kept in memory for the life of the process and dropped with the file.
+
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    reads: u64,
+    writes: u64,
+}
+
+#[derive(Default)]
+pub struct Heatmap {
+    // record one access in this many, 0 disables recording
+    sample_every: u64,
+    accesses: AtomicU64,
+    // path -> page number -> sampled counts
+    pages: Mutex<HashMap<String, BTreeMap<u64, Counts>>>,
+}
+
+impl Heatmap {
+    pub fn new(sample_every: u64) -> Self {
+        Self {
+            sample_every,
+            ..Default::default()
+        }
+    }
+
+    pub fn record_read(&self, path: &str, offset: usize, len: usize) {
+        self.record(path, offset, len, |counts| counts.reads += 1);
+    }
+
+    pub fn record_write(&self, path: &str, offset: usize, len: usize) {
+        self.record(path, offset, len, |counts| counts.writes += 1);
+    }
+
+    fn record(&self, path: &str, offset: usize, len: usize, bump: impl FnOnce(&mut Counts)) {
+        if self.sample_every == 0
+            || len == 0
+            || !self
+                .accesses
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.sample_every)
+        {
+            return;
+        }
+        // SQLite reads and writes database pages whole, anything smaller is the header
+        let page = match len {
+            512.. if len.is_power_of_two() => (offset / len) as u64 + 1,
+            _ => 1,
+        };
+        let mut pages = self.pages.lock();
+        if !pages.contains_key(path) {
+            pages.insert(path.to_string(), BTreeMap::new());
+        }
+        if let Some(counts) = pages.get_mut(path) {
+            bump(counts.entry(page).or_default());
+        }
+    }
+
+    pub fn remove(&self, path: &str) {
+        self.pages.lock().remove(path);
+    }
+
+    /// `page,reads,writes` lines for `path` after a header, busiest page first.
+    pub fn to_csv(&self, path: &str) -> String {
+        let mut rows: Vec<(u64, u64, u64)> = self
+            .pages
+            .lock()
+            .get(path)
+            .into_iter()
+            .flatten()
+            .map(|(&page, counts)| {
+                (
+                    page,
+                    counts.reads * self.sample_every,
+                    counts.writes * self.sample_every,
+                )
+            })
+            .collect();
+        rows.sort_by_key(|&(page, reads, writes)| (std::cmp::Reverse(reads + writes), page));
+        let mut csv = String::from("page,reads,writes\n");
+        for (page, reads, writes) in rows {
+            csv.push_str(&format!("{page},{reads},{writes}\n"));
+        }
+        csv
+    }
+}
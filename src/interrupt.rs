@@ -0,0 +1,80 @@
+//! Interrupting a handle's in-flight operations.
+//!
+//! `sqlite3_interrupt` never reaches the VFS, so a read or commit stuck behind slow S3
+//! requests carries on long after the host gave up on it. A host fetches a handle's id
+//! with `S3QLITE_FCNTL_INTERRUPT_ID` and passes it to `s3qlite_interrupt`, from any
+//! thread, alongside `sqlite3_interrupt`. `sqlite3_file_control` waits for the connection,
+//! so the id has to be fetched before the statement starts.
+//!
+//! Operations running on the handle at that moment fail with `SQLITE_INTERRUPT`: waits
+//! for pages are abandoned at once, and commits and truncates stop before their batch is
+//! written. Like `sqlite3_interrupt`, nothing is remembered for later operations.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+/// `sqlite3_file_control` opcode writing the handle's id to a `sqlite3_int64`, see
+/// `fcntl`.
+pub const FCNTL_INTERRUPT_ID: i32 = 0x5333_0001;
+
+#[derive(Debug, Default)]
+struct Inner {
+    // bumped by every interrupt, an operation is interrupted once it changes
+    generation: AtomicU64,
+    notify: Notify,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<Inner>);
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        self.0.notify.notify_waiters();
+    }
+
+    /// Start an operation, interrupted by any later `interrupt`.
+    pub fn guard(&self) -> Guard {
+        Guard {
+            interrupt: self.clone(),
+            generation: self.0.generation.load(Ordering::Acquire),
+        }
+    }
+}
+
+pub struct Guard {
+    interrupt: Interrupt,
+    generation: u64,
+}
+
+impl Guard {
+    pub fn check(&self) -> Result<(), i32> {
+        if self.interrupt.0.generation.load(Ordering::Acquire) != self.generation {
+            return Err(sqlite_plugin::vars::SQLITE_INTERRUPT);
+        }
+        Ok(())
+    }
+
+    /// Run `future`, dropping it with `SQLITE_INTERRUPT` if the handle is interrupted
+    /// first. Only for futures that are safe to abandon, reads rather than writes.
+    pub async fn run<T>(&self, future: impl Future<Output = Result<T, i32>>) -> Result<T, i32> {
+        self.check()?;
+        tokio::select! {
+            result = future => result,
+            _ = self.interrupted() => Err(sqlite_plugin::vars::SQLITE_INTERRUPT),
+        }
+    }
+
+    async fn interrupted(&self) {
+        loop {
+            let notified = self.interrupt.0.notify.notified();
+            let mut notified = std::pin::pin!(notified);
+            notified.as_mut().enable();
+            if self.check().is_err() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Application metadata stored next to a database, outside its tables.
+//!
+//! `PRAGMA s3qlite_put='schema_version=42'` sets a key, `PRAGMA s3qlite_get='schema_version'`
+//! reads it back (no row when unset) and `PRAGMA s3qlite_delete='schema_version'` removes
+//! it. Keys live under `{path}:kv:{key}`, so every process sees them, and go with the
+//! database when it's deleted.
+//!
+//! Outside a write transaction a put is stored straight away and returns `stored`. Inside
+//! one it returns `staged`: it's held until the transaction commits and stored in the
+//! same batch as its pages, or dropped if it rolls back. A transaction that commits
+//! without a batch, or without writing a page at all, stores them when SQLite reports
+//! the commit with `SQLITE_FCNTL_COMMIT_PHASETWO`, which a rollback never sends.
+
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+
+/// Keys and values are for markers and versions, not data.
+pub const MAX_KEY_BYTES: usize = 256;
+pub const MAX_VALUE_BYTES: usize = 64 * 1024;
+
+pub fn kv_key(path: &str, key: &str) -> String {
+    format!("{path}:kv:{key}")
+}
+
+pub fn kv_prefix(path: &str) -> String {
+    format!("{path}:kv:")
+}
+
+pub fn check_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_KEY_BYTES {
+        return Err(format!("keys are 1 to {MAX_KEY_BYTES} bytes"));
+    }
+    Ok(())
+}
+
+/// Split a `key=value` put.
+pub fn parse_put(arg: &str) -> Result<(&str, &str), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got {arg}"))?;
+    check_key(key)?;
+    if value.len() > MAX_VALUE_BYTES {
+        return Err(format!("values are at most {MAX_VALUE_BYTES} bytes"));
+    }
+    Ok((key, value))
+}
+
+/// Puts made inside the write transaction in progress, None for deletes.
+#[derive(Debug, Default)]
+pub struct Staged(Mutex<BTreeMap<String, Option<String>>>);
+
+impl Staged {
+    pub fn put(&self, key: &str, value: Option<&str>) {
+        self.0
+            .lock()
+            .insert(key.to_string(), value.map(str::to_string));
+    }
+
+    /// The staged value of `key`: None if it isn't staged, Some(None) if it's deleted.
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        self.0.lock().get(key).cloned()
+    }
+
+    /// A copy of the staged puts, which stay staged.
+    pub fn entries(&self) -> BTreeMap<String, Option<String>> {
+        self.0.lock().clone()
+    }
+
+    pub fn take(&self) -> BTreeMap<String, Option<String>> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
@@ -0,0 +1,154 @@
+//! The VFS registered with SQLite. It defers building the runtime, SlateDB and the
+//! background tasks until the first file operation, so registering the extension is
+//! free: no threads and no storage access. Mobile hosts register at startup but may
+//! never touch an s3qlite database in a given session.
+
+use crate::{GrpcVfs, get_grpc_vfs, handle::GrpcVfsHandle};
+use sqlite_plugin::flags::{AccessFlags, LockLevel, OpenOpts};
+use sqlite_plugin::logger::SqliteLogger;
+use sqlite_plugin::vars;
+use sqlite_plugin::vfs::{self, Pragma, PragmaErr, Vfs, VfsResult};
+use std::borrow::Cow;
+use std::ffi::{c_int, c_void};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct LazyGrpcVfs;
+
+impl LazyGrpcVfs {
+    fn vfs(&self, code: i32) -> VfsResult<Arc<GrpcVfs>> {
+        get_grpc_vfs().map_err(|e| {
+            log::error!("grpsqlite failed to initialize: {e}");
+            code
+        })
+    }
+}
+
+impl Vfs for LazyGrpcVfs {
+    type Handle = GrpcVfsHandle;
+
+    fn register_logger(&self, logger: SqliteLogger) {
+        crate::install_logger(logger);
+    }
+
+    fn canonical_path<'a>(&self, path: Cow<'a, str>) -> VfsResult<Cow<'a, str>> {
+        self.vfs(vars::SQLITE_CANTOPEN)?.canonical_path(path)
+    }
+
+    fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle> {
+        self.vfs(vars::SQLITE_CANTOPEN)?.open(path, opts)
+    }
+
+    fn open_with_params(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: &[(String, String)],
+    ) -> VfsResult<Self::Handle> {
+        self.vfs(vars::SQLITE_CANTOPEN)?
+            .open_with_params(path, opts, params)
+    }
+
+    fn delete(&self, path: &str) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR_DELETE)?.delete(path)
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool> {
+        self.vfs(vars::SQLITE_IOERR_ACCESS)?.access(path, flags)
+    }
+
+    fn file_size(&self, handle: &mut Self::Handle) -> VfsResult<usize> {
+        self.vfs(vars::SQLITE_IOERR_FSTAT)?.file_size(handle)
+    }
+
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR_TRUNCATE)?
+            .truncate(handle, size)
+    }
+
+    fn write(&self, handle: &mut Self::Handle, offset: usize, data: &[u8]) -> VfsResult<usize> {
+        self.vfs(vars::SQLITE_IOERR_WRITE)?
+            .write(handle, offset, data)
+    }
+
+    fn read(&self, handle: &mut Self::Handle, offset: usize, data: &mut [u8]) -> VfsResult<usize> {
+        self.vfs(vars::SQLITE_IOERR_READ)?
+            .read(handle, offset, data)
+    }
+
+    fn lock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR_LOCK)?.lock(handle, level)
+    }
+
+    fn unlock(&self, handle: &mut Self::Handle, level: LockLevel) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR_UNLOCK)?.unlock(handle, level)
+    }
+
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        self.vfs(vars::SQLITE_IOERR_CHECKRESERVEDLOCK)?
+            .check_reserved_lock(handle)
+    }
+
+    fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR_FSYNC)?.sync(handle)
+    }
+
+    fn close(&self, handle: Self::Handle) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR_CLOSE)?.close(handle)
+    }
+
+    fn pragma(
+        &self,
+        handle: &mut Self::Handle,
+        pragma: Pragma<'_>,
+    ) -> Result<Option<String>, PragmaErr> {
+        self.vfs(vars::SQLITE_IOERR)
+            .map_err(|e| PragmaErr::Fail(e, None))?
+            .pragma(handle, pragma)
+    }
+
+    fn sector_size(&self) -> i32 {
+        self.vfs(vars::SQLITE_IOERR)
+            .map_or(vfs::DEFAULT_SECTOR_SIZE, |vfs| vfs.sector_size())
+    }
+
+    fn device_characteristics(&self) -> i32 {
+        self.vfs(vars::SQLITE_IOERR)
+            .map_or(vfs::DEFAULT_DEVICE_CHARACTERISTICS, |vfs| {
+                vfs.device_characteristics()
+            })
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.vfs(vars::SQLITE_IOERR).ok()?.sleep(duration)
+    }
+
+    fn current_time_ms(&self) -> Option<i64> {
+        self.vfs(vars::SQLITE_IOERR).ok()?.current_time_ms()
+    }
+
+    fn file_control(
+        &self,
+        handle: &mut Self::Handle,
+        op: c_int,
+        p_arg: *mut c_void,
+    ) -> VfsResult<()> {
+        self.vfs(vars::SQLITE_IOERR)?
+            .file_control(handle, op, p_arg)
+    }
+
+    fn custom_file_control_arg(&self, op: c_int) -> Option<vfs::CustomFcntlArg> {
+        crate::fcntl::arg(op)
+    }
+
+    fn custom_file_control(
+        &self,
+        handle: &mut Self::Handle,
+        op: c_int,
+        arg: vfs::CustomFcntl<'_>,
+    ) -> VfsResult<Option<String>> {
+        self.vfs(vars::SQLITE_IOERR)?
+            .custom_file_control(handle, op, arg)
+    }
+}
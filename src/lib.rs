@@ -1,21 +1,74 @@
+use futures::{StreamExt, TryStreamExt};
+use panic_guard::catch_panic;
 use parking_lot::Mutex;
 use slatedb::bytes::Bytes;
-use slatedb::config::{PutOptions, WriteOptions};
-use slatedb::object_store::{ObjectStore, memory::InMemory};
-use slatedb::{Db, Settings, WriteBatch};
+use slatedb::config::{
+    CheckpointOptions, CheckpointScope, DbReaderOptions, PutOptions, WriteOptions,
+};
+use slatedb::object_store::ObjectStore;
+use slatedb::{Db, DbReader, Settings, WriteBatch};
 use sqlite_plugin::flags;
 use sqlite_plugin::vfs;
-use std::collections::HashMap;
-use std::ffi::{CStr, c_char, c_int, c_void};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
 use std::sync::{
     Arc, OnceLock,
-    atomic::{AtomicBool, AtomicU64, Ordering},
+    atomic::{AtomicBool, Ordering},
 };
 use tracing::{Level, instrument, span};
-use tracing_chrome::ChromeLayerBuilder;
-use tracing_subscriber::{Registry, layer::SubscriberExt};
+mod analyze;
+mod autotune;
+mod bootstrap;
+mod cache_manifest;
+mod clock;
+mod cost;
+pub mod credentials;
+mod database_config;
+mod diagnostics;
+mod endpoints;
+mod env_config;
+mod eviction;
+mod extent;
+mod fcntl;
+mod features;
+mod flush;
+mod generation;
 mod handle;
+mod handle_registry;
+mod health;
+mod heatmap;
+mod interrupt;
+mod kv;
+mod lazy;
 mod lock_manager;
+mod lock_wait;
+mod memory_budget;
+mod meta;
+mod names;
+mod page_cache;
+mod panic_guard;
+mod pending_writes;
+mod point_in_time;
+mod progress;
+mod read_repair;
+pub mod reader;
+mod readonly;
+mod recovery;
+mod remote;
+mod runtime_metrics;
+mod schema;
+mod shadow;
+mod sharded;
+mod size_limit;
+mod snapshot;
+mod stall;
+mod stats;
+mod store;
+mod threading;
+mod throttle;
+mod trace_context;
+mod ttl;
+pub mod watch;
 
 #[derive(Clone)]
 struct Capabilities {
@@ -24,22 +77,61 @@ struct Capabilities {
     sector_size: i32,
 }
 
-struct BatchWrite {
-    offset: usize,
-    data: Vec<u8>,
+const CAPABILITIES: Capabilities = Capabilities {
+    atomic_batch: true,
+    point_in_time_reads: true,
+    sector_size: 4096,
+};
+
+/// What a host loading the extension gets back, see `sqlite3_s3qlite_init`. Only the
+/// object store's scheme is reported, the url can carry credentials.
+fn capabilities_json(config: &env_config::EnvConfig) -> String {
+    let scheme = match &config.object_store_url {
+        Some(url) => url.split("://").next().unwrap_or_default(),
+        None => "memory",
+    };
+    format!(
+        "{{\"vfs\":\"{}\",\"default\":false,\"version\":\"{}\",\"page_size\":{},\"atomic_batch\":{},\"point_in_time_reads\":{},\"wal\":false,\"object_store\":\"{scheme}\",\"cache_policy\":\"{}\",\"diagnostics\":{}}}",
+        EXTENSION_VFS_NAME.to_string_lossy(),
+        env!("CARGO_PKG_VERSION"),
+        config.block_size,
+        CAPABILITIES.atomic_batch,
+        CAPABILITIES.point_in_time_reads,
+        config.cache_policy.name(),
+        cfg!(feature = "diagnostics"),
+    )
 }
 
 #[derive(Clone)]
 struct FileState {
-    pending_writes: Arc<Mutex<Vec<BatchWrite>>>,
+    pending_writes: Arc<Mutex<pending_writes::PendingWrites>>,
     batch_open: Arc<AtomicBool>,
+    // Some while the file is newly created and not synced yet, see `bootstrap`
+    fresh: Arc<Mutex<Option<bootstrap::FreshFile>>>,
+    size_limit: Arc<size_limit::SizeLimit>,
+    // marked read-only, see `readonly`
+    readonly: Arc<AtomicBool>,
+    generation: Arc<generation::Generation>,
+    // puts made inside the write transaction in progress, see `kv`
+    kv: Arc<kv::Staged>,
+    // changes since ANALYZE last ran, see `analyze`
+    churn: Arc<analyze::Churn>,
+    // bytes stored per page key, see `meta`
+    block_size: Arc<meta::BlockSize>,
 }
 
 impl FileState {
-    fn new() -> Self {
+    fn new(spill: pending_writes::SpillConfig) -> Self {
         Self {
-            pending_writes: Arc::new(Mutex::new(Vec::new())),
+            pending_writes: Arc::new(Mutex::new(pending_writes::PendingWrites::new(spill))),
             batch_open: Arc::new(AtomicBool::new(false)),
+            fresh: Arc::new(Mutex::new(None)),
+            size_limit: Arc::new(size_limit::SizeLimit::default()),
+            readonly: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(generation::Generation::default()),
+            kv: Arc::new(kv::Staged::default()),
+            churn: Arc::new(analyze::Churn::default()),
+            block_size: Arc::new(meta::BlockSize::default()),
         }
     }
 }
@@ -49,54 +141,490 @@ struct GrpcVfs {
     runtime: Arc<tokio::runtime::Runtime>,
     capabilities: Capabilities,
     db: Arc<Db>,
-    files: Arc<Mutex<HashMap<String, FileState>>>,
-    _guard: Arc<Mutex<Option<tracing_chrome::FlushGuard>>>,
-    handle_counter: Arc<AtomicU64>,
+    object_store: Arc<dyn ObjectStore>,
+    // snapshot readers by checkpoint id, shared by every handle open at that snapshot
+    snapshots: Arc<Mutex<HashMap<String, Arc<DbReader>>>>,
+    files: Arc<sharded::Sharded<FileState>>,
+    _guard: Arc<Mutex<Option<diagnostics::FlushGuard>>>,
+    ids: Arc<dyn clock::Ids>,
+    clock: Arc<dyn clock::Clock>,
+    // set with DETERMINISTIC_SEED, moved by `PRAGMA s3qlite_advance_clock`
+    manual_clock: Option<Arc<clock::ManualClock>>,
+    handles: Arc<handle_registry::Registry>,
+    names: Option<Arc<names::NameCodec>>,
+    // settings of single databases by stored path, see `database_config`
+    databases: Arc<HashMap<String, database_config::DatabaseConfig>>,
     lock_manager: lock_manager::LockManager,
+    cache: Arc<page_cache::PageCache>,
+    // Paths that have already been warmed from their cache manifest
+    warmed: Arc<Mutex<HashSet<String>>>,
+    // Paths that have already been preloaded, see `preload_cache`
+    preloaded: Arc<Mutex<HashSet<String>>>,
+    // Journals that may still be fresh, super-journals first, see `bootstrap`
+    fresh_journals: Arc<Mutex<Vec<String>>>,
+    // Journals known not to exist, set with JOURNAL_EXISTENCE_CACHE
+    absent_journals: Option<Arc<Mutex<HashSet<String>>>>,
+    traffic: Arc<cost::Traffic>,
+    // writes since the last explicit flush, see `flush`
+    unflushed: Arc<flush::Unflushed>,
+    // pages worth of in-flight commit data, shared by every handle
+    commit_budget: Arc<tokio::sync::Semaphore>,
+    signals: Arc<autotune::Signals>,
+    stats: Arc<stats::Stats>,
+    heatmap: Arc<heatmap::Heatmap>,
+    recoveries: Arc<recovery::Recoveries>,
+    // set with SHADOW_WRITES, see `shadow`
+    shadow: Option<Arc<shadow::Shadow>>,
+    // set with STALL_THRESHOLD_MS, see `stall`
+    stalls: Option<Arc<stall::Stalls>>,
+    config: env_config::EnvConfig,
+}
+
+/// Times a write, commit or sync until dropped, see `stall`.
+struct Timed<'a> {
+    vfs: &'a GrpcVfs,
+    stalls: &'a stall::Stalls,
+    op: &'static str,
+    path: String,
+    start: std::time::Instant,
+}
+
+impl Drop for Timed<'_> {
+    fn drop(&mut self) {
+        let timing = stall::Timing {
+            op: self.op,
+            path: std::mem::take(&mut self.path),
+            elapsed: self.vfs.clock.now().saturating_duration_since(self.start),
+            at_ms: ttl::unix_ms(self.vfs.clock.system_now()),
+        };
+        if self.stalls.record(timing.clone()) {
+            self.vfs.stalled(self.stalls, &timing);
+        }
+    }
 }
 
+/// The default block size, see `meta`.
 const PAGE_SIZE: usize = 4096;
 
+/// Split `len` bytes at `offset` into the parts falling in each page of `block_size`
+/// bytes, as (page offset, offset in page, range within the data). Page `k` holds exactly
+/// the file bytes `[k * block_size, (k + 1) * block_size)`, so a write crossing a page
+/// boundary has to be split rather than stored whole in the page it starts in.
+fn page_spans(
+    block_size: usize,
+    offset: usize,
+    len: usize,
+) -> impl Iterator<Item = (usize, usize, std::ops::Range<usize>)> {
+    let mut pos = offset;
+    std::iter::from_fn(move || {
+        if pos >= offset + len {
+            return None;
+        }
+        let page_offset = (pos / block_size) * block_size;
+        let offset_in_page = pos - page_offset;
+        let n = (block_size - offset_in_page).min(offset + len - pos);
+        let span = (page_offset, offset_in_page, pos - offset..pos - offset + n);
+        pos += n;
+        Some(span)
+    })
+}
+
+/// A boolean URI parameter, read the way `sqlite3_uri_boolean` reads it.
+/// The range of keys starting with `prefix`, for a scan. Keys are text and no UTF-8 byte
+/// is 0xff, so incrementing the prefix's last byte never carries.
+fn prefix_range(prefix: &str) -> std::ops::Range<Vec<u8>> {
+    let start = prefix.as_bytes().to_vec();
+    let mut end = start.clone();
+    *end.last_mut().expect("scan prefixes aren't empty") += 1;
+    start..end
+}
+
+fn uri_boolean(value: &str) -> bool {
+    ["1", "yes", "true", "on"]
+        .iter()
+        .any(|t| value.eq_ignore_ascii_case(t))
+}
+
+/// Whether `path` names a super-journal, which SQLite's pager names `{main db}-mj`
+/// followed by 9 hex digits.
+fn is_super_journal(path: &str) -> bool {
+    path.rsplit_once("-mj").is_some_and(|(_, suffix)| {
+        suffix.len() == 9 && suffix.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+fn add_kv(batch: &mut WriteBatch, path: &str, entries: &BTreeMap<String, Option<String>>) {
+    for (key, value) in entries {
+        match value {
+            Some(value) => batch.put(kv::kv_key(path, key), value),
+            None => batch.delete(kv::kv_key(path, key)),
+        }
+    }
+}
+
+/// Whether `path` names a rollback journal or WAL, which SQLite looks for on nearly
+/// every transaction and which usually don't exist.
+fn is_journal(path: &str) -> bool {
+    path.ends_with("-journal") || path.ends_with("-wal")
+}
+
+/// Page reads a `?scan=true` handle keeps in flight while fetching a range.
+const SCAN_CONCURRENCY: usize = 32;
+
+/// How long `PRAGMA s3qlite_health` waits for the object store by default.
+const DEFAULT_HEALTH_DEADLINE_MS: u64 = 1000;
+
+/// Where SlateDB keeps its manifest and SSTs within the object store.
+const SLATEDB_PATH: &str = "test_db";
+
 impl GrpcVfs {
-    pub fn new() -> Self {
+    pub fn try_new() -> Result<Self, String> {
+        Self::from_config(env_config::EnvConfig::new())
+    }
+
+    /// Build the VFS from `config` rather than the environment, for tests that need a
+    /// store of their own.
+    fn from_config(config: env_config::EnvConfig) -> Result<Self, String> {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_time()
             .enable_io()
             .build()
-            .unwrap();
+            .map_err(|e| format!("failed to build tokio runtime: {e}"))?;
 
-        let db = runtime.block_on(async {
-            let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
-            Db::builder("test_db", object_store)
+        let names = config
+            .path_key
+            .as_deref()
+            .map(names::NameCodec::from_hex)
+            .transpose()?
+            .map(Arc::new);
+        let databases = match &config.config_file {
+            Some(path) => database_config::load(path)?,
+            None => HashMap::new(),
+        };
+        // looked up by the path databases are stored under
+        let databases = databases
+            .into_iter()
+            .map(|(name, database)| match &names {
+                Some(names) => (names.encode(&name), database),
+                None => (name, database),
+            })
+            .collect();
+        let credentials = credentials::provider_from_config(config.credentials_source.as_deref())?;
+        let manual_clock = config
+            .deterministic_seed
+            .map(|_| Arc::new(clock::ManualClock::new()));
+        let clock: Arc<dyn clock::Clock> = match &manual_clock {
+            Some(manual_clock) => manual_clock.clone(),
+            None => Arc::new(clock::SystemClock),
+        };
+        let random: Box<dyn clock::Random> = match config.deterministic_seed {
+            Some(seed) => Box::new(clock::SeededRandom::new(seed)),
+            None => Box::new(clock::SystemRandom),
+        };
+        let stats = Arc::new(stats::Stats::default());
+        stats
+            .throttle
+            .configure(config.throttle_max_window, clock.clone());
+        stats.memory.configure(config.memory_budget_bytes);
+        let object_store: Arc<dyn ObjectStore> = Arc::new(throttle::ThrottledStore::new(
+            store::object_store_from_url(config.object_store_url.as_deref(), credentials)?,
+            stats.clone(),
+        ));
+        let db = threading::block_on(&runtime, async {
+            Db::builder(SLATEDB_PATH, object_store.clone())
                 .with_settings(Settings::default())
+                .with_seed(random.next_u64())
                 .build()
                 .await
-                .unwrap()
-        });
-        let guard = setup_tracing();
+                .map_err(|e| format!("failed to open slatedb: {e}"))
+        })?;
+        // tracing is best effort, a read-only state directory shouldn't stop the VFS
+        let guard = match config
+            .trace_file
+            .then(|| diagnostics::setup_tracing(&config.state_dir))
+        {
+            None => None,
+            Some(Ok(guard)) => Some(guard),
+            Some(Err(e)) => {
+                if !env_config::silent() {
+                    eprintln!("chrome tracing disabled: {e}");
+                }
+                None
+            }
+        };
+        let guard = Arc::new(Mutex::new(guard));
+        if config.trace_flush_interval_ms > 0 {
+            runtime.spawn(flush_traces_periodically(
+                guard.clone(),
+                std::time::Duration::from_millis(config.trace_flush_interval_ms),
+            ));
+        }
 
-        Self {
+        let vfs = Self {
             db: Arc::new(db),
+            object_store,
+            snapshots: Arc::new(Mutex::new(HashMap::new())),
             runtime: Arc::new(runtime),
-            files: Arc::new(Mutex::new(HashMap::new())),
-            capabilities: Capabilities {
-                atomic_batch: true,
-                point_in_time_reads: false,
-                sector_size: 4096,
-            },
-            _guard: Arc::new(Mutex::new(Some(guard))),
-            handle_counter: Arc::new(AtomicU64::new(1)),
-            lock_manager: lock_manager::LockManager::new(),
+            files: Arc::new(sharded::Sharded::new()),
+            capabilities: CAPABILITIES,
+            _guard: guard,
+            ids: Arc::new(clock::SequentialIds::default()),
+            handles: Arc::new(handle_registry::Registry::new(clock.clone())),
+            names,
+            databases: Arc::new(databases),
+            lock_manager: lock_manager::LockManager::new(
+                Some(config.lock_timeout_ms)
+                    .filter(|&ms| ms > 0)
+                    .map(std::time::Duration::from_millis),
+                clock.clone(),
+            ),
+            cache: Arc::new(page_cache::PageCache::new(
+                config
+                    .max_cache_bytes
+                    .unwrap_or(page_cache::DEFAULT_MAX_CACHE_BYTES),
+                config.cache_policy,
+                config.cache_simulate,
+            )),
+            warmed: Arc::new(Mutex::new(HashSet::new())),
+            preloaded: Arc::new(Mutex::new(HashSet::new())),
+            fresh_journals: Arc::new(Mutex::new(Vec::new())),
+            absent_journals: config
+                .journal_existence_cache
+                .then(|| Arc::new(Mutex::new(HashSet::new()))),
+            traffic: Arc::new(cost::Traffic::default()),
+            unflushed: Arc::new(flush::Unflushed::default()),
+            commit_budget: Arc::new(tokio::sync::Semaphore::new(
+                config.commit_inflight_permits() as usize,
+            )),
+            signals: Arc::new(autotune::Signals::default()),
+            stats,
+            heatmap: Arc::new(heatmap::Heatmap::new(config.heatmap_sample_every)),
+            recoveries: Arc::new(recovery::Recoveries::new(
+                config.recovery_reports_keep,
+                clock.clone(),
+            )),
+            clock,
+            manual_clock,
+            shadow: config
+                .shadow_writes
+                .then(|| Arc::new(shadow::Shadow::default())),
+            stalls: Some(config.stall_threshold_ms)
+                .filter(|&ms| ms > 0)
+                .map(|ms| Arc::new(stall::Stalls::new(std::time::Duration::from_millis(ms)))),
+            config,
+        };
+        vfs.stats.runtime.watch(vfs.runtime.handle().clone());
+        if vfs.config.cache_manifest_interval_secs > 0 {
+            vfs.runtime
+                .spawn(vfs.clone().publish_cache_manifests_periodically(
+                    std::time::Duration::from_secs(vfs.config.cache_manifest_interval_secs),
+                ));
+        }
+        vfs.signals
+            .prefetch_window
+            .store(vfs.config.prefetch_pages, Ordering::Relaxed);
+        if vfs.config.autotune && vfs.config.autotune_interval_secs > 0 {
+            vfs.runtime.spawn(
+                vfs.clone()
+                    .autotune_periodically(std::time::Duration::from_secs(
+                        vfs.config.autotune_interval_secs,
+                    )),
+            );
+        }
+        if vfs.config.memory_budget_bytes > 0 {
+            vfs.runtime
+                .spawn(vfs.clone().enforce_memory_budget_periodically());
+        }
+        if vfs.config.cache_verify_interval_secs > 0 {
+            vfs.runtime.spawn(vfs.clone().verify_cache_periodically(
+                std::time::Duration::from_secs(vfs.config.cache_verify_interval_secs),
+            ));
+        }
+        if vfs.shadow.is_some() && vfs.config.shadow_verify_interval_secs > 0 {
+            vfs.runtime.spawn(vfs.clone().verify_shadow_periodically(
+                std::time::Duration::from_secs(vfs.config.shadow_verify_interval_secs),
+            ));
+        }
+        if vfs.config.stats_dump_interval_secs > 0 {
+            vfs.runtime.spawn(
+                vfs.clone()
+                    .dump_stats_periodically(std::time::Duration::from_secs(
+                        vfs.config.stats_dump_interval_secs,
+                    )),
+            );
+        }
+        if vfs.config.generation_poll_ms > 0 {
+            vfs.runtime
+                .spawn(vfs.clone().refresh_generations_periodically(
+                    std::time::Duration::from_millis(vfs.config.generation_poll_ms),
+                ));
+        }
+        if vfs.config.expire_interval_secs > 0 {
+            vfs.runtime.spawn(vfs.clone().expire_databases_periodically(
+                std::time::Duration::from_secs(vfs.config.expire_interval_secs),
+            ));
+        }
+        if vfs.config.handle_warn_after_secs > 0 {
+            vfs.runtime
+                .spawn(vfs.clone().warn_overdue_handles_periodically(
+                    std::time::Duration::from_secs(vfs.config.handle_warn_after_secs),
+                ));
+        }
+        Ok(vfs)
+    }
+
+    /// Get or create the batch state of a file.
+    fn file_state(&self, path: &str) -> FileState {
+        self.files
+            .shard(path)
+            .entry(path.to_string())
+            .or_insert_with(|| {
+                FileState::new(pending_writes::SpillConfig {
+                    threshold_bytes: self.config.spill_threshold_bytes,
+                    dir: self.spill_dir(),
+                })
+            })
+            .clone()
+    }
+
+    /// The path `path` is stored under, opaque when `PATH_KEY` is set.
+    fn store_path<'a>(&self, path: &'a str) -> std::borrow::Cow<'a, str> {
+        match &self.names {
+            Some(names) if !path.is_empty() => names.encode(path).into(),
+            _ => path.into(),
+        }
+    }
+
+    /// Expire `path` `ttl_secs` from now, or never with 0, see `ttl`. Returns the
+    /// deadline.
+    async fn set_ttl(&self, path: &str, ttl_secs: u64) -> Result<Option<u64>, i32> {
+        let key = ttl::expires_key(path);
+        if ttl_secs == 0 {
+            self.db_write({
+                let mut batch = WriteBatch::new();
+                batch.delete(&key);
+                batch
+            })
+            .await?;
+            self.cache.remove(key.as_bytes());
+            return Ok(None);
+        }
+        let now = ttl::unix_ms(self.clock.system_now());
+        let expires_at = now.saturating_add(ttl_secs.saturating_mul(1000));
+        self.put(&key, expires_at.to_string()).await?;
+        Ok(Some(expires_at))
+    }
+
+    /// Delete every expired database no handle here has open, see `ttl`. Returns their
+    /// paths.
+    async fn expire_databases(&self) -> Result<Vec<String>, i32> {
+        let now = ttl::unix_ms(self.clock.system_now());
+        let open: HashSet<String> = self
+            .handles
+            .list()
+            .iter()
+            .map(|h| self.store_path(&h.path).into_owned())
+            .collect();
+        let fail = |e: slatedb::SlateDBError| {
+            log::error!("error listing expiring databases: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
+        };
+        let mut iter = self
+            .db
+            .scan(prefix_range(ttl::EXPIRES_PREFIX))
+            .await
+            .map_err(fail)?;
+        let mut expired = Vec::new();
+        while let Some(kv) = iter.next().await.map_err(fail)? {
+            let path = String::from_utf8_lossy(&kv.key[ttl::EXPIRES_PREFIX.len()..]);
+            if ttl::parse(&kv.value).is_some_and(|expires_at| expires_at <= now)
+                && !open.contains(path.as_ref())
+            {
+                expired.push(path.into_owned());
+            }
+        }
+        drop(iter);
+
+        for path in &expired {
+            log::info!("deleting expired database {path}");
+            self.delete_stored(path).await?;
+            // the keys a delete keeps, since SQLite deletes a database's files but the
+            // database lives on
+            let mut keys = self
+                .prefixed_keys(&snapshot::snapshot_key(path, ""))
+                .await?;
+            keys.push(Bytes::from(generation::generation_key(path)));
+            keys.push(Bytes::from(readonly::readonly_key(path)));
+            keys.push(Bytes::from(ttl::expires_key(path)));
+            let mut batch = WriteBatch::new();
+            for key in &keys {
+                batch.delete(key);
+            }
+            self.db_write(batch).await?;
+            for key in &keys {
+                self.cache.remove(key);
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn expire_databases_periodically(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.expire_databases().await {
+                log::warn!("failed to delete expired databases: {e}");
+            }
         }
     }
 
+    /// The real paths of the databases stored under opaque names, as a JSON object
+    /// mapping stored path to path.
+    async fn names_json(&self) -> Result<String, vfs::PragmaErr> {
+        let Some(names) = &self.names else {
+            return Err(vfs::PragmaErr::Fail(
+                sqlite_plugin::vars::SQLITE_ERROR,
+                Some("PATH_KEY is not set, paths are stored as is".to_string()),
+            ));
+        };
+        let fail = |e: slatedb::SlateDBError| {
+            log::error!("error listing names: {e}");
+            vfs::PragmaErr::Fail(sqlite_plugin::vars::SQLITE_IOERR_READ, None)
+        };
+        let mut iter = self
+            .db
+            .scan(prefix_range(names::NAMES_PREFIX))
+            .await
+            .map_err(fail)?;
+        let mut entries = Vec::new();
+        while let Some(kv) = iter.next().await.map_err(fail)? {
+            let stored = String::from_utf8_lossy(&kv.key[names::NAMES_PREFIX.len()..]);
+            let path = names.open(&kv.value).unwrap_or_else(|| "?".to_string());
+            entries.push(format!(
+                "{}:{}",
+                health::json_string(&stored),
+                health::json_string(&path)
+            ));
+        }
+        Ok(format!("{{{}}}", entries.join(",")))
+    }
+
+    fn spill_dir(&self) -> std::path::PathBuf {
+        self.config
+            .local_cache_dir
+            .clone()
+            .map(Into::into)
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
     fn block_on<F, T>(&self, future: F) -> Result<T, i32>
     where
         F: std::future::Future<Output = Result<T, i32>>,
     {
         let span = span!(Level::INFO, "block_on");
         let _guard = span.enter();
-        self.runtime.block_on(future)
+        threading::block_on(&self.runtime, future)
     }
 
     pub async fn put<K, V>(&self, key: K, value: V) -> Result<(), i32>
@@ -106,10 +634,11 @@ impl GrpcVfs {
     {
         let span = span!(Level::INFO, "put");
         let _guard = span.enter();
+        self.unflushed.record_batch();
         self.db
             .put_with_options(
-                key,
-                value,
+                &key,
+                &value,
                 &PutOptions::default(),
                 &WriteOptions {
                     await_durable: false,
@@ -119,353 +648,2280 @@ impl GrpcVfs {
             .map_err(|e| {
                 log::error!("error putting page: {e}");
                 sqlite_plugin::vars::SQLITE_IOERR_WRITE
-            })
+            })?;
+        let pages = usize::from(key.as_ref().windows(6).any(|w| w == b":page:"));
+        self.record_stored(key.as_ref(), pages, value.as_ref().len());
+        self.cache
+            .insert(key.as_ref(), Bytes::copy_from_slice(value.as_ref()));
+        Ok(())
     }
 
-    pub async fn delete<K>(&self, key: K) -> Result<(), i32>
-    where
-        K: AsRef<[u8]>,
-    {
-        let span = span!(Level::INFO, "delete");
-        let _guard = span.enter();
-        self.db
-            .delete_with_options(
-                key,
-                &WriteOptions {
-                    await_durable: false,
-                },
-            )
-            .await
-            .map_err(|e| {
-                log::error!("error deleting page: {e}");
-                sqlite_plugin::vars::SQLITE_IOERR_DELETE
-            })
-    }
-    pub async fn db_write(&self, batch: WriteBatch) -> Result<(), i32> {
-        let span = span!(Level::INFO, "db_write");
-        let _guard = span.enter();
-        self.db
-            .write_with_options(
-                batch,
-                &WriteOptions {
-                    await_durable: false,
-                },
-            )
-            .await
-            .map_err(|e| {
-                log::error!("error writing page: {e}");
-                sqlite_plugin::vars::SQLITE_IOERR_WRITE
-            })
+    /// The settings `CONFIG_FILE` gives the database at `path`, see `database_config`.
+    fn database_config(&self, path: &str) -> Option<&database_config::DatabaseConfig> {
+        self.databases.get(path)
     }
 
-
-    pub async fn get<K>(&self, key: K) -> Result<Option<Bytes>, i32>
-    where
-        K: AsRef<[u8]> + Send,
-    {
-        let span = span!(Level::INFO, "get");
-        let _guard = span.enter();
-        self.db.get(key).await.map_err(|e| {
-            log::error!("error getting page: {e}");
-            sqlite_plugin::vars::SQLITE_IOERR_READ
-        })
+    /// The size limit of the database at `path` when it has none of its own.
+    fn max_db_bytes(&self, path: &str) -> u64 {
+        self.database_config(path)
+            .and_then(|database| database.max_db_bytes)
+            .unwrap_or(self.config.max_db_bytes)
     }
-}
 
-impl vfs::Vfs for GrpcVfs {
-    type Handle = handle::GrpcVfsHandle;
+    fn durable_unlock(&self, path: &str) -> bool {
+        self.database_config(path)
+            .and_then(|database| database.durable_unlock)
+            .unwrap_or(self.config.durable_unlock)
+    }
 
-    fn register_logger(&self, logger: sqlite_plugin::logger::SqliteLogger) {
-        struct LogCompat {
-            logger: Mutex<sqlite_plugin::logger::SqliteLogger>,
+    /// Pin the tables `CONFIG_FILE` lists for the database at `path`. A database without
+    /// them yet is left alone until it's next opened.
+    async fn pin_configured_tables(&self, path: &str) -> Result<(), i32> {
+        let Some(tables) = self
+            .database_config(path)
+            .map(|database| &database.pin)
+            .filter(|tables| !tables.is_empty())
+        else {
+            return Ok(());
+        };
+        let tables: Vec<&str> = tables.iter().map(String::as_str).collect();
+        match self.pin_tables(path, &tables).await {
+            Ok(pinned) => log::debug!("pinned {pinned} pages of {path}"),
+            Err(vfs::PragmaErr::Fail(_, Some(msg))) => log::info!("not pinning {path} yet: {msg}"),
+            Err(vfs::PragmaErr::Fail(e, None)) => return Err(e),
+            Err(vfs::PragmaErr::NotFound) => {}
         }
+        Ok(())
+    }
 
-        impl log::Log for LogCompat {
-            fn enabled(&self, _metadata: &log::Metadata) -> bool {
-                true
-            }
-
-            fn log(&self, record: &log::Record) {
-                let level = match record.level() {
-                    log::Level::Error => sqlite_plugin::logger::SqliteLogLevel::Error,
-                    log::Level::Warn => sqlite_plugin::logger::SqliteLogLevel::Warn,
-                    _ => sqlite_plugin::logger::SqliteLogLevel::Notice,
-                };
-                if !record.target().contains("s3qlite") {
-                    // Filter out logs from other modules.
-                    return;
-                }
-                let msg = format!("{}", record.args());
-                println!("{msg}");
-                self.logger.lock().log(level, msg.as_bytes());
-            }
+    /// Load the size limit of the database at `path` into its file state, see
+    /// `size_limit`.
+    async fn load_size_limit(&self, path: &str) -> Result<(), i32> {
+        let stored = self.get(size_limit::size_limit_key(path)).await?;
+        let limit = stored
+            .as_deref()
+            .and_then(size_limit::parse)
+            .unwrap_or(self.max_db_bytes(path));
+        self.file_state(path)
+            .size_limit
+            .set(Some(limit).filter(|&limit| limit > 0));
+        Ok(())
+    }
 
-            fn flush(&self) {
-                println!("flush");
+    /// Give the database at `path` a size limit of its own, or with `None` fall back to
+    /// `MAX_DB_BYTES`.
+    async fn set_size_limit(&self, path: &str, limit: Option<u64>) -> Result<(), i32> {
+        let key = size_limit::size_limit_key(path);
+        match limit.filter(|&limit| limit > 0) {
+            Some(limit) => self.put(&key, limit.to_string()).await?,
+            None => {
+                let mut batch = WriteBatch::new();
+                batch.delete(&key);
+                self.db_write(batch).await?;
+                self.cache.remove(key.as_bytes());
             }
         }
+        self.load_size_limit(path).await
+    }
 
-        let log = LogCompat {
-            logger: Mutex::new(logger),
-        };
-        if let Err(e) = log::set_boxed_logger(Box::new(log)) {
-            // Logger already set, ignore the error
-            eprintln!("Logger already initialized: {e}");
-        }
+    async fn load_readonly(&self, path: &str) -> Result<(), i32> {
+        let readonly = self.get(readonly::readonly_key(path)).await?.is_some();
+        self.file_state(path)
+            .readonly
+            .store(readonly, Ordering::Release);
+        Ok(())
     }
 
-    #[instrument(level = "info", skip(self, path, opts))]
-    fn open(&self, path: Option<&str>, opts: flags::OpenOpts) -> vfs::VfsResult<Self::Handle> {
-        let path = path.unwrap_or("");
-        log::debug!("open: path={path}, opts={opts:?}");
-        let mode = opts.mode();
+    /// The size of `path` from its `{path}:extent`, for files stored before `{path}:meta`
+    /// that go on past a gap, see `extent`.
+    async fn legacy_extent(&self, path: &str) -> Result<usize, i32> {
+        let stored = self.get(extent::legacy_key(path)).await?;
+        Ok(stored
+            .as_deref()
+            .and_then(meta::parse)
+            .map_or(0, |meta| meta.len))
+    }
 
-        if mode.is_readonly() && !self.capabilities.point_in_time_reads {
-            log::error!("read-only mode is not supported for this server");
-            return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+    /// The stored length of the file behind `handle`, past which reads come up short;
+    /// before it, missing pages are a gap that reads as zeros, see `extent`.
+    async fn handle_extent(&self, handle: &handle::GrpcVfsHandle) -> Result<usize, i32> {
+        if let Some(snapshot) = &handle.snapshot {
+            let stored = match snapshot.get(meta::meta_key(&snapshot.base)).await? {
+                Some(stored) => Some(stored),
+                None => snapshot.get(extent::legacy_key(&snapshot.base)).await?,
+            };
+            return Ok(stored
+                .as_deref()
+                .and_then(meta::parse)
+                .map_or(0, |meta| meta.len));
         }
-
-        if !path.is_empty() {
-            self.block_on(async { self.put(&path, &[]).await })?;
+        let file_state = self.file_state(&handle.path);
+        if let Some(fresh) = &*file_state.fresh.lock() {
+            return Ok(fresh.size());
         }
+        match self.stored_size(&handle.path).await? {
+            Some(size) => Ok(size),
+            None => self.legacy_extent(&handle.path).await,
+        }
+    }
 
-        let handle_id = self.handle_counter.fetch_add(1, Ordering::SeqCst);
-        let handle = handle::GrpcVfsHandle::new(path.to_string(), mode.is_readonly(), handle_id);
-        Ok(handle)
+    /// The length of `path` stored under `{path}:meta`, see `meta`.
+    async fn stored_size(&self, path: &str) -> Result<Option<usize>, i32> {
+        let stored = self.get(meta::meta_key(path)).await?;
+        Ok(stored.as_deref().and_then(meta::parse).map(|meta| meta.len))
     }
 
-    #[instrument(level = "info", skip(self))]
-    fn delete(&self, path: &str) -> vfs::VfsResult<()> {
-        log::debug!("delete: path={path}");
+    /// Store `size` as the length of `path`, see `meta`.
+    async fn store_size(&self, path: &str, size: usize) -> Result<(), i32> {
+        self.put(meta::meta_key(path), self.meta_record(path, size))
+            .await
+    }
 
-        self.block_on(async {
-            // Delete all pages for this file
-            let mut page_offset = 0;
-            loop {
-                let page_key = format!("{path}:page:{page_offset}");
-                let exists = self.get(&page_key).await?;
+    /// The `{path}:meta` record of `path` at length `len`.
+    fn meta_record(&self, path: &str, len: usize) -> String {
+        meta::Meta {
+            len,
+            block_size: self.block_size(path),
+        }
+        .encode()
+    }
 
-                if exists.is_some() {
-                    self.delete(&page_key).await?;
-                    page_offset += PAGE_SIZE;
-                } else {
-                    break;
-                }
+    /// The bytes stored per page key of `path`, see `meta`.
+    fn block_size(&self, path: &str) -> usize {
+        self.file_state(path).block_size.get()
+    }
+
+    /// Give the file behind `handle` blocks of `block_size` bytes, which it can only
+    /// change while it's empty, see `meta`.
+    fn set_block_size(
+        &self,
+        handle: &mut handle::GrpcVfsHandle,
+        block_size: usize,
+    ) -> Result<(), vfs::PragmaErr> {
+        let fail = |msg: String| vfs::PragmaErr::Fail(sqlite_plugin::vars::SQLITE_ERROR, Some(msg));
+        if !meta::valid_block_size(block_size) {
+            return Err(fail(format!(
+                "invalid block size {block_size}, expected a power of two from 512 to 1048576"
+            )));
+        }
+        let file_state = self.file_state(&handle.path);
+        if file_state.block_size.get() == block_size {
+            return Ok(());
+        }
+        if handle.immutable() {
+            return Err(fail(format!("{} is read-only", handle.path)));
+        }
+        let has_data = |size: usize| {
+            fail(format!(
+                "can't change the block size of {}, it is {size} bytes",
+                handle.path
+            ))
+        };
+        if let Some(fresh) = file_state.fresh.lock().as_mut() {
+            if fresh.size() > 0 {
+                return Err(has_data(fresh.size()));
             }
-            self.delete(&path).await?;
-            Ok::<(), i32>(())
-        })?;
+            fresh.block_size = block_size;
+            file_state.block_size.set(block_size);
+            return Ok(());
+        }
+        let (_, size) = self
+            .block_on(self.current_size(&handle.path))
+            .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+        if size > 0 {
+            return Err(has_data(size));
+        }
+        file_state.block_size.set(block_size);
+        self.block_on(self.store_size(&handle.path, 0))
+            .map_err(|e| vfs::PragmaErr::Fail(e, None))
+    }
 
+    /// Load the block size of the stored file at `path` into its file state.
+    async fn load_block_size(&self, path: &str) -> Result<(), i32> {
+        let stored = self.get(meta::meta_key(path)).await?;
+        let block_size = stored
+            .as_deref()
+            .and_then(meta::parse)
+            .map_or(meta::DEFAULT_BLOCK_SIZE, |meta| meta.block_size);
+        self.file_state(path).block_size.set(block_size);
         Ok(())
     }
 
-    #[instrument(level = "info", skip(self, path, flags))]
-    fn access(&self, path: &str, flags: flags::AccessFlags) -> vfs::VfsResult<bool> {
-        let exists = self.block_on(async { self.get(path).await })?.is_some();
-        log::debug!("access: path={path}, flags={flags:?}, exists={exists}");
-        Ok(exists)
+    /// The length of `path`, found from its pages when it has none stored, see `meta`.
+    async fn current_size(&self, path: &str) -> Result<(Option<usize>, usize), i32> {
+        let stored = self.stored_size(path).await?;
+        let size = match stored {
+            Some(size) => size,
+            None => self.scanned_size(path).await?,
+        };
+        Ok((stored, size))
     }
 
-    #[instrument(level = "info", skip(self, handle))]
-    fn file_size(&self, handle: &mut Self::Handle) -> vfs::VfsResult<usize> {
-        let max_size = self.block_on(async {
-            // Find the highest page offset for this file to calculate total size
-            // This is a simplified approach - in a real implementation you might want to
-            // track file metadata separately for better performance
-            let mut max_size = 0usize;
-
-            // Check pages starting from 0 until we find no more
-            let mut page_offset = 0;
-            loop {
-                let page_key = format!("{}:page:{}", handle.path, page_offset);
-                let page_data = self.get(&page_key).await?;
-
-                if let Some(page) = page_data {
-                    max_size = page_offset + page.len();
-                    page_offset += PAGE_SIZE;
-                } else {
-                    break;
-                }
-            }
+    /// The size of `path` from the end of its last stored page, for files stored before
+    /// `{path}:meta`.
+    async fn scanned_size(&self, path: &str) -> Result<usize, i32> {
+        let pages = self.stored_pages("file_size", path).await?;
+        let max_size = pages
+            .into_iter()
+            .map(|(offset, len)| offset + len)
+            .max()
+            .unwrap_or(0);
+        // the file may go on past a gap, see `extent`
+        Ok(max_size.max(self.legacy_extent(path).await?))
+    }
 
-            Ok::<usize, i32>(max_size)
+    /// Catch the generation of `path` up with the one stored, returning whether it moved.
+    async fn load_generation(&self, path: &str) -> Result<bool, i32> {
+        let key = generation::generation_key(path);
+        // read past the cache, another process may have moved it on
+        let stored = self.db.get(&key).await.map_err(|e| {
+            log::error!("error reading {key}: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
         })?;
-
-        Ok(max_size)
+        let Some(stored) = stored.as_deref().and_then(generation::parse) else {
+            return Ok(false);
+        };
+        let generation = &self.file_state(path).generation;
+        let moved = stored > generation.get();
+        generation.advance_to(stored);
+        Ok(moved)
     }
 
-    #[instrument(level = "info", skip(self, handle, size))]
-    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> vfs::VfsResult<()> {
-        if size == 0 {
-            self.block_on(async { self.delete(handle.path.as_str()).await })?;
-            return Ok(());
+    /// Catch the generation of `path` up like `load_generation`, dropping the cached pages
+    /// when it moved: they may be older than the commit made elsewhere.
+    async fn refresh_generation(&self, path: &str) -> Result<bool, i32> {
+        if !self.load_generation(path).await? {
+            return Ok(false);
         }
+        self.cache.remove_prefix(format!("{path}:page:").as_bytes());
+        self.cache.remove(extent::legacy_key(path).as_bytes());
+        self.cache.remove(meta::meta_key(path).as_bytes());
+        Ok(true)
+    }
 
-        self.block_on(async {
-            // Calculate which page contains the truncation point
-            let truncate_page_offset = (size / PAGE_SIZE) * PAGE_SIZE;
-            let truncate_offset_in_page = size % PAGE_SIZE;
-
-            // Truncate the page that contains the truncation point
-            let page_key = format!("{}:page:{}", handle.path, truncate_page_offset);
-            let page_data = self.get(&page_key).await?;
+    /// Wait for `path` to reach generation `min`, see `generation`.
+    async fn wait_for_generation(&self, path: &str, min: u64) -> Result<u64, i32> {
+        let generation = self.file_state(path).generation;
+        if generation.get() < min {
+            self.refresh_generation(path).await?;
+        }
+        let timeout = std::time::Duration::from_millis(self.config.min_generation_wait_ms);
+        if !generation.wait_for(min, timeout).await {
+            log::warn!(
+                "{path} is at generation {}, gave up waiting for {min}",
+                generation.get()
+            );
+            return Err(sqlite_plugin::vars::SQLITE_BUSY);
+        }
+        Ok(generation.get())
+    }
 
-            if let Some(page) = page_data {
-                let mut page_vec = page.clone();
-                if truncate_offset_in_page < page_vec.len() {
-                    page_vec.truncate(truncate_offset_in_page);
-                    self.put(&page_key, page_vec).await?;
-                }
+    /// Whether the database open on `handle` is due an ANALYZE, see `analyze`.
+    fn analyze_advice(
+        &self,
+        handle: &mut handle::GrpcVfsHandle,
+        churn: &analyze::Churn,
+    ) -> Result<String, i32> {
+        let size = vfs::Vfs::file_size(self, handle)?;
+        let mut header = [0; 100];
+        if size < header.len() {
+            return Ok(churn.advice(&[], 0, self.config.analyze_churn_pct));
+        }
+        vfs::Vfs::read(self, handle, 0, &mut header)?;
+        let (page_size, usable) =
+            analyze::page_sizes(&header).ok_or(sqlite_plugin::vars::SQLITE_CORRUPT)?;
+        let page_count = (size / page_size) as u64;
+        let mut schema = Vec::new();
+        let mut pages = vec![1u32];
+        let mut page = vec![0; page_size];
+        let mut visited = 0;
+        while let Some(page_no) = pages.pop() {
+            visited += 1;
+            // a cycle in a corrupt schema would never end
+            if page_no == 0 || page_no as u64 > page_count || visited > page_count {
+                return Err(sqlite_plugin::vars::SQLITE_CORRUPT);
             }
+            vfs::Vfs::read(self, handle, (page_no as usize - 1) * page_size, &mut page)?;
+            match analyze::parse_schema_page(&page, page_no, usable) {
+                Some(analyze::SchemaPage::Leaf(entries)) => schema.extend(entries),
+                Some(analyze::SchemaPage::Interior(children)) => pages.extend(children),
+                None => return Err(sqlite_plugin::vars::SQLITE_CORRUPT),
+            }
+        }
+        Ok(churn.advice(&schema, page_count, self.config.analyze_churn_pct))
+    }
 
-            // Delete all pages beyond the truncation point
-            let mut page_offset = truncate_page_offset + PAGE_SIZE;
-            loop {
-                let page_key = format!("{}:page:{}", handle.path, page_offset);
-                let exists = self.get(&page_key).await?;
-
-                if exists.is_some() {
-                    self.delete(&page_key).await?;
-                    page_offset += PAGE_SIZE;
-                } else {
-                    break;
-                }
+    /// Follow the commit generation of the database at `path`, see `watch`.
+    pub fn watch_generation(&self, path: &str) -> tokio::sync::watch::Receiver<u64> {
+        let stored = self.store_path(path);
+        let receiver = self.file_state(&stored).generation.subscribe();
+        // a database not opened yet hasn't loaded its generation
+        let vfs = self.clone();
+        let stored = stored.into_owned();
+        self.runtime.spawn(async move {
+            if let Err(e) = vfs.refresh_generation(&stored).await {
+                log::warn!("failed to read the generation of {stored}: {e}");
             }
+        });
+        receiver
+    }
 
-            Ok::<(), i32>(())
-        })?;
+    /// Mark the database at `path` read-only, or lift the mark.
+    async fn set_readonly(&self, path: &str, readonly: bool) -> Result<(), i32> {
+        let key = readonly::readonly_key(path);
+        if readonly {
+            self.put(&key, "").await?;
+        } else {
+            let mut batch = WriteBatch::new();
+            batch.delete(&key);
+            self.db_write(batch).await?;
+            self.cache.remove(key.as_bytes());
+        }
+        log::info!("marked {path} readonly={readonly}");
+        self.load_readonly(path).await
+    }
 
+    /// Store key-value puts of `path` in one batch, see `kv`.
+    async fn store_kv(
+        &self,
+        path: &str,
+        entries: &BTreeMap<String, Option<String>>,
+    ) -> Result<(), i32> {
+        let mut batch = WriteBatch::new();
+        add_kv(&mut batch, path, entries);
+        self.db_write(batch).await?;
+        self.cache_kv(path, entries);
         Ok(())
     }
 
-    fn write(
-        &self,
-        handle: &mut Self::Handle,
-        offset: usize,
-        data: &[u8],
-    ) -> vfs::VfsResult<usize> {
-        let span = span!(Level::INFO, "write");
-        let _guard = span.enter();
+    /// Keys of every key-value entry stored for `path`.
+    async fn kv_keys(&self, path: &str) -> Result<Vec<Bytes>, i32> {
+        self.prefixed_keys(&kv::kv_prefix(path)).await
+    }
 
-        // Get or create file state
-        let file_state = {
-            let mut files = self.files.lock();
-            files
-                .entry(handle.path.clone())
-                .or_insert_with(FileState::new)
-                .clone()
+    /// Every stored key starting with `prefix`.
+    async fn prefixed_keys(&self, prefix: &str) -> Result<Vec<Bytes>, i32> {
+        let fail = |e: slatedb::SlateDBError| {
+            log::error!("error listing {prefix}: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
         };
-        let is_batch_write = file_state.batch_open.load(Ordering::Acquire);
-        log::debug!(
-            "write: path={}, offset={offset}, is_batch_write={is_batch_write}",
-            handle.path
-        );
-
-        // Check if we're in batch mode for this file
-        if is_batch_write {
-            let mut pending_writes = file_state.pending_writes.lock();
-            pending_writes.push(BatchWrite {
-                offset,
-                data: data.to_vec(),
-            });
-            span.record("pending_writes", pending_writes.len());
-            return Ok(data.len());
+        let mut iter = self.db.scan(prefix_range(prefix)).await.map_err(fail)?;
+        let mut keys = Vec::new();
+        while let Some(kv) = iter.next().await.map_err(fail)? {
+            keys.push(kv.key);
         }
+        Ok(keys)
+    }
 
-        // Write over the server
-        self.block_on(async move {
-            let page_offset = (offset / PAGE_SIZE) * PAGE_SIZE;
-            let page_key = format!("{}:page:{}", handle.path, page_offset);
-
-            // Get existing page data
-            let existing_page = self.get(&page_key).await?;
-
-            let mut page_data = if let Some(existing) = existing_page {
-                existing.to_vec()
-            } else {
-                Vec::new()
-            };
-
-            let offset_in_page = offset % PAGE_SIZE;
-
-            // Resize page if needed
-            if offset_in_page + data.len() > page_data.len() {
-                page_data.resize(offset_in_page + data.len(), 0);
+    /// Keep the cache in step with key-value puts just stored.
+    fn cache_kv(&self, path: &str, entries: &BTreeMap<String, Option<String>>) {
+        for (key, value) in entries {
+            let key = kv::kv_key(path, key);
+            match value {
+                Some(value) => self
+                    .cache
+                    .insert(key.as_bytes(), Bytes::copy_from_slice(value.as_bytes())),
+                None => self.cache.remove(key.as_bytes()),
             }
+        }
+    }
 
-            println!(
-                "write data at page {} offset {} length {}",
-                page_offset,
-                offset_in_page,
-                data.len()
-            );
-            page_data[offset_in_page..offset_in_page + data.len()].copy_from_slice(data);
-
-            self.put(&page_key, page_data).await
-        })?;
-        Ok(data.len())
+    /// Checkpoint the store and record it as snapshot `name` of `path`. Returns the
+    /// checkpoint id.
+    async fn create_snapshot(&self, path: &str, name: &str) -> Result<String, i32> {
+        let checkpoint = self
+            .db
+            .create_checkpoint(CheckpointScope::All, &CheckpointOptions::default())
+            .await
+            .map_err(|e| {
+                log::error!("error creating checkpoint for {path}@{name}: {e}");
+                sqlite_plugin::vars::SQLITE_IOERR
+            })?;
+        let id = checkpoint.id.to_string();
+        self.put(snapshot::snapshot_key(path, name), &id).await?;
+        log::info!("created snapshot {path}@{name} at checkpoint {id}");
+        Ok(id)
     }
 
-    #[instrument(level = "info", skip(self, data))]
-    fn read(
+    async fn open_snapshot(
         &self,
-        handle: &mut Self::Handle,
-        offset: usize,
-        data: &mut [u8],
-    ) -> vfs::VfsResult<usize> {
-        // Read from the server
-        self.block_on(async move {
-            // Calculate the page key using integer division
-            let page_offset = (offset / PAGE_SIZE) * PAGE_SIZE;
-            let page_key = format!("{}:page:{}", handle.path, page_offset);
-
-            let page_data = self.get(&page_key).await?;
-
-            if page_data.is_none() {
-                println!("read page not found, returning empty data");
-                return Ok::<usize, i32>(0);
+        path: &str,
+        name: &str,
+    ) -> Result<Option<snapshot::Snapshot>, i32> {
+        let Some(id) = self.get(snapshot::snapshot_key(path, name)).await? else {
+            return Ok(None);
+        };
+        let id = String::from_utf8_lossy(&id).into_owned();
+        let existing = self.snapshots.lock().get(&id).cloned();
+        let reader = match existing {
+            Some(reader) => reader,
+            None => {
+                let checkpoint = uuid::Uuid::parse_str(&id).map_err(|e| {
+                    log::error!("snapshot {path}@{name} has a bad checkpoint id {id}: {e}");
+                    sqlite_plugin::vars::SQLITE_CORRUPT
+                })?;
+                let reader = DbReader::open(
+                    SLATEDB_PATH,
+                    self.object_store.clone(),
+                    Some(checkpoint),
+                    DbReaderOptions::default(),
+                )
+                .await
+                .map_err(|e| {
+                    log::error!("error opening snapshot {path}@{name}: {e}");
+                    sqlite_plugin::vars::SQLITE_CANTOPEN
+                })?;
+                let reader = Arc::new(reader);
+                self.snapshots.lock().insert(id, reader.clone());
+                reader
             }
+        };
+        Ok(Some(snapshot::Snapshot {
+            base: path.to_string(),
+            reader,
+        }))
+    }
 
-            let page = page_data.unwrap();
-            let offset_in_page = offset % PAGE_SIZE;
+    /// Open a read-only handle on the database at `path`, see `point_in_time`.
+    fn open_read_only(
+        &self,
+        path: &str,
+        as_of: Option<std::time::SystemTime>,
+    ) -> Result<handle::GrpcVfsHandle, i32> {
+        let stored = self.store_path(path);
+        let (snapshot, pinned) = self.block_on(self.open_point_in_time(&stored, as_of))?;
+        let handle_id = self.ids.next_id();
+        let mut handle = handle::GrpcVfsHandle::new(stored.to_string(), true, handle_id);
+        handle.snapshot = Some(snapshot);
+        handle.pinned = Some(Arc::new(pinned));
+        handle.activity = Some(self.handles.register(handle_id, path, &handle.interrupt));
+        Ok(handle)
+    }
 
-            // Check if offset is beyond page size
-            if offset_in_page >= page.len() {
-                println!("read offset is beyond page size");
-                return Ok(0);
+    /// Checkpoint the store for a read-only handle on `path`, at its current state or as
+    /// it was at `as_of`, see `point_in_time`.
+    async fn open_point_in_time(
+        &self,
+        path: &str,
+        as_of: Option<std::time::SystemTime>,
+    ) -> Result<(snapshot::Snapshot, point_in_time::Pinned), i32> {
+        let (scope, source, as_of) = match as_of {
+            Some(as_of) => {
+                let (id, created) = self.checkpoint_as_of(as_of).await?;
+                (CheckpointScope::Durable, Some(id), created)
+            }
+            None => {
+                // a new database this process hasn't stored yet, see `bootstrap`
+                self.flush_fresh(path).await?;
+                (CheckpointScope::All, None, self.clock.system_now())
             }
+        };
+        let options = CheckpointOptions {
+            lifetime: Some(point_in_time::LIFETIME),
+            source,
+        };
+        let checkpoint = self
+            .db
+            .create_checkpoint(scope, &options)
+            .await
+            .map_err(|e| {
+                log::error!("error creating checkpoint to read {path} at: {e}");
+                sqlite_plugin::vars::SQLITE_CANTOPEN
+            })?;
+        let pinned = point_in_time::Pinned::new(checkpoint.id, as_of, self.clock.now());
+        let reader = match DbReader::open(
+            SLATEDB_PATH,
+            self.object_store.clone(),
+            Some(checkpoint.id),
+            DbReaderOptions::default(),
+        )
+        .await
+        {
+            Ok(reader) => reader,
+            Err(e) => {
+                log::error!("error opening {path} at checkpoint {}: {e}", checkpoint.id);
+                self.delete_checkpoint(checkpoint.id).await;
+                return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+            }
+        };
+        let snapshot = snapshot::Snapshot {
+            base: path.to_string(),
+            reader: Arc::new(reader),
+        };
+        if let Err(e) = self.check_point_in_time(path, &snapshot).await {
+            self.close_point_in_time(&snapshot, &pinned).await;
+            return Err(e);
+        }
+        log::debug!("reading {path} at checkpoint {}", checkpoint.id);
+        Ok((snapshot, pinned))
+    }
+
+    /// Check that `path` can be read at `snapshot`: it existed then, and it had the
+    /// block size the handle will read it with, that of the live file.
+    async fn check_point_in_time(
+        &self,
+        path: &str,
+        snapshot: &snapshot::Snapshot,
+    ) -> Result<(), i32> {
+        if snapshot.get(path.to_string()).await?.is_none() {
+            log::debug!("open: {path} doesn't exist at the time asked for");
+            return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+        }
+        let stored = snapshot.get(meta::meta_key(path)).await?;
+        let block_size = stored
+            .as_deref()
+            .and_then(meta::parse)
+            .map_or(meta::DEFAULT_BLOCK_SIZE, |meta| meta.block_size);
+        self.load_block_size(path).await?;
+        // a database deleted and made again since may have another
+        if self.block_size(path) != block_size {
+            log::error!("{path} has been made again with another block size since");
+            return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+        }
+        Ok(())
+    }
+
+    /// The newest checkpoint still retained created before `as_of`, and when it was.
+    async fn checkpoint_as_of(
+        &self,
+        as_of: std::time::SystemTime,
+    ) -> Result<(uuid::Uuid, std::time::SystemTime), i32> {
+        let checkpoints =
+            slatedb::admin::AdminBuilder::new(SLATEDB_PATH, self.object_store.clone())
+                .build()
+                .list_checkpoints()
+                .await
+                .map_err(|e| {
+                    log::error!("error listing checkpoints: {e}");
+                    sqlite_plugin::vars::SQLITE_IOERR_READ
+                })?;
+        // A checkpoint made from another, by an `as_of` handle say, reads the state the
+        // other does, so a state was current when the first checkpoint of it was made.
+        let now = self.clock.system_now();
+        let mut made: HashMap<u64, (std::time::SystemTime, uuid::Uuid)> = HashMap::new();
+        for checkpoint in checkpoints {
+            if checkpoint.expire_time.is_some_and(|expires| expires <= now) {
+                continue;
+            }
+            let first = made
+                .entry(checkpoint.manifest_id)
+                .or_insert((checkpoint.create_time, checkpoint.id));
+            *first = (*first).min((checkpoint.create_time, checkpoint.id));
+        }
+        made.into_iter()
+            // kept to the second, so one made in the second of `as_of` may be after it
+            .filter(|(_, (created, _))| *created + std::time::Duration::from_secs(1) <= as_of)
+            .max_by_key(|(manifest_id, _)| *manifest_id)
+            .map(|(_, (created, id))| (id, created))
+            .ok_or_else(|| {
+                log::error!("no checkpoint is retained from before {as_of:?}");
+                sqlite_plugin::vars::SQLITE_CANTOPEN
+            })
+    }
+
+    /// Move the read-only `handle` to the database as it was at `as_of`, dropping the
+    /// checkpoint it read at.
+    fn read_as_of(
+        &self,
+        handle: &mut handle::GrpcVfsHandle,
+        as_of: std::time::SystemTime,
+    ) -> Result<(), i32> {
+        let (snapshot, pinned) =
+            self.block_on(self.open_point_in_time(&handle.path, Some(as_of)))?;
+        if let (Some(old), Some(old_pinned)) = (&handle.snapshot, &handle.pinned) {
+            threading::block_on(&self.runtime, self.close_point_in_time(old, old_pinned));
+        }
+        handle.snapshot = Some(snapshot);
+        handle.pinned = Some(Arc::new(pinned));
+        Ok(())
+    }
+
+    /// Extend the checkpoint of a read-only handle still in use, see `point_in_time`.
+    async fn refresh_point_in_time(&self, pinned: &point_in_time::Pinned) {
+        if !pinned.take_due(self.clock.now()) {
+            return;
+        }
+        let admin =
+            slatedb::admin::AdminBuilder::new(SLATEDB_PATH, self.object_store.clone()).build();
+        if let Err(e) = admin
+            .refresh_checkpoint(pinned.checkpoint, Some(point_in_time::LIFETIME))
+            .await
+        {
+            log::error!("error extending checkpoint {}: {e}", pinned.checkpoint);
+        }
+    }
+
+    /// Drop the checkpoint of a read-only handle being closed.
+    async fn close_point_in_time(
+        &self,
+        snapshot: &snapshot::Snapshot,
+        pinned: &point_in_time::Pinned,
+    ) {
+        if let Err(e) = snapshot.reader.close().await {
+            log::warn!(
+                "error closing the reader at checkpoint {}: {e}",
+                pinned.checkpoint
+            );
+        }
+        self.delete_checkpoint(pinned.checkpoint).await;
+    }
+
+    async fn delete_checkpoint(&self, id: uuid::Uuid) {
+        let admin =
+            slatedb::admin::AdminBuilder::new(SLATEDB_PATH, self.object_store.clone()).build();
+        // left to expire if this fails
+        if let Err(e) = admin.delete_checkpoint(id).await {
+            log::warn!("error deleting checkpoint {id}: {e}");
+        }
+    }
+
+    /// The snapshots of `path`, newest checkpoint first. Snapshots whose checkpoint has
+    /// expired are left out.
+    async fn snapshot_names(&self, path: &str) -> Result<Vec<String>, i32> {
+        let fail = |e: &dyn std::fmt::Display| {
+            log::error!("error listing snapshots of {path}: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
+        };
+        let prefix = snapshot::snapshot_key(path, "");
+        let mut iter = self
+            .db
+            .scan(prefix_range(&prefix))
+            .await
+            .map_err(|e| fail(&e))?;
+        let mut snapshots = Vec::new();
+        while let Some(kv) = iter.next().await.map_err(|e| fail(&e))? {
+            let key = String::from_utf8_lossy(&kv.key);
+            let name = key[prefix.len()..].to_string();
+            snapshots.push((name, String::from_utf8_lossy(&kv.value).into_owned()));
+        }
+        if snapshots.is_empty() {
+            return Ok(Vec::new());
+        }
+        let checkpoints =
+            slatedb::admin::AdminBuilder::new(SLATEDB_PATH, self.object_store.clone())
+                .build()
+                .list_checkpoints()
+                .await
+                .map_err(|e| fail(&e))?;
+        let created: HashMap<String, std::time::SystemTime> = checkpoints
+            .into_iter()
+            .map(|checkpoint| (checkpoint.id.to_string(), checkpoint.create_time))
+            .collect();
+        let mut snapshots: Vec<_> = snapshots
+            .into_iter()
+            .filter_map(|(name, id)| Some((*created.get(&id)?, name)))
+            .collect();
+        snapshots.sort_by_key(|(created, _)| std::cmp::Reverse(*created));
+        Ok(snapshots.into_iter().map(|(_, name)| name).collect())
+    }
+
+    /// The size the SQLite header of `path` gives the database, when it has a valid one.
+    async fn header_size(&self, path: &str) -> Result<Option<usize>, i32> {
+        Ok(self
+            .get(format!("{path}:page:0"))
+            .await?
+            .and_then(|page| read_repair::header_size(&page)))
+    }
 
-            // Read as much data as available from this page, up to the requested length
-            let end_offset_in_page = std::cmp::min(offset_in_page + data.len(), page.len());
-            let d = page[offset_in_page..end_offset_in_page].to_vec();
+    /// Find page `page_offset` of `path`, missing from the store, in its snapshots if its
+    /// header says it should exist. See `read_repair`.
+    async fn repair_page(&self, path: &str, page_offset: usize) -> Result<Option<Bytes>, i32> {
+        if !self.config.read_repair
+            || page_offset == 0
+            || self
+                .header_size(path)
+                .await?
+                .is_none_or(|size| page_offset >= size)
+        {
+            return Ok(None);
+        }
+        for name in self.snapshot_names(path).await? {
+            let Some(snapshot) = self.open_snapshot(path, &name).await? else {
+                continue;
+            };
+            if let Some(page) = snapshot.get_page(page_offset).await? {
+                log::warn!(
+                    "page {page_offset} of {path} is missing, repaired from snapshot {name}"
+                );
+                self.stats.pages_repaired.fetch_add(1, Ordering::Relaxed);
+                let key = format!("{path}:page:{page_offset}");
+                self.cache.insert(key.as_bytes(), page.clone());
+                return Ok(Some(page));
+            }
+        }
+        log::error!("page {page_offset} of {path} is missing, and no snapshot has it");
+        self.stats
+            .page_repairs_failed
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
 
-            println!("read data length: {} from page {}", data.len(), page_offset);
+    /// Delete the file stored at `path`: its pages, key-value entries and markers.
+    async fn delete_stored(&self, path: &str) -> Result<(), i32> {
+        self.heatmap.remove(path);
+        self.recoveries.finished(path);
+        if self.file_state(path).fresh.lock().take().is_some() {
+            // never synced, so nothing was stored
+            return Ok(());
+        }
 
-            let len = data.len().min(d.len());
-            data[..len].copy_from_slice(&d[..len]);
-            Ok(len)
+        // The pages, the file marker and the cache manifest go in one batch, so a crash
+        // can't leave pages behind for a file that no longer exists
+        self.flush_fresh_journals().await?;
+        let mut batch = WriteBatch::new();
+        let page_keys = self.page_keys_from("delete", path, 0).await?;
+        for page_key in &page_keys {
+            batch.delete(page_key);
+        }
+        for kv_key in self.kv_keys(path).await? {
+            batch.delete(kv_key);
+        }
+        batch.delete(path);
+        batch.delete(cache_manifest::manifest_key(path));
+        batch.delete(size_limit::size_limit_key(path));
+        batch.delete(extent::legacy_key(path));
+        batch.delete(meta::meta_key(path));
+        if self.names.is_some() {
+            batch.delete(names::names_key(path));
+        }
+        self.mirrored(self.db_write(batch), |shadow| {
+            for page_key in &page_keys {
+                shadow.delete(page_key.as_bytes());
+            }
         })
+        .await?;
+        self.record_stored(path.as_bytes(), 0, 0);
+        self.cache.remove_prefix(format!("{path}:page:").as_bytes());
+        self.cache.remove_prefix(kv::kv_prefix(path).as_bytes());
+        self.cache.remove(path.as_bytes());
+        self.cache
+            .remove(cache_manifest::manifest_key(path).as_bytes());
+        self.cache
+            .remove(size_limit::size_limit_key(path).as_bytes());
+        self.cache.remove(extent::legacy_key(path).as_bytes());
+        self.cache.remove(meta::meta_key(path).as_bytes());
+        Ok(())
     }
 
-    #[instrument(level = "info", skip(self))]
-    fn close(&self, handle: Self::Handle) -> vfs::VfsResult<()> {
-        log::debug!("close: path={} handle_id={}", handle.path, handle.handle_id);
+    /// Store a fresh file's marker and pages in one batch, ending its bootstrap.
+    async fn flush_fresh(&self, path: &str) -> Result<(), i32> {
+        let file_state = self.file_state(path);
+        let Some(fresh) = file_state.fresh.lock().take() else {
+            return Ok(());
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(path, b"");
+        if let Some(name_entry) = &fresh.name_entry {
+            batch.put(names::names_key(path), name_entry);
+        }
+        let mut bytes = 0;
+        for (page_offset, page) in fresh.pages() {
+            batch.put(format!("{path}:page:{page_offset}"), page);
+            bytes += page.len();
+        }
+        let record = self.meta_record(path, fresh.size());
+        batch.put(meta::meta_key(path), &record);
+        let mirror = |shadow: &shadow::Shadow| {
+            for (page_offset, page) in fresh.pages() {
+                let page_key = format!("{path}:page:{page_offset}");
+                shadow.put(page_key.as_bytes(), Bytes::copy_from_slice(page));
+            }
+        };
+        if let Err(e) = self.mirrored(self.db_write(batch), mirror).await {
+            *file_state.fresh.lock() = Some(fresh);
+            return Err(e);
+        }
+        self.record_stored(path.as_bytes(), fresh.pages().count(), bytes);
+        self.cache.insert(path.as_bytes(), Bytes::new());
+        self.cache
+            .insert(meta::meta_key(path).as_bytes(), Bytes::from(record));
+        if let Some(absent) = &self.absent_journals {
+            absent.lock().remove(path);
+        }
+        for (page_offset, page) in fresh.pages() {
+            self.cache.insert(
+                format!("{path}:page:{page_offset}").as_bytes(),
+                Bytes::copy_from_slice(page),
+            );
+        }
+        Ok(())
+    }
+
+    /// Store every fresh journal that has been written to, super-journals first. Called
+    /// before a write to any other file reaches the store, see `bootstrap`.
+    async fn flush_fresh_journals(&self) -> Result<(), i32> {
+        let journals = self.fresh_journals.lock().clone();
+        for path in journals {
+            let written = self
+                .file_state(&path)
+                .fresh
+                .lock()
+                .as_ref()
+                .map(|fresh| fresh.size() > 0);
+            match written {
+                Some(false) => continue,
+                Some(true) => self.flush_fresh(&path).await?,
+                None => {}
+            }
+            self.fresh_journals.lock().retain(|p| *p != path);
+        }
+        Ok(())
+    }
+
+    /// The byte ranges of `path` held in the page cache, as `start-end` pairs (end
+    /// exclusive) separated by commas.
+    fn cached_ranges(&self, path: &str) -> String {
+        let prefix = format!("{path}:page:");
+        let mut ranges: Vec<(usize, usize)> = self
+            .cache
+            .lens_with_prefix(prefix.as_bytes())
+            .into_iter()
+            .filter_map(|(key, len)| {
+                let offset = std::str::from_utf8(&key[prefix.len()..])
+                    .ok()?
+                    .parse()
+                    .ok()?;
+                Some((offset, offset + len))
+            })
+            .collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+            .iter()
+            .map(|(start, end)| format!("{start}-{end}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Probe the object store and the cache directory, giving up on the store after
+    /// `deadline`.
+    async fn health(&self, deadline: std::time::Duration) -> health::Health {
+        let start = std::time::Instant::now();
+        let prefix = slatedb::object_store::path::Path::from(SLATEDB_PATH);
+        let store = match tokio::time::timeout(
+            deadline,
+            self.object_store.list_with_delimiter(Some(&prefix)),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(start.elapsed()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no response within {deadline:?}")),
+        };
+        let files = self.files.values();
+        let pending_bytes = files
+            .iter()
+            .map(|f| {
+                f.pending_writes.lock().bytes()
+                    + f.fresh
+                        .lock()
+                        .as_ref()
+                        .map_or(0, |fresh| fresh.bytes() as u64)
+            })
+            .sum();
+        health::Health {
+            store,
+            cache_dir: health::check_dir_writable(&self.spill_dir()),
+            pending_bytes,
+        }
+    }
+
+    /// The offset and length of every stored page of `path`, in one range scan rather
+    /// than a GET per page, so pages past a hole are found too. They come in key order,
+    /// not by offset.
+    async fn stored_pages(&self, operation: &str, path: &str) -> Result<Vec<(usize, usize)>, i32> {
+        let fail = |e: slatedb::SlateDBError| {
+            log::error!("error listing pages of {path}: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
+        };
+        let prefix = format!("{path}:page:");
+        let mut iter = self.db.scan(prefix_range(&prefix)).await.map_err(fail)?;
+        let mut pages = Vec::new();
+        while let Some(kv) = iter.next().await.map_err(fail)? {
+            let offset = std::str::from_utf8(&kv.key[prefix.len()..])
+                .ok()
+                .and_then(|offset| offset.parse().ok());
+            // another database's keys can share the prefix, e.g. `a.db:page:1:size_limit`
+            let Some(offset) = offset else {
+                continue;
+            };
+            pages.push((offset, kv.value.len()));
+            if pages.len().is_multiple_of(progress::CHUNK_PAGES) {
+                progress::report(operation, path, pages.len()).await?;
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Keys of the stored pages of `path` from `page_offset` on.
+    async fn page_keys_from(
+        &self,
+        operation: &str,
+        path: &str,
+        page_offset: usize,
+    ) -> Result<Vec<String>, i32> {
+        Ok(self
+            .stored_pages(operation, path)
+            .await?
+            .into_iter()
+            .filter(|&(offset, _)| offset >= page_offset)
+            .map(|(offset, _)| format!("{path}:page:{offset}"))
+            .collect())
+    }
 
-        // Remove handle from lock manager
-        self.lock_manager.remove_handle(&handle.path, handle.handle_id);
+    pub async fn db_write(&self, batch: WriteBatch) -> Result<(), i32> {
+        let span = span!(Level::INFO, "db_write");
+        let _guard = span.enter();
+        self.unflushed.record_batch();
+        self.db
+            .write_with_options(
+                batch,
+                &WriteOptions {
+                    await_durable: false,
+                },
+            )
+            .await
+            .map_err(|e| {
+                log::error!("error writing page: {e}");
+                sqlite_plugin::vars::SQLITE_IOERR_WRITE
+            })
+    }
 
-        // Clean up file state if needed (keep for batch writes)
-        // Note: We keep file states around for batch operations, lock manager handles its own cleanup
+    /// Count a write of `pages` pages and `bytes` bytes to `key`'s database, for the cost
+    /// estimate and the next explicit flush.
+    fn record_stored(&self, key: &[u8], pages: usize, bytes: usize) {
+        self.traffic.record_put(key, bytes);
+        self.unflushed.record_pages(pages, bytes);
+    }
 
-        // Flush traces on every close to ensure data is written
-        let guard = self._guard.lock();
-        if let Some(guard) = &*guard {
-            guard.flush();
+    /// Make everything written so far durable, see `flush`.
+    async fn flush(&self) -> Result<flush::Flushed, slatedb::SlateDBError> {
+        let mut flushed = self.unflushed.take();
+        let start = std::time::Instant::now();
+        if let Err(e) = self.db.flush().await {
+            self.unflushed.restore(&flushed);
+            return Err(e);
         }
+        flushed.elapsed = start.elapsed();
+        Ok(flushed)
+    }
+
+    /// Run `write`, then mirror the pages it stored with `mirror` when `SHADOW_WRITES`
+    /// is set, see `shadow`.
+    async fn mirrored<T>(
+        &self,
+        write: impl Future<Output = Result<T, i32>>,
+        mirror: impl FnOnce(&shadow::Shadow),
+    ) -> Result<T, i32> {
+        let Some(shadow) = &self.shadow else {
+            return write.await;
+        };
+        let _writing = shadow.writing().await;
+        let result = write.await?;
+        mirror(shadow);
+        Ok(result)
+    }
 
+    pub async fn get<K>(&self, key: K) -> Result<Option<Bytes>, i32>
+    where
+        K: AsRef<[u8]> + Send,
+    {
+        let span = span!(Level::INFO, "get");
+        let _guard = span.enter();
+        if let Some(cached) = self.cache.get(key.as_ref()) {
+            self.signals.record_hit(key.as_ref());
+            return Ok(Some(cached));
+        }
+        let start = std::time::Instant::now();
+        // a write landing while this reads wins over what it read, see `page_cache`
+        let generation = self.cache.generation(key.as_ref());
+        let value = self.db.get(key.as_ref()).await.map_err(|e| {
+            log::error!("error getting page: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
+        })?;
+        self.signals.record_miss(start.elapsed());
+        self.traffic
+            .record_get(key.as_ref(), value.as_ref().map_or(0, |v| v.len()));
+        if let Some(value) = &value {
+            self.cache.fill(key.as_ref(), value.clone(), generation);
+        }
+        Ok(value)
+    }
+
+    /// Read `len` bytes of a file starting at `offset`, zero padding anything past the end.
+    async fn read_range(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>, i32> {
+        let mut out = vec![0u8; len];
+        for (page_offset, offset_in_page, range) in page_spans(self.block_size(path), offset, len) {
+            if let Some(page) = self.get(format!("{path}:page:{page_offset}")).await? {
+                let end = page.len().min(offset_in_page + range.len());
+                if offset_in_page < end {
+                    out[range.start..range.start + end - offset_in_page]
+                        .copy_from_slice(&page[offset_in_page..end]);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Read the `bytes` aligned range of `path` around `page_offset` into the cache, for
+    /// `?scan=true` handles. Page keys don't sort by offset, so the range is read as
+    /// concurrent page reads rather than one scan of the store.
+    async fn fetch_scan_range(
+        &self,
+        path: &str,
+        page_offset: usize,
+        bytes: usize,
+    ) -> Result<(), i32> {
+        let block_size = self.block_size(path);
+        let bytes = bytes.max(block_size);
+        let start = page_offset - page_offset % bytes;
+        self.fetch_pages(
+            path,
+            (start..start + bytes).step_by(block_size),
+            SCAN_CONCURRENCY,
+        )
+        .await
+    }
+
+    /// Read the pages of `path` at `page_offsets` that aren't cached into the cache, as
+    /// up to `concurrency` concurrent page reads.
+    async fn fetch_pages(
+        &self,
+        path: &str,
+        page_offsets: impl Iterator<Item = usize>,
+        concurrency: usize,
+    ) -> Result<(), i32> {
+        let keys: Vec<String> = page_offsets
+            .map(|offset| format!("{path}:page:{offset}"))
+            .filter(|key| !self.cache.contains(key.as_bytes()))
+            .collect();
+        futures::stream::iter(keys)
+            .map(|key| async move { self.get(&key).await.map(|_| ()) })
+            .buffer_unordered(self.stats.throttle.limit(concurrency))
+            .try_collect::<Vec<()>>()
+            .await?;
         Ok(())
     }
 
+    /// Read the pages following `page_offset` into the cache in the background.
+    fn prefetch_after(&self, path: &str, page_offset: usize) {
+        let window = self
+            .stats
+            .throttle
+            .limit(self.signals.prefetch_window.load(Ordering::Relaxed));
+        if window == 0 {
+            return;
+        }
+        let vfs = self.clone();
+        let path = path.to_string();
+        let block_size = self.block_size(&path);
+        self.runtime.spawn(async move {
+            for i in 1..=window {
+                let key = format!("{path}:page:{}", page_offset + i * block_size);
+                if vfs.cache.contains(key.as_bytes()) {
+                    continue;
+                }
+                let generation = vfs.cache.generation(key.as_bytes());
+                match vfs.db.get(key.as_bytes()).await {
+                    Ok(Some(page)) => {
+                        vfs.traffic.record_get(key.as_bytes(), page.len());
+                        // a commit since the read has the newer page cached
+                        if vfs.cache.fill(key.as_bytes(), page, generation) {
+                            vfs.signals.record_prefetch(key.as_bytes());
+                        }
+                    }
+                    // past the end of the file
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::warn!("failed to prefetch {key}: {e}");
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn autotune_periodically(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            // the memory budget has the cache and prefetching until it recovers
+            if memory_budget::degraded() {
+                continue;
+            }
+            let state = autotune::CacheState {
+                bytes: self.cache.bytes(),
+                max_bytes: self.cache.max_bytes(),
+            };
+            let max_bytes = autotune::tick(&self.signals, state, &self.config.autotune_bounds);
+            if max_bytes != self.cache.max_bytes() {
+                self.cache.set_max_bytes(max_bytes);
+            }
+        }
+    }
+
+    /// Bytes held by the cache and by buffered writes, or allocated by the extension
+    /// with the `memory-accounting` feature.
+    fn memory_used(&self) -> u64 {
+        if let Some(allocated) = memory_budget::allocated() {
+            return allocated;
+        }
+        let buffered: u64 = self
+            .files
+            .values()
+            .iter()
+            .map(|f| {
+                let pending = f.pending_writes.lock();
+                // spilled writes are on disk
+                let pending = pending.bytes() - pending.spilled_bytes();
+                pending
+                    + f.fresh
+                        .lock()
+                        .as_ref()
+                        .map_or(0, |fresh| fresh.bytes() as u64)
+            })
+            .sum();
+        self.cache.bytes() + buffered
+    }
+
+    async fn enforce_memory_budget_periodically(self) {
+        let mut ticker = tokio::time::interval(memory_budget::CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match self.stats.memory.check(self.memory_used()) {
+                Some(memory_budget::Change::Degrade(excess)) => {
+                    let max_bytes = self
+                        .cache
+                        .bytes()
+                        .saturating_sub(excess)
+                        .max(memory_budget::MIN_CACHE_BYTES);
+                    if max_bytes < self.cache.max_bytes() {
+                        self.cache.set_max_bytes(max_bytes);
+                    }
+                    self.signals.prefetch_window.store(0, Ordering::Relaxed);
+                }
+                Some(memory_budget::Change::Recover) => {
+                    let configured = self
+                        .config
+                        .max_cache_bytes
+                        .unwrap_or(page_cache::DEFAULT_MAX_CACHE_BYTES);
+                    self.cache.set_max_bytes(
+                        configured
+                            .min(self.stats.memory.low_water())
+                            .max(memory_budget::MIN_CACHE_BYTES),
+                    );
+                    self.signals
+                        .prefetch_window
+                        .store(self.config.prefetch_pages, Ordering::Relaxed);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Re-fetch a sample of cached pages from the store and evict any whose cached copy
+    /// differs. Returns the number of mismatches.
+    ///
+    /// A page written between the two reads looks like a mismatch too; evicting it is
+    /// harmless, the next read fetches it again.
+    async fn verify_cache(&self) -> Result<usize, i32> {
+        let mut mismatches = 0;
+        for (key, cached) in self.cache.sample(self.config.cache_verify_sample_pages) {
+            let stored = self.db.get(&key).await.map_err(|e| {
+                log::error!("error re-fetching page for cache verification: {e}");
+                sqlite_plugin::vars::SQLITE_IOERR_READ
+            })?;
+            self.traffic
+                .record_get(&key, stored.as_ref().map_or(0, |v| v.len()));
+            self.stats
+                .cache_verified_pages
+                .fetch_add(1, Ordering::Relaxed);
+            let matches = stored.is_some_and(|stored| {
+                xxhash_rust::xxh3::xxh3_64(&stored) == xxhash_rust::xxh3::xxh3_64(&cached)
+            });
+            if !matches {
+                log::warn!(
+                    "cached copy of {} differs from the store, evicting",
+                    String::from_utf8_lossy(&key)
+                );
+                self.cache.remove(&key);
+                self.stats
+                    .cache_verify_mismatches
+                    .fetch_add(1, Ordering::Relaxed);
+                mismatches += 1;
+            }
+        }
+        Ok(mismatches)
+    }
+
+    async fn verify_cache_periodically(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.verify_cache().await {
+                log::warn!("cache verification failed: {e}");
+            }
+        }
+    }
+
+    /// Compare every page mirrored by `SHADOW_WRITES` with the store, see `shadow`.
+    async fn verify_shadow(&self, shadow: &shadow::Shadow) -> Result<shadow::Report, i32> {
+        let report = shadow
+            .verify(|key| async move {
+                self.db.get(&key).await.map_err(|e| {
+                    log::error!(
+                        "error reading {} for shadow verification: {e}",
+                        String::from_utf8_lossy(&key)
+                    );
+                    sqlite_plugin::vars::SQLITE_IOERR_READ
+                })
+            })
+            .await?;
+        self.stats
+            .shadow_mismatches
+            .fetch_add(report.mismatched.len() as u64, Ordering::Relaxed);
+        Ok(report)
+    }
+
+    async fn verify_shadow_periodically(self, interval: std::time::Duration) {
+        let Some(shadow) = self.shadow.clone() else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.verify_shadow(&shadow).await {
+                log::warn!("shadow verification failed: {e}");
+            }
+        }
+    }
+
+    /// Write the counters to a new file in `dir`, keeping the newest `STATS_DUMP_KEEP`
+    /// there, see `stats`.
+    fn dump_stats(&self, dir: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+        self.stats.dump(
+            dir,
+            self.config.stats_dump_format,
+            self.config.stats_dump_keep,
+            self.clock.system_now(),
+        )
+    }
+
+    async fn dump_stats_periodically(self, interval: std::time::Duration) {
+        let dir = self.config.state_dir.join("stats");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.dump_stats(&dir) {
+                log::warn!("failed to dump stats to {}: {e}", dir.display());
+            }
+        }
+    }
+
+    /// Time an operation on `path` while the result is held, when `STALL_THRESHOLD_MS`
+    /// is set.
+    fn timed(&self, op: &'static str, path: &str) -> Option<Timed<'_>> {
+        let stalls = self.stalls.as_deref()?;
+        Some(Timed {
+            vfs: self,
+            stalls,
+            op,
+            path: path.to_string(),
+            start: self.clock.now(),
+        })
+    }
+
+    /// Count and log a stall, and write a bundle for it unless one was just written.
+    fn stalled(&self, stalls: &stall::Stalls, timing: &stall::Timing) {
+        self.stats.stalls.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "{} of {} took {:?}, over the stall threshold of {:?}",
+            timing.op,
+            timing.path,
+            timing.elapsed,
+            stalls.threshold
+        );
+        if !stalls.take_bundle_due(self.clock.now()) {
+            return;
+        }
+        // the span tree of the stall is in the chrome trace, see `diagnostics`
+        let trace_file = match &*self._guard.lock() {
+            Some(guard) => {
+                guard.flush();
+                Some(self.config.state_dir.join("s3qlite_trace.cpuprofile"))
+            }
+            None => None,
+        };
+        let span = tracing::Span::current();
+        let bundle = format!(
+            "{{\"stall\":{},\"threshold_ms\":{},\"span\":{},\"trace_id\":{},\"trace_file\":{},\"recent\":{},\"handles\":{},\"stats\":{}}}\n",
+            timing.to_json(),
+            stalls.threshold.as_millis(),
+            span.metadata()
+                .map_or("null".to_string(), |span| health::json_string(span.name())),
+            trace_context::current().map_or("null".to_string(), |id| health::json_string(&id)),
+            trace_file.map_or("null".to_string(), |path| {
+                health::json_string(&path.to_string_lossy())
+            }),
+            stalls.recent_json(),
+            self.open_handles_json(),
+            self.stats.to_json(),
+        );
+        let dir = self.config.state_dir.join("stalls");
+        match stall::write_bundle(&dir, &bundle, self.config.stall_bundles_keep, timing.at_ms) {
+            Ok(path) => log::warn!("wrote stall diagnostics to {}", path.display()),
+            Err(e) => log::warn!(
+                "failed to write stall diagnostics to {}: {e}",
+                dir.display()
+            ),
+        }
+    }
+
+    /// Every open handle as a JSON array, oldest first.
+    fn open_handles_json(&self) -> String {
+        let handles: Vec<String> = self
+            .handles
+            .list()
+            .iter()
+            .map(|h| {
+                format!(
+                    "{{\"handle_id\":{},\"path\":{},\"lock\":\"{:?}\",\"open_ms\":{},\"idle_ms\":{}}}",
+                    h.handle_id,
+                    health::json_string(&h.path),
+                    self.lock_manager
+                        .handle_lock_level(&self.store_path(&h.path), h.handle_id),
+                    h.open_for.as_millis(),
+                    h.idle_for.as_millis()
+                )
+            })
+            .collect();
+        format!("[{}]", handles.join(","))
+    }
+
+    async fn warn_overdue_handles_periodically(self, threshold: std::time::Duration) {
+        let mut ticker =
+            tokio::time::interval((threshold / 4).max(std::time::Duration::from_secs(1)));
+        loop {
+            ticker.tick().await;
+            for h in self.handles.take_overdue(threshold) {
+                log::warn!(
+                    "handle {} on {} has been open for {:?} and idle for {:?}, holding {:?}; \
+                     is a connection being leaked?",
+                    h.handle_id,
+                    h.path,
+                    h.open_for,
+                    h.idle_for,
+                    self.lock_manager
+                        .handle_lock_level(&self.store_path(&h.path), h.handle_id)
+                );
+            }
+        }
+    }
+
+    /// Re-read the stored generation of every watched database, see `generation`.
+    async fn refresh_generations_periodically(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            for (path, file_state) in self.files.entries() {
+                if !file_state.generation.watched() {
+                    continue;
+                }
+                if let Err(e) = self.refresh_generation(&path).await {
+                    log::warn!("failed to refresh the generation of {path}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Publish the hot page list of every cached database on a fixed interval.
+    async fn publish_cache_manifests_periodically(self, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately, and there is nothing cached yet
+        ticker.tick().await;
+        // the hash of the manifest last published for each path
+        let mut published: HashMap<String, u64> = HashMap::new();
+        loop {
+            ticker.tick().await;
+            let limit = (self.cache.max_bytes() as usize / PAGE_SIZE).max(1);
+            let keys = self.cache.hot_keys(limit);
+            for (path, offsets) in cache_manifest::group_page_keys(&keys) {
+                let manifest = cache_manifest::encode(&offsets);
+                // an unchanged hot set isn't written again
+                let hash = xxhash_rust::xxh3::xxh3_64(manifest.as_bytes());
+                if published.get(&path) == Some(&hash) {
+                    continue;
+                }
+                match self
+                    .put(cache_manifest::manifest_key(&path), manifest)
+                    .await
+                {
+                    Ok(()) => {
+                        self.stats
+                            .cache_manifests_published
+                            .fetch_add(1, Ordering::Relaxed);
+                        published.insert(path, hash);
+                    }
+                    Err(e) => log::warn!("failed to publish cache manifest for {path}: {e}"),
+                }
+            }
+        }
+    }
+
+    /// Prefetch the pages listed in the cache manifest for `path` without blocking the caller.
+    /// Only the first open of a path in this process warms, and only if nothing is cached yet.
+    fn warm_from_manifest(&self, path: &str) {
+        if !self.config.warm_from_manifest || !self.warmed.lock().insert(path.to_string()) {
+            return;
+        }
+        if self
+            .cache
+            .contains_prefix(format!("{path}:page:").as_bytes())
+        {
+            return;
+        }
+        let vfs = self.clone();
+        let path = path.to_string();
+        self.runtime.spawn(async move {
+            let manifest = match vfs.db.get(cache_manifest::manifest_key(&path)).await {
+                Ok(Some(manifest)) => manifest,
+                Ok(None) => return,
+                Err(e) => {
+                    log::warn!("failed to read cache manifest for {path}: {e}");
+                    return;
+                }
+            };
+            let offsets = cache_manifest::decode(&manifest);
+            log::debug!(
+                "warming {} pages of {path} from cache manifest",
+                offsets.len()
+            );
+            for (i, offset) in offsets.into_iter().enumerate() {
+                if let Err(e) = vfs.get(format!("{path}:page:{offset}")).await {
+                    log::warn!("failed to warm {path} page {offset}: {e}");
+                    return;
+                }
+                if (i + 1).is_multiple_of(progress::CHUNK_PAGES)
+                    && progress::report("warm", &path, i + 1).await.is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Read the pages of `path` into the cache from the first on, without blocking the
+    /// caller, until the file ends or the cache is full. Only the first open of a path in
+    /// this process preloads, and a full cache isn't preloaded into, so preloading never
+    /// evicts pages that were read.
+    fn preload_cache(&self, path: &str) {
+        if !self.config.preload_cache || !self.preloaded.lock().insert(path.to_string()) {
+            return;
+        }
+        let vfs = self.clone();
+        let path = path.to_string();
+        self.runtime.spawn(async move {
+            let size = match vfs.current_size(&path).await {
+                Ok((_, size)) => size,
+                Err(_) => {
+                    log::warn!("failed to preload {path}, its size couldn't be read");
+                    return;
+                }
+            };
+            let block_size = vfs.block_size(&path);
+            let concurrency = (vfs.config.preload_cache_concurrency as usize).max(1);
+            let chunk_bytes = progress::CHUNK_PAGES * block_size;
+            let mut pages_done = 0;
+            for start in (0..size).step_by(chunk_bytes) {
+                if vfs.cache.bytes() + chunk_bytes as u64 > vfs.cache.max_bytes()
+                    || memory_budget::degraded()
+                {
+                    break;
+                }
+                let end = (start + chunk_bytes).min(size);
+                if let Err(e) = vfs
+                    .fetch_pages(&path, (start..end).step_by(block_size), concurrency)
+                    .await
+                {
+                    log::warn!("failed to preload {path} at {start}: {e}");
+                    return;
+                }
+                pages_done += (end - start).div_ceil(block_size);
+                if progress::report("preload", &path, pages_done)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            log::debug!("preloaded {pages_done} pages of {path}");
+        });
+    }
+
+    /// Pin page 1, the schema pages and the root pages of `tables` in the page cache.
+    /// Returns the number of backing pages pinned for this file.
+    async fn pin_tables(&self, path: &str, tables: &[&str]) -> Result<usize, vfs::PragmaErr> {
+        let schema = schema::read_schema(|offset, len| self.read_range(path, offset, len))
+            .await
+            .map_err(|e| vfs::PragmaErr::Fail(e, None))?
+            .ok_or_else(|| {
+                vfs::PragmaErr::Fail(
+                    sqlite_plugin::vars::SQLITE_ERROR,
+                    Some("database has no schema yet".to_string()),
+                )
+            })?;
+
+        let mut pages = schema.schema_pages.clone();
+        for table in tables {
+            let roots = schema.root_pages_for_table(table);
+            if roots.is_empty() {
+                return Err(vfs::PragmaErr::Fail(
+                    sqlite_plugin::vars::SQLITE_ERROR,
+                    Some(format!("no such table: {table}")),
+                ));
+            }
+            pages.extend(roots);
+        }
+
+        for page_no in pages {
+            let start = (page_no as usize - 1) * schema.page_size;
+            // a block may hold several SQLite pages, or a page several blocks
+            let block_size = self.block_size(path);
+            let first = start / block_size * block_size;
+            for page_offset in (first..start + schema.page_size).step_by(block_size) {
+                let key = format!("{path}:page:{page_offset}");
+                if !self.cache.pin(key.as_bytes()) {
+                    // loading the page inserts it into the cache as pinned
+                    self.get(&key)
+                        .await
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                }
+            }
+        }
+        Ok(self.cache.pinned_count(format!("{path}:page:").as_bytes()))
+    }
+}
+
+impl vfs::Vfs for GrpcVfs {
+    type Handle = handle::GrpcVfsHandle;
+
+    fn register_logger(&self, logger: sqlite_plugin::logger::SqliteLogger) {
+        install_logger(logger);
+    }
+
+    /// A database named by URL, `s3://bucket/prefix/app` say, is the database `app` of
+    /// the configured object store, see `store::database_name`. HTTP(S) URLs are left to
+    /// `remote`.
+    fn canonical_path<'a>(
+        &self,
+        path: std::borrow::Cow<'a, str>,
+    ) -> vfs::VfsResult<std::borrow::Cow<'a, str>> {
+        if remote::is_url(&path) {
+            return Ok(path);
+        }
+        match store::database_name(&path, self.config.object_store_url.as_deref()) {
+            Ok(None) => Ok(path),
+            Ok(Some(name)) => Ok(name.to_string().into()),
+            Err(e) => {
+                log::error!("{e}");
+                Err(sqlite_plugin::vars::SQLITE_CANTOPEN)
+            }
+        }
+    }
+
+    #[instrument(level = "info", skip(self, path, opts))]
+    fn open(&self, path: Option<&str>, opts: flags::OpenOpts) -> vfs::VfsResult<Self::Handle> {
+        catch_panic("open", sqlite_plugin::vars::SQLITE_CANTOPEN, || {
+            let path = path.unwrap_or("");
+            log::debug!("open: path={path}, opts={opts:?}");
+            let mode = opts.mode();
+
+            // `orders.db@nightly` opens snapshot `nightly` of `orders.db`, always read-only.
+            // Without such a snapshot it's an ordinary name, like `user@example.db`.
+            let snapshot = match snapshot::split_path(path) {
+                Some((base, name)) => self
+                    .block_on(self.open_snapshot(&self.store_path(base), &self.store_path(name)))?,
+                None => None,
+            };
+            if let Some(snapshot) = snapshot {
+                let handle_id = self.ids.next_id();
+                let mut handle = handle::GrpcVfsHandle::new(path.to_string(), true, handle_id);
+                // read with the blocks the database had then
+                let stored = self.block_on(snapshot.get(meta::meta_key(&snapshot.base)))?;
+                let block_size = stored
+                    .as_deref()
+                    .and_then(meta::parse)
+                    .map_or(meta::DEFAULT_BLOCK_SIZE, |meta| meta.block_size);
+                self.file_state(path).block_size.set(block_size);
+                handle.snapshot = Some(snapshot);
+                handle.activity = Some(self.handles.register(handle_id, path, &handle.interrupt));
+                return Ok(handle);
+            }
+
+            // a database published over HTTP(S), always read-only
+            if remote::is_url(path) {
+                let remote = self.block_on(remote::RemoteFile::open(path, self.cache.clone()))?;
+                let handle_id = self.ids.next_id();
+                let mut handle = handle::GrpcVfsHandle::new(path.to_string(), true, handle_id);
+                handle.remote = Some(remote);
+                handle.activity = Some(self.handles.register(handle_id, path, &handle.interrupt));
+                return Ok(handle);
+            }
+
+            if opts.kind() == flags::OpenKind::Wal {
+                log::error!("WAL is not supported: {path}");
+                return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+            }
+
+            // a database opened read-only is read as it was at the open, see
+            // `point_in_time`. Hot journal recovery opens journals and super-journals
+            // read-only, which are read live.
+            if mode.is_readonly() && opts.kind() == flags::OpenKind::MainDb {
+                if !self.capabilities.point_in_time_reads {
+                    log::error!("read-only mode is not supported for this server");
+                    return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+                }
+                return self.open_read_only(path, None);
+            }
+
+            let stored = self.store_path(path);
+            if let Some(absent) = &self.absent_journals {
+                absent.lock().remove(stored.as_ref());
+            }
+            if !stored.is_empty() {
+                let file_state = self.file_state(&stored);
+                let fresh = file_state.fresh.lock().is_some();
+                if !fresh && self.block_on(self.get(stored.as_ref()))?.is_none() {
+                    if !mode.may_create() {
+                        log::debug!("open: {path} doesn't exist and CREATE wasn't requested");
+                        return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+                    }
+                    // nothing is stored until the first sync, marker included, see
+                    // `bootstrap`
+                    let name_entry = match &self.names {
+                        Some(names) if opts.kind() == flags::OpenKind::MainDb => {
+                            Some(names.seal(path).map_err(|e| {
+                                log::error!("failed to record the name of {path}: {e}");
+                                sqlite_plugin::vars::SQLITE_CANTOPEN
+                            })?)
+                        }
+                        _ => None,
+                    };
+                    file_state
+                        .fresh
+                        .lock()
+                        .get_or_insert_with(|| {
+                            let block_size = self
+                                .database_config(&stored)
+                                .and_then(|database| database.block_size)
+                                .unwrap_or(self.config.block_size);
+                            file_state.block_size.set(block_size);
+                            bootstrap::FreshFile::new(block_size)
+                        })
+                        .name_entry = name_entry;
+                    let mut journals = self.fresh_journals.lock();
+                    match opts.kind() {
+                        flags::OpenKind::SuperJournal => journals.insert(0, stored.to_string()),
+                        flags::OpenKind::MainJournal => journals.push(stored.to_string()),
+                        _ => {}
+                    }
+                    if opts.kind() == flags::OpenKind::MainDb {
+                        // a new database has no limit of its own yet
+                        let limit = Some(self.max_db_bytes(&stored)).filter(|&limit| limit > 0);
+                        file_state.size_limit.set(limit);
+                    }
+                } else {
+                    self.warm_from_manifest(&stored);
+                    // an existing journal is only opened without CREATE to recover from it
+                    if opts.kind() == flags::OpenKind::MainJournal {
+                        match path.strip_suffix("-journal") {
+                            Some(database) if !mode.may_create() => self.recoveries.started(
+                                &stored,
+                                database,
+                                &self.store_path(database),
+                            ),
+                            _ => self.recoveries.finished(&stored),
+                        }
+                    }
+                    self.block_on(self.load_block_size(&stored))?;
+                    if opts.kind() == flags::OpenKind::MainDb {
+                        self.block_on(self.load_size_limit(&stored))?;
+                        self.block_on(self.load_readonly(&stored))?;
+                        self.block_on(self.load_generation(&stored))?;
+                        self.block_on(self.pin_configured_tables(&stored))?;
+                        self.preload_cache(&stored);
+                    }
+                }
+            }
+
+            // a database marked read-only opens read-only whatever was asked for
+            let readonly = mode.is_readonly()
+                || (opts.kind() == flags::OpenKind::MainDb
+                    && self.file_state(&stored).readonly.load(Ordering::Acquire));
+            let handle_id = self.ids.next_id();
+            let mut handle = handle::GrpcVfsHandle::new(stored.to_string(), readonly, handle_id);
+            handle.main_db = opts.kind() == flags::OpenKind::MainDb;
+            handle.activity = Some(self.handles.register(handle_id, path, &handle.interrupt));
+            Ok(handle)
+        })
+    }
+
+    /// `file:orders.db?scan=true` opens a read-only handle for analytics that fetches
+    /// `SCAN_FETCH_BYTES` of pages per miss instead of one page at a time.
+    /// `?block_size=N` creates the file with blocks of `N` bytes, see `meta`.
+    /// `?as_of=2024-06-01T00:00:00Z` opens it read-only as it was then, see
+    /// `point_in_time`.
+    fn open_with_params(
+        &self,
+        path: Option<&str>,
+        opts: flags::OpenOpts,
+        params: &[(String, String)],
+    ) -> vfs::VfsResult<Self::Handle> {
+        if let Some((_, value)) = params.iter().find(|(key, _)| key == "as_of")
+            && opts.kind() == flags::OpenKind::MainDb
+        {
+            let Some(as_of) = point_in_time::parse_time(value) else {
+                log::error!("invalid as_of for {}: {value}", path.unwrap_or(""));
+                return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+            };
+            return catch_panic("open", sqlite_plugin::vars::SQLITE_CANTOPEN, || {
+                self.open_read_only(path.unwrap_or(""), Some(as_of))
+            });
+        }
+        let mut handle = vfs::Vfs::open(self, path, opts)?;
+        let scan = params
+            .iter()
+            .any(|(key, value)| key == "scan" && uri_boolean(value));
+        // only a file with no data yet takes a block size, one that has data keeps its
+        // own, see `meta`
+        let block_size = params.iter().find(|(key, _)| key == "block_size");
+        if let Some((_, value)) = block_size {
+            let Some(block_size) = value
+                .parse::<usize>()
+                .ok()
+                .filter(|&block_size| meta::valid_block_size(block_size))
+            else {
+                log::error!("invalid block_size for {}: {value}", handle.path);
+                return Err(sqlite_plugin::vars::SQLITE_CANTOPEN);
+            };
+            if let Err(vfs::PragmaErr::Fail(_, Some(msg))) =
+                self.set_block_size(&mut handle, block_size)
+            {
+                log::info!("{msg}, keeping {}", self.block_size(&handle.path));
+            }
+        }
+        if scan {
+            log::debug!("open: scan mode for {}", handle.path);
+            let bytes = self.config.scan_fetch_bytes;
+            handle.scan_fetch_bytes = Some(bytes);
+            if let Some(remote) = &mut handle.remote {
+                remote.scan_fetch_bytes = Some(bytes);
+            }
+        }
+        Ok(handle)
+    }
+
+    #[instrument(level = "info", skip(self))]
+    fn delete(&self, path: &str) -> vfs::VfsResult<()> {
+        catch_panic("delete", sqlite_plugin::vars::SQLITE_IOERR_DELETE, || {
+            log::debug!("delete: path={path}");
+            // decided on SQLite's name, a stored `PATH_KEY` name has no suffix
+            let journal = is_journal(path);
+            let path = self.store_path(path);
+            if let Some(absent) = &self.absent_journals
+                && journal
+            {
+                absent.lock().insert(path.to_string());
+            }
+            self.block_on(self.delete_stored(&path))
+        })
+    }
+
+    #[instrument(level = "info", skip(self, path, flags))]
+    fn access(&self, path: &str, flags: flags::AccessFlags) -> vfs::VfsResult<bool> {
+        catch_panic("access", sqlite_plugin::vars::SQLITE_IOERR_ACCESS, || {
+            let super_journal = is_super_journal(path);
+            let journal = is_journal(path);
+            let path = self.store_path(path);
+            let path = path.as_ref();
+            let fresh = self.file_state(path).fresh.lock().is_some();
+            // every journal this process deletes or finds missing stays missing until it
+            // creates it again
+            let absent = self.absent_journals.as_ref().filter(|_| journal);
+            if !fresh && absent.is_some_and(|absent| absent.lock().contains(path)) {
+                self.stats
+                    .absent_journal_hits
+                    .fetch_add(1, Ordering::Relaxed);
+                log::debug!("access: path={path}, flags={flags:?}, exists=false (known absent)");
+                return Ok(false);
+            }
+            // Whether a super-journal exists decides if the hot journals naming it are
+            // rolled back, so it's read from the store, never from a cached marker that
+            // may have outlived a delete.
+            let exists = fresh
+                || if super_journal {
+                    self.block_on(async {
+                        self.db.get(path).await.map_err(|e| {
+                            log::error!("error checking for super-journal {path}: {e}");
+                            sqlite_plugin::vars::SQLITE_IOERR_ACCESS
+                        })
+                    })?
+                    .is_some()
+                } else {
+                    self.block_on(async { self.get(path).await })?.is_some()
+                };
+            if let Some(absent) = absent
+                && !exists
+            {
+                absent.lock().insert(path.to_string());
+            }
+            log::debug!("access: path={path}, flags={flags:?}, exists={exists}");
+            Ok(exists)
+        })
+    }
+
+    #[instrument(level = "info", skip(self, handle), fields(trace_id = handle.trace_id.as_deref()))]
+    fn file_size(&self, handle: &mut Self::Handle) -> vfs::VfsResult<usize> {
+        catch_panic("file_size", sqlite_plugin::vars::SQLITE_IOERR_FSTAT, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            if let Some(remote) = &handle.remote {
+                return Ok(remote.size);
+            }
+            if let Some(fresh) = &*self.file_state(&handle.path).fresh.lock() {
+                return Ok(fresh.size());
+            }
+            let Some(snapshot) = &handle.snapshot else {
+                return self.block_on(async {
+                    let (_, size) = self.current_size(&handle.path).await?;
+                    Ok(size)
+                });
+            };
+            self.block_on(async {
+                let stored = snapshot.get(meta::meta_key(&snapshot.base)).await?;
+                if let Some(meta) = stored.as_deref().and_then(meta::parse) {
+                    return Ok(meta.len);
+                }
+                let mut max_size = 0usize;
+                let mut page_offset = 0;
+                while let Some(page) = snapshot.get_page(page_offset).await? {
+                    max_size = page_offset + page.len();
+                    page_offset += meta::DEFAULT_BLOCK_SIZE;
+                }
+                // the file may go on past a gap, see `extent`
+                Ok(max_size.max(self.handle_extent(handle).await?))
+            })
+        })
+    }
+
+    #[instrument(level = "info", skip(self, handle, size), fields(trace_id = handle.trace_id.as_deref()))]
+    fn truncate(&self, handle: &mut Self::Handle, size: usize) -> vfs::VfsResult<()> {
+        catch_panic(
+            "truncate",
+            sqlite_plugin::vars::SQLITE_IOERR_TRUNCATE,
+            || {
+                let _trace = trace_context::enter(handle.trace_id.as_deref());
+                if handle.immutable() {
+                    return Err(sqlite_plugin::vars::SQLITE_READONLY);
+                }
+                let file_state = self.file_state(&handle.path);
+                if file_state.readonly.load(Ordering::Acquire) {
+                    return Err(sqlite_plugin::vars::SQLITE_READONLY);
+                }
+                file_state.generation.mark_dirty();
+                if size == 0 {
+                    // how TRUNCATE journal mode ends a journal
+                    self.recoveries.finished(&handle.path);
+                }
+                if !file_state.size_limit.allows(size) {
+                    log::warn!("truncate of {} would pass its size limit", handle.path);
+                    self.stats.size_limit_full.fetch_add(1, Ordering::Relaxed);
+                    return Err(sqlite_plugin::vars::SQLITE_FULL);
+                }
+                if let Some(fresh) = file_state.fresh.lock().as_mut() {
+                    fresh.truncate(size);
+                    return Ok(());
+                }
+                let path = handle.path.as_str();
+                let interrupt = handle.interrupt.guard();
+                self.block_on(async {
+                    self.flush_fresh_journals().await?;
+                    // Growing the file only stores its new size, see `extent`
+                    let (stored, current) = interrupt.run(self.current_size(path)).await?;
+                    if size > current {
+                        return self.store_size(path, size).await;
+                    }
+
+                    // Calculate which page contains the truncation point
+                    let block_size = file_state.block_size.get();
+                    let truncate_page_offset = (size / block_size) * block_size;
+                    let truncate_offset_in_page = size % block_size;
+
+                    // The shortened page, the dropped pages and the new size go in one
+                    // batch, so a crash can't leave a half truncated file
+                    let mut batch = WriteBatch::new();
+                    let mut removed = Vec::new();
+                    let mut shortened = None;
+                    let resized = stored != Some(size);
+                    let record = self.meta_record(path, size);
+                    batch.put(meta::meta_key(path), &record);
+
+                    let page_key = format!("{path}:page:{truncate_page_offset}");
+                    if let Some(page) = interrupt.run(self.get(&page_key)).await? {
+                        if truncate_offset_in_page == 0 {
+                            batch.delete(&page_key);
+                            removed.push(page_key);
+                        } else if truncate_offset_in_page < page.len() {
+                            let page = page.slice(..truncate_offset_in_page);
+                            batch.put(&page_key, &page);
+                            shortened = Some((page_key, page));
+                        }
+                    }
+
+                    // Delete all pages beyond the truncation point
+                    let dropped = interrupt
+                        .run(self.page_keys_from(
+                            "truncate",
+                            path,
+                            truncate_page_offset + block_size,
+                        ))
+                        .await?;
+                    for page_key in dropped {
+                        batch.delete(&page_key);
+                        removed.push(page_key);
+                    }
+
+                    if removed.is_empty() && shortened.is_none() && !resized {
+                        return Ok(());
+                    }
+                    interrupt.check()?;
+                    self.mirrored(self.db_write(batch), |shadow| {
+                        for page_key in &removed {
+                            shadow.delete(page_key.as_bytes());
+                        }
+                        if let Some((page_key, page)) = &shortened {
+                            shadow.put(page_key.as_bytes(), page.clone());
+                        }
+                    })
+                    .await?;
+                    self.record_stored(
+                        path.as_bytes(),
+                        usize::from(shortened.is_some()),
+                        shortened.as_ref().map_or(0, |(_, page)| page.len()),
+                    );
+                    for page_key in removed {
+                        self.cache.remove(page_key.as_bytes());
+                    }
+                    if let Some((page_key, page)) = shortened {
+                        self.cache.insert(page_key.as_bytes(), page);
+                    }
+                    self.cache
+                        .insert(meta::meta_key(path).as_bytes(), Bytes::from(record));
+                    Ok::<(), i32>(())
+                })?;
+
+                Ok(())
+            },
+        )
+    }
+
+    fn write(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &[u8],
+    ) -> vfs::VfsResult<usize> {
+        catch_panic("write", sqlite_plugin::vars::SQLITE_IOERR_WRITE, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            let span = span!(Level::INFO, "write", trace_id = handle.trace_id.as_deref());
+            let _guard = span.enter();
+            let _timed = self.timed("write", &handle.path);
+            if handle.immutable() {
+                return Err(sqlite_plugin::vars::SQLITE_READONLY);
+            }
+            if data.is_empty() {
+                // see `extent`
+                if self.config.zero_length_writes == extent::ZeroLengthWrites::Extend
+                    && offset > vfs::Vfs::file_size(self, handle)?
+                {
+                    vfs::Vfs::truncate(self, handle, offset)?;
+                }
+                return Ok(0);
+            }
+
+            // Get or create file state
+            let file_state = self.file_state(&handle.path);
+            if file_state.readonly.load(Ordering::Acquire) {
+                return Err(sqlite_plugin::vars::SQLITE_READONLY);
+            }
+            file_state.generation.mark_dirty();
+            if handle.main_db {
+                file_state.churn.record_write(offset, data);
+            }
+            let block_size = file_state.block_size.get();
+            if !file_state.size_limit.allows(offset + data.len()) {
+                log::warn!("write to {} would pass its size limit", handle.path);
+                self.stats.size_limit_full.fetch_add(1, Ordering::Relaxed);
+                return Err(sqlite_plugin::vars::SQLITE_FULL);
+            }
+            self.heatmap.record_write(&handle.path, offset, data.len());
+            self.recoveries.record_write(&handle.path);
+            let fresh_bytes = file_state.fresh.lock().as_mut().map(|fresh| {
+                fresh.write(offset, data);
+                fresh.bytes()
+            });
+            if let Some(fresh_bytes) = fresh_bytes {
+                // a new file that outgrows memory is stored early instead
+                if fresh_bytes > self.config.spill_threshold_bytes || memory_budget::degraded() {
+                    self.block_on(self.flush_fresh(&handle.path))?;
+                    self.stats
+                        .fresh_files_flushed_early
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                return Ok(data.len());
+            }
+            let is_batch_write = file_state.batch_open.load(Ordering::Acquire);
+            log::debug!(
+                "write: path={}, offset={offset}, is_batch_write={is_batch_write}",
+                handle.path
+            );
+
+            // Check if we're in batch mode for this file
+            if is_batch_write {
+                let mut pending_writes = file_state.pending_writes.lock();
+                let max_bytes = self.config.pending_writes_max_bytes;
+                if max_bytes > 0 && pending_writes.bytes() + data.len() as u64 > max_bytes {
+                    log::warn!(
+                        "transaction on {} passed {max_bytes} buffered bytes",
+                        handle.path
+                    );
+                    self.stats
+                        .pending_writes_full
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Err(sqlite_plugin::vars::SQLITE_FULL);
+                }
+                pending_writes.push(offset, data, block_size).map_err(|e| {
+                    log::error!("failed to buffer write for {}: {e}", handle.path);
+                    sqlite_plugin::vars::SQLITE_IOERR_WRITE
+                })?;
+                span.record("pending_writes", pending_writes.len());
+                return Ok(data.len());
+            }
+
+            // Write over the server
+            self.block_on(async move {
+                self.flush_fresh_journals().await?;
+                let end = offset + data.len();
+                // The pages a write spans and its length go in one batch, so
+                // a crash can't leave half of a write that crosses pages behind
+                let mut batch = WriteBatch::new();
+                let mut pages = Vec::new();
+                for (page_offset, offset_in_page, range) in
+                    page_spans(block_size, offset, data.len())
+                {
+                    let data = &data[range];
+                    let page_key = format!("{}:page:{}", handle.path, page_offset);
+
+                    // Get existing page data
+                    let existing_page = self.get(&page_key).await?;
+
+                    // SQLite rewrites pages unchanged, e.g. after a rollback. Skip the PUT.
+                    if let Some(existing) = &existing_page
+                        && existing.get(offset_in_page..offset_in_page + data.len()) == Some(data)
+                    {
+                        log::debug!("write to page {page_offset} is unchanged, skipping");
+                        self.traffic.record_skipped_put(page_key.as_bytes());
+                        continue;
+                    }
+
+                    let mut page_data = if let Some(existing) = existing_page {
+                        existing.to_vec()
+                    } else {
+                        Vec::new()
+                    };
+
+                    // Resize page if needed
+                    if offset_in_page + data.len() > page_data.len() {
+                        page_data.resize(offset_in_page + data.len(), 0);
+                    }
+
+                    log::debug!(
+                        "write data at page {} offset {} length {}",
+                        page_offset,
+                        offset_in_page,
+                        data.len()
+                    );
+                    page_data[offset_in_page..offset_in_page + data.len()].copy_from_slice(data);
+                    batch.put(&page_key, &page_data);
+                    pages.push((page_key, Bytes::from(page_data)));
+                }
+                // its length covers a file that goes on past a gap, see `meta` and `extent`
+                let (stored, size) = self.current_size(&handle.path).await?;
+                let record = (end > size || stored.is_none())
+                    .then(|| self.meta_record(&handle.path, end.max(size)));
+                if let Some(record) = &record {
+                    batch.put(meta::meta_key(&handle.path), record);
+                }
+                if pages.is_empty() && record.is_none() {
+                    return Ok(());
+                }
+
+                self.mirrored(self.db_write(batch), |shadow| {
+                    for (page_key, page_data) in &pages {
+                        shadow.put(page_key.as_bytes(), page_data.clone());
+                    }
+                })
+                .await?;
+                self.record_stored(
+                    handle.path.as_bytes(),
+                    pages.len(),
+                    pages.iter().map(|(_, data)| data.len()).sum(),
+                );
+                for (page_key, page_data) in pages {
+                    self.cache.insert(page_key.as_bytes(), page_data);
+                }
+                if let Some(record) = record {
+                    self.cache
+                        .insert(meta::meta_key(&handle.path).as_bytes(), Bytes::from(record));
+                }
+                Ok(())
+            })?;
+            Ok(data.len())
+        })
+    }
+
+    #[instrument(level = "info", skip(self, data))]
+    fn read(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        data: &mut [u8],
+    ) -> vfs::VfsResult<usize> {
+        catch_panic("read", sqlite_plugin::vars::SQLITE_IOERR_READ, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            self.heatmap.record_read(&handle.path, offset, data.len());
+            let interrupt = handle.interrupt.guard();
+            if let Some(remote) = &handle.remote {
+                return self.block_on(interrupt.run(remote.read(offset, data)));
+            }
+            // Read from the server, page by page since a read may span pages
+            let block_size = self.block_size(&handle.path);
+            self.block_on(interrupt.run(async move {
+                // the pages of a read larger than one, e.g. with a 64KiB page size, are
+                // fetched together rather than one round trip after another
+                if offset % block_size + data.len() > block_size
+                    && handle.snapshot.is_none()
+                    && handle.scan_fetch_bytes.is_none()
+                    && self.file_state(&handle.path).fresh.lock().is_none()
+                {
+                    self.fetch_pages(
+                        &handle.path,
+                        page_spans(block_size, offset, data.len())
+                            .map(|(page_offset, _, _)| page_offset),
+                        SCAN_CONCURRENCY,
+                    )
+                    .await?;
+                }
+                let mut read = 0;
+                let mut extent = None;
+                for (page_offset, offset_in_page, range) in
+                    page_spans(block_size, offset, data.len())
+                {
+                    let fresh = self
+                        .file_state(&handle.path)
+                        .fresh
+                        .lock()
+                        .as_ref()
+                        .map(|f| f.page(page_offset));
+                    let page_data = match (&handle.snapshot, fresh) {
+                        (Some(snapshot), _) => snapshot.get_page(page_offset).await?,
+                        (None, Some(page)) => page,
+                        (None, None) => {
+                            let page_key = format!("{}:page:{}", handle.path, page_offset);
+                            let cached = self.cache.contains(page_key.as_bytes());
+                            if !cached && let Some(bytes) = handle.scan_fetch_bytes {
+                                self.fetch_scan_range(&handle.path, page_offset, bytes)
+                                    .await?;
+                            }
+                            let page_data = self.get(&page_key).await?;
+                            if !cached && handle.scan_fetch_bytes.is_none() {
+                                self.prefetch_after(&handle.path, page_offset);
+                            }
+                            match page_data {
+                                Some(page) => Some(page),
+                                None => self.repair_page(&handle.path, page_offset).await?,
+                            }
+                        }
+                    };
+
+                    // Read as much data as available from this page
+                    let page = page_data.unwrap_or_default();
+                    let mut n = page.len().saturating_sub(offset_in_page).min(range.len());
+                    if n > 0 {
+                        data[range.start..range.start + n]
+                            .copy_from_slice(&page[offset_in_page..offset_in_page + n]);
+                    }
+                    log::debug!("read {n} bytes from page {page_offset}");
+
+                    // A missing or short page is the end of the file, unless it's a gap
+                    // before the stored size, which reads as zeros, see `extent`
+                    if n < range.len() {
+                        let extent = match extent {
+                            Some(extent) => extent,
+                            None => *extent.insert(self.handle_extent(handle).await?),
+                        };
+                        let zeros = extent
+                            .saturating_sub(page_offset + offset_in_page + n)
+                            .min(range.len() - n);
+                        data[range.start + n..range.start + n + zeros].fill(0);
+                        n += zeros;
+                    }
+                    read += n;
+                    if n < range.len() {
+                        log::debug!("read reached the end of the file in page {page_offset}");
+                        break;
+                    }
+                }
+                Ok::<usize, i32>(read)
+            }))
+        })
+    }
+
+    #[instrument(level = "info", skip(self))]
+    fn close(&self, handle: Self::Handle) -> vfs::VfsResult<()> {
+        catch_panic("close", sqlite_plugin::vars::SQLITE_IOERR_CLOSE, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            log::debug!("close: path={} handle_id={}", handle.path, handle.handle_id);
+            if let (Some(snapshot), Some(pinned)) = (&handle.snapshot, &handle.pinned) {
+                threading::block_on(&self.runtime, self.close_point_in_time(snapshot, pinned));
+                self.handles.remove(handle.handle_id);
+                return Ok(());
+            }
+            // data written without a sync still survives the close
+            self.block_on(self.flush_fresh(&handle.path))?;
+
+            // Remove handle from lock manager
+            self.lock_manager
+                .remove_handle(&handle.path, handle.handle_id);
+            self.handles.remove(handle.handle_id);
+
+            // Clean up file state if needed (keep for batch writes)
+            // Note: We keep file states around for batch operations, lock manager handles its own cleanup
+            // Traces are flushed by the background task, see `flush_traces_periodically`
+
+            Ok(())
+        })
+    }
+
     fn device_characteristics(&self) -> i32 {
         log::debug!("device_characteristics");
         let mut characteristics: i32 = vfs::DEFAULT_DEVICE_CHARACTERISTICS;
@@ -476,147 +2932,770 @@ impl vfs::Vfs for GrpcVfs {
         characteristics
     }
 
+    /// With DETERMINISTIC_SEED set a busy wait moves the clock instead of sleeping, so
+    /// `busy_timeout` runs out at once, see `clock`.
+    fn sleep(&self, duration: std::time::Duration) -> Option<std::time::Duration> {
+        let manual_clock = self.manual_clock.as_ref()?;
+        manual_clock.advance(duration);
+        Some(duration)
+    }
+
+    fn current_time_ms(&self) -> Option<i64> {
+        Some(clock::julian_ms(self.clock.system_now()))
+    }
+
     fn pragma(
         &self,
         handle: &mut Self::Handle,
         pragma: vfs::Pragma<'_>,
     ) -> Result<Option<String>, vfs::PragmaErr> {
-        log::debug!("pragma: file2={:?}, pragma={:?}", handle.path, pragma);
-        if pragma.name == "is_memory_server" {
-            return Ok(Some("maybe?".to_string()));
-        }
-        Ok(None)
+        catch_panic(
+            "pragma",
+            vfs::PragmaErr::Fail(
+                sqlite_plugin::vars::SQLITE_IOERR,
+                Some("s3qlite pragma panicked".to_string()),
+            ),
+            || {
+                let _trace = trace_context::enter(handle.trace_id.as_deref());
+                log::debug!("pragma: file2={:?}, pragma={:?}", handle.path, pragma);
+                if pragma.name == "is_memory_server" {
+                    return Ok(Some("maybe?".to_string()));
+                }
+                // WAL needs shared memory, which this VFS doesn't provide. Refuse it
+                // here rather than letting SQLite record WAL mode in the header, which
+                // leaves a database nobody can open again.
+                if pragma.name.eq_ignore_ascii_case("journal_mode")
+                    && pragma
+                        .arg
+                        .is_some_and(|mode| mode.eq_ignore_ascii_case("wal"))
+                {
+                    return Err(vfs::PragmaErr::Fail(
+                        sqlite_plugin::vars::SQLITE_ERROR,
+                        Some(
+                            "WAL is not supported by s3qlite, use a rollback journal mode"
+                                .to_string(),
+                        ),
+                    ));
+                }
+                if pragma.name == "s3qlite_pin" {
+                    let tables: Vec<&str> = pragma
+                        .arg
+                        .unwrap_or("")
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .collect();
+                    let pinned =
+                        threading::block_on(&self.runtime, self.pin_tables(&handle.path, &tables))?;
+                    return Ok(Some(pinned.to_string()));
+                }
+                if pragma.name == "s3qlite_trace_id" {
+                    if let Some(trace_id) = pragma.arg {
+                        handle.trace_id = (!trace_id.is_empty()).then(|| trace_id.to_string());
+                    }
+                    return Ok(Some(handle.trace_id.clone().unwrap_or_default()));
+                }
+                if pragma.name == "s3qlite_admin_token" {
+                    let token = pragma
+                        .arg
+                        .ok_or_else(|| vfs::PragmaErr::required_arg(&pragma))?;
+                    handle.admin =
+                        readonly::is_admin_token(self.config.admin_token.as_deref(), token);
+                    if !handle.admin {
+                        log::warn!("wrong admin token given for {}", handle.path);
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_AUTH,
+                            Some("wrong admin token".to_string()),
+                        ));
+                    }
+                    return Ok(None);
+                }
+                if pragma.name == "s3qlite_set_readonly" {
+                    if let Some(arg) = pragma.arg {
+                        if !handle.admin {
+                            return Err(vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_AUTH,
+                                Some(
+                                    "give PRAGMA s3qlite_admin_token before changing the \
+                                     read-only mark"
+                                        .to_string(),
+                                ),
+                            ));
+                        }
+                        if handle.immutable() {
+                            return Err(vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some(format!("can't mark {} read-only", handle.path)),
+                            ));
+                        }
+                        self.block_on(self.set_readonly(&handle.path, uri_boolean(arg)))
+                            .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    }
+                    let readonly = self
+                        .file_state(&handle.path)
+                        .readonly
+                        .load(Ordering::Acquire);
+                    return Ok(Some(readonly.to_string()));
+                }
+                if pragma.name == "s3qlite_put" || pragma.name == "s3qlite_delete" {
+                    let arg = pragma
+                        .arg
+                        .ok_or_else(|| vfs::PragmaErr::required_arg(&pragma))?;
+                    let fail =
+                        |msg| vfs::PragmaErr::Fail(sqlite_plugin::vars::SQLITE_ERROR, Some(msg));
+                    let (key, value) = if pragma.name == "s3qlite_put" {
+                        let (key, value) = kv::parse_put(arg).map_err(fail)?;
+                        (key, Some(value.to_string()))
+                    } else {
+                        kv::check_key(arg).map_err(fail)?;
+                        (arg, None)
+                    };
+                    let file_state = self.file_state(&handle.path);
+                    if handle.immutable()
+                        || vfs::VfsHandle::readonly(handle)
+                        || file_state.readonly.load(Ordering::Acquire)
+                    {
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_READONLY,
+                            Some(format!("{} is read-only", handle.path)),
+                        ));
+                    }
+                    let level = self
+                        .lock_manager
+                        .handle_lock_level(&handle.path, handle.handle_id);
+                    if level >= flags::LockLevel::Reserved {
+                        file_state.kv.put(key, value.as_deref());
+                        return Ok(Some("staged".to_string()));
+                    }
+                    let entries = BTreeMap::from([(key.to_string(), value)]);
+                    self.block_on(self.store_kv(&handle.path, &entries))
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(Some("stored".to_string()));
+                }
+                if pragma.name == "s3qlite_get" {
+                    let key = pragma
+                        .arg
+                        .ok_or_else(|| vfs::PragmaErr::required_arg(&pragma))?;
+                    // a transaction reads its own staged puts
+                    let level = self
+                        .lock_manager
+                        .handle_lock_level(&handle.path, handle.handle_id);
+                    if level >= flags::LockLevel::Reserved
+                        && let Some(staged) = self.file_state(&handle.path).kv.get(key)
+                    {
+                        return Ok(staged);
+                    }
+                    let value = match &handle.snapshot {
+                        Some(snapshot) => {
+                            self.block_on(snapshot.get(kv::kv_key(&snapshot.base, key)))
+                        }
+                        None => self.block_on(self.get(kv::kv_key(&handle.path, key))),
+                    }
+                    .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(value.map(|value| String::from_utf8_lossy(&value).into_owned()));
+                }
+                if pragma.name == "s3qlite_as_of" {
+                    let Some(pinned) = &handle.pinned else {
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_ERROR,
+                            Some("s3qlite_as_of needs a read-only connection".to_string()),
+                        ));
+                    };
+                    if let Some(arg) = pragma.arg {
+                        if pinned.is_reading() {
+                            return Err(vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some("can't move a read transaction in progress".to_string()),
+                            ));
+                        }
+                        let as_of = point_in_time::parse_time(arg).ok_or_else(|| {
+                            vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some(format!("invalid time: {arg}")),
+                            )
+                        })?;
+                        self.read_as_of(handle, as_of).map_err(|e| {
+                            vfs::PragmaErr::Fail(
+                                e,
+                                Some(format!("can't read {} as of {arg}", handle.path)),
+                            )
+                        })?;
+                    }
+                    let pinned = handle
+                        .pinned
+                        .as_ref()
+                        .expect("read-only handles are pinned");
+                    return Ok(Some(pinned.to_json()));
+                }
+                if pragma.name == "s3qlite_snapshot" {
+                    let name = pragma
+                        .arg
+                        .filter(|name| !name.is_empty())
+                        .ok_or_else(|| vfs::PragmaErr::required_arg(&pragma))?;
+                    if handle.immutable() || name.contains(['@', '/']) {
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_ERROR,
+                            Some(format!("can't create snapshot {name} of {}", handle.path)),
+                        ));
+                    }
+                    let id = self
+                        .block_on(self.create_snapshot(&handle.path, &self.store_path(name)))
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(Some(id));
+                }
+                if pragma.name == "s3qlite_verify_cache" {
+                    let mismatches = self
+                        .block_on(self.verify_cache())
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(Some(mismatches.to_string()));
+                }
+                if pragma.name == "s3qlite_flush" {
+                    let flushed = self
+                        .block_on(async {
+                            self.flush_fresh_journals().await?;
+                            self.flush_fresh(&handle.path).await?;
+                            self.flush().await.map_err(|e| {
+                                log::error!("error flushing {}: {e}", handle.path);
+                                sqlite_plugin::vars::SQLITE_IOERR_FSYNC
+                            })
+                        })
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(Some(flushed.to_json()));
+                }
+                if pragma.name == "s3qlite_shadow_verify" {
+                    let Some(shadow) = &self.shadow else {
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_ERROR,
+                            Some("SHADOW_WRITES is not set".to_string()),
+                        ));
+                    };
+                    let report = self
+                        .block_on(self.verify_shadow(shadow))
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(Some(report.to_json()));
+                }
+                if pragma.name == "s3qlite_analyze_advice" {
+                    let churn = self.file_state(&handle.path).churn;
+                    if pragma.arg == Some("reset") {
+                        churn.reset();
+                    }
+                    let advice = self
+                        .analyze_advice(handle, &churn)
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    return Ok(Some(advice));
+                }
+                if pragma.name == "s3qlite_generation" {
+                    let generation = self.file_state(&handle.path).generation.get();
+                    return Ok(Some(generation.to_string()));
+                }
+                if pragma.name == "s3qlite_min_generation" {
+                    let arg = pragma
+                        .arg
+                        .ok_or_else(|| vfs::PragmaErr::required_arg(&pragma))?;
+                    let min = arg.parse::<u64>().map_err(|_| {
+                        vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_ERROR,
+                            Some(format!("invalid generation: {arg}")),
+                        )
+                    })?;
+                    let generation = self
+                        .block_on(self.wait_for_generation(&handle.path, min))
+                        .map_err(|e| {
+                            vfs::PragmaErr::Fail(
+                                e,
+                                Some(format!("database hasn't reached generation {min}")),
+                            )
+                        })?;
+                    return Ok(Some(generation.to_string()));
+                }
+                if pragma.name == "s3qlite_advance_clock" {
+                    let Some(manual_clock) = &self.manual_clock else {
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_ERROR,
+                            Some("the clock only moves by hand with DETERMINISTIC_SEED set".into()),
+                        ));
+                    };
+                    let Some(ms) = pragma.arg.and_then(|ms| ms.parse::<u64>().ok()) else {
+                        return Err(vfs::PragmaErr::Fail(
+                            sqlite_plugin::vars::SQLITE_ERROR,
+                            Some("s3qlite_advance_clock takes milliseconds".into()),
+                        ));
+                    };
+                    let elapsed = manual_clock.advance(std::time::Duration::from_millis(ms));
+                    return Ok(Some(elapsed.as_millis().to_string()));
+                }
+                if pragma.name == "s3qlite_recoveries" {
+                    return Ok(Some(self.recoveries.to_json()));
+                }
+                if pragma.name == "s3qlite_heatmap" {
+                    return Ok(Some(self.heatmap.to_csv(&handle.path)));
+                }
+                if pragma.name == "s3qlite_cached_ranges" {
+                    return Ok(Some(self.cached_ranges(&handle.path)));
+                }
+                if pragma.name == "s3qlite_health" {
+                    let deadline_ms = match pragma.arg {
+                        Some(ms) => ms.parse::<u64>().map_err(|_| {
+                            vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some(format!("invalid deadline in milliseconds: {ms}")),
+                            )
+                        })?,
+                        None => DEFAULT_HEALTH_DEADLINE_MS,
+                    };
+                    let health = threading::block_on(
+                        &self.runtime,
+                        self.health(std::time::Duration::from_millis(deadline_ms)),
+                    );
+                    return Ok(Some(health.to_json()));
+                }
+                if pragma.name == "s3qlite_block_size" {
+                    if let Some(arg) = pragma.arg {
+                        let block_size = arg.parse::<usize>().map_err(|_| {
+                            vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some(format!("invalid block size in bytes: {arg}")),
+                            )
+                        })?;
+                        self.set_block_size(handle, block_size)?;
+                    }
+                    return Ok(Some(self.block_size(&handle.path).to_string()));
+                }
+                if pragma.name == "s3qlite_ttl" {
+                    let now = ttl::unix_ms(self.clock.system_now());
+                    let expires_at = match pragma.arg {
+                        Some(arg) => {
+                            let ttl_secs = arg.parse::<u64>().map_err(|_| {
+                                vfs::PragmaErr::Fail(
+                                    sqlite_plugin::vars::SQLITE_ERROR,
+                                    Some(format!("invalid TTL in seconds: {arg}")),
+                                )
+                            })?;
+                            if handle.immutable() {
+                                return Err(vfs::PragmaErr::Fail(
+                                    sqlite_plugin::vars::SQLITE_READONLY,
+                                    Some(format!("{} is read-only", handle.path)),
+                                ));
+                            }
+                            self.block_on(self.set_ttl(&handle.path, ttl_secs))
+                                .map_err(|e| vfs::PragmaErr::Fail(e, None))?
+                        }
+                        None => self
+                            .block_on(self.get(ttl::expires_key(&handle.path)))
+                            .map_err(|e| vfs::PragmaErr::Fail(e, None))?
+                            .as_deref()
+                            .and_then(ttl::parse),
+                    };
+                    return Ok(Some(ttl::to_json(expires_at, now)));
+                }
+                if pragma.name == "s3qlite_expire" {
+                    let expired = self
+                        .block_on(self.expire_databases())
+                        .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    let expired: Vec<String> = expired
+                        .iter()
+                        .map(|path| health::json_string(path))
+                        .collect();
+                    return Ok(Some(format!("[{}]", expired.join(","))));
+                }
+                if pragma.name == "s3qlite_names" {
+                    return threading::block_on(&self.runtime, self.names_json()).map(Some);
+                }
+                if pragma.name == "s3qlite_open_handles" {
+                    return Ok(Some(self.open_handles_json()));
+                }
+                if pragma.name == "s3qlite_stats" {
+                    return Ok(Some(self.stats.to_json()));
+                }
+                if pragma.name == "s3qlite_features" {
+                    let marked = self
+                        .file_state(&handle.path)
+                        .readonly
+                        .load(Ordering::Acquire);
+                    let features = features::Features {
+                        batch_atomic: self.capabilities.atomic_batch && !handle.immutable(),
+                        snapshots: !handle.immutable(),
+                        path_encryption: self.names.is_some(),
+                        compression: Settings::default().compression_codec.is_some(),
+                        readonly: vfs::VfsHandle::readonly(handle) || marked,
+                    };
+                    return Ok(Some(features.to_json()));
+                }
+                if pragma.name == "s3qlite_capabilities" {
+                    return Ok(Some(capabilities_json(&self.config)));
+                }
+                if pragma.name == "s3qlite_cache_policy" {
+                    return Ok(Some(self.cache.policy_json()));
+                }
+                if pragma.name == "s3qlite_dump_stats" {
+                    let dir = self.config.state_dir.join("stats");
+                    return match self.dump_stats(&dir) {
+                        Ok(path) => Ok(Some(path.display().to_string())),
+                        Err(e) => {
+                            log::error!("failed to dump stats to {}: {e}", dir.display());
+                            Err(vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_IOERR,
+                                Some(e.to_string()),
+                            ))
+                        }
+                    };
+                }
+                if pragma.name == "s3qlite_size_limit" {
+                    let file_size = |vfs: &Self, handle: &mut Self::Handle| {
+                        vfs::Vfs::file_size(vfs, handle).map_err(|e| {
+                            vfs::PragmaErr::Fail(e, Some("failed to size database".to_string()))
+                        })
+                    };
+                    if let Some(arg) = pragma.arg {
+                        let limit = arg.parse::<u64>().map_err(|_| {
+                            vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some(format!("invalid size limit in bytes: {arg}")),
+                            )
+                        })?;
+                        let size = file_size(self, handle)?;
+                        if handle.immutable() || (limit > 0 && limit < size as u64) {
+                            return Err(vfs::PragmaErr::Fail(
+                                sqlite_plugin::vars::SQLITE_ERROR,
+                                Some(format!(
+                                    "can't limit {} to {limit} bytes, it is {size} bytes",
+                                    handle.path
+                                )),
+                            ));
+                        }
+                        self.block_on(self.set_size_limit(&handle.path, Some(limit)))
+                            .map_err(|e| vfs::PragmaErr::Fail(e, None))?;
+                    }
+                    let size = file_size(self, handle)?;
+                    let limit = self.file_state(&handle.path).size_limit.get();
+                    return Ok(Some(size_limit::to_json(limit, size)));
+                }
+                if pragma.name == "s3qlite_cost_estimate" {
+                    let stored = vfs::Vfs::file_size(self, handle).map_err(|e| {
+                        vfs::PragmaErr::Fail(e, Some("failed to size database".to_string()))
+                    })?;
+                    let estimate =
+                        self.traffic
+                            .estimate(&handle.path, stored, &self.config.pricing);
+                    return Ok(Some(estimate));
+                }
+                // Every other pragma is SQLite's own. Ok(None) would tell SQLite the
+                // VFS handled it with no result, so `PRAGMA user_version` and the like
+                // would silently return nothing
+                Err(vfs::PragmaErr::NotFound)
+            },
+        )
     }
 
-    #[instrument(level = "info", skip(self, handle, op, _p_arg))]
+    #[instrument(level = "info", skip(self, handle, op, p_arg), fields(trace_id = handle.trace_id.as_deref()))]
     fn file_control(
         &self,
         handle: &mut Self::Handle,
         op: c_int,
-        _p_arg: *mut c_void,
+        p_arg: *mut c_void,
     ) -> vfs::VfsResult<()> {
-        let op_name = match op {
-            sqlite_plugin::vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => "begin_atomic_write",
-            sqlite_plugin::vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => "commit_atomic_write",
-            sqlite_plugin::vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => "rollback_atomic_write",
-            _ => "",
-        };
-        let op_name = if op_name.is_empty() {
-            format!("{op:?}")
-        } else {
-            op_name.to_string()
-        };
-        log::debug!("file_control: file={:?}, op={op_name}", handle.path);
-        match op {
-            sqlite_plugin::vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => {
-                let file_state = {
-                    let mut files = self.files.lock();
-                    files
-                        .entry(handle.path.clone())
-                        .or_insert_with(FileState::new)
-                        .clone()
-                };
-                // Open the write batch
-                file_state.batch_open.store(true, Ordering::Release);
-                Ok(())
-            }
-            sqlite_plugin::vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => {
-                let file_state = {
-                    let mut files = self.files.lock();
-                    files
-                        .entry(handle.path.clone())
-                        .or_insert_with(FileState::new)
-                        .clone()
-                };
-
-                // Close the write batch
-                file_state.batch_open.store(false, Ordering::Release);
-
-                // Send the batch over the server
-                self.block_on(async {
-                    let batch = {
-                        let mut pending = file_state.pending_writes.lock();
-                        std::mem::take(&mut *pending)
-                    };
-                    if batch.is_empty() {
-                        log::debug!("write batch is empty, nothing to commit");
-                        return Ok(());
+        catch_panic("file_control", sqlite_plugin::vars::SQLITE_IOERR, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            let op_name = match op {
+                sqlite_plugin::vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => "begin_atomic_write",
+                sqlite_plugin::vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => "commit_atomic_write",
+                sqlite_plugin::vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => "rollback_atomic_write",
+                _ => "",
+            };
+            let op_name = if op_name.is_empty() {
+                format!("{op:?}")
+            } else {
+                op_name.to_string()
+            };
+            log::debug!("file_control: file={:?}, op={op_name}", handle.path);
+            match op {
+                sqlite_plugin::vars::SQLITE_FCNTL_SIZE_LIMIT => {
+                    // As in SQLite's memdb: a negative limit only queries, any other
+                    // sets it but never below the current size. Reports -1 for no limit.
+                    let limit = p_arg.cast::<i64>();
+                    if limit.is_null() || handle.immutable() {
+                        return Err(sqlite_plugin::vars::SQLITE_MISUSE);
                     }
-                    let mut page_writes: HashMap<usize, Vec<_>> = HashMap::new();
-                    for write in batch.iter() {
-                        let offset = write.offset;
-                        let page_offset = (offset / PAGE_SIZE) * PAGE_SIZE;
-
-                        page_writes
-                            .entry(page_offset)
-                            .or_default()
-                            .push((offset, write));
+                    let requested = unsafe { *limit };
+                    if requested >= 0 {
+                        let size = vfs::Vfs::file_size(self, handle)? as u64;
+                        let requested = (requested as u64).max(size);
+                        self.block_on(self.set_size_limit(&handle.path, Some(requested)))?;
                     }
-                    let db = self.db.clone();
+                    let current = self.file_state(&handle.path).size_limit.get();
+                    unsafe { *limit = current.map_or(-1, |limit| limit as i64) };
+                    Ok(())
+                }
+                sqlite_plugin::vars::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => {
+                    // a fresh file is stored first, so the batch can be rolled back
+                    self.block_on(self.flush_fresh(&handle.path))?;
+                    let file_state = self.file_state(&handle.path);
+                    // Open the write batch
+                    file_state.batch_open.store(true, Ordering::Release);
+                    Ok(())
+                }
+                sqlite_plugin::vars::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => {
+                    let _timed = self.timed("commit_atomic_write", &handle.path);
+                    let file_state = self.file_state(&handle.path);
 
-                    // Prepare WriteBatch for atomic operation
-                    let mut batch = WriteBatch::new();
+                    // Close the write batch
+                    file_state.batch_open.store(false, Ordering::Release);
 
-                    // Apply writes to each affected page
-                    for (page_offset, writes) in page_writes {
-                        let page_key = format!("{}:page:{}", handle.path, page_offset);
+                    // Send the batch over the server
+                    let interrupt = handle.interrupt.guard();
+                    self.block_on(async {
+                        let pending = file_state.pending_writes.lock().take();
+                        // key-value puts made in the transaction go in the same batch. They
+                        // stay staged until it's written: after a failed batch SQLite may
+                        // commit through the journal instead, see COMMIT_PHASETWO
+                        let staged = file_state.kv.entries();
+                        let block_size = file_state.block_size.get();
+                        if pending.is_empty() && staged.is_empty() {
+                            log::debug!("write batch is empty, nothing to commit");
+                            return Ok(());
+                        }
+                        log::debug!(
+                            "committing {} writes to {}, {} superseded writes dropped",
+                            pending.len(),
+                            handle.path,
+                            pending.superseded()
+                        );
+                        self.stats
+                            .spilled_bytes
+                            .fetch_add(pending.spilled_bytes(), Ordering::Relaxed);
 
-                        // Get existing page data
-                        let existing_page = db.get(&page_key).await.map_err(|e| {
-                            log::error!("error getting page during atomic write: {e}");
-                            sqlite_plugin::vars::SQLITE_IOERR_WRITE
-                        })?;
+                        let page_offsets: HashSet<usize> = pending
+                            .offsets()
+                            .map(|offset| (offset / block_size) * block_size)
+                            .collect();
+                        // writes smaller than a page can stay under the limit while the
+                        // pages they change don't, so check again before loading them
+                        let page_bytes = page_offsets.len().saturating_mul(block_size);
+                        let max_bytes = self.config.pending_writes_max_bytes;
+                        if max_bytes > 0 && page_bytes as u64 > max_bytes {
+                            log::warn!(
+                                "commit to {} changes {page_bytes} bytes of pages, past {max_bytes}",
+                                handle.path
+                            );
+                            self.stats
+                                .pending_writes_full
+                                .fetch_add(1, Ordering::Relaxed);
+                            return Err(sqlite_plugin::vars::SQLITE_FULL);
+                        }
 
-                        let mut page_data = if let Some(existing) = existing_page {
-                            existing.to_vec()
-                        } else {
-                            Vec::new()
+                        // Reserve this commit's share of the in-flight budget, what it
+                        // holds at its peak: the stored and patched image of every block
+                        // and the batch's copy, see `pending_writes::COMMIT_COPIES`. A
+                        // commit bigger than the whole budget takes all of it rather than
+                        // waiting forever, so it runs alone, over budget.
+                        let bytes = page_bytes
+                            .saturating_mul(pending_writes::COMMIT_COPIES)
+                            .min(self.config.commit_inflight_permits() as usize)
+                            as u32;
+                        let _budget = match self.commit_budget.try_acquire_many(bytes) {
+                            Ok(permit) => permit,
+                            Err(_) => {
+                                self.stats
+                                    .commit_budget_waits
+                                    .fetch_add(1, Ordering::Relaxed);
+                                self.commit_budget
+                                    .acquire_many(bytes)
+                                    .await
+                                    .map_err(|_| sqlite_plugin::vars::SQLITE_INTERNAL)?
+                            }
                         };
 
-                        // Apply all writes for this page
-                        for (offset, write) in writes {
-                            let offset_in_page = offset % PAGE_SIZE;
+                        // Load the current image of every affected page. These, and the
+                        // batch's copy of the changed ones, are all in memory until the
+                        // batch is written, see `pending_writes`.
+                        let path = handle.path.as_str();
+                        let mut page_images: HashMap<usize, (Option<Bytes>, Vec<u8>)> = interrupt
+                            .run(
+                                futures::stream::iter(page_offsets)
+                                    .map(|page_offset| async move {
+                                        let page_key = format!("{}:page:{}", path, page_offset);
+                                        let existing_page =
+                                            self.get(&page_key).await.map_err(|e| {
+                                                log::error!(
+                                                    "error getting page during atomic write: {e}"
+                                                );
+                                                sqlite_plugin::vars::SQLITE_IOERR_WRITE
+                                            })?;
+                                        let image = existing_page.as_deref().map(<[u8]>::to_vec);
+                                        Ok::<_, i32>((
+                                            page_offset,
+                                            (existing_page, image.unwrap_or_default()),
+                                        ))
+                                    })
+                                    .buffer_unordered(
+                                        self.stats
+                                            .throttle
+                                            .limit(self.config.commit_max_concurrency)
+                                            .max(1),
+                                    )
+                                    .try_collect(),
+                            )
+                            .await?;
 
+                        // Apply the writes in order, spilled ones are streamed back from disk
+                        let mut end = 0;
+                        pending.for_each(|offset, data| {
+                            end = end.max(offset + data.len());
+                            let page_offset = (offset / block_size) * block_size;
+                            let offset_in_page = offset % block_size;
                             log::debug!(
                                 "atomic_write_batch write page={} offset_in_page={} length={}",
                                 page_offset,
                                 offset_in_page,
-                                write.data.len(),
+                                data.len(),
                             );
+                            let (_, page_data) = page_images
+                                .get_mut(&page_offset)
+                                .ok_or(sqlite_plugin::vars::SQLITE_INTERNAL)?;
+                            if offset_in_page + data.len() > page_data.len() {
+                                page_data.resize(offset_in_page + data.len(), 0);
+                            }
+                            page_data[offset_in_page..offset_in_page + data.len()]
+                                .copy_from_slice(data);
+                            Ok(())
+                        })?;
 
-                            if offset_in_page + write.data.len() > page_data.len() {
-                                page_data.resize(offset_in_page + write.data.len(), 0);
+                        // Prepare WriteBatch for atomic operation
+                        let mut batch = WriteBatch::new();
+
+                        // its length covers a file that goes on past a gap, see `meta` and
+                        // `extent`
+                        let (stored, size) = self.current_size(path).await?;
+                        let record = (end > size || stored.is_none())
+                            .then(|| self.meta_record(path, end.max(size)));
+                        if let Some(record) = &record {
+                            batch.put(meta::meta_key(path), record);
+                        }
+
+                        let mut pages = Vec::with_capacity(page_images.len());
+                        for (page_offset, (original, page_data)) in page_images {
+                            let page_key = format!("{}:page:{}", handle.path, page_offset);
+                            // pages rewritten with identical contents are left alone
+                            if original.as_deref() == Some(page_data.as_slice()) {
+                                self.traffic.record_skipped_put(page_key.as_bytes());
+                                continue;
+                            }
+                            batch.put(&page_key, &page_data);
+                            pages.push((page_key, Bytes::from(page_data)));
+                        }
+                        if pages.is_empty() && staged.is_empty() {
+                            log::debug!("every page in the batch is unchanged, nothing to commit");
+                            return Ok(());
+                        }
+                        add_kv(&mut batch, &handle.path, &staged);
+
+                        // Execute all page updates atomically, unless interrupted meanwhile
+                        interrupt.check()?;
+                        self.flush_fresh_journals().await?;
+                        self.mirrored(self.db_write(batch), |shadow| {
+                            for (page_key, page_data) in &pages {
+                                shadow.put(page_key.as_bytes(), page_data.clone());
                             }
-                            page_data[offset_in_page..offset_in_page + write.data.len()]
-                                .copy_from_slice(&write.data);
+                        })
+                        .await?;
+                        self.record_stored(
+                            handle.path.as_bytes(),
+                            pages.len(),
+                            pages.iter().map(|(_, data)| data.len()).sum(),
+                        );
+                        for (page_key, page_data) in pages {
+                            self.cache.insert(page_key.as_bytes(), page_data);
+                        }
+                        file_state.kv.take();
+                        self.cache_kv(&handle.path, &staged);
+                        if let Some(record) = record {
+                            self.cache
+                                .insert(meta::meta_key(path).as_bytes(), Bytes::from(record));
                         }
+                        Ok(())
+                    })?;
 
-                        // Add the page update to the batch
-                        batch.put(&page_key, page_data);
+                    Ok(())
+                }
+                sqlite_plugin::vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => {
+                    let file_state = self.file_state(&handle.path);
+                    // Close the write batch
+                    file_state.batch_open.store(false, Ordering::Release);
+                    // Clear the batch. Staged puts are kept: after an I/O error SQLite
+                    // retries the commit through the journal, and a transaction that
+                    // rolls back instead drops them at unlock.
+                    file_state.pending_writes.lock().clear();
+                    Ok(())
+                }
+                sqlite_plugin::vars::SQLITE_FCNTL_COMMIT_PHASETWO => {
+                    // Sent once a write transaction has committed, whether it wrote a
+                    // batch, wrote through the journal or wrote nothing at all. Puts the
+                    // batch didn't already store are stored now.
+                    let staged = self.file_state(&handle.path).kv.take();
+                    if staged.is_empty() {
+                        return Ok(());
                     }
+                    self.block_on(self.store_kv(&handle.path, &staged))
+                }
+                _ => Err(sqlite_plugin::vars::SQLITE_NOTFOUND),
+            }
+        })
+    }
 
-                    // Execute all page updates atomically
-                    self.db_write(batch).await
-                })?;
+    fn custom_file_control_arg(&self, op: c_int) -> Option<vfs::CustomFcntlArg> {
+        fcntl::arg(op)
+    }
 
-                Ok(())
-            }
-            sqlite_plugin::vars::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => {
-                let file_state = {
-                    let mut files = self.files.lock();
-                    files
-                        .entry(handle.path.clone())
-                        .or_insert_with(FileState::new)
-                        .clone()
-                };
-                // Close the write batch
-                file_state.batch_open.store(false, Ordering::Release);
-                // Clear the batch
-                file_state.pending_writes.lock().clear();
-                Ok(())
-            }
-            _ => Err(sqlite_plugin::vars::SQLITE_NOTFOUND),
-        }
+    fn custom_file_control(
+        &self,
+        handle: &mut Self::Handle,
+        op: c_int,
+        arg: vfs::CustomFcntl<'_>,
+    ) -> vfs::VfsResult<Option<String>> {
+        catch_panic(
+            "custom_file_control",
+            sqlite_plugin::vars::SQLITE_IOERR,
+            || {
+                let _trace = trace_context::enter(handle.trace_id.as_deref());
+                log::debug!("custom_file_control: file={:?}, op={op:#x}", handle.path);
+                match (op, arg) {
+                    (fcntl::FCNTL_INTERRUPT_ID, vfs::CustomFcntl::Int64(id)) => {
+                        *id = handle.handle_id as i64;
+                        Ok(None)
+                    }
+                    (fcntl::FCNTL_FLUSH, vfs::CustomFcntl::None) => {
+                        self.block_on(async {
+                            self.flush_fresh(&handle.path).await?;
+                            self.flush().await.map_err(|e| {
+                                log::error!("error flushing {}: {e}", handle.path);
+                                sqlite_plugin::vars::SQLITE_IOERR_FSYNC
+                            })
+                        })?;
+                        Ok(None)
+                    }
+                    (op, vfs::CustomFcntl::Text(arg)) => {
+                        let name =
+                            fcntl::pragma_name(op).ok_or(sqlite_plugin::vars::SQLITE_NOTFOUND)?;
+                        match vfs::Vfs::pragma(self, handle, vfs::Pragma { name, arg }) {
+                            Ok(result) => Ok(result),
+                            Err(vfs::PragmaErr::NotFound) => {
+                                Err(sqlite_plugin::vars::SQLITE_NOTFOUND)
+                            }
+                            Err(vfs::PragmaErr::Fail(code, msg)) => {
+                                if let Some(msg) = msg {
+                                    log::error!(
+                                        "file control {op:#x} on {} failed: {msg}",
+                                        handle.path
+                                    );
+                                }
+                                Err(code)
+                            }
+                        }
+                    }
+                    _ => Err(sqlite_plugin::vars::SQLITE_NOTFOUND),
+                }
+            },
+        )
     }
 
     fn sector_size(&self) -> i32 {
@@ -626,54 +3705,212 @@ impl vfs::Vfs for GrpcVfs {
 
     #[instrument(level = "info", skip(self))]
     fn unlock(&self, handle: &mut Self::Handle, level: flags::LockLevel) -> vfs::VfsResult<()> {
-        self.lock_manager.unlock(&handle.path, handle.handle_id, level)
+        catch_panic("unlock", sqlite_plugin::vars::SQLITE_IOERR_UNLOCK, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            handle.touch();
+            // never locked, see `point_in_time`
+            if let Some(pinned) = &handle.pinned {
+                pinned.set_reading(level >= flags::LockLevel::Shared);
+                return Ok(());
+            }
+            let releasing_exclusive = level < flags::LockLevel::Exclusive
+                && self.lock_manager.get_max_lock_level(&handle.path)
+                    == flags::LockLevel::Exclusive;
+            // A committed transaction stored its puts at COMMIT_PHASETWO, any still staged
+            // when it ends belong to one that rolled back
+            let file_state = self.file_state(&handle.path);
+            if level < flags::LockLevel::Reserved {
+                let staged = file_state.kv.take();
+                if !staged.is_empty() {
+                    log::debug!("dropping {} staged puts to {}", staged.len(), handle.path);
+                }
+            }
+            // A transaction that wrote ends here, stored behind the pages it committed
+            let generation = file_state.generation;
+            let bumped = if releasing_exclusive && generation.take_dirty() {
+                let next = generation.get() + 1;
+                self.block_on(self.put(generation::generation_key(&handle.path), next.to_string()))
+                    .map(|()| generation.advance_to(next))
+            } else {
+                Ok(())
+            };
+            // Flush while still holding EXCLUSIVE, so whoever takes the lock next sees
+            // the commit. The lock is released even if the flush fails.
+            let flushed = if self.durable_unlock(&handle.path) && releasing_exclusive {
+                self.block_on(async {
+                    self.flush().await.map(drop).map_err(|e| {
+                        log::error!("error flushing {} before unlock: {e}", handle.path);
+                        sqlite_plugin::vars::SQLITE_IOERR_FSYNC
+                    })
+                })
+            } else {
+                Ok(())
+            };
+            self.lock_manager
+                .unlock(&handle.path, handle.handle_id, level)?;
+            bumped.and(flushed)
+        })
     }
     #[instrument(level = "info", skip(self))]
     fn lock(&self, handle: &mut Self::Handle, level: flags::LockLevel) -> vfs::VfsResult<()> {
-        self.lock_manager.lock(&handle.path, handle.handle_id, level)
+        catch_panic("lock", sqlite_plugin::vars::SQLITE_IOERR_LOCK, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            handle.touch();
+            // reads at a checkpoint need no lock, see `point_in_time`
+            if let Some(pinned) = &handle.pinned {
+                pinned.set_reading(level >= flags::LockLevel::Shared);
+                threading::block_on(&self.runtime, self.refresh_point_in_time(pinned));
+                return Ok(());
+            }
+            self.lock_manager
+                .lock(&handle.path, handle.handle_id, level)
+        })
+    }
+    #[instrument(level = "info", skip(self))]
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> vfs::VfsResult<bool> {
+        catch_panic(
+            "check_reserved_lock",
+            sqlite_plugin::vars::SQLITE_IOERR_CHECKRESERVEDLOCK,
+            || {
+                // a writer may be using the journal, which isn't this handle's to roll
+                // back, see `point_in_time`
+                if handle.pinned.is_some() {
+                    return Ok(true);
+                }
+                let level = self.lock_manager.get_max_lock_level(&handle.path);
+                Ok(level >= flags::LockLevel::Reserved)
+            },
+        )
     }
     #[instrument(level = "info", skip(self))]
     fn sync(&self, handle: &mut Self::Handle) -> vfs::VfsResult<()> {
-        log::debug!("sync: path={}", handle.path);
-        // self.runtime.block_on(async {
-        //     let db = self.db.clone();
-        //     db.flush().await.map_err(|e| {
-        //         log::error!("error flushing database: {e}");
-        //         sqlite_plugin::vars::SQLITE_IOERR_FSYNC
-        //     })
-        // })?;
-        Ok(())
+        catch_panic("sync", sqlite_plugin::vars::SQLITE_IOERR_FSYNC, || {
+            let _trace = trace_context::enter(handle.trace_id.as_deref());
+            log::debug!("sync: path={}", handle.path);
+            let _timed = self.timed("sync", &handle.path);
+            self.block_on(self.flush_fresh(&handle.path))?;
+            // self.runtime.block_on(async {
+            //     let db = self.db.clone();
+            //     db.flush().await.map_err(|e| {
+            //         log::error!("error flushing database: {e}");
+            //         sqlite_plugin::vars::SQLITE_IOERR_FSYNC
+            //     })
+            // })?;
+            Ok(())
+        })
     }
 }
 
 const VFS_NAME: &CStr = c"grpsqlite";
+/// Registered by `sqlite3_s3qlite_init`, never as the default.
+const EXTENSION_VFS_NAME: &CStr = c"s3qlite";
 
-static GRPC_VFS_INSTANCE: OnceLock<Arc<GrpcVfs>> = OnceLock::new();
+static EXTENSION_REGISTERED: OnceLock<c_int> = OnceLock::new();
 
-fn get_grpc_vfs() -> Arc<GrpcVfs> {
+static GRPC_VFS_INSTANCE: OnceLock<Result<Arc<GrpcVfs>, String>> = OnceLock::new();
+
+fn vfs_initialized() -> bool {
+    GRPC_VFS_INSTANCE.get().is_some()
+}
+
+/// The process wide VFS. Construction failures (and panics) are remembered, so every
+/// entry point reports the same error instead of retrying or aborting the host.
+fn get_grpc_vfs() -> Result<Arc<GrpcVfs>, String> {
     GRPC_VFS_INSTANCE
-        .get_or_init(|| Arc::new(GrpcVfs::new()))
+        .get_or_init(|| {
+            let build = || {
+                std::panic::catch_unwind(GrpcVfs::try_new)
+                    .unwrap_or_else(|panic| Err(panic_guard::panic_message(&*panic).to_string()))
+            };
+            // the VFS's runtime can't be built and dropped on failure inside another, see
+            // `threading`
+            let vfs = match threading::Context::current() {
+                threading::Context::None => build(),
+                _ => std::thread::spawn(build)
+                    .join()
+                    .unwrap_or_else(|_| Err("failed to build the VFS".to_string())),
+            };
+            vfs.map(Arc::new)
+        })
         .clone()
 }
 
-fn setup_tracing() -> tracing_chrome::FlushGuard {
-    use std::fs::File;
-    use std::io::BufWriter;
+/// Register the VFS as the default and build it, for Rust hosts already inside a tokio
+/// runtime. The VFS is built on the runtime's blocking pool rather than on the first
+/// open, see `threading`.
+pub async fn initialize() -> Result<(), String> {
+    let rc = unsafe { initialize_grpsqlite() };
+    if rc != sqlite_plugin::vars::SQLITE_OK {
+        return Err(format!("failed to register the VFS: {rc}"));
+    }
+    tokio::task::spawn_blocking(get_grpc_vfs)
+        .await
+        .map_err(|e| format!("failed to build the VFS: {e}"))?
+        .map(|_| ())
+}
+
+/// Route `log` records from this crate to SQLite's logger.
+fn install_logger(logger: sqlite_plugin::logger::SqliteLogger) {
+    struct LogCompat {
+        logger: Mutex<sqlite_plugin::logger::SqliteLogger>,
+    }
+
+    impl log::Log for LogCompat {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
 
-    let (chrome_layer, guard) = ChromeLayerBuilder::new()
-        .writer(BufWriter::new(
-            File::create("s3qlite_trace.cpuprofile").unwrap(),
-        ))
-        .build();
+        fn log(&self, record: &log::Record) {
+            let level = match record.level() {
+                log::Level::Error => sqlite_plugin::logger::SqliteLogLevel::Error,
+                log::Level::Warn => sqlite_plugin::logger::SqliteLogLevel::Warn,
+                _ => sqlite_plugin::logger::SqliteLogLevel::Notice,
+            };
+            if !record.target().contains("s3qlite") {
+                // Filter out logs from other modules.
+                return;
+            }
+            let msg = match trace_context::current() {
+                Some(trace_id) => format!("[trace_id={trace_id}] {}", record.args()),
+                None => format!("{}", record.args()),
+            };
+            if !env_config::silent() {
+                println!("{msg}");
+            }
+            self.logger.lock().log(level, msg.as_bytes());
+        }
 
-    // Don't call init() which would take over global logging
-    // Instead, just set up tracing without interfering with existing log setup
-    let subscriber = Registry::default().with(chrome_layer);
+        fn flush(&self) {
+            if !env_config::silent() {
+                println!("flush");
+            }
+        }
+    }
 
-    // Only set the global default if there isn't one already
-    let _ = tracing::subscriber::set_global_default(subscriber);
+    let log = LogCompat {
+        logger: Mutex::new(logger),
+    };
+    if let Err(e) = log::set_boxed_logger(Box::new(log)) {
+        // Logger already set, ignore the error
+        if !env_config::silent() {
+            eprintln!("Logger already initialized: {e}");
+        }
+    }
+}
 
-    guard
+/// Flush the chrome trace on a fixed interval until the guard is taken by `flush_traces`.
+async fn flush_traces_periodically(
+    guard: Arc<Mutex<Option<diagnostics::FlushGuard>>>,
+    interval: std::time::Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match &*guard.lock() {
+            Some(guard) => guard.flush(),
+            None => break,
+        }
+    }
 }
 
 /// This function initializes the VFS statically.
@@ -684,14 +3921,14 @@ fn setup_tracing() -> tracing_chrome::FlushGuard {
 /// with SQLite and doesn't access any raw pointers or perform unsafe operations.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn initialize_grpsqlite() -> i32 {
-    let vfs = get_grpc_vfs();
-
     if let Err(err) = vfs::register_static(
         VFS_NAME.to_owned(),
-        (*vfs).clone(),
+        lazy::LazyGrpcVfs,
         vfs::RegisterOpts { make_default: true },
     ) {
-        eprintln!("Failed to initialize grpsqlite: {err}");
+        if !env_config::silent() {
+            eprintln!("Failed to initialize grpsqlite: {err}");
+        }
         return err;
     }
 
@@ -700,16 +3937,180 @@ pub unsafe extern "C" fn initialize_grpsqlite() -> i32 {
     sqlite_plugin::vars::SQLITE_OK
 }
 
+/// Final flush of the chrome trace, to be called on shutdown. The periodic flusher
+/// stops once this has run.
+///
+/// # Safety
+/// This function takes no arguments and is safe to call from C at any time.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn flush_traces() {
-    let vfs = get_grpc_vfs();
+    let Some(Ok(vfs)) = GRPC_VFS_INSTANCE.get() else {
+        return;
+    };
     let guard = vfs._guard.lock().take();
+    // dropping the guard finishes the trace file
     if let Some(guard) = guard {
         guard.flush();
-        drop(guard);
     }
 }
 
+/// Make everything written so far durable and flush the trace. Mobile hosts call this
+/// when the app moves to the background, where it may be suspended or killed without
+/// further notice. Does nothing if no database has been opened yet.
+///
+/// # Safety
+/// This function takes no arguments and is safe to call from C at any time.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_app_background() -> c_int {
+    let Some(Ok(vfs)) = GRPC_VFS_INSTANCE.get() else {
+        return sqlite_plugin::vars::SQLITE_OK;
+    };
+    if let Some(guard) = &*vfs._guard.lock() {
+        guard.flush();
+    }
+    match threading::block_on(&vfs.runtime, vfs.flush()) {
+        Ok(_) => sqlite_plugin::vars::SQLITE_OK,
+        Err(e) => {
+            log::error!("failed to flush on app background: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_FSYNC
+        }
+    }
+}
+
+/// Copy `s` into `buf` as a NUL terminated string, or return `SQLITE_TOOBIG` if it
+/// doesn't fit in `len` bytes.
+///
+/// # Safety
+/// `buf` must be null or valid for writes of `len` bytes.
+unsafe fn copy_to_c_buf(s: &str, buf: *mut c_char, len: usize) -> c_int {
+    if buf.is_null() || s.len() >= len {
+        return sqlite_plugin::vars::SQLITE_TOOBIG;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr(), buf.cast::<u8>(), s.len());
+        *buf.add(s.len()) = 0;
+    }
+    sqlite_plugin::vars::SQLITE_OK
+}
+
+/// Copy the state directory diagnostic artifacts are written to into `buf` as a NUL
+/// terminated string. Returns `SQLITE_TOOBIG` if it doesn't fit in `len` bytes.
+///
+/// # Safety
+/// `buf` must be valid for writes of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_state_dir(buf: *mut c_char, len: usize) -> c_int {
+    let dir = env_config::state_dir();
+    unsafe { copy_to_c_buf(&dir.to_string_lossy(), buf, len) }
+}
+
+/// Readiness probe: checks that the object store answers within `deadline_ms`, that
+/// the cache directory is writable, and reports the bytes not yet stored. Initializes
+/// the VFS if needed. Returns `SQLITE_OK` when healthy and `SQLITE_IOERR` otherwise.
+///
+/// The status is written to `buf` as JSON, e.g.
+/// `{"healthy":true,"store":{"ok":true,"latency_ms":12},"cache_dir":{"ok":true},"pending_bytes":0}`.
+/// `buf` may be null when only the result code is needed; a status that doesn't fit is
+/// left out.
+///
+/// # Safety
+/// `buf` must be null or valid for writes of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_healthcheck(
+    deadline_ms: u32,
+    buf: *mut c_char,
+    len: usize,
+) -> c_int {
+    let result = panic_guard::catch_panic("healthcheck", String::new(), || {
+        let vfs = get_grpc_vfs()?;
+        let health = threading::block_on(
+            &vfs.runtime,
+            vfs.health(std::time::Duration::from_millis(deadline_ms.into())),
+        );
+        Ok((health.healthy(), health.to_json()))
+    });
+    let (healthy, status) = match result {
+        Ok(status) => status,
+        Err(e) => (
+            false,
+            format!(
+                "{{\"healthy\":false,\"error\":\"{}\"}}",
+                e.replace('"', "'")
+            ),
+        ),
+    };
+    if !buf.is_null() {
+        unsafe { copy_to_c_buf(&status, buf, len) };
+    }
+    if healthy {
+        sqlite_plugin::vars::SQLITE_OK
+    } else {
+        sqlite_plugin::vars::SQLITE_IOERR
+    }
+}
+
+/// Interrupt the operations running on the handle with id `handle_id`, fetched with the
+/// `FCNTL_INTERRUPT_ID` (`0x53330001`) file control, see `interrupt`. Safe to call from
+/// any thread. Returns `SQLITE_NOTFOUND` if the handle isn't open.
+///
+/// # Safety
+/// This function takes no pointers and is safe to call from C at any time.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_interrupt(handle_id: i64) -> c_int {
+    match GRPC_VFS_INSTANCE.get() {
+        Some(Ok(vfs)) if vfs.handles.interrupt(handle_id as u64) => sqlite_plugin::vars::SQLITE_OK,
+        _ => sqlite_plugin::vars::SQLITE_NOTFOUND,
+    }
+}
+
+/// Register `callback` to be told about long operations and to cancel them, see
+/// `progress`. It's called with `arg`, the operation (`delete`, `truncate` or `warm`),
+/// the file and the pages done so far; returning non-zero cancels the operation, which
+/// fails with `SQLITE_INTERRUPT`. A null `callback` removes the handler.
+///
+/// # Safety
+/// `callback` may be called from any thread, so it and `arg` must be safe to use from
+/// any thread until the handler is replaced.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_progress_handler(
+    callback: Option<progress::Callback>,
+    arg: *mut c_void,
+) {
+    progress::set_handler(callback, arg);
+}
+
+/// Register `callback` to be told when a lock another handle is waiting for is released
+/// or downgraded, see `lock_wait`. It's called with `arg`, the file and the id of the
+/// releasing handle. A null `callback` removes it.
+///
+/// # Safety
+/// `callback` may be called from any thread, so it and `arg` must be safe to use from
+/// any thread until the callback is replaced.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_unlock_notify(
+    callback: Option<lock_wait::Callback>,
+    arg: *mut c_void,
+) {
+    lock_wait::set_handler(callback, arg);
+}
+
+/// Set a configuration value by its environment variable name (e.g. `LOCAL_CACHE_DIR`
+/// pointing at the app's cache directory). Must be called before the first database is
+/// opened; returns `SQLITE_MISUSE` afterwards.
+///
+/// # Safety
+/// `name` and `value` must be valid NUL terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_config_set(name: *const c_char, value: *const c_char) -> c_int {
+    if name.is_null() || value.is_null() || vfs_initialized() {
+        return sqlite_plugin::vars::SQLITE_MISUSE;
+    }
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    let value = unsafe { CStr::from_ptr(value) }.to_string_lossy();
+    env_config::set_override(&name, &value);
+    sqlite_plugin::vars::SQLITE_OK
+}
+
 /// This function is called by `SQLite` when the extension is loaded. It registers
 /// the memvfs VFS with `SQLite`.
 ///
@@ -722,12 +4123,11 @@ pub unsafe extern "C" fn sqlite3_grpsqlite_init(
     _pz_err_msg: *mut *mut c_char,
     p_api: *mut sqlite_plugin::sqlite3_api_routines,
 ) -> std::os::raw::c_int {
-    let vfs = get_grpc_vfs();
     if let Err(err) = unsafe {
         vfs::register_dynamic(
             p_api,
             VFS_NAME.to_owned(),
-            (*vfs).clone(),
+            lazy::LazyGrpcVfs,
             vfs::RegisterOpts { make_default: true },
         )
     } {
@@ -739,3 +4139,71 @@ pub unsafe extern "C" fn sqlite3_grpsqlite_init(
 
     sqlite_plugin::vars::SQLITE_OK_LOAD_PERMANENTLY
 }
+
+/// Register the `s3qlite` VFS once per process. The VFS itself is still built lazily
+/// on the first open.
+unsafe fn register_extension_vfs(p_api: *mut sqlite_plugin::sqlite3_api_routines) -> c_int {
+    *EXTENSION_REGISTERED.get_or_init(|| {
+        match unsafe {
+            vfs::register_dynamic(
+                p_api,
+                EXTENSION_VFS_NAME.to_owned(),
+                lazy::LazyGrpcVfs,
+                vfs::RegisterOpts {
+                    make_default: false,
+                },
+            )
+        } {
+            Ok(()) => {
+                log::set_max_level(log::LevelFilter::Trace);
+                sqlite_plugin::vars::SQLITE_OK
+            }
+            Err(err) => err,
+        }
+    })
+}
+
+/// Entry point `load_extension` finds by itself for `libs3qlite`, for hosts like
+/// Python's sqlite3, Ruby and Go wrappers that can't call anything else. Registers the
+/// `s3qlite` VFS without making it the default, so only connections opened with
+/// `vfs=s3qlite` use it, and writes the capabilities JSON (see `PRAGMA
+/// s3qlite_capabilities`) to `pz_err_msg`. SQLite's own loader drops that message on
+/// success, a host that calls the entry point itself can read and `sqlite3_free` it.
+///
+/// # Safety
+/// This function should only be called by sqlite's extension loading mechanism.
+/// The provided pointers must be valid SQLite API structures.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sqlite3_s3qlite_init(
+    _db: *mut c_void,
+    pz_err_msg: *mut *mut c_char,
+    p_api: *mut sqlite_plugin::sqlite3_api_routines,
+) -> c_int {
+    let rc = unsafe { register_extension_vfs(p_api) };
+    if rc != sqlite_plugin::vars::SQLITE_OK {
+        return rc;
+    }
+    if !pz_err_msg.is_null() {
+        // only reads the config, the VFS isn't built until a database is opened
+        let json = capabilities_json(&env_config::EnvConfig::new());
+        let mprintf = unsafe { (*p_api).mprintf };
+        if let (Some(mprintf), Ok(json)) = (mprintf, CString::new(json)) {
+            unsafe { *pz_err_msg = mprintf(c"%s".as_ptr(), json.as_ptr()) };
+        }
+    }
+    sqlite_plugin::vars::SQLITE_OK_LOAD_PERMANENTLY
+}
+
+/// Entry point for `sqlite3_auto_extension`, which runs it for every new connection.
+/// Registers the `s3qlite` VFS on the first call and does nothing after that.
+///
+/// # Safety
+/// Only to be called by SQLite as an auto extension, with valid SQLite API structures.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn s3qlite_auto_extension(
+    _db: *mut c_void,
+    _pz_err_msg: *mut *mut c_char,
+    p_api: *mut sqlite_plugin::sqlite3_api_routines,
+) -> c_int {
+    unsafe { register_extension_vfs(p_api) }
+}
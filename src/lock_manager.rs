@@ -1,36 +1,74 @@
+use crate::clock::Clock;
+use crate::lock_wait;
+use crate::sharded::Sharded;
+use parking_lot::{Condvar, Mutex};
 use sqlite_plugin::flags;
 use std::collections::HashMap;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{debug, instrument};
 
 /// Manages SQLite-style hierarchical locking for files with multiple handles
 #[derive(Clone)]
 pub struct LockManager {
-    // Map of file_path -> file lock state
-    files: Arc<Mutex<HashMap<String, FileLockState>>>,
+    // Map of file_path -> file lock state, sharded by path
+    files: Arc<Sharded<FileLockState>>,
+    // How long a lock request waits before failing with SQLITE_BUSY, forever if None
+    timeout: Option<Duration>,
+    // Measures how long waits took, see `clock`
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Clone)]
 struct FileLockState {
-    // Map of handle_id -> lock_level for this file
-    handle_locks: Arc<Mutex<HashMap<u64, flags::LockLevel>>>,
+    handle_locks: Arc<Mutex<HandleLocks>>,
     // Condition variable to notify waiting lock requests
     lock_condvar: Arc<Condvar>,
 }
 
+#[derive(Default)]
+struct HandleLocks {
+    // Map of handle_id -> lock_level for this file
+    levels: HashMap<u64, flags::LockLevel>,
+    // Set once the state has been removed from the file map, a lock taken on a retired
+    // state would not be seen by other handles so callers must look the file up again
+    retired: bool,
+    // Lock requests waiting on the condition variable
+    waiting: usize,
+}
+
 impl FileLockState {
     fn new() -> Self {
         Self {
-            handle_locks: Arc::new(Mutex::new(HashMap::new())),
+            handle_locks: Arc::new(Mutex::new(HandleLocks::default())),
             lock_condvar: Arc::new(Condvar::new()),
         }
     }
 }
 
+impl HandleLocks {
+    /// At most one handle may hold RESERVED or above, and EXCLUSIVE excludes every other
+    /// handle. Anything else means an earlier operation was interrupted halfway.
+    fn is_consistent(&self) -> bool {
+        let writers = self
+            .levels
+            .values()
+            .filter(|&&level| LockManager::lock_level_to_u8(level) >= 2)
+            .count();
+        let exclusive = self
+            .levels
+            .values()
+            .any(|&level| level == flags::LockLevel::Exclusive);
+        writers <= 1 && (!exclusive || self.levels.len() == 1)
+    }
+}
+
 impl LockManager {
-    pub fn new() -> Self {
+    pub fn new(timeout: Option<Duration>, clock: Arc<dyn Clock>) -> Self {
         Self {
-            files: Arc::new(Mutex::new(HashMap::new())),
+            files: Arc::new(Sharded::new()),
+            timeout,
+            clock,
         }
     }
 
@@ -39,28 +77,76 @@ impl LockManager {
     pub fn lock(&self, file_path: &str, handle_id: u64, level: flags::LockLevel) -> Result<(), i32> {
         debug!("lock request: path={} handle_id={} level={:?}", file_path, handle_id, level);
         
-        // Get or create file lock state
-        let file_state = {
-            let mut files = self.files.lock().unwrap();
-            files.entry(file_path.to_string())
-                .or_insert_with(FileLockState::new)
-                .clone()
-        };
+        // Set once the request has had to wait, see `lock_wait`: when for the timeout,
+        // and when by the clock for reporting
+        let mut wait_started: Option<(Instant, Instant)> = None;
+        loop {
+            // Get or create file lock state
+            let file_state = {
+                let mut files = self.files.shard(file_path);
+                files.entry(file_path.to_string())
+                    .or_insert_with(FileLockState::new)
+                    .clone()
+            };
+            let mut handle_locks = file_state.handle_locks.lock();
+            if handle_locks.retired {
+                // the last handle was removed after we looked the file up, look again
+                continue;
+            }
 
-        // Wait for lock to become available, then acquire it
-        let mut handle_locks = file_state.handle_locks.lock().unwrap();
-        
-        // Wait until the lock is compatible
-        while !Self::is_lock_compatible(level, &handle_locks, handle_id) {
-            debug!("lock waiting: path={} handle_id={} level={:?}", file_path, handle_id, level);
-            handle_locks = file_state.lock_condvar.wait(handle_locks).unwrap();
-        }
+            if !handle_locks.is_consistent() {
+                // Clearing the locks would leave the handles that hold RESERVED or
+                // EXCLUSIVE writing on, unaware, alongside whoever locks next. Refuse every
+                // lock until they release theirs instead.
+                log::error!(
+                    "inconsistent lock state for {file_path}, refusing locks until released: {:?}",
+                    handle_locks.levels
+                );
+                return Err(sqlite_plugin::vars::SQLITE_IOERR_LOCK);
+            }
 
-        // Acquire the lock
-        handle_locks.insert(handle_id, level);
-        debug!("lock acquired: path={} handle_id={} level={:?}", file_path, handle_id, level);
-        
-        Ok(())
+            // Wait until the lock is compatible
+            while !Self::is_lock_compatible(level, &handle_locks.levels, handle_id) {
+                debug!("lock waiting: path={} handle_id={} level={:?}", file_path, handle_id, level);
+                let (started, reported) = *wait_started.get_or_insert_with(|| {
+                    let blockers = Self::blockers(level, &handle_locks.levels, handle_id);
+                    let holders: Vec<_> = handle_locks.levels.iter().map(|(&id, &level)| (id, level)).collect();
+                    lock_wait::started(file_path, handle_id, level, &blockers, &holders);
+                    (Instant::now(), self.clock.now())
+                });
+                handle_locks.waiting += 1;
+                let timed_out = match self.timeout {
+                    Some(timeout) => {
+                        let remaining = timeout.saturating_sub(started.elapsed());
+                        file_state.lock_condvar.wait_for(&mut handle_locks, remaining).timed_out()
+                    }
+                    None => {
+                        file_state.lock_condvar.wait(&mut handle_locks);
+                        false
+                    }
+                };
+                handle_locks.waiting -= 1;
+                if timed_out && !Self::is_lock_compatible(level, &handle_locks.levels, handle_id) {
+                    let waited = self.clock.now().saturating_duration_since(reported);
+                    lock_wait::ended(file_path, handle_id, level, waited, false);
+                    return Err(sqlite_plugin::vars::SQLITE_BUSY);
+                }
+            }
+            if handle_locks.retired {
+                // every other handle closed while we were waiting
+                continue;
+            }
+
+            // Acquire the lock
+            handle_locks.levels.insert(handle_id, level);
+            debug!("lock acquired: path={} handle_id={} level={:?}", file_path, handle_id, level);
+            if let Some((_, reported)) = wait_started {
+                let waited = self.clock.now().saturating_duration_since(reported);
+                lock_wait::ended(file_path, handle_id, level, waited, true);
+            }
+
+            return Ok(());
+        }
     }
 
     /// Release or downgrade a lock on a file for a specific handle
@@ -70,22 +156,22 @@ impl LockManager {
         
         // Get file lock state
         let file_state = {
-            let files = self.files.lock().unwrap();
+            let files = self.files.shard(file_path);
             files.get(file_path).cloned()
         };
 
         if let Some(file_state) = file_state {
-            let mut handle_locks = file_state.handle_locks.lock().unwrap();
+            let mut handle_locks = file_state.handle_locks.lock();
             
             match level {
                 flags::LockLevel::Unlocked => {
                     // Completely unlock - remove this handle's lock
-                    handle_locks.remove(&handle_id);
+                    handle_locks.levels.remove(&handle_id);
                     debug!("lock removed: path={} handle_id={}", file_path, handle_id);
                 }
                 _ => {
                     // Downgrade to specified level
-                    handle_locks.insert(handle_id, level);
+                    handle_locks.levels.insert(handle_id, level);
                     debug!("lock downgraded: path={} handle_id={} to level={:?}", file_path, handle_id, level);
                 }
             }
@@ -93,6 +179,11 @@ impl LockManager {
             // Notify any waiting threads that lock state has changed
             file_state.lock_condvar.notify_all();
             debug!("lock waiters notified: path={}", file_path);
+            let waiting = handle_locks.waiting > 0;
+            drop(handle_locks);
+            if waiting {
+                lock_wait::notify(file_path, handle_id);
+            }
         }
 
         Ok(())
@@ -103,38 +194,46 @@ impl LockManager {
     pub fn remove_handle(&self, file_path: &str, handle_id: u64) {
         debug!("removing handle: path={} handle_id={}", file_path, handle_id);
         
-        let should_remove_file = {
-            let mut files = self.files.lock().unwrap();
-            if let Some(file_state) = files.get(file_path) {
-                let mut handle_locks = file_state.handle_locks.lock().unwrap();
-                handle_locks.remove(&handle_id);
-                
-                // Notify waiters in case this was blocking someone
-                file_state.lock_condvar.notify_all();
-                
-                // Check if file has any remaining handles
-                let should_remove = handle_locks.is_empty();
-                drop(handle_locks);
-                should_remove
-            } else {
-                false
-            }
+        let mut files = self.files.shard(file_path);
+        let Some(file_state) = files.get(file_path).cloned() else {
+            return;
         };
+        let mut handle_locks = file_state.handle_locks.lock();
+        handle_locks.levels.remove(&handle_id);
+
+        // Notify waiters in case this was blocking someone
+        file_state.lock_condvar.notify_all();
 
-        // Remove the entire file state if no handles remain
-        if should_remove_file {
-            let mut files = self.files.lock().unwrap();
+        // Remove the entire file state if no handles remain. Both locks are held so no
+        // other handle can slip in between the check and the removal.
+        if handle_locks.levels.is_empty() {
+            handle_locks.retired = true;
             files.remove(file_path);
             debug!("removed file state: path={}", file_path);
         }
+        let waiting = handle_locks.waiting > 0;
+        drop(handle_locks);
+        drop(files);
+        if waiting {
+            lock_wait::notify(file_path, handle_id);
+        }
+    }
+
+    /// Get the lock level a handle holds on a file
+    pub fn handle_lock_level(&self, file_path: &str, handle_id: u64) -> flags::LockLevel {
+        let files = self.files.shard(file_path);
+        files
+            .get(file_path)
+            .and_then(|file_state| file_state.handle_locks.lock().levels.get(&handle_id).copied())
+            .unwrap_or(flags::LockLevel::Unlocked)
     }
 
-    /// Get the current maximum lock level for a file (for diagnostics)
+    /// Get the current maximum lock level for a file
     pub fn get_max_lock_level(&self, file_path: &str) -> flags::LockLevel {
-        let files = self.files.lock().unwrap();
+        let files = self.files.shard(file_path);
         if let Some(file_state) = files.get(file_path) {
-            let handle_locks = file_state.handle_locks.lock().unwrap();
-            handle_locks.values()
+            let handle_locks = file_state.handle_locks.lock();
+            handle_locks.levels.values()
                 .map(|&level| Self::lock_level_to_u8(level))
                 .max()
                 .map(Self::u8_to_lock_level)
@@ -173,33 +272,72 @@ impl LockManager {
         existing_locks: &HashMap<u64, flags::LockLevel>,
         handle_id: u64,
     ) -> bool {
+        // Skip our own handle - we can always upgrade our own lock
+        existing_locks.iter().all(|(&existing_handle_id, &existing_level)| {
+            existing_handle_id == handle_id || !Self::conflicts(requested, existing_level)
+        })
+    }
+
+    // The other handles whose locks keep a handle from taking `requested`
+    fn blockers(
+        requested: flags::LockLevel,
+        existing_locks: &HashMap<u64, flags::LockLevel>,
+        handle_id: u64,
+    ) -> Vec<(u64, flags::LockLevel)> {
+        existing_locks
+            .iter()
+            .filter(|&(&existing_handle_id, &existing_level)| {
+                existing_handle_id != handle_id && Self::conflicts(requested, existing_level)
+            })
+            .map(|(&existing_handle_id, &existing_level)| (existing_handle_id, existing_level))
+            .collect()
+    }
+
+    // Whether another handle's lock rules out a requested one
+    fn conflicts(requested: flags::LockLevel, existing_level: flags::LockLevel) -> bool {
         // SQLite locking rules:
         // - Multiple SHARED locks are allowed
         // - Only one RESERVED, PENDING, or EXCLUSIVE lock is allowed
         // - EXCLUSIVE lock excludes all other locks
         // - A handle can always upgrade its own lock
+        match (requested, existing_level) {
+            // Can't have EXCLUSIVE with any other lock
+            (flags::LockLevel::Exclusive, _) | (_, flags::LockLevel::Exclusive) => true,
+            // Can't have PENDING with RESERVED or PENDING
+            (flags::LockLevel::Pending, flags::LockLevel::Reserved) => true,
+            (flags::LockLevel::Pending, flags::LockLevel::Pending) => true,
+            (flags::LockLevel::Reserved, flags::LockLevel::Pending) => true,
+            // Can't have multiple RESERVED locks
+            (flags::LockLevel::Reserved, flags::LockLevel::Reserved) => true,
+            // SHARED with SHARED is OK, everything else with UNLOCKED is OK
+            _ => false,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flags::LockLevel;
 
-        for (&existing_handle_id, &existing_level) in existing_locks.iter() {
-            // Skip our own handle - we can always upgrade our own lock
-            if existing_handle_id == handle_id {
-                continue;
-            }
+    #[test]
+    fn inconsistent_state_refuses_locks_without_resetting() {
+        let locks = LockManager::new(Some(Duration::from_millis(10)), Arc::new(crate::clock::SystemClock));
+        locks.lock("app.db", 1, LockLevel::Shared).unwrap();
+        locks.lock("app.db", 1, LockLevel::Reserved).unwrap();
+        // two writers at once, as an operation interrupted halfway might leave it
+        let file_state = locks.files.shard("app.db").get("app.db").cloned().unwrap();
+        file_state.handle_locks.lock().levels.insert(2, LockLevel::Reserved);
 
-            match (requested, existing_level) {
-                // Can't have EXCLUSIVE with any other lock
-                (flags::LockLevel::Exclusive, _) | (_, flags::LockLevel::Exclusive) => {
-                    return false;
-                }
-                // Can't have PENDING with RESERVED or PENDING
-                (flags::LockLevel::Pending, flags::LockLevel::Reserved) => return false,
-                (flags::LockLevel::Pending, flags::LockLevel::Pending) => return false,
-                (flags::LockLevel::Reserved, flags::LockLevel::Pending) => return false,
-                // Can't have multiple RESERVED locks
-                (flags::LockLevel::Reserved, flags::LockLevel::Reserved) => return false,
-                // SHARED with SHARED is OK, everything else with UNLOCKED is OK
-                _ => continue,
-            }
-        }
-        true
+        assert_eq!(locks.lock("app.db", 3, LockLevel::Shared), Err(sqlite_plugin::vars::SQLITE_IOERR_LOCK));
+        assert_eq!(locks.lock("app.db", 1, LockLevel::Exclusive), Err(sqlite_plugin::vars::SQLITE_IOERR_LOCK));
+        // neither writer has lost its lock without being told
+        let levels = file_state.handle_locks.lock().levels.clone();
+        assert_eq!(levels.get(&1), Some(&LockLevel::Reserved));
+        assert_eq!(levels.get(&2), Some(&LockLevel::Reserved));
+
+        // once one of them releases, locking works again
+        locks.unlock("app.db", 2, LockLevel::Unlocked).unwrap();
+        locks.lock("app.db", 3, LockLevel::Shared).unwrap();
+        assert_eq!(locks.lock("app.db", 3, LockLevel::Reserved), Err(sqlite_plugin::vars::SQLITE_BUSY));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,101 @@
+//! Reporting lock waits, and telling the host when a lock it waited on is released.
+//!
+//! A handle asking for a lock another handle in the process holds waits for it. Each
+//! wait is emitted as a `lock wait started` tracing event naming the handles in the way
+//! and what they hold, and a `lock wait ended` one with how long it took, so slow
+//! transactions can be traced to the one blocking them.
+//!
+//! With `LOCK_TIMEOUT_MS` set a wait gives up after that long and the lock fails with
+//! `SQLITE_BUSY`. Rather than retrying in a loop, as SQLite's own busy handler does, a
+//! host can park the transaction and register a callback with `s3qlite_unlock_notify`,
+//! in the spirit of `sqlite3_unlock_notify`: it's called with the file and the
+//! releasing handle whenever a lock someone is waiting on is released or downgraded.
+
+use parking_lot::RwLock;
+use sqlite_plugin::flags;
+use std::ffi::{CString, c_char, c_void};
+use std::time::Duration;
+
+pub type Callback = unsafe extern "C" fn(arg: *mut c_void, path: *const c_char, released_by: u64);
+
+struct Handler {
+    callback: Callback,
+    arg: *mut c_void,
+}
+
+// the host promises `arg` can be used from any thread, see `s3qlite_unlock_notify`
+unsafe impl Send for Handler {}
+unsafe impl Sync for Handler {}
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+pub fn set_handler(callback: Option<Callback>, arg: *mut c_void) {
+    *HANDLER.write() = callback.map(|callback| Handler { callback, arg });
+}
+
+/// `3:Reserved,5:Shared`, the handles holding locks and their levels.
+fn holders_string(holders: &[(u64, flags::LockLevel)]) -> String {
+    holders
+        .iter()
+        .map(|(handle_id, level)| format!("{handle_id}:{level:?}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// `handle_id` started waiting for `level` on `path`, blocked by `blockers`.
+pub fn started(
+    path: &str,
+    handle_id: u64,
+    level: flags::LockLevel,
+    blockers: &[(u64, flags::LockLevel)],
+    holders: &[(u64, flags::LockLevel)],
+) {
+    let blocked_by = holders_string(blockers);
+    let holders = holders_string(holders);
+    tracing::info!(
+        path,
+        handle_id,
+        level = ?level,
+        blocked_by = blocked_by.as_str(),
+        holders = holders.as_str(),
+        "lock wait started"
+    );
+    log::debug!("handle {handle_id} waiting for {level:?} on {path}, blocked by {blocked_by}");
+}
+
+/// The wait of `handle_id` for `level` on `path` is over, after `waited`.
+pub fn ended(
+    path: &str,
+    handle_id: u64,
+    level: flags::LockLevel,
+    waited: Duration,
+    acquired: bool,
+) {
+    let waited_ms = waited.as_millis() as u64;
+    tracing::info!(
+        path,
+        handle_id,
+        level = ?level,
+        waited_ms,
+        acquired,
+        "lock wait ended"
+    );
+    if !acquired {
+        log::warn!(
+            "handle {handle_id} gave up waiting for {level:?} on {path} after {waited_ms}ms"
+        );
+    }
+}
+
+/// `released_by` released or downgraded a lock on `path` that another handle is
+/// waiting for.
+pub fn notify(path: &str, released_by: u64) {
+    let handler = HANDLER.read();
+    let Some(handler) = &*handler else {
+        return;
+    };
+    let Ok(path) = CString::new(path) else {
+        return;
+    };
+    unsafe { (handler.callback)(handler.arg, path.as_ptr(), released_by) };
+}
@@ -0,0 +1,157 @@
+//! Keeping the VFS's memory under a budget, for small devices.
+//!
+//! Left alone the VFS holds as much as it's configured to: a full page cache, every
+//! write of a transaction and of a new file until it's synced, and whatever prefetching
+//! reads ahead. On a 128-256MB device that can be enough to get the host killed, so with
+//! `MEMORY_BUDGET_BYTES` set the VFS degrades instead once its memory passes
+//! `HIGH_WATER_PCT` of the budget:
+//!
+//! - the page cache is shrunk to bring memory down to `TARGET_PCT`, but not below
+//!   `MIN_CACHE_BYTES`,
+//! - prefetching stops,
+//! - transactions spill their writes to disk from the first byte, and new files are
+//!   stored as they're written rather than buffered until their first sync.
+//!
+//! It's checked every `CHECK_INTERVAL`, and stays degraded until memory falls below
+//! `LOW_WATER_PCT`, when prefetching comes back and the cache may grow again, to at most
+//! the low water mark.
+//!
+//! Memory is what the VFS accounts for itself: cached pages and buffered writes. With the
+//! `memory-accounting` feature every allocation the extension makes goes through
+//! `Accounting` instead, and the budget covers all of them, SlateDB's included.
+//!
+//! The memory used, the budget and the number of times it was passed are in
+//! `PRAGMA s3qlite_stats`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+pub const HIGH_WATER_PCT: u64 = 90;
+
+pub const LOW_WATER_PCT: u64 = 70;
+
+pub const TARGET_PCT: u64 = 60;
+
+/// The page cache is never shrunk below this, so reads keep some locality.
+pub const MIN_CACHE_BYTES: u64 = 256 * 1024;
+
+// read on every buffered write, so it's kept out of `MemoryBudget`
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+
+/// Whether memory is over budget and buffers should go to disk instead.
+pub fn degraded() -> bool {
+    DEGRADED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Memory is over the high water mark, this many bytes past `TARGET_PCT`.
+    Degrade(u64),
+    /// Memory is back under the low water mark.
+    Recover,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    // 0 when there's no budget
+    limit: AtomicU64,
+    used: AtomicU64,
+    degradations: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn configure(&self, limit: u64) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn low_water(&self) -> u64 {
+        self.limit() / 100 * LOW_WATER_PCT
+    }
+
+    /// Record that `used` bytes are in use, and whether that should change anything.
+    pub fn check(&self, used: u64) -> Option<Change> {
+        self.used.store(used, Ordering::Relaxed);
+        let limit = self.limit();
+        if limit == 0 {
+            return None;
+        }
+        if used > limit / 100 * HIGH_WATER_PCT {
+            if !DEGRADED.swap(true, Ordering::Relaxed) {
+                self.degradations.fetch_add(1, Ordering::Relaxed);
+                log::warn!("memory passed {used} of a {limit} byte budget, degrading");
+            }
+            return Some(Change::Degrade(used - limit / 100 * TARGET_PCT));
+        }
+        if used < self.low_water() && DEGRADED.swap(false, Ordering::Relaxed) {
+            log::info!("memory back to {used} of a {limit} byte budget");
+            return Some(Change::Recover);
+        }
+        None
+    }
+
+    pub fn gauges(&self) -> [(&'static str, u64); 4] {
+        [
+            ("memory_bytes", self.used.load(Ordering::Relaxed)),
+            ("memory_budget_bytes", self.limit()),
+            ("memory_degraded", degraded() as u64),
+            (
+                "memory_degradations",
+                self.degradations.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes currently allocated by the extension, with the `memory-accounting` feature.
+pub fn allocated() -> Option<u64> {
+    cfg!(feature = "memory-accounting").then(|| ALLOCATED.load(Ordering::Relaxed))
+}
+
+/// The system allocator, counting the bytes it hands out.
+#[cfg(feature = "memory-accounting")]
+pub struct Accounting;
+
+#[cfg(feature = "memory-accounting")]
+#[global_allocator]
+static GLOBAL: Accounting = Accounting;
+
+#[cfg(feature = "memory-accounting")]
+unsafe impl std::alloc::GlobalAlloc for Accounting {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let ptr = unsafe { std::alloc::System.alloc(layout) };
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let ptr = unsafe { std::alloc::System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        unsafe { std::alloc::System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { std::alloc::System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_add(new_size as u64, Ordering::Relaxed);
+            ALLOCATED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
@@ -0,0 +1,79 @@
+//! File lengths and block sizes.
+//!
+//! Finding a file's size by looking for its pages one GET at a time costs a round trip
+//! per page, on every `file_size`. Instead each file's length is stored under
+//! `{path}:meta`, kept in step with its pages: written with them on write, atomic commit
+//! and the first sync of a new file, and with the new size on truncate. `file_size` is
+//! then one lookup, usually served from the cache.
+//!
+//! The record also holds the file's block size, the bytes stored per page key. It is
+//! 4096 unless chosen when the file is created, with `BLOCK_SIZE` for every new file,
+//! `file:orders.db?block_size=65536` for one, or `PRAGMA s3qlite_block_size=N` while it
+//! is still empty. Databases with larger SQLite pages then store each page as one
+//! object rather than sixteen. The block size of a file never changes once it has data,
+//! and every process reads it from the record, so files made with different sizes are
+//! used side by side.
+//!
+//! The record is `{length} {block size}`, in decimal. Files stored before it existed
+//! have no `{path}:meta`: their size still comes from scanning their pages and any
+//! `{path}:extent` left by a gap, see `extent`, their blocks are 4096 bytes, and the record is written with the next change
+//! to them.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The block size of files without one of their own.
+pub const DEFAULT_BLOCK_SIZE: usize = crate::PAGE_SIZE;
+
+pub fn meta_key(path: &str) -> String {
+    format!("{path}:meta")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Meta {
+    pub len: usize,
+    pub block_size: usize,
+}
+
+impl Meta {
+    pub fn encode(&self) -> String {
+        format!("{} {}", self.len, self.block_size)
+    }
+}
+
+/// A stored record. One holding only a length predates block sizes.
+pub fn parse(value: &[u8]) -> Option<Meta> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (len, block_size) = match value.split_once(' ') {
+        Some((len, block_size)) => (len, block_size.parse().ok()?),
+        None => (value, DEFAULT_BLOCK_SIZE),
+    };
+    Some(Meta {
+        len: len.parse().ok()?,
+        block_size,
+    })
+}
+
+/// Block sizes a file may use: powers of two from 512 bytes to 1MiB.
+pub fn valid_block_size(block_size: usize) -> bool {
+    block_size.is_power_of_two() && (512..=1 << 20).contains(&block_size)
+}
+
+/// The block size of one file, known from its open on.
+#[derive(Debug)]
+pub struct BlockSize(AtomicUsize);
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        Self(AtomicUsize::new(DEFAULT_BLOCK_SIZE))
+    }
+}
+
+impl BlockSize {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, block_size: usize) {
+        self.0.store(block_size, Ordering::Release);
+    }
+}
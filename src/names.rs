@@ -0,0 +1,106 @@
+//! Opaque storage names for database paths.
+//!
+//! Keys start with the database path, so anyone who can read the bucket learns every
+//! tenant or customer name. With `PATH_KEY` set (32 bytes, hex), each path component is
+//! replaced by a truncated HMAC-SHA256 of it before any key is built: `acme/orders.db`
+//! is stored as `9c1f…/4be0…`. The mapping is deterministic, so the same path always
+//! finds the same data, and the directory structure is kept.
+//!
+//! For administration the real path of each database is sealed with AES-256-GCM under
+//! `s3qlite:names:{stored path}`, listed by `PRAGMA s3qlite_names`.
+
+use ring::aead::{self, AES_256_GCM, LessSafeKey, Nonce, UnboundKey};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub const NAMES_PREFIX: &str = "s3qlite:names:";
+
+/// Bytes of the HMAC kept per component, hex encoded in the key.
+const COMPONENT_BYTES: usize = 16;
+
+pub fn names_key(stored_path: &str) -> String {
+    format!("{NAMES_PREFIX}{stored_path}")
+}
+
+pub struct NameCodec {
+    hmac: hmac::Key,
+    aead: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl NameCodec {
+    pub fn from_hex(key: &str) -> Result<Self, String> {
+        let key = decode_hex(key.trim())
+            .filter(|key| key.len() == 32)
+            .ok_or("PATH_KEY must be 32 bytes, hex encoded")?;
+        // separate keys for naming and sealing, both derived from the configured one
+        let hmac = hmac::Key::new(hmac::HMAC_SHA256, &key);
+        let sealing_key = hmac::sign(&hmac, b"s3qlite name table");
+        let aead = UnboundKey::new(&AES_256_GCM, sealing_key.as_ref())
+            .map_err(|_| "failed to derive the name table key")?;
+        Ok(Self {
+            hmac,
+            aead: LessSafeKey::new(aead),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// The name `path` is stored under.
+    pub fn encode(&self, path: &str) -> String {
+        path.split('/')
+            .map(|component| {
+                if component.is_empty() {
+                    return String::new();
+                }
+                let tag = hmac::sign(&self.hmac, component.as_bytes());
+                tag.as_ref()[..COMPONENT_BYTES]
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Encrypt `path` for the name table, as nonce followed by ciphertext.
+    pub fn seal(&self, path: &str) -> Result<Vec<u8>, String> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| "failed to generate a nonce")?;
+        let mut sealed = path.as_bytes().to_vec();
+        self.aead
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| "failed to seal name")?;
+        Ok([&nonce[..], &sealed].concat())
+    }
+
+    /// Decrypt a name table entry, or `None` if it wasn't sealed with this key.
+    pub fn open(&self, sealed: &[u8]) -> Option<String> {
+        if sealed.len() < aead::NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut buf = ciphertext.to_vec();
+        let path = self
+            .aead
+            .open_in_place(nonce, aead::Aad::empty(), &mut buf)
+            .ok()?;
+        String::from_utf8(path.to_vec()).ok()
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
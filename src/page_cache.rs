@@ -0,0 +1,346 @@
+use crate::eviction::{Policy, PolicyKind};
+use parking_lot::Mutex;
+use slatedb::bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_MAX_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Write generations are kept per stripe of keys rather than per key, so they take no
+/// more memory as the cache grows. Two keys sharing a stripe only cost a skipped fill.
+const GENERATION_STRIPES: usize = 1024;
+
+/// In-memory cache of backing pages, keyed by their storage key. Which pages are
+/// evicted is up to the configured policy, see `eviction`.
+///
+/// Pinned keys are never evicted and do not count against the capacity, so page 1,
+/// the schema pages and hot table roots stay resident across idle periods.
+///
+/// A value read from the store goes in with `fill`, not `insert`, against the
+/// `generation` of its key from before the read. A write or removal of the key while the
+/// read was in flight makes the fill lose, so a prefetch, a preload or a warm running
+/// alongside a commit can't put the page back as it was before it.
+pub struct PageCache {
+    max_bytes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: HashMap<Vec<u8>, Bytes>,
+    // tracks every unpinned entry
+    policy: Policy,
+    pinned: HashSet<Vec<u8>>,
+    simulation: Option<Simulation>,
+    // bumped by every write or removal of a key in the stripe
+    generations: Vec<u64>,
+}
+
+/// Data-less policies of every kind fed the same reads and inserts as the cache.
+struct Simulation {
+    policies: Vec<(Policy, u64, u64)>,
+}
+
+impl PageCache {
+    pub fn new(max_bytes: u64, policy: PolicyKind, simulate: bool) -> Self {
+        Self {
+            max_bytes: AtomicU64::new(max_bytes),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                policy: Policy::new(policy, max_bytes),
+                pinned: HashSet::new(),
+                simulation: simulate.then(|| Simulation {
+                    policies: PolicyKind::ALL
+                        .into_iter()
+                        .map(|kind| (Policy::new(kind, max_bytes), 0, 0))
+                        .collect(),
+                }),
+                generations: vec![0; GENERATION_STRIPES],
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        let max_bytes = self.max_bytes();
+        let mut inner = self.inner.lock();
+        if !inner.pinned.contains(key) {
+            inner.policy.access(key, max_bytes);
+            if let Some(simulation) = &mut inner.simulation {
+                for (policy, hits, misses) in &mut simulation.policies {
+                    match policy.access(key, max_bytes) {
+                        true => *hits += 1,
+                        false => *misses += 1,
+                    }
+                }
+            }
+        }
+        let data = inner.entries.get(key).cloned();
+        match &data {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        data
+    }
+
+    /// Cache `data` as written to `key`.
+    pub fn insert(&self, key: &[u8], data: Bytes) {
+        let max_bytes = self.max_bytes();
+        let mut inner = self.inner.lock();
+        inner.generations[stripe(key)] += 1;
+        inner.put(key, data, max_bytes);
+    }
+
+    /// The write generation of `key`, to `fill` it with a value read after this.
+    pub fn generation(&self, key: &[u8]) -> u64 {
+        self.inner.lock().generations[stripe(key)]
+    }
+
+    /// Cache `data` as read from the store for `key`, unless the key was written or
+    /// removed since `generation`. Returns whether it was cached.
+    pub fn fill(&self, key: &[u8], data: Bytes, generation: u64) -> bool {
+        let max_bytes = self.max_bytes();
+        let mut inner = self.inner.lock();
+        if inner.generations[stripe(key)] != generation {
+            return false;
+        }
+        inner.put(key, data, max_bytes);
+        true
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        let mut inner = self.inner.lock();
+        inner.generations[stripe(key)] += 1;
+        inner.remove(key);
+    }
+
+    /// Drop every cached and pinned key starting with `prefix`.
+    pub fn remove_prefix(&self, prefix: &[u8]) {
+        let mut inner = self.inner.lock();
+        let keys: Vec<Vec<u8>> = inner
+            .entries
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            inner.remove(&key);
+        }
+        inner.pinned.retain(|k| !k.starts_with(prefix));
+        // the keys in flight under the prefix aren't known, so every fill loses
+        for generation in &mut inner.generations {
+            *generation += 1;
+        }
+    }
+
+    /// Pin a key so it is never evicted. Returns false if the key is not cached yet,
+    /// in which case the caller should load it and `insert` it.
+    pub fn pin(&self, key: &[u8]) -> bool {
+        let mut inner = self.inner.lock();
+        if !inner.pinned.insert(key.to_vec()) {
+            return inner.entries.contains_key(key);
+        }
+        if !inner.entries.contains_key(key) {
+            return false;
+        }
+        // pinned pages don't count against the capacity
+        inner.policy.remove(key);
+        true
+    }
+
+    /// Up to `limit` cached keys, pinned keys first and then the ones the policy most
+    /// wants to keep.
+    pub fn hot_keys(&self, limit: usize) -> Vec<Vec<u8>> {
+        let inner = self.inner.lock();
+        inner
+            .pinned
+            .iter()
+            .filter(|k| inner.entries.contains_key(*k))
+            .chain(inner.policy.hot_keys())
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Up to `limit` cached entries, starting from a different place on every call.
+    pub fn sample(&self, limit: usize) -> Vec<(Vec<u8>, Bytes)> {
+        use std::hash::{BuildHasher, Hasher};
+        let inner = self.inner.lock();
+        if inner.entries.is_empty() {
+            return Vec::new();
+        }
+        let start = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish() as usize
+            % inner.entries.len();
+        inner
+            .entries
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(limit.min(inner.entries.len()))
+            .map(|(key, data)| (key.clone(), data.clone()))
+            .collect()
+    }
+
+    /// Cached keys starting with `prefix`, with the length of their data.
+    pub fn lens_with_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, usize)> {
+        let inner = self.inner.lock();
+        inner
+            .entries
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, data)| (k.clone(), data.len()))
+            .collect()
+    }
+
+    /// Whether any key starting with `prefix` is cached.
+    pub fn contains_prefix(&self, prefix: &[u8]) -> bool {
+        let inner = self.inner.lock();
+        inner.entries.keys().any(|k| k.starts_with(prefix))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.inner.lock().entries.contains_key(key)
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Change the capacity, evicting immediately if it shrank.
+    pub fn set_max_bytes(&self, max_bytes: u64) {
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+        let mut inner = self.inner.lock();
+        for evicted in inner.policy.evict(max_bytes) {
+            inner.entries.remove(&evicted);
+        }
+        if let Some(simulation) = &mut inner.simulation {
+            for (policy, _, _) in &mut simulation.policies {
+                policy.evict(max_bytes);
+            }
+        }
+    }
+
+    /// Bytes held by unpinned entries.
+    pub fn bytes(&self) -> u64 {
+        self.inner.lock().policy.bytes()
+    }
+
+    /// `{"policy":…,"hits":…,"misses":…,"simulated":…}`, with the hits and misses each
+    /// policy would have had when `CACHE_SIMULATE` is on, or a null `simulated`.
+    pub fn policy_json(&self) -> String {
+        let inner = self.inner.lock();
+        let simulated = match &inner.simulation {
+            Some(simulation) => {
+                let policies: Vec<String> = simulation
+                    .policies
+                    .iter()
+                    .map(|(policy, hits, misses)| {
+                        format!(
+                            "\"{}\":{{\"hits\":{hits},\"misses\":{misses}}}",
+                            policy.kind().name()
+                        )
+                    })
+                    .collect();
+                format!("{{{}}}", policies.join(","))
+            }
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"policy\":\"{}\",\"hits\":{},\"misses\":{},\"simulated\":{simulated}}}",
+            inner.policy.kind().name(),
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        )
+    }
+
+    pub fn pinned_count(&self, prefix: &[u8]) -> usize {
+        let inner = self.inner.lock();
+        inner
+            .pinned
+            .iter()
+            .filter(|k| k.starts_with(prefix))
+            .count()
+    }
+}
+
+fn stripe(key: &[u8]) -> usize {
+    xxhash_rust::xxh3::xxh3_64(key) as usize % GENERATION_STRIPES
+}
+
+impl Inner {
+    fn put(&mut self, key: &[u8], data: Bytes, max_bytes: u64) {
+        if self.pinned.contains(key) {
+            self.entries.insert(key.to_vec(), data);
+            return;
+        }
+        let len = data.len() as u64;
+        self.entries.insert(key.to_vec(), data);
+        for evicted in self.policy.insert(key, len, max_bytes) {
+            self.entries.remove(&evicted);
+        }
+        if let Some(simulation) = &mut self.simulation {
+            for (policy, _, _) in &mut simulation.policies {
+                policy.insert(key, len, max_bytes);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if self.entries.remove(key).is_some() {
+            self.policy.remove(key);
+        }
+        if let Some(simulation) = &mut self.simulation {
+            for (policy, _, _) in &mut simulation.policies {
+                policy.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> PageCache {
+        PageCache::new(DEFAULT_MAX_CACHE_BYTES, PolicyKind::Lru, false)
+    }
+
+    #[test]
+    fn fill_loses_to_a_commit_while_reading() {
+        let cache = cache();
+        let key = b"app.db:page:4096";
+        // a prefetch notes the generation and starts reading the page as committed
+        let generation = cache.generation(key);
+        // a commit lands, writing the page and caching it
+        cache.insert(key, Bytes::from_static(b"new"));
+        // the prefetch's read of the old page comes back after
+        assert!(!cache.fill(key, Bytes::from_static(b"old"), generation));
+        assert_eq!(cache.get(key), Some(Bytes::from_static(b"new")));
+
+        // a read started after the commit fills as usual
+        cache.remove(key);
+        let generation = cache.generation(key);
+        assert!(cache.fill(key, Bytes::from_static(b"new"), generation));
+        assert_eq!(cache.get(key), Some(Bytes::from_static(b"new")));
+    }
+
+    #[test]
+    fn fill_loses_to_a_removal_while_reading() {
+        let cache = cache();
+        let key = b"app.db:page:0";
+        let generation = cache.generation(key);
+        cache.remove(key);
+        assert!(!cache.fill(key, Bytes::from_static(b"old"), generation));
+        assert!(!cache.contains(key));
+
+        // truncating or deleting the file removes every page under its prefix
+        let generation = cache.generation(key);
+        cache.remove_prefix(b"app.db:page:");
+        assert!(!cache.fill(key, Bytes::from_static(b"old"), generation));
+        assert!(!cache.contains(key));
+    }
+}
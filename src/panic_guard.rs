@@ -0,0 +1,23 @@
+//! Panics must never unwind out of a VFS callback into SQLite: that is undefined
+//! behaviour across the FFI boundary and at best aborts the host process.
+
+use std::any::Any;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+/// Run `f`, converting a panic into `on_panic` after logging which operation failed.
+pub fn catch_panic<T, E>(op: &str, on_panic: E, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        log::error!("{op} panicked: {}", panic_message(&*panic));
+        Err(on_panic)
+    })
+}
+
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
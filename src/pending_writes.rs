@@ -0,0 +1,312 @@
+//! Writes buffered between BEGIN_ATOMIC_WRITE and COMMIT_ATOMIC_WRITE.
+//!
+//! Once the buffered bytes pass the spill threshold, further writes are appended to an
+//! anonymous temp file and read back one at a time during commit, so a large transaction
+//! costs disk space rather than memory while it's being written. While the VFS is over
+//! its memory budget every write is spilled, see `memory_budget`.
+//!
+//! The commit can't stream: SlateDB applies a write batch atomically from memory, so
+//! every page the transaction changes is loaded, patched and copied into one batch, up
+//! to `COMMIT_COPIES` copies of each. What bounds it is `PENDING_WRITES_MAX_BYTES`: a
+//! transaction that buffers more fails with `SQLITE_FULL` while it's written, and one
+//! whose pages come to more fails at commit, before any of them is loaded. By default
+//! the limit keeps a commit within `COMMIT_INFLIGHT_BYTES` and `MEMORY_BUDGET_BYTES`, so
+//! a multi-gigabyte transaction is refused rather than running the process out of
+//! memory. With the limit set to 0 nothing bounds it.
+//!
+//! A write that completely covers an earlier write of the same transaction supersedes
+//! it, so the earlier one is dropped instead of being applied and then overwritten.
+//! Writes are split at page boundaries when buffered, so this goes page by page: a write
+//! over several pages supersedes the pieces of earlier writes in each of them. A piece
+//! only covered by several later writes together is kept, and applied before them.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static SPILL_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Copies of each changed page a commit holds at its peak: the stored image, the patched
+/// one and the write batch's.
+pub const COMMIT_COPIES: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    pub threshold_bytes: usize,
+    pub dir: PathBuf,
+}
+
+enum Data {
+    Memory(Vec<u8>),
+    Spilled { pos: u64, len: usize },
+}
+
+struct PendingWrite {
+    offset: usize,
+    data: Data,
+}
+
+pub struct PendingWrites {
+    config: SpillConfig,
+    // superseded writes are replaced with None
+    writes: Vec<Option<PendingWrite>>,
+    // page offset -> indexes of the live writes starting in that page
+    by_page: HashMap<usize, Vec<usize>>,
+    live: usize,
+    superseded: usize,
+    memory_bytes: usize,
+    spill: Option<File>,
+    spill_len: u64,
+}
+
+impl PendingWrites {
+    pub fn new(config: SpillConfig) -> Self {
+        Self {
+            config,
+            writes: Vec::new(),
+            by_page: HashMap::new(),
+            live: 0,
+            superseded: 0,
+            memory_bytes: 0,
+            spill: None,
+            spill_len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Bytes buffered in memory and in the spill file.
+    pub fn bytes(&self) -> u64 {
+        self.memory_bytes as u64 + self.spill_len
+    }
+
+    /// Bytes buffered in the spill file.
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spill_len
+    }
+
+    /// Number of writes dropped because a later write covered them.
+    pub fn superseded(&self) -> usize {
+        self.superseded
+    }
+
+    /// File offsets of the buffered writes, without reading any spilled data.
+    pub fn offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.writes.iter().flatten().map(|w| w.offset)
+    }
+
+    /// Buffer a write, split at the boundaries of `block_size` pages so each buffered
+    /// write lies in one page.
+    pub fn push(&mut self, offset: usize, data: &[u8], block_size: usize) -> std::io::Result<()> {
+        for (page_offset, offset_in_page, range) in
+            crate::page_spans(block_size, offset, data.len())
+        {
+            self.push_in_page(page_offset, offset_in_page, &data[range])?;
+        }
+        Ok(())
+    }
+
+    fn push_in_page(
+        &mut self,
+        page_offset: usize,
+        offset_in_page: usize,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let offset = page_offset + offset_in_page;
+        self.drop_covered(page_offset, offset, data.len());
+        self.by_page
+            .entry(page_offset)
+            .or_default()
+            .push(self.writes.len());
+        self.live += 1;
+
+        if self.memory_bytes + data.len() <= self.config.threshold_bytes
+            && !crate::memory_budget::degraded()
+        {
+            self.memory_bytes += data.len();
+            self.writes.push(Some(PendingWrite {
+                offset,
+                data: Data::Memory(data.to_vec()),
+            }));
+            return Ok(());
+        }
+        let pos = self.spill_len;
+        let file = match &mut self.spill {
+            Some(file) => file,
+            None => {
+                log::info!(
+                    "pending writes passed {} bytes, spilling to {}",
+                    self.config.threshold_bytes,
+                    self.config.dir.display()
+                );
+                self.spill.insert(create_spill_file(&self.config.dir)?)
+            }
+        };
+        file.seek(SeekFrom::Start(pos))?;
+        file.write_all(data)?;
+        self.spill_len += data.len() as u64;
+        self.writes.push(Some(PendingWrite {
+            offset,
+            data: Data::Spilled {
+                pos,
+                len: data.len(),
+            },
+        }));
+        Ok(())
+    }
+
+    /// Drop earlier writes in the same page that `[offset, offset + len)`, a write within
+    /// that page, fully covers.
+    /// Space in the spill file is not reclaimed, the write is just skipped on commit.
+    fn drop_covered(&mut self, page_offset: usize, offset: usize, len: usize) {
+        let Some(indexes) = self.by_page.get_mut(&page_offset) else {
+            return;
+        };
+        let writes = &mut self.writes;
+        let mut freed = 0;
+        let mut dropped = 0;
+        indexes.retain(|&i| {
+            let Some(write) = &writes[i] else {
+                return false;
+            };
+            let write_len = match &write.data {
+                Data::Memory(data) => data.len(),
+                Data::Spilled { len, .. } => *len,
+            };
+            let covered = write.offset >= offset && write.offset + write_len <= offset + len;
+            if covered {
+                if let Data::Memory(data) = &write.data {
+                    freed += data.len();
+                }
+                writes[i] = None;
+                dropped += 1;
+            }
+            !covered
+        });
+        self.memory_bytes -= freed;
+        self.live -= dropped;
+        self.superseded += dropped;
+    }
+
+    /// Take every buffered write, leaving this buffer empty.
+    pub fn take(&mut self) -> PendingWrites {
+        std::mem::replace(self, PendingWrites::new(self.config.clone()))
+    }
+
+    pub fn clear(&mut self) {
+        self.take();
+    }
+
+    /// Visit the writes in the order they were made. Spilled writes are read back one at
+    /// a time.
+    pub fn for_each(
+        mut self,
+        mut f: impl FnMut(usize, &[u8]) -> Result<(), i32>,
+    ) -> Result<(), i32> {
+        let mut buf = Vec::new();
+        for write in self.writes.iter().flatten() {
+            match &write.data {
+                Data::Memory(data) => f(write.offset, data)?,
+                Data::Spilled { pos, len } => {
+                    let file = self
+                        .spill
+                        .as_mut()
+                        .ok_or(sqlite_plugin::vars::SQLITE_INTERNAL)?;
+                    buf.resize(*len, 0);
+                    file.seek(SeekFrom::Start(*pos))
+                        .and_then(|_| file.read_exact(&mut buf))
+                        .map_err(|e| {
+                            log::error!("failed to read back spilled write: {e}");
+                            sqlite_plugin::vars::SQLITE_IOERR_READ
+                        })?;
+                    f(write.offset, &buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create a spill file and unlink it straight away, so it disappears with the process
+/// even if the transaction never finishes.
+fn create_spill_file(dir: &std::path::Path) -> std::io::Result<File> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "s3qlite-spill-{}-{}",
+        std::process::id(),
+        SPILL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 16;
+
+    fn pending(threshold_bytes: usize) -> PendingWrites {
+        PendingWrites::new(SpillConfig {
+            threshold_bytes,
+            dir: std::env::temp_dir(),
+        })
+    }
+
+    fn writes(pending: PendingWrites) -> Vec<(usize, Vec<u8>)> {
+        let mut writes = Vec::new();
+        pending
+            .for_each(|offset, data| {
+                writes.push((offset, data.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        writes
+    }
+
+    #[test]
+    fn a_write_over_several_pages_supersedes_earlier_writes_in_each() {
+        for threshold_bytes in [usize::MAX, 0] {
+            let mut pending = pending(threshold_bytes);
+            pending.push(2, &[1; 4], BLOCK).unwrap();
+            // split into [12, 16) and [16, 20), each covered on its own page
+            pending.push(12, &[2; 8], BLOCK).unwrap();
+            pending.push(40, &[3; 2], BLOCK).unwrap();
+            pending.push(0, &[9; 48], BLOCK).unwrap();
+            assert_eq!(pending.superseded(), 4);
+            assert_eq!(pending.len(), 3);
+            assert_eq!(
+                writes(pending),
+                [(0, vec![9; 16]), (16, vec![9; 16]), (32, vec![9; 16])]
+            );
+        }
+    }
+
+    #[test]
+    fn partly_covered_writes_are_kept() {
+        let mut pending = pending(usize::MAX);
+        pending.push(4, &[1; 8], BLOCK).unwrap();
+        // overlaps the first write without covering it
+        pending.push(8, &[2; 8], BLOCK).unwrap();
+        // covers it together with the second write
+        pending.push(0, &[3; 8], BLOCK).unwrap();
+        assert_eq!(pending.superseded(), 0);
+        assert_eq!(
+            writes(pending),
+            [(4, vec![1; 8]), (8, vec![2; 8]), (0, vec![3; 8])]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Read-only connections.
+//!
+//! A database opened read-only, `SQLITE_OPEN_READONLY` or `?mode=ro`, is read as it was
+//! when it was opened, whatever is committed to it meanwhile. The open checkpoints the
+//! store, flushing what this process has written first, and the handle reads at the
+//! checkpoint the way `orders.db@nightly` reads a snapshot. It takes no locks, so writers
+//! go on committing while it reads, and it reports RESERVED as held so SQLite leaves the
+//! writers' journals alone rather than rolling them back as hot. Reopen for a newer view.
+//!
+//! Each open costs a memtable flush and a manifest write. The checkpoint lives for
+//! `LIFETIME`, and a handle still reading halfway through it extends it, so one left by
+//! a process that crashed expires on its own. Closing the handle deletes it.
+//!
+//! `file:orders.db?as_of=2024-06-01T00:00:00Z`, or `PRAGMA s3qlite_as_of='...'` on a
+//! read-only connection between transactions, reads the database as it was at the newest
+//! checkpoint still retained from before then: a snapshot's, see `snapshot`, or another
+//! reader's. The handle clones it into a checkpoint of its own, so it reads on even if
+//! that one expires or its snapshot is deleted, and a clone dates from when the state it
+//! holds was first checkpointed, not from when it was made. SlateDB keeps checkpoint
+//! times to the second, so one made in the same second as the time asked for counts as
+//! after it. Times are RFC 3339, `Z` or an offset, or a bare date for midnight UTC. The
+//! pragma reports the checkpoint and the time read at, as JSON.
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// The checkpoint a read-only handle reads at.
+#[derive(Debug)]
+pub struct Pinned {
+    pub checkpoint: uuid::Uuid,
+    /// When the state read was current
+    pub as_of: SystemTime,
+    refreshed_at: Mutex<Instant>,
+    // SHARED or more is held, SQLite is in a read transaction
+    reading: AtomicBool,
+}
+
+impl Pinned {
+    pub fn new(checkpoint: uuid::Uuid, as_of: SystemTime, now: Instant) -> Self {
+        Self {
+            checkpoint,
+            as_of,
+            refreshed_at: Mutex::new(now),
+            reading: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_reading(&self, reading: bool) {
+        self.reading.store(reading, Ordering::Relaxed);
+    }
+
+    pub fn is_reading(&self) -> bool {
+        self.reading.load(Ordering::Relaxed)
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"checkpoint\":\"{}\",\"as_of_ms\":{}}}",
+            self.checkpoint,
+            crate::ttl::unix_ms(self.as_of)
+        )
+    }
+
+    /// Whether the checkpoint is halfway through its lifetime, counting it as extended
+    /// from `now` if so.
+    pub fn take_due(&self, now: Instant) -> bool {
+        let mut refreshed_at = self.refreshed_at.lock();
+        if now.saturating_duration_since(*refreshed_at) < LIFETIME / 2 {
+            return false;
+        }
+        *refreshed_at = now;
+        true
+    }
+}
+
+/// Parse an `as_of` time, `2024-06-01T12:30:00Z`, `2024-06-01T14:30:00.5+02:00` or
+/// `2024-06-01`.
+pub fn parse_time(value: &str) -> Option<SystemTime> {
+    let (date, time) = value
+        .split_once(['T', 't', ' '])
+        .unwrap_or((value, "00:00:00Z"));
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: i64 = date.next()?.parse().ok()?;
+    let day: i64 = date.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, offset_secs) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let at = time.rfind(['+', '-'])?;
+            let (hours, minutes) = time[at + 1..].split_once(':')?;
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            let sign = if time[at..].starts_with('-') { -1 } else { 1 };
+            (&time[..at], sign * offset)
+        }
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let minute: i64 = time.next()?.parse().ok()?;
+    let second: i64 = time.next()?.parse().ok()?;
+    if hour > 23 || minute > 59 || second > 60 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{fraction:0<9}").get(..9)?.parse::<u32>().ok()?;
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+        - offset_secs;
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
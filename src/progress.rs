@@ -0,0 +1,63 @@
+//! Progress reports and cancellation for long VFS operations.
+//!
+//! Deleting or truncating a large file first lists its pages to find the keys to
+//! remove, which can take a while against S3 while the calling SQLite thread waits. A host registers a callback with `s3qlite_progress_handler`, called every
+//! `CHUNK_PAGES` pages with the operation, the file and the pages done so far. Returning
+//! non-zero cancels the operation with `SQLITE_INTERRUPT` before anything is changed.
+//!
+//! Warming the cache from a manifest, or preloading it, reports the same way from a
+//! background thread, and stops early when cancelled.
+
+use parking_lot::RwLock;
+use std::ffi::{CString, c_char, c_int, c_void};
+
+/// Pages between progress reports.
+pub const CHUNK_PAGES: usize = 256;
+
+pub type Callback = unsafe extern "C" fn(
+    arg: *mut c_void,
+    operation: *const c_char,
+    path: *const c_char,
+    pages_done: u64,
+) -> c_int;
+
+struct Handler {
+    callback: Callback,
+    arg: *mut c_void,
+}
+
+// the host promises `arg` can be used from any thread, see `s3qlite_progress_handler`
+unsafe impl Send for Handler {}
+unsafe impl Sync for Handler {}
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+pub fn set_handler(callback: Option<Callback>, arg: *mut c_void) {
+    *HANDLER.write() = callback.map(|callback| Handler { callback, arg });
+}
+
+/// Report `pages_done` pages of `operation` on `path`, and yield so the operation doesn't
+/// hold its runtime thread. Fails with `SQLITE_INTERRUPT` if the host cancelled.
+pub async fn report(operation: &str, path: &str, pages_done: usize) -> Result<(), i32> {
+    tokio::task::yield_now().await;
+    let handler = HANDLER.read();
+    let Some(handler) = &*handler else {
+        return Ok(());
+    };
+    let (Ok(operation), Ok(path)) = (CString::new(operation), CString::new(path)) else {
+        return Ok(());
+    };
+    let cancel = unsafe {
+        (handler.callback)(
+            handler.arg,
+            operation.as_ptr(),
+            path.as_ptr(),
+            pages_done as u64,
+        )
+    };
+    if cancel != 0 {
+        log::info!("{operation:?} of {path:?} cancelled after {pages_done} pages");
+        return Err(sqlite_plugin::vars::SQLITE_INTERRUPT);
+    }
+    Ok(())
+}
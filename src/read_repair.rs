@@ -0,0 +1,34 @@
+//! Serving pages lost from the live store out of snapshots.
+//!
+//! A database's pages are never missing below the size in its header: SQLite only
+//! shrinks a database after lowering it. A page that is missing anyway was lost, to a
+//! bad garbage collection say, and SQLite would report the database as corrupt, or read
+//! zeros where a gap is allowed (see `extent`).
+//!
+//! Instead the read looks the page up in the database's snapshots, newest checkpoint
+//! first, and serves the first copy found. The repair is logged and counted in
+//! `pages_repaired` of `PRAGMA s3qlite_stats`, and the page kept in the cache; it's
+//! stored again the next time SQLite writes it. A page no snapshot has is counted in
+//! `page_repairs_failed` and read as before.
+//!
+//! The size in the header is only trusted when SQLite marked it valid, and only for
+//! files that start with the SQLite header. `READ_REPAIR=false` turns repairs off.
+
+const MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// The size in bytes the SQLite header on `page` gives its database, when it's valid.
+pub fn header_size(page: &[u8]) -> Option<usize> {
+    if page.len() < 100 || !page.starts_with(MAGIC) {
+        return None;
+    }
+    let be = |offset: usize| u32::from_be_bytes(page[offset..offset + 4].try_into().unwrap());
+    // the page count is only valid if the change counter matches version-valid-for
+    if be(24) != be(92) {
+        return None;
+    }
+    let page_size = match u16::from_be_bytes([page[16], page[17]]) {
+        1 => 65536,
+        n => n as usize,
+    };
+    Some(be(28) as usize * page_size).filter(|&size| size > 0)
+}
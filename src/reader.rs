@@ -0,0 +1,284 @@
+//! A read-only Rust API over the stored databases, for exporters, verifiers and
+//! converters that would otherwise have to go through SQL or scrape the REPL.
+//!
+//! `Reader::open` reads the store as it was when opened, so what it returns belongs to
+//! one commit generation whatever is written meanwhile, for up to ten minutes. Longer
+//! jobs, or ones that have to see exactly the same data again later, read a snapshot
+//! with `Reader::open_snapshot`, which never moves. A reader only sees what SlateDB
+//! has made durable. Paths are the stored ones: with `PATH_KEY` set that's the opaque
+//! names, see `names`.
+//!
+//! The key layout behind this may change, this API is what stays.
+
+use crate::{extent, generation, meta, readonly, size_limit, snapshot};
+use slatedb::bytes::Bytes;
+use slatedb::config::DbReaderOptions;
+use slatedb::object_store::ObjectStore;
+use slatedb::{DbIterator, DbReader};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long `Reader::open` holds the state it opened at before moving on to the latest.
+const PINNED_FOR: Duration = Duration::from_secs(10 * 60);
+
+pub struct Reader {
+    reader: DbReader,
+}
+
+/// What's stored about a database besides its pages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Database {
+    /// Length of the database file in bytes, see `meta`
+    pub len: u64,
+    /// Bytes stored per page key, see `meta`
+    pub block_size: usize,
+    /// The commit generation read, see `generation`
+    pub generation: u64,
+    /// Size cap in bytes, see `size_limit`
+    pub size_limit: Option<u64>,
+    /// Marked with `PRAGMA s3qlite_set_readonly`
+    pub readonly: bool,
+    /// Names of the snapshots taken of it, sorted
+    pub snapshots: Vec<String>,
+}
+
+/// A stored page, `data` is what sits at `offset` in the database file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub offset: usize,
+    pub data: Bytes,
+}
+
+impl Reader {
+    /// Open the store at `url`, any `OBJECT_STORE_URL` but `memory://`, at its current
+    /// state.
+    pub async fn open(url: &str) -> Result<Self, String> {
+        Self::open_store(crate::store::object_store_from_url(Some(url), None)?).await
+    }
+
+    pub async fn open_store(object_store: Arc<dyn ObjectStore>) -> Result<Self, String> {
+        // SlateDB checkpoints the state for the reader itself, and would move it on at
+        // every poll
+        let options = DbReaderOptions {
+            manifest_poll_interval: PINNED_FOR,
+            checkpoint_lifetime: PINNED_FOR * 2 + Duration::from_secs(60),
+            ..DbReaderOptions::default()
+        };
+        Self::open_reader(object_store, None, options).await
+    }
+
+    /// Open snapshot `name` of `path` in the store at `url`.
+    pub async fn open_snapshot(url: &str, path: &str, name: &str) -> Result<Self, String> {
+        let object_store = crate::store::object_store_from_url(Some(url), None)?;
+        // the snapshot's checkpoint id is only found by reading the live database
+        let live = Self::open_store(object_store.clone()).await?;
+        let id = live.get(&snapshot::snapshot_key(path, name)).await;
+        live.close().await?;
+        let id = id?.ok_or_else(|| format!("no snapshot named {name} of {path}"))?;
+        let id = String::from_utf8_lossy(&id).into_owned();
+        let checkpoint = uuid::Uuid::parse_str(&id)
+            .map_err(|e| format!("snapshot {path}@{name} has a bad checkpoint id {id}: {e}"))?;
+        Self::open_reader(object_store, Some(checkpoint), DbReaderOptions::default()).await
+    }
+
+    async fn open_reader(
+        object_store: Arc<dyn ObjectStore>,
+        checkpoint: Option<uuid::Uuid>,
+        options: DbReaderOptions,
+    ) -> Result<Self, String> {
+        let reader = DbReader::open(crate::SLATEDB_PATH, object_store, checkpoint, options)
+            .await
+            .map_err(|e| format!("error opening the store: {e}"))?;
+        Ok(Self { reader })
+    }
+
+    /// What's stored about `path`, None if there's no such database.
+    pub async fn database(&self, path: &str) -> Result<Option<Database>, String> {
+        if self.get(path).await?.is_none() {
+            return Ok(None);
+        }
+        let meta = match self.get(&meta::meta_key(path)).await? {
+            Some(value) => meta::parse(&value).ok_or_else(|| format!("bad {path}:meta"))?,
+            None => self.scanned_meta(path).await?,
+        };
+        let generation = self
+            .get(&generation::generation_key(path))
+            .await?
+            .and_then(|value| generation::parse(&value))
+            .unwrap_or(0);
+        let size_limit = self
+            .get(&size_limit::size_limit_key(path))
+            .await?
+            .as_deref()
+            .and_then(size_limit::parse)
+            .filter(|&limit| limit > 0);
+        let readonly = self.get(&readonly::readonly_key(path)).await?.is_some();
+        let prefix = snapshot::snapshot_key(path, "");
+        let mut iter = self.scan_prefix(&prefix).await?;
+        let mut snapshots = Vec::new();
+        while let Some(kv) = iter.next().await.map_err(|e| scan_error(&prefix, e))? {
+            snapshots.push(String::from_utf8_lossy(&kv.key[prefix.len()..]).into_owned());
+        }
+        Ok(Some(Database {
+            len: meta.len as u64,
+            block_size: meta.block_size,
+            generation,
+            size_limit,
+            readonly,
+            snapshots,
+        }))
+    }
+
+    /// The length of `path` from the end of its last page, for databases stored before
+    /// `{path}:meta`.
+    async fn scanned_meta(&self, path: &str) -> Result<meta::Meta, String> {
+        let mut len = self
+            .get(&extent::legacy_key(path))
+            .await?
+            .as_deref()
+            .and_then(meta::parse)
+            .map_or(0, |meta| meta.len);
+        let mut pages = self.pages(path).await?;
+        while let Some(page) = pages.next().await? {
+            len = len.max(page.offset + page.data.len());
+        }
+        Ok(meta::Meta {
+            len,
+            block_size: meta::DEFAULT_BLOCK_SIZE,
+        })
+    }
+
+    /// Every stored page of `path`, streamed from the store. Pages come in key order,
+    /// not by offset.
+    pub async fn pages(&self, path: &str) -> Result<Pages<'_>, String> {
+        let prefix = format!("{path}:page:");
+        let iter = self.scan_prefix(&prefix).await?;
+        Ok(Pages { iter, prefix })
+    }
+
+    pub async fn close(self) -> Result<(), String> {
+        self.reader
+            .close()
+            .await
+            .map_err(|e| format!("error closing the reader: {e}"))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, String> {
+        self.reader
+            .get(key)
+            .await
+            .map_err(|e| format!("error reading {key}: {e}"))
+    }
+
+    async fn scan_prefix(&self, prefix: &str) -> Result<DbIterator<'_>, String> {
+        self.reader
+            .scan(crate::prefix_range(prefix))
+            .await
+            .map_err(|e| scan_error(prefix, e))
+    }
+}
+
+pub struct Pages<'a> {
+    iter: DbIterator<'a>,
+    prefix: String,
+}
+
+impl Pages<'_> {
+    pub async fn next(&mut self) -> Result<Option<Page>, String> {
+        while let Some(kv) = self
+            .iter
+            .next()
+            .await
+            .map_err(|e| scan_error(&self.prefix, e))?
+        {
+            let offset = std::str::from_utf8(&kv.key[self.prefix.len()..])
+                .ok()
+                .and_then(|offset| offset.parse().ok());
+            // another database's keys can share the prefix, e.g. `a.db:page:1:size_limit`
+            if let Some(offset) = offset {
+                return Ok(Some(Page {
+                    offset,
+                    data: kv.value,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn scan_error(prefix: &str, e: slatedb::SlateDBError) -> String {
+    format!("error scanning {prefix}: {e}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{Connection, OpenFlags};
+
+    #[test]
+    fn reads_back_a_database_written_through_the_vfs() {
+        let dir = std::env::temp_dir().join(format!("s3qlite_reader_{}", std::process::id()));
+        let url = format!("file://{}", dir.display());
+        let mut config = crate::env_config::EnvConfig::new();
+        config.object_store_url = Some(url.clone());
+        let vfs = crate::GrpcVfs::from_config(config).unwrap();
+        sqlite_plugin::vfs::register_static(
+            c"s3qlite_reader_test".to_owned(),
+            vfs,
+            sqlite_plugin::vfs::RegisterOpts {
+                make_default: false,
+            },
+        )
+        .unwrap();
+
+        let connection = Connection::open_with_flags_and_vfs(
+            "reader.db",
+            OpenFlags::default(),
+            c"s3qlite_reader_test",
+        )
+        .unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, body BLOB);
+                 WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 40)
+                 INSERT INTO t (body) SELECT randomblob(1000) FROM s;",
+            )
+            .unwrap();
+        let page_count: u64 = connection
+            .query_row("PRAGMA page_count", [], |row| row.get(0))
+            .unwrap();
+        let generation: String = connection
+            .query_row("PRAGMA s3qlite_generation", [], |row| row.get(0))
+            .unwrap();
+        // a reader only sees what's durable
+        connection.execute_batch("PRAGMA s3qlite_flush").unwrap();
+        drop(connection);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let reader = Reader::open(&url).await.unwrap();
+            let database = reader.database("reader.db").await.unwrap().unwrap();
+            assert_eq!(database.len, page_count * 4096);
+            assert_eq!(database.block_size, 4096);
+            assert_eq!(database.generation.to_string(), generation);
+            assert!(!database.readonly);
+
+            // the pages add up to the file SQLite wrote
+            let mut pages = reader.pages("reader.db").await.unwrap();
+            let mut file = vec![0; database.len as usize];
+            while let Some(page) = pages.next().await.unwrap() {
+                file[page.offset..page.offset + page.data.len()].copy_from_slice(&page.data);
+            }
+            drop(pages);
+            assert!(file.starts_with(b"SQLite format 3\0"));
+            assert_eq!(
+                u32::from_be_bytes(file[28..32].try_into().unwrap()) as u64,
+                page_count
+            );
+
+            assert_eq!(reader.database("missing.db").await.unwrap(), None);
+            reader.close().await.unwrap();
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
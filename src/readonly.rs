@@ -0,0 +1,29 @@
+//! Freezing a database at the VFS level.
+//!
+//! Published datasets are meant to stay as they are, but any host that can open them
+//! read-write can also change them. `PRAGMA s3qlite_set_readonly=true` marks a database
+//! read-only under `{path}:readonly`, so every process sees it: from then on it opens
+//! read-only whatever the flags, and writes through handles already open fail with
+//! `SQLITE_READONLY`. `PRAGMA s3qlite_set_readonly=false` lifts it for later opens.
+//!
+//! Changing the mark takes the `ADMIN_TOKEN` the process was configured with, given to
+//! the connection first with `PRAGMA s3qlite_admin_token='…'`. Without an `ADMIN_TOKEN`
+//! nobody can change it. Like size limits, a mark set by another process applies from
+//! the next open.
+
+pub fn readonly_key(path: &str) -> String {
+    format!("{path}:readonly")
+}
+
+/// Whether `given` is the configured admin token, compared in constant time.
+pub fn is_admin_token(configured: Option<&str>, given: &str) -> bool {
+    let Some(configured) = configured.filter(|token| !token.is_empty()) else {
+        return false;
+    };
+    configured.len() == given.len()
+        && configured
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,186 @@
+//! Reports of hot journal recoveries, so operators can audit them instead of guessing.
+//!
+//! A process that dies mid-transaction leaves its rollback journal behind, and the next
+//! connection to take a lock rolls the database back from it. SQLite gives no sign of
+//! that, but the VFS sees it open an existing journal without `SQLITE_OPEN_CREATE`. What
+//! happens until the journal is removed is one recovery: pages written back to the
+//! database mean it was rolled back, none mean SQLite discarded it, e.g. because the
+//! super-journal it names is gone and the transaction had committed.
+//!
+//! Each finished recovery is logged and emitted as a tracing event, and the last
+//! `RECOVERY_REPORTS_KEEP` are returned by `PRAGMA s3qlite_recoveries`, followed by any
+//! still in progress: a recovery that failed leaves its journal in place for the next
+//! connection to try again.
+
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, UNIX_EPOCH};
+
+use crate::clock::Clock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    RolledBack,
+    Discarded,
+    InProgress,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::RolledBack => "rolled_back",
+            Self::Discarded => "discarded",
+            Self::InProgress => "in_progress",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// When the recovery started
+    pub at_ms: u128,
+    /// The database as SQLite named it
+    pub database: String,
+    pub outcome: Outcome,
+    pub pages_restored: u64,
+    pub duration_ms: u128,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"at_ms\":{},\"database\":{},\"found\":\"hot_journal\",\"outcome\":\"{}\",\"pages_restored\":{},\"duration_ms\":{}}}",
+            self.at_ms,
+            crate::health::json_string(&self.database),
+            self.outcome.as_str(),
+            self.pages_restored,
+            self.duration_ms,
+        )
+    }
+}
+
+struct InProgress {
+    database: String,
+    // the database's stored path, which the writes restoring it name
+    stored_database: String,
+    started: Instant,
+    pages_restored: u64,
+}
+
+impl InProgress {
+    fn to_report(&self, outcome: Outcome, clock: &dyn Clock) -> Report {
+        let elapsed = clock.now().saturating_duration_since(self.started);
+        Report {
+            at_ms: (clock.system_now() - elapsed)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            database: self.database.clone(),
+            outcome,
+            pages_restored: self.pages_restored,
+            duration_ms: elapsed.as_millis(),
+        }
+    }
+}
+
+pub struct Recoveries {
+    keep: usize,
+    clock: Arc<dyn Clock>,
+    // stored journal path -> the recovery reading it
+    in_progress: Mutex<HashMap<String, InProgress>>,
+    // in_progress.len(), so writes don't take the lock when nothing is recovering
+    active: AtomicUsize,
+    reports: Mutex<VecDeque<Report>>,
+}
+
+impl Recoveries {
+    pub fn new(keep: usize, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            keep,
+            clock,
+            in_progress: Mutex::default(),
+            active: AtomicUsize::default(),
+            reports: Mutex::default(),
+        }
+    }
+
+    /// SQLite opened `journal`, an existing journal of `database`, to recover from it.
+    /// It opens it once to check it's hot and again to play it back, or again after a
+    /// failed attempt, all part of the same recovery.
+    pub fn started(&self, journal: &str, database: &str, stored_database: &str) {
+        let mut in_progress = self.in_progress.lock();
+        if in_progress.contains_key(journal) {
+            return;
+        }
+        log::warn!("recovering {database} from a hot journal");
+        in_progress.insert(
+            journal.to_string(),
+            InProgress {
+                database: database.to_string(),
+                stored_database: stored_database.to_string(),
+                started: self.clock.now(),
+                pages_restored: 0,
+            },
+        );
+        self.active.store(in_progress.len(), Ordering::Relaxed);
+    }
+
+    /// A write to `stored_database`, which restores a page if it's being recovered.
+    pub fn record_write(&self, stored_database: &str) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        for recovery in self.in_progress.lock().values_mut() {
+            if recovery.stored_database == stored_database {
+                recovery.pages_restored += 1;
+            }
+        }
+    }
+
+    /// `journal` was removed, or truncated or reopened for a new transaction, so any
+    /// recovery reading it is over.
+    pub fn finished(&self, journal: &str) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut in_progress = self.in_progress.lock();
+        if let Some(recovery) = in_progress.remove(journal) {
+            let outcome = match recovery.pages_restored {
+                0 => Outcome::Discarded,
+                _ => Outcome::RolledBack,
+            };
+            self.report(recovery, outcome);
+        }
+        self.active.store(in_progress.len(), Ordering::Relaxed);
+    }
+
+    fn report(&self, recovery: InProgress, outcome: Outcome) {
+        let report = recovery.to_report(outcome, &*self.clock);
+        tracing::warn!(
+            database = report.database.as_str(),
+            outcome = outcome.as_str(),
+            pages_restored = report.pages_restored,
+            duration_ms = report.duration_ms as u64,
+            "hot journal recovery"
+        );
+        log::warn!("hot journal recovery: {}", report.to_json());
+        let mut reports = self.reports.lock();
+        reports.push_back(report);
+        while reports.len() > self.keep {
+            reports.pop_front();
+        }
+    }
+
+    /// The kept reports, oldest first, then the recoveries in progress, as a JSON array.
+    pub fn to_json(&self) -> String {
+        let mut reports: Vec<String> = self.reports.lock().iter().map(Report::to_json).collect();
+        reports.extend(self.in_progress.lock().values().map(|recovery| {
+            recovery
+                .to_report(Outcome::InProgress, &*self.clock)
+                .to_json()
+        }));
+        format!("[{}]", reports.join(","))
+    }
+}
@@ -0,0 +1,162 @@
+//! Read-only databases served as a single object over plain HTTP(S), such as a
+//! pre-signed S3 URL or a file on a CDN.
+//!
+//! Opening `https://cdn.example.com/datasets/cities.db` reads the database with ranged
+//! GETs, no bucket credentials needed. The object is treated as immutable: its ETag is
+//! recorded on open and every later read must match it, and fetched pages stay in the
+//! page cache for as long as they aren't evicted. Reads fetch `FETCH_PAGES` pages at a
+//! time since a query touching one page usually touches its neighbours, or with
+//! `?scan=true` whole aligned ranges of `SCAN_FETCH_BYTES`.
+
+use crate::PAGE_SIZE;
+use crate::page_cache::PageCache;
+use slatedb::bytes::Bytes;
+use slatedb::object_store::http::HttpBuilder;
+use slatedb::object_store::path::Path;
+use slatedb::object_store::{ClientOptions, GetOptions, GetRange, ObjectStore};
+use std::sync::Arc;
+
+/// Pages fetched per cache miss.
+const FETCH_PAGES: usize = 16;
+
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("https://") || path.starts_with("http://")
+}
+
+#[derive(Clone)]
+pub struct RemoteFile {
+    url: String,
+    store: Arc<dyn ObjectStore>,
+    cache: Arc<PageCache>,
+    e_tag: Option<String>,
+    pub size: usize,
+    /// Fetch aligned ranges of this many bytes per miss, see `GrpcVfsHandle`
+    pub scan_fetch_bytes: Option<usize>,
+}
+
+impl std::fmt::Debug for RemoteFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // pre-signed URLs carry credentials in the query string
+        let url = self.url.split('?').next().unwrap_or_default();
+        f.debug_struct("RemoteFile")
+            .field("url", &url)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RemoteFile {
+    /// Fetch the first pages of `url`, learning its size and ETag.
+    pub async fn open(url: &str, cache: Arc<PageCache>) -> Result<Self, i32> {
+        let store = HttpBuilder::new()
+            .with_url(url)
+            .with_client_options(ClientOptions::new().with_allow_http(true))
+            .build()
+            .map_err(|e| {
+                log::error!("invalid database url: {e}");
+                sqlite_plugin::vars::SQLITE_CANTOPEN
+            })?;
+        let mut file = Self {
+            url: url.to_string(),
+            store: Arc::new(store),
+            cache,
+            e_tag: None,
+            size: 0,
+            scan_fetch_bytes: None,
+        };
+        // the object itself is the url, so its path within the store is empty
+        let result = file
+            .store
+            .get_opts(
+                &Path::default(),
+                GetOptions {
+                    range: Some(GetRange::Bounded(0..(FETCH_PAGES * PAGE_SIZE) as u64)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| {
+                log::error!("failed to open {file:?}: {e}");
+                sqlite_plugin::vars::SQLITE_CANTOPEN
+            })?;
+        file.size = result.meta.size as usize;
+        file.e_tag = result.meta.e_tag.clone();
+        let data = result.bytes().await.map_err(|e| {
+            log::error!("failed to read {file:?}: {e}");
+            sqlite_plugin::vars::SQLITE_CANTOPEN
+        })?;
+        file.cache_pages(0, data);
+        Ok(file)
+    }
+
+    /// Read into `buf` from `offset`, returning the number of bytes read. Reads may span
+    /// pages, published databases aren't necessarily written with 4096 byte pages.
+    pub async fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, i32> {
+        let end = (offset + buf.len()).min(self.size);
+        let mut pos = offset;
+        while pos < end {
+            let page_offset = (pos / PAGE_SIZE) * PAGE_SIZE;
+            let page = self.get_page(page_offset).await?;
+            let offset_in_page = pos - page_offset;
+            let n = (page.len().saturating_sub(offset_in_page)).min(end - pos);
+            if n == 0 {
+                break;
+            }
+            buf[pos - offset..pos - offset + n]
+                .copy_from_slice(&page[offset_in_page..offset_in_page + n]);
+            pos += n;
+        }
+        Ok(pos.saturating_sub(offset))
+    }
+
+    async fn get_page(&self, page_offset: usize) -> Result<Bytes, i32> {
+        if let Some(page) = self.cache.get(self.page_key(page_offset).as_bytes()) {
+            return Ok(page);
+        }
+        let (start, end) = match self.scan_fetch_bytes {
+            Some(bytes) => {
+                let start = page_offset - page_offset % bytes;
+                (start, (start + bytes).min(self.size))
+            }
+            None => (
+                page_offset,
+                (page_offset + FETCH_PAGES * PAGE_SIZE).min(self.size),
+            ),
+        };
+        let result = self
+            .store
+            .get_opts(
+                &Path::default(),
+                GetOptions {
+                    range: Some(GetRange::Bounded(start as u64..end as u64)),
+                    if_match: self.e_tag.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| {
+                log::error!("failed to read {self:?} at {page_offset}: {e}");
+                sqlite_plugin::vars::SQLITE_IOERR_READ
+            })?;
+        let data = result.bytes().await.map_err(|e| {
+            log::error!("failed to read {self:?} at {page_offset}: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
+        })?;
+        let offset_in_data = (page_offset - start).min(data.len());
+        let page = data.slice(offset_in_data..data.len().min(offset_in_data + PAGE_SIZE));
+        self.cache_pages(start, data);
+        Ok(page)
+    }
+
+    fn cache_pages(&self, offset: usize, data: Bytes) {
+        for start in (0..data.len()).step_by(PAGE_SIZE) {
+            let page = data.slice(start..data.len().min(start + PAGE_SIZE));
+            self.cache
+                .insert(self.page_key(offset + start).as_bytes(), page);
+        }
+    }
+
+    fn page_key(&self, page_offset: usize) -> String {
+        format!("{}:page:{page_offset}", self.url)
+    }
+}
@@ -0,0 +1,88 @@
+//! Scheduler metrics of the VFS's tokio runtime, reported alongside the counters in
+//! `stats`.
+//!
+//! The flusher, the preloader, prefetching and every SQLite call share one runtime, so a
+//! saturated scheduler shows up as slow reads with no slow requests behind them. These
+//! gauges tell the two apart. Worker utilization covers the time since the previous
+//! report, so a periodic dump (`STATS_DUMP_INTERVAL_SECS`) gives one value per interval.
+//!
+//! Tokio only exposes the blocking pool to builds with `RUSTFLAGS="--cfg tokio_unstable"`,
+//! the `runtime_blocking_*` gauges are left out otherwise.
+
+use parking_lot::Mutex;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct RuntimeMetrics {
+    handle: OnceLock<tokio::runtime::Handle>,
+    // when the last report was taken, and the workers' total busy time then
+    last: Mutex<Option<(Instant, Duration)>>,
+}
+
+impl RuntimeMetrics {
+    pub fn watch(&self, handle: tokio::runtime::Handle) {
+        let _ = self.handle.set(handle);
+    }
+
+    /// The gauges, empty until a runtime is watched.
+    pub fn gauges(&self) -> Vec<(&'static str, u64)> {
+        let Some(handle) = self.handle.get() else {
+            return Vec::new();
+        };
+        let metrics = handle.metrics();
+        let workers = metrics.num_workers();
+        let busy: Duration = (0..workers)
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .sum();
+        let parks: u64 = (0..workers)
+            .map(|worker| metrics.worker_park_count(worker))
+            .sum();
+
+        let now = Instant::now();
+        let previous = self.last.lock().replace((now, busy));
+        let utilization_pct = match previous {
+            Some((at, previous_busy)) if workers > 0 => {
+                let elapsed = now.duration_since(at).as_secs_f64() * workers as f64;
+                let busy = busy.saturating_sub(previous_busy).as_secs_f64();
+                if elapsed > 0.0 {
+                    (busy / elapsed * 100.0).round().min(100.0) as u64
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        };
+
+        let gauges = vec![
+            ("runtime_workers", workers as u64),
+            ("runtime_worker_utilization_pct", utilization_pct),
+            ("runtime_worker_parks", parks),
+            ("runtime_alive_tasks", metrics.num_alive_tasks() as u64),
+            (
+                "runtime_global_queue_depth",
+                metrics.global_queue_depth() as u64,
+            ),
+        ];
+        #[cfg(tokio_unstable)]
+        let gauges = [
+            gauges,
+            vec![
+                (
+                    "runtime_blocking_threads",
+                    metrics.num_blocking_threads() as u64,
+                ),
+                (
+                    "runtime_idle_blocking_threads",
+                    metrics.num_idle_blocking_threads() as u64,
+                ),
+                (
+                    "runtime_blocking_queue_depth",
+                    metrics.blocking_queue_depth() as u64,
+                ),
+            ],
+        ]
+        .concat();
+        gauges
+    }
+}
@@ -0,0 +1,259 @@
+//! Minimal reader for the `sqlite_schema` b-tree, used to map table names to the
+//! pages that back them without going through a SQLite connection.
+//! File format reference: https://www.sqlite.org/fileformat2.html
+
+use std::future::Future;
+
+const HEADER_SIZE: usize = 100;
+const LEAF_TABLE: u8 = 0x0d;
+const INTERIOR_TABLE: u8 = 0x05;
+
+#[derive(Debug, Clone)]
+pub struct SchemaEntry {
+    /// Table the entry belongs to (for indexes, the indexed table)
+    pub tbl_name: String,
+    pub root_page: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    pub page_size: usize,
+    /// Pages making up the schema b-tree, always including page 1
+    pub schema_pages: Vec<u32>,
+    pub entries: Vec<SchemaEntry>,
+}
+
+impl Schema {
+    /// Root pages of the named table and all of its indexes.
+    pub fn root_pages_for_table(&self, table: &str) -> Vec<u32> {
+        self.entries
+            .iter()
+            .filter(|e| e.root_page > 0 && e.tbl_name.eq_ignore_ascii_case(table))
+            .map(|e| e.root_page)
+            .collect()
+    }
+}
+
+/// Page size from the database header, or None if the header is missing or invalid.
+pub fn page_size_from_header(header: &[u8]) -> Option<usize> {
+    if header.len() < HEADER_SIZE || !header.starts_with(b"SQLite format 3\0") {
+        return None;
+    }
+    match u16::from_be_bytes([header[16], header[17]]) {
+        1 => Some(65536),
+        n if n >= 512 && n.is_power_of_two() => Some(n as usize),
+        _ => None,
+    }
+}
+
+/// Walk the schema b-tree rooted at page 1. `read_range(offset, len)` must return the
+/// bytes of the database file at that range, zero padded if the file is shorter.
+pub async fn read_schema<F, Fut>(read_range: F) -> Result<Option<Schema>, i32>
+where
+    F: Fn(usize, usize) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, i32>>,
+{
+    let header = read_range(0, HEADER_SIZE).await?;
+    let Some(page_size) = page_size_from_header(&header) else {
+        return Ok(None);
+    };
+    let usable = page_size - header[20] as usize;
+
+    let mut schema = Schema {
+        page_size,
+        ..Default::default()
+    };
+    let mut stack = vec![1u32];
+    while let Some(page_no) = stack.pop() {
+        // guard against cycles in a corrupt file
+        if schema.schema_pages.contains(&page_no) {
+            continue;
+        }
+        schema.schema_pages.push(page_no);
+        // a corrupt file can point anywhere, page 0 included
+        let Some(offset) = (page_no as usize)
+            .checked_sub(1)
+            .and_then(|index| index.checked_mul(page_size))
+        else {
+            log::warn!("schema b-tree points at page {page_no}");
+            return Ok(None);
+        };
+        let page = read_range(offset, page_size).await?;
+        let start = if page_no == 1 { HEADER_SIZE } else { 0 };
+        let parsed = match page.get(start) {
+            Some(&INTERIOR_TABLE) => interior_children(&page, start).map(|children| {
+                stack.extend(children);
+            }),
+            Some(&LEAF_TABLE) => leaf_cells(&page, start, usable).map(|cells| {
+                schema
+                    .entries
+                    .extend(cells.into_iter().filter_map(parse_entry));
+            }),
+            Some(other) => {
+                log::warn!("unexpected schema b-tree page type {other:#x} on page {page_no}");
+                Some(())
+            }
+            None => None,
+        };
+        if parsed.is_none() {
+            log::warn!("schema b-tree page {page_no} is truncated");
+            return Ok(None);
+        }
+    }
+    Ok(Some(schema))
+}
+
+fn be_u16(page: &[u8], at: usize) -> Option<usize> {
+    Some(u16::from_be_bytes(page.get(at..at + 2)?.try_into().ok()?) as usize)
+}
+
+fn be_u32(page: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(page.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// The cell offsets of a b-tree page, or None if the page is too short for them.
+fn cell_pointers(page: &[u8], start: usize, header_len: usize) -> Option<Vec<usize>> {
+    let count = be_u16(page, start + 3)?;
+    (0..count)
+        .map(|i| be_u16(page, start + header_len + i * 2))
+        .collect()
+}
+
+fn interior_children(page: &[u8], start: usize) -> Option<Vec<u32>> {
+    let mut children = cell_pointers(page, start, 12)?
+        .into_iter()
+        .map(|cell| be_u32(page, cell))
+        .collect::<Option<Vec<u32>>>()?;
+    children.push(be_u32(page, start + 8)?);
+    Some(children)
+}
+
+/// Local payload of every cell on a leaf table page. Overflow pages are not followed,
+/// and cells that don't fit the page are skipped.
+fn leaf_cells(page: &[u8], start: usize, usable: usize) -> Option<Vec<&[u8]>> {
+    let cells = cell_pointers(page, start, 8)?
+        .into_iter()
+        .filter_map(|cell| {
+            let (payload_len, n) = varint(page.get(cell..)?)?;
+            let (_rowid, m) = varint(page.get(cell + n..)?)?;
+            let local = local_payload_len(payload_len as usize, usable);
+            page.get(cell + n + m..cell + n + m + local)
+        });
+    Some(cells.collect())
+}
+
+fn local_payload_len(payload_len: usize, usable: usize) -> usize {
+    let max_local = usable - 35;
+    if payload_len <= max_local {
+        return payload_len;
+    }
+    let min_local = ((usable - 12) * 32 / 255) - 23;
+    let k = min_local + ((payload_len - min_local) % (usable - 4));
+    if k <= max_local { k } else { min_local }
+}
+
+/// Decode the tbl_name and rootpage columns of a schema record.
+fn parse_entry(payload: &[u8]) -> Option<SchemaEntry> {
+    let (header_len, mut pos) = varint(payload)?;
+    let mut serial_types = Vec::with_capacity(4);
+    while pos < header_len as usize && serial_types.len() < 4 {
+        let (serial_type, n) = varint(payload.get(pos..)?)?;
+        serial_types.push(serial_type);
+        pos += n;
+    }
+    let mut body = header_len as usize;
+    let mut values = Vec::with_capacity(4);
+    for serial_type in serial_types {
+        let len = serial_type_len(serial_type);
+        values.push((serial_type, payload.get(body..body + len)?));
+        body += len;
+    }
+    let text = |i: usize| -> Option<String> {
+        let (serial_type, bytes) = values.get(i)?;
+        (*serial_type >= 13 && serial_type % 2 == 1)
+            .then(|| String::from_utf8_lossy(bytes).into_owned())
+    };
+    let root_page = match values.get(3)? {
+        (1..=6, bytes) => bytes.iter().fold(0i64, |acc, b| (acc << 8) | *b as i64) as u32,
+        (8, _) => 0,
+        (9, _) => 1,
+        _ => 0,
+    };
+    Some(SchemaEntry {
+        tbl_name: text(2)?,
+        root_page,
+    })
+}
+
+fn serial_type_len(serial_type: u64) -> usize {
+    match serial_type {
+        0 | 8 | 9 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        5 => 6,
+        6 | 7 => 8,
+        n if n >= 12 => ((n - 12) / 2) as usize,
+        _ => 0,
+    }
+}
+
+/// SQLite big-endian varint. Returns the value and the number of bytes consumed.
+fn varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in buf.iter().take(9).enumerate() {
+        if i == 8 {
+            return Some(((value << 8) | *byte as u64, 9));
+        }
+        value = (value << 7) | (*byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 512 byte page 1 with the b-tree page header's `kind`, cell count and right child
+    fn page_one(kind: u8, cells: u16, right_child: u32) -> Vec<u8> {
+        let mut page = vec![0u8; 512];
+        page[..16].copy_from_slice(b"SQLite format 3\0");
+        page[16..18].copy_from_slice(&512u16.to_be_bytes());
+        page[HEADER_SIZE] = kind;
+        page[HEADER_SIZE + 3..HEADER_SIZE + 5].copy_from_slice(&cells.to_be_bytes());
+        page[HEADER_SIZE + 8..HEADER_SIZE + 12].copy_from_slice(&right_child.to_be_bytes());
+        page
+    }
+
+    fn read(file: Vec<u8>) -> Option<Schema> {
+        futures::executor::block_on(read_schema(|offset, len| {
+            let mut bytes = file.get(offset..).unwrap_or_default().to_vec();
+            bytes.resize(len, 0);
+            async move { Ok(bytes) }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn empty_schema() {
+        let schema = read(page_one(LEAF_TABLE, 0, 0)).unwrap();
+        assert_eq!(schema.page_size, 512);
+        assert_eq!(schema.schema_pages, [1]);
+        assert!(schema.entries.is_empty());
+    }
+
+    #[test]
+    fn child_page_zero_is_rejected() {
+        assert!(read(page_one(INTERIOR_TABLE, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn cell_pointers_past_the_page_are_rejected() {
+        assert!(read(page_one(LEAF_TABLE, u16::MAX, 0)).is_none());
+        assert!(read(page_one(INTERIOR_TABLE, u16::MAX, 2)).is_none());
+    }
+}
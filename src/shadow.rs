@@ -0,0 +1,89 @@
+//! Shadow writes, a debug mode for landing changes to the write path.
+//!
+//! With `SHADOW_WRITES` set every page the VFS stores or removes is mirrored to an
+//! in-memory copy once the store accepted it. Every `SHADOW_VERIFY_INTERVAL_SECS`, and
+//! on `PRAGMA s3qlite_shadow_verify`, each mirrored page is read back from SlateDB and
+//! compared byte for byte. A page that differs, or exists on one side only, is logged as
+//! an error straight away and counted in the `shadow_mismatches` stat.
+//!
+//! A verification pass holds off writes until it's done, so a commit caught halfway
+//! between the store and the mirror is never reported. The mirror only sees this
+//! process's writes and holds every page in memory, so it's for tests and staging.
+
+use parking_lot::Mutex;
+use slatedb::bytes::Bytes;
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct Shadow {
+    // page key -> contents, None once deleted
+    pages: Mutex<HashMap<Vec<u8>, Option<Bytes>>>,
+    // shared by writes on their way to the store and the mirror, exclusive for a pass
+    writes: tokio::sync::RwLock<()>,
+}
+
+pub struct Report {
+    pub checked: usize,
+    pub mismatched: Vec<String>,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        let keys: Vec<String> = self
+            .mismatched
+            .iter()
+            .map(|key| format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        format!(
+            "{{\"checked\":{},\"mismatched\":[{}]}}",
+            self.checked,
+            keys.join(",")
+        )
+    }
+}
+
+impl Shadow {
+    pub async fn writing(&self) -> tokio::sync::RwLockReadGuard<'_, ()> {
+        self.writes.read().await
+    }
+
+    pub fn put(&self, key: &[u8], value: Bytes) {
+        self.pages.lock().insert(key.to_vec(), Some(value));
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        self.pages.lock().insert(key.to_vec(), None);
+    }
+
+    /// Compare every mirrored page with what `get` reads from the store.
+    pub async fn verify<F, Fut>(&self, get: F) -> Result<Report, i32>
+    where
+        F: Fn(Vec<u8>) -> Fut,
+        Fut: Future<Output = Result<Option<Bytes>, i32>>,
+    {
+        let _pass = self.writes.write().await;
+        let pages = self.pages.lock().clone();
+        let mut mismatched = Vec::new();
+        for (key, expected) in &pages {
+            let stored = get(key.clone()).await?;
+            if stored != *expected {
+                let key = String::from_utf8_lossy(key).into_owned();
+                let describe = |page: &Option<Bytes>| match page {
+                    Some(page) => format!("{} bytes", page.len()),
+                    None => "missing".to_string(),
+                };
+                log::error!(
+                    "shadow mismatch for {key}: stored {}, mirrored {}",
+                    describe(&stored),
+                    describe(expected)
+                );
+                mismatched.push(key);
+            }
+        }
+        mismatched.sort();
+        Ok(Report {
+            checked: pages.len(),
+            mismatched,
+        })
+    }
+}
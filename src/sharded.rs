@@ -0,0 +1,57 @@
+//! Maps keyed by file path, split into shards by path hash so that operations on
+//! different files don't serialize on one mutex. Services with many connections open
+//! across many databases used to queue on the single lock and file maps.
+
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+
+const SHARDS: usize = 64;
+
+pub struct Sharded<V> {
+    hasher: RandomState,
+    shards: Box<[Mutex<HashMap<String, V>>]>,
+}
+
+impl<V> Sharded<V> {
+    pub fn new() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Lock the shard holding `path`. Every entry for `path` lives in that shard.
+    pub fn shard(&self, path: &str) -> MutexGuard<'_, HashMap<String, V>> {
+        let i = self.hasher.hash_one(path) as usize % SHARDS;
+        self.shards[i].lock()
+    }
+
+    /// Every value, locking one shard at a time.
+    pub fn values(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.lock().values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Every entry, locking one shard at a time.
+    pub fn entries(&self) -> Vec<(String, V)>
+    where
+        V: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock();
+                shard
+                    .iter()
+                    .map(|(path, value)| (path.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
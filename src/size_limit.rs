@@ -0,0 +1,50 @@
+//! Size caps for databases.
+//!
+//! Nothing stops a database on metered storage from growing without bound. `MAX_DB_BYTES`
+//! caps every database, and `PRAGMA s3qlite_size_limit=N` (or `SQLITE_FCNTL_SIZE_LIMIT`)
+//! caps a single one, stored under `{path}:size_limit` so every process sees it. A write
+//! or truncate that would grow the database past its cap fails with `SQLITE_FULL`.
+//!
+//! The cap is read when the database is opened, so a cap set by another process applies
+//! from the next open. Journals aren't capped.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub fn size_limit_key(path: &str) -> String {
+    format!("{path}:size_limit")
+}
+
+/// The cap of one database in bytes, 0 when it has none.
+#[derive(Debug, Default)]
+pub struct SizeLimit(AtomicU64);
+
+impl SizeLimit {
+    pub fn get(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|&limit| limit > 0)
+    }
+
+    pub fn set(&self, limit: Option<u64>) {
+        self.0.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Whether the file may extend to `end` bytes.
+    pub fn allows(&self, end: usize) -> bool {
+        self.get().is_none_or(|limit| end as u64 <= limit)
+    }
+}
+
+/// A stored cap, a decimal byte count.
+pub fn parse(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// `{"limit":…,"size":…,"headroom":…}`, with a null limit and headroom when uncapped.
+pub fn to_json(limit: Option<u64>, size: usize) -> String {
+    match limit {
+        Some(limit) => format!(
+            "{{\"limit\":{limit},\"size\":{size},\"headroom\":{}}}",
+            limit.saturating_sub(size as u64)
+        ),
+        None => format!("{{\"limit\":null,\"size\":{size},\"headroom\":null}}"),
+    }
+}
@@ -0,0 +1,52 @@
+//! Named, read-only snapshots of a database, backed by SlateDB checkpoints.
+//!
+//! `PRAGMA s3qlite_snapshot='nightly'` checkpoints the store and records the checkpoint
+//! id under `{path}:snapshot:nightly`. Opening `{path}@nightly` then reads the database
+//! as it was at that checkpoint, so `ATTACH 'orders.db@nightly' AS old` can be diffed
+//! against the live data in plain SQL. A name with an `@` that doesn't refer to a
+//! snapshot, like `user@example.db`, opens an ordinary database.
+
+use slatedb::DbReader;
+use slatedb::bytes::Bytes;
+use std::sync::Arc;
+
+/// Split `orders.db@nightly` into the database path and the name of the snapshot it may
+/// refer to.
+pub fn split_path(path: &str) -> Option<(&str, &str)> {
+    let (base, name) = path.rsplit_once('@')?;
+    // `@` is also legal in directory names
+    (!base.is_empty() && !name.is_empty() && !name.contains('/')).then_some((base, name))
+}
+
+pub fn snapshot_key(path: &str, name: &str) -> String {
+    format!("{path}:snapshot:{name}")
+}
+
+/// A database opened at a snapshot.
+#[derive(Clone)]
+pub struct Snapshot {
+    /// The path of the live database
+    pub base: String,
+    pub reader: Arc<DbReader>,
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("base", &self.base)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Snapshot {
+    pub async fn get_page(&self, page_offset: usize) -> Result<Option<Bytes>, i32> {
+        self.get(format!("{}:page:{page_offset}", self.base)).await
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<Bytes>, i32> {
+        self.reader.get(&key).await.map_err(|e| {
+            log::error!("error reading {key} from snapshot: {e}");
+            sqlite_plugin::vars::SQLITE_IOERR_READ
+        })
+    }
+}
@@ -74,6 +74,14 @@ impl OpenMode {
     pub fn is_readonly(&self) -> bool {
         matches!(self, Self::ReadOnly)
     }
+    pub fn may_create(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadWrite {
+                create: CreateMode::Create | CreateMode::MustCreate
+            }
+        )
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -178,3 +186,26 @@ impl From<i32> for LockLevel {
         }
     }
 }
+
+/// A change to the locks on a range of the shared-memory wal-index's lock slots, see
+/// `Vfs::shm_lock`. Exclusive locks are only ever taken from no lock, and shared ones
+/// never alongside an exclusive lock held by the same connection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShmLockMode {
+    LockShared,
+    LockExclusive,
+    UnlockShared,
+    UnlockExclusive,
+}
+
+impl From<i32> for ShmLockMode {
+    fn from(flags: i32) -> Self {
+        let exclusive = flags & vars::SQLITE_SHM_EXCLUSIVE > 0;
+        match (flags & vars::SQLITE_SHM_LOCK > 0, exclusive) {
+            (true, false) => Self::LockShared,
+            (true, true) => Self::LockExclusive,
+            (false, false) => Self::UnlockShared,
+            (false, true) => Self::UnlockExclusive,
+        }
+    }
+}
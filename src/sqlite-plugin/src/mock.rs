@@ -4,6 +4,7 @@
 extern crate std;
 
 use core::fmt::{self, Display};
+use core::time::Duration;
 use std::boxed::Box;
 use std::collections::HashMap;
 use std::println;
@@ -14,12 +15,12 @@ use alloc::format;
 use alloc::sync::Arc;
 use parking_lot::{Mutex, MutexGuard};
 
-use crate::flags::{self, AccessFlags, OpenOpts};
+use crate::flags::{self, AccessFlags, OpenOpts, ShmLockMode};
 use crate::logger::{SqliteLogLevel, SqliteLogger};
 use crate::vars;
 use crate::vfs::{
-    DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, Pragma, PragmaErr, Vfs, VfsHandle,
-    VfsResult,
+    CustomFcntl, CustomFcntlArg, DEFAULT_DEVICE_CHARACTERISTICS, DEFAULT_SECTOR_SIZE, Pragma,
+    PragmaErr, ShmRegion, Vfs, VfsHandle, VfsResult, shm_region,
 };
 
 pub struct File {
@@ -32,6 +33,7 @@ pub struct File {
 pub trait Hooks {
     fn canonical_path(&mut self, path: &str) {}
     fn open(&mut self, path: &Option<&str>, opts: &OpenOpts) {}
+    fn open_params(&mut self, path: &Option<&str>, params: &[(String, String)]) {}
     fn delete(&mut self, path: &str) {}
     fn access(&mut self, path: &str, flags: AccessFlags) {}
     fn file_size(&mut self, handle: MockHandle) {}
@@ -47,6 +49,27 @@ pub trait Hooks {
     ) -> Result<Option<String>, PragmaErr> {
         Err(PragmaErr::NotFound)
     }
+    fn custom_file_control_arg(&mut self, op: i32) -> Option<CustomFcntlArg> {
+        None
+    }
+    fn custom_file_control(
+        &mut self,
+        handle: MockHandle,
+        op: i32,
+        arg: CustomFcntl<'_>,
+    ) -> VfsResult<Option<String>> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+    fn shared_memory(&mut self) -> bool {
+        false
+    }
+    fn shm_map(&mut self, handle: MockHandle, region: usize, extend: bool) {}
+    fn sleep(&mut self, duration: Duration) -> Option<Duration> {
+        None
+    }
+    fn current_time_ms(&mut self) -> Option<i64> {
+        None
+    }
     fn sector_size(&mut self) {}
     fn device_characteristics(&mut self) {
         println!("device_characteristics");
@@ -95,6 +118,9 @@ struct Shared {
     files: HashMap<MockHandle, File>,
     hooks: Box<dyn Hooks + Send>,
     log: Option<SqliteLogger>,
+    // wal-index regions and lock slots by file name; a lock slot counts shared locks,
+    // or is -1 when held exclusively
+    shm: HashMap<String, (Vec<ShmRegion>, [i32; 8])>,
 }
 
 impl MockVfs {
@@ -105,6 +131,7 @@ impl MockVfs {
                 files: HashMap::new(),
                 hooks,
                 log: None,
+                shm: HashMap::new(),
             })),
         }
     }
@@ -124,6 +151,11 @@ impl Shared {
         }
     }
 
+    fn file_name(&self, handle: &MockHandle) -> VfsResult<String> {
+        let file = self.files.get(handle).ok_or(vars::SQLITE_IOERR_SHMOPEN)?;
+        Ok(file.name.clone())
+    }
+
     fn next_id(&mut self) -> usize {
         let id = self.next_id;
         self.next_id += 1;
@@ -147,6 +179,16 @@ impl Vfs for MockVfs {
         Ok(path)
     }
 
+    fn open_with_params(
+        &self,
+        path: Option<&str>,
+        opts: flags::OpenOpts,
+        params: &[(String, String)],
+    ) -> VfsResult<Self::Handle> {
+        self.shared().hooks.open_params(&path, params);
+        self.open(path, opts)
+    }
+
     fn open(&self, path: Option<&str>, opts: flags::OpenOpts) -> VfsResult<Self::Handle> {
         let mut shared = self.shared();
         shared.log(format_args!("open: path={path:?} opts={opts:?}"));
@@ -280,6 +322,97 @@ impl Vfs for MockVfs {
         shared.hooks.pragma(*meta, pragma)
     }
 
+    fn custom_file_control_arg(&self, op: i32) -> Option<CustomFcntlArg> {
+        self.shared().hooks.custom_file_control_arg(op)
+    }
+
+    fn custom_file_control(
+        &self,
+        meta: &mut Self::Handle,
+        op: i32,
+        arg: CustomFcntl<'_>,
+    ) -> VfsResult<Option<String>> {
+        let mut shared = self.shared();
+        shared.log(format_args!(
+            "custom_file_control: handle={meta:?} op={op} arg={arg:?}"
+        ));
+        shared.hooks.custom_file_control(*meta, op, arg)
+    }
+
+    fn shared_memory(&self) -> bool {
+        self.shared().hooks.shared_memory()
+    }
+
+    fn shm_map(
+        &self,
+        meta: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<ShmRegion>> {
+        let mut shared = self.shared();
+        shared.log(format_args!(
+            "shm_map: handle={meta:?} region={region} size={size} extend={extend}"
+        ));
+        shared.hooks.shm_map(*meta, region, extend);
+        let name = shared.file_name(meta)?;
+        let (regions, _) = shared.shm.entry(name).or_default();
+        if region >= regions.len() {
+            if !extend {
+                return Ok(None);
+            }
+            regions.resize_with(region + 1, || shm_region(size));
+        }
+        Ok(Some(regions[region].clone()))
+    }
+
+    fn shm_lock(
+        &self,
+        meta: &mut Self::Handle,
+        offset: usize,
+        n: usize,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        let mut shared = self.shared();
+        shared.log(format_args!(
+            "shm_lock: handle={meta:?} offset={offset} n={n} mode={mode:?}"
+        ));
+        let name = shared.file_name(meta)?;
+        let (_, locks) = shared.shm.entry(name).or_default();
+        let slots = &mut locks[offset..offset + n];
+        match mode {
+            ShmLockMode::LockShared if slots.iter().any(|&l| l < 0) => {
+                return Err(vars::SQLITE_BUSY);
+            }
+            ShmLockMode::LockExclusive if slots.iter().any(|&l| l != 0) => {
+                return Err(vars::SQLITE_BUSY);
+            }
+            ShmLockMode::LockShared => slots.iter_mut().for_each(|l| *l += 1),
+            ShmLockMode::LockExclusive => slots.fill(-1),
+            ShmLockMode::UnlockShared => slots.iter_mut().for_each(|l| *l = (*l - 1).max(0)),
+            ShmLockMode::UnlockExclusive => slots.fill(0),
+        }
+        Ok(())
+    }
+
+    fn shm_unmap(&self, meta: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        let mut shared = self.shared();
+        shared.log(format_args!("shm_unmap: handle={meta:?} delete={delete}"));
+        if delete {
+            let name = shared.file_name(meta)?;
+            shared.shm.remove(&name);
+        }
+        Ok(())
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.shared().hooks.sleep(duration)
+    }
+
+    fn current_time_ms(&self) -> Option<i64> {
+        self.shared().hooks.current_time_ms()
+    }
+
     fn sector_size(&self) -> i32 {
         let mut shared = self.shared();
         shared.log(format_args!("sector_size"));
@@ -1,4 +1,4 @@
-use crate::flags::{AccessFlags, LockLevel, OpenOpts};
+use crate::flags::{AccessFlags, LockLevel, OpenKind, OpenOpts, ShmLockMode};
 use crate::logger::SqliteLogger;
 use crate::vars::SQLITE_ERROR;
 use crate::{ffi, vars};
@@ -7,8 +7,12 @@ use alloc::boxed::Box;
 use alloc::ffi::CString;
 use alloc::format;
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::mem::{self, ManuallyDrop, MaybeUninit, size_of};
 use core::slice;
+use core::sync::atomic::AtomicU8;
+use core::time::Duration;
 use core::{
     ffi::{CStr, c_char, c_int, c_void},
     ptr::null_mut,
@@ -46,6 +50,8 @@ struct FileWrapper<Handle> {
     file: ffi::sqlite3_file,
     vfs: *mut ffi::sqlite3_vfs,
     handle: MaybeUninit<Handle>,
+    // the shared-memory regions this file has mapped, kept alive until it unmaps them
+    shm: MaybeUninit<Vec<Option<ShmRegion>>>,
 }
 
 struct AppData<Vfs> {
@@ -79,6 +85,41 @@ impl PragmaErr {
     }
 }
 
+/// The lowest `sqlite3_file_control` opcode a VFS can define for itself with
+/// [`Vfs::custom_file_control`]. `SQLite`'s own opcodes are small integers, so opcodes from
+/// here on are left to the VFS.
+pub const FCNTL_CUSTOM_MIN: c_int = 0x1000_0000;
+
+/// How the argument of a custom file control is passed, see [`Vfs::custom_file_control_arg`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomFcntlArg {
+    /// The argument is ignored.
+    None,
+    /// A `sqlite3_int64*`, read and written in place.
+    Int64,
+    /// A `char**` pointing at an input string or null. A result replaces it, allocated
+    /// with `sqlite3_mprintf` for the caller to `sqlite3_free`, as with
+    /// `SQLITE_FCNTL_PRAGMA`.
+    Text,
+}
+
+/// The argument of a custom file control, as declared by [`Vfs::custom_file_control_arg`].
+#[derive(Debug)]
+pub enum CustomFcntl<'a> {
+    None,
+    Int64(&'a mut i64),
+    Text(Option<&'a str>),
+}
+
+/// A region of shared memory backing the wal-index, see [`Vfs::shm_map`]. `SQLite` reads
+/// and writes it directly, from every connection that maps it.
+pub type ShmRegion = Arc<[AtomicU8]>;
+
+/// A zeroed region of `size` bytes.
+pub fn shm_region(size: usize) -> ShmRegion {
+    (0..size).map(|_| AtomicU8::new(0)).collect()
+}
+
 fn fallible(mut cb: impl FnMut() -> Result<i32, SqliteErr>) -> i32 {
     cb().unwrap_or_else(|err| err)
 }
@@ -168,6 +209,17 @@ pub trait Vfs: Send + Sync {
 
     // file system operations
     fn open(&self, path: Option<&str>, opts: OpenOpts) -> VfsResult<Self::Handle>;
+
+    /// Open a main database with the parameters of its `file:` URI, e.g. `[("scan",
+    /// "true")]` for `file:app.db?scan=true`. Defaults to `open`, ignoring them.
+    fn open_with_params(
+        &self,
+        path: Option<&str>,
+        opts: OpenOpts,
+        params: &[(String, String)],
+    ) -> VfsResult<Self::Handle> {
+        self.open(path, opts)
+    }
     fn delete(&self, path: &str) -> VfsResult<()>;
     fn access(&self, path: &str, flags: AccessFlags) -> VfsResult<bool>;
 
@@ -185,6 +237,12 @@ pub trait Vfs: Send + Sync {
         Ok(())
     }
 
+    /// Whether any connection holds a RESERVED or higher lock on the file. SQLite asks
+    /// before treating a leftover journal as hot and rolling it back.
+    fn check_reserved_lock(&self, handle: &mut Self::Handle) -> VfsResult<bool> {
+        Ok(false)
+    }
+
     fn sync(&self, handle: &mut Self::Handle) -> VfsResult<()> {
         Ok(())
     }
@@ -216,6 +274,80 @@ pub trait Vfs: Send + Sync {
     ) -> VfsResult<()> {
         Err(vars::SQLITE_NOTFOUND)
     }
+
+    /// How the argument of custom file control `op` is passed, or `None` if the VFS
+    /// doesn't define it. Only asked for opcodes from [`FCNTL_CUSTOM_MIN`] on; the
+    /// opcodes it doesn't define go to `file_control`.
+    fn custom_file_control_arg(&self, op: c_int) -> Option<CustomFcntlArg> {
+        None
+    }
+
+    /// Run custom file control `op` with its argument unpacked. A returned string is
+    /// passed back through a `Text` argument, and ignored for the others.
+    fn custom_file_control(
+        &self,
+        handle: &mut Self::Handle,
+        op: c_int,
+        arg: CustomFcntl<'_>,
+    ) -> VfsResult<Option<String>> {
+        Err(vars::SQLITE_NOTFOUND)
+    }
+
+    /// Whether the VFS implements the `shm_*` methods below, asked once when it's
+    /// registered. Without them `SQLite` only allows WAL mode with `locking_mode=EXCLUSIVE`,
+    /// keeping the wal-index in heap memory.
+    fn shared_memory(&self) -> bool {
+        false
+    }
+
+    /// The wal-index region `region` of `size` bytes, shared by every connection to the
+    /// file. A region that doesn't exist yet is created zeroed if `extend` is set, and
+    /// otherwise `None` returned. The same region is asked for with the same size each
+    /// time. The handle keeps the region it's given until `shm_unmap`.
+    fn shm_map(
+        &self,
+        handle: &mut Self::Handle,
+        region: usize,
+        size: usize,
+        extend: bool,
+    ) -> VfsResult<Option<ShmRegion>> {
+        Err(vars::SQLITE_IOERR_SHMMAP)
+    }
+
+    /// Take or release locks on `n` of the wal-index's lock slots starting at `offset`.
+    /// A lock that conflicts with another connection's fails with `SQLITE_BUSY`.
+    fn shm_lock(
+        &self,
+        handle: &mut Self::Handle,
+        offset: usize,
+        n: usize,
+        mode: ShmLockMode,
+    ) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// A memory barrier, so writes to the shared regions before it are seen by other
+    /// connections before writes after it. The plugin issues a fence after calling it.
+    fn shm_barrier(&self, handle: &mut Self::Handle) {}
+
+    /// The handle is done with the shared regions, and they're deleted if `delete` is set.
+    fn shm_unmap(&self, handle: &mut Self::Handle, delete: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    /// Sleep for at least `duration`, as `SQLite` does between retries while waiting out a
+    /// `busy_timeout`, returning how long it slept. `None`, the default, leaves it to the
+    /// base VFS.
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        None
+    }
+
+    /// The current time in milliseconds since the Julian epoch (noon in Greenwich on
+    /// November 24, 4714 B.C.), for `'now'` and the like. `None`, the default, leaves it to
+    /// the base VFS.
+    fn current_time_ms(&self) -> Option<i64> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -225,6 +357,8 @@ pub(crate) struct SqliteApi {
     mprintf: unsafe extern "C" fn(arg1: *const c_char, ...) -> *mut c_char,
     log: unsafe extern "C" fn(arg1: c_int, arg2: *const c_char, ...),
     libversion_number: unsafe extern "C" fn() -> c_int,
+    uri_key: unsafe extern "C" fn(z: *const c_char, n: c_int) -> *const c_char,
+    uri_parameter: unsafe extern "C" fn(z: *const c_char, param: *const c_char) -> *const c_char,
 }
 
 impl SqliteApi {
@@ -236,6 +370,8 @@ impl SqliteApi {
             mprintf: ffi::sqlite3_mprintf,
             log: ffi::sqlite3_log,
             libversion_number: ffi::sqlite3_libversion_number,
+            uri_key: ffi::sqlite3_uri_key,
+            uri_parameter: ffi::sqlite3_uri_parameter,
         }
     }
 
@@ -247,6 +383,8 @@ impl SqliteApi {
             mprintf: api.mprintf.ok_or(vars::SQLITE_INTERNAL)?,
             log: api.log.ok_or(vars::SQLITE_INTERNAL)?,
             libversion_number: api.libversion_number.ok_or(vars::SQLITE_INTERNAL)?,
+            uri_key: api.uri_key.ok_or(vars::SQLITE_INTERNAL)?,
+            uri_parameter: api.uri_parameter.ok_or(vars::SQLITE_INTERNAL)?,
         })
     }
 }
@@ -290,6 +428,7 @@ fn register_inner<T: Vfs>(
         );
     }
 
+    let shm = vfs.shared_memory();
     let io_methods = ffi::sqlite3_io_methods {
         iVersion: 3,
         xClose: Some(x_close::<T>),
@@ -300,14 +439,14 @@ fn register_inner<T: Vfs>(
         xFileSize: Some(x_file_size::<T>),
         xLock: Some(x_lock::<T>),
         xUnlock: Some(x_unlock::<T>),
-        xCheckReservedLock: None,
+        xCheckReservedLock: Some(x_check_reserved_lock::<T>),
         xFileControl: Some(x_file_control::<T>),
         xSectorSize: Some(x_sector_size::<T>),
         xDeviceCharacteristics: Some(x_device_characteristics::<T>),
-        xShmMap: None,
-        xShmLock: None,
-        xShmBarrier: None,
-        xShmUnmap: None,
+        xShmMap: shm.then_some(x_shm_map::<T> as _),
+        xShmLock: shm.then_some(x_shm_lock::<T> as _),
+        xShmBarrier: shm.then_some(x_shm_barrier::<T> as _),
+        xShmUnmap: shm.then_some(x_shm_unmap::<T> as _),
         xFetch: None,
         xUnfetch: None,
     };
@@ -370,13 +509,18 @@ unsafe extern "C" fn x_open<T: Vfs>(
     p_out_flags: *mut c_int,
 ) -> c_int {
     fallible(|| {
-        let opts = flags.into();
+        let opts: OpenOpts = flags.into();
         let name = unsafe { lossy_cstr(z_name) }.ok();
         let vfs = unwrap_vfs!(p_vfs, T)?;
-        let handle = vfs.open(name.as_ref().map(|s| s.as_ref()), opts)?;
+        let appdata = unwrap_appdata!(p_vfs, T)?;
+        let handle = if opts.kind() == OpenKind::MainDb && !z_name.is_null() {
+            let params = unsafe { uri_params(&appdata.sqlite_api, z_name) };
+            vfs.open_with_params(name.as_ref().map(|s| s.as_ref()), opts, &params)?
+        } else {
+            vfs.open(name.as_ref().map(|s| s.as_ref()), opts)?
+        };
 
         let out_file = unwrap_file!(p_file, T)?;
-        let appdata = unwrap_appdata!(p_vfs, T)?;
 
         if let Some(p_out_flags) = unsafe { p_out_flags.as_mut() } {
             let mut out_flags = flags;
@@ -392,11 +536,37 @@ unsafe extern "C" fn x_open<T: Vfs>(
         out_file.file.pMethods = &appdata.io_methods;
         out_file.vfs = p_vfs;
         out_file.handle.write(handle);
+        out_file.shm.write(Vec::new());
 
         Ok(vars::SQLITE_OK)
     })
 }
 
+/// The parameters of a `file:` URI, empty for a plain filename.
+/// # Safety
+/// `z_name` must be a main database filename passed to `xOpen`
+unsafe fn uri_params(api: &SqliteApi, z_name: ffi::sqlite3_filename) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    for n in 0.. {
+        let key = unsafe { (api.uri_key)(z_name, n) };
+        if key.is_null() {
+            break;
+        }
+        let value = unsafe { (api.uri_parameter)(z_name, key) };
+        let key = unsafe { CStr::from_ptr(key) }
+            .to_string_lossy()
+            .into_owned();
+        let value = match unsafe { value.as_ref() } {
+            Some(value) => unsafe { CStr::from_ptr(value) }
+                .to_string_lossy()
+                .into_owned(),
+            None => String::new(),
+        };
+        params.push((key, value));
+    }
+    params
+}
+
 unsafe extern "C" fn x_delete<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     z_name: ffi::sqlite3_filename,
@@ -455,6 +625,7 @@ unsafe extern "C" fn x_close<T: Vfs>(p_file: *mut ffi::sqlite3_file) -> c_int {
         let vfs = unwrap_vfs!(file.vfs, T)?;
         let handle = mem::replace(&mut file.handle, MaybeUninit::uninit());
         let handle = unsafe { handle.assume_init() };
+        unsafe { file.shm.assume_init_drop() };
         vfs.close(handle)?;
         Ok(vars::SQLITE_OK)
     })
@@ -553,6 +724,20 @@ unsafe extern "C" fn x_unlock<T: Vfs>(p_file: *mut ffi::sqlite3_file, raw_lock:
     })
 }
 
+unsafe extern "C" fn x_check_reserved_lock<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    p_res_out: *mut c_int,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let reserved = vfs.check_reserved_lock(unsafe { file.handle.assume_init_mut() })?;
+        let p_res_out = unsafe { p_res_out.as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+        *p_res_out = reserved as c_int;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
 unsafe extern "C" fn x_file_control<T: Vfs>(
     p_file: *mut ffi::sqlite3_file,
     op: c_int,
@@ -608,7 +793,122 @@ unsafe extern "C" fn x_file_control<T: Vfs>(
     fallible(|| {
         let file = unwrap_file!(p_file, T)?;
         let vfs = unwrap_vfs!(file.vfs, T)?;
-        vfs.file_control(unsafe { file.handle.assume_init_mut() }, op, p_arg)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let custom = (op >= FCNTL_CUSTOM_MIN)
+            .then(|| vfs.custom_file_control_arg(op))
+            .flatten();
+        let Some(kind) = custom else {
+            vfs.file_control(handle, op, p_arg)?;
+            return Ok(vars::SQLITE_OK);
+        };
+        if kind != CustomFcntlArg::None && p_arg.is_null() {
+            return Err(vars::SQLITE_MISUSE);
+        }
+        match kind {
+            CustomFcntlArg::None => {
+                vfs.custom_file_control(handle, op, CustomFcntl::None)?;
+            }
+            CustomFcntlArg::Int64 => {
+                let arg = unsafe { &mut *p_arg.cast::<i64>() };
+                vfs.custom_file_control(handle, op, CustomFcntl::Int64(arg))?;
+            }
+            CustomFcntlArg::Text => {
+                let p_text = p_arg.cast::<*mut c_char>();
+                let text = unsafe {
+                    (*p_text)
+                        .as_ref()
+                        .map(|p| CStr::from_ptr(p).to_string_lossy())
+                };
+                let result =
+                    vfs.custom_file_control(handle, op, CustomFcntl::Text(text.as_deref()))?;
+                if let Some(result) = result {
+                    let appdata = unwrap_appdata!(file.vfs, T)?;
+                    unsafe { *p_text = sqlite3_mprintf(&appdata.sqlite_api, &result)? };
+                }
+            }
+        }
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+// shared memory
+
+unsafe extern "C" fn x_shm_map<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    i_region: c_int,
+    sz_region: c_int,
+    b_extend: c_int,
+    pp: *mut *mut c_void,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let pp = unsafe { pp.as_mut() }.ok_or(vars::SQLITE_INTERNAL)?;
+        let region: usize = i_region.try_into().map_err(|_| vars::SQLITE_IOERR_SHMMAP)?;
+        let size: usize = sz_region
+            .try_into()
+            .map_err(|_| vars::SQLITE_IOERR_SHMMAP)?;
+        let handle = unsafe { file.handle.assume_init_mut() };
+        let shm = unsafe { file.shm.assume_init_mut() };
+        if let Some(Some(mapped)) = shm.get(region) {
+            *pp = mapped.as_ptr().cast_mut().cast();
+            return Ok(vars::SQLITE_OK);
+        }
+        let Some(mapped) = vfs.shm_map(handle, region, size, b_extend != 0)? else {
+            *pp = null_mut();
+            return Ok(vars::SQLITE_OK);
+        };
+        if mapped.len() < size {
+            return Err(vars::SQLITE_IOERR_SHMSIZE);
+        }
+        *pp = mapped.as_ptr().cast_mut().cast();
+        if shm.len() <= region {
+            shm.resize(region + 1, None);
+        }
+        shm[region] = Some(mapped);
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_shm_lock<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    offset: c_int,
+    n: c_int,
+    flags: c_int,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        let offset: usize = offset.try_into().map_err(|_| vars::SQLITE_IOERR_SHMLOCK)?;
+        let n: usize = n.try_into().map_err(|_| vars::SQLITE_IOERR_SHMLOCK)?;
+        vfs.shm_lock(
+            unsafe { file.handle.assume_init_mut() },
+            offset,
+            n,
+            flags.into(),
+        )?;
+        Ok(vars::SQLITE_OK)
+    })
+}
+
+unsafe extern "C" fn x_shm_barrier<T: Vfs>(p_file: *mut ffi::sqlite3_file) {
+    if let Ok(file) = unwrap_file!(p_file, T) {
+        if let Ok(vfs) = unwrap_vfs!(file.vfs, T) {
+            vfs.shm_barrier(unsafe { file.handle.assume_init_mut() });
+        }
+    }
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+unsafe extern "C" fn x_shm_unmap<T: Vfs>(
+    p_file: *mut ffi::sqlite3_file,
+    delete_flag: c_int,
+) -> c_int {
+    fallible(|| {
+        let file = unwrap_file!(p_file, T)?;
+        let vfs = unwrap_vfs!(file.vfs, T)?;
+        unsafe { file.shm.assume_init_mut() }.clear();
+        vfs.shm_unmap(unsafe { file.handle.assume_init_mut() }, delete_flag != 0)?;
         Ok(vars::SQLITE_OK)
     })
 }
@@ -694,6 +994,12 @@ unsafe extern "C" fn x_randomness<T: Vfs>(
 }
 
 unsafe extern "C" fn x_sleep<T: Vfs>(p_vfs: *mut ffi::sqlite3_vfs, microseconds: c_int) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        let duration = Duration::from_micros(microseconds.max(0) as u64);
+        if let Some(slept) = vfs.sleep(duration) {
+            return slept.as_micros().try_into().unwrap_or(c_int::MAX);
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_sleep) = vfs.xSleep {
             return unsafe { x_sleep(vfs, microseconds) };
@@ -706,6 +1012,15 @@ unsafe extern "C" fn x_current_time<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     p_time: *mut f64,
 ) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        if let Some(ms) = vfs.current_time_ms() {
+            let Some(p_time) = (unsafe { p_time.as_mut() }) else {
+                return vars::SQLITE_INTERNAL;
+            };
+            *p_time = ms as f64 / 86_400_000.0;
+            return vars::SQLITE_OK;
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_current_time) = vfs.xCurrentTime {
             return unsafe { x_current_time(vfs, p_time) };
@@ -718,6 +1033,15 @@ unsafe extern "C" fn x_current_time_int64<T: Vfs>(
     p_vfs: *mut ffi::sqlite3_vfs,
     p_time: *mut i64,
 ) -> c_int {
+    if let Ok(vfs) = unwrap_vfs!(p_vfs, T) {
+        if let Some(ms) = vfs.current_time_ms() {
+            let Some(p_time) = (unsafe { p_time.as_mut() }) else {
+                return vars::SQLITE_INTERNAL;
+            };
+            *p_time = ms;
+            return vars::SQLITE_OK;
+        }
+    }
     if let Ok(vfs) = unwrap_base_vfs!(p_vfs, T) {
         if let Some(x_current_time_int64) = vfs.xCurrentTimeInt64 {
             return unsafe { x_current_time_int64(vfs, p_time) };
@@ -736,7 +1060,10 @@ mod tests {
         flags::{CreateMode, OpenKind, OpenMode},
         mock::*,
     };
+    use alloc::string::ToString;
+    use alloc::sync::Arc;
     use alloc::vec::Vec;
+    use parking_lot::Mutex;
     use rusqlite::{Connection, OpenFlags};
     use std::{boxed::Box, io::Write, println};
 
@@ -820,4 +1147,243 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn custom_file_control() -> Result<(), Box<dyn std::error::Error>> {
+        const NONE: c_int = FCNTL_CUSTOM_MIN;
+        const INT64: c_int = FCNTL_CUSTOM_MIN + 1;
+        const TEXT: c_int = FCNTL_CUSTOM_MIN + 2;
+
+        struct H {
+            calls: Arc<Mutex<usize>>,
+        }
+        impl Hooks for H {
+            fn custom_file_control_arg(&mut self, op: i32) -> Option<CustomFcntlArg> {
+                match op {
+                    NONE => Some(CustomFcntlArg::None),
+                    INT64 => Some(CustomFcntlArg::Int64),
+                    TEXT => Some(CustomFcntlArg::Text),
+                    _ => None,
+                }
+            }
+            fn custom_file_control(
+                &mut self,
+                _: MockHandle,
+                op: i32,
+                arg: CustomFcntl<'_>,
+            ) -> VfsResult<Option<String>> {
+                match (op, arg) {
+                    (NONE, CustomFcntl::None) => *self.calls.lock() += 1,
+                    (INT64, CustomFcntl::Int64(n)) => *n *= 2,
+                    (TEXT, CustomFcntl::Text(text)) => {
+                        return Ok(Some(text.unwrap_or("none").to_uppercase()));
+                    }
+                    (op, arg) => panic!("unexpected {op} {arg:?}"),
+                }
+                Ok(None)
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(0));
+        register_static(
+            CString::new("mock_custom_file_control").unwrap(),
+            MockVfs::new(Box::new(H { calls: calls.clone() })),
+            RegisterOpts { make_default: false },
+        )
+        .map_err(|_| "failed to register vfs")?;
+        let conn = Connection::open_with_flags_and_vfs(
+            "custom_file_control.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_custom_file_control",
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        let file_control = |op: c_int, arg: *mut c_void| unsafe {
+            rusqlite::ffi::sqlite3_file_control(conn.handle(), c"main".as_ptr(), op, arg)
+        };
+
+        assert_eq!(file_control(NONE, null_mut()), vars::SQLITE_OK);
+        assert_eq!(*calls.lock(), 1);
+
+        let mut n: i64 = 21;
+        assert_eq!(file_control(INT64, (&raw mut n).cast()), vars::SQLITE_OK);
+        assert_eq!(n, 42);
+        // a pointer argument is required
+        assert_eq!(file_control(INT64, null_mut()), vars::SQLITE_MISUSE);
+
+        let input = CString::new("hello").unwrap();
+        let mut text = input.as_ptr().cast_mut();
+        assert_eq!(file_control(TEXT, (&raw mut text).cast()), vars::SQLITE_OK);
+        assert_ne!(text, input.as_ptr().cast_mut());
+        assert_eq!(unsafe { CStr::from_ptr(text) }.to_str()?, "HELLO");
+        unsafe { rusqlite::ffi::sqlite3_free(text.cast()) };
+
+        // opcodes the vfs doesn't define fall through to file_control
+        assert_eq!(
+            file_control(FCNTL_CUSTOM_MIN + 3, null_mut()),
+            vars::SQLITE_NOTFOUND
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shared_memory() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            shared_memory: bool,
+            maps: Arc<Mutex<usize>>,
+        }
+        impl Hooks for H {
+            fn shared_memory(&mut self) -> bool {
+                self.shared_memory
+            }
+            fn shm_map(&mut self, _: MockHandle, _: usize, _: bool) {
+                *self.maps.lock() += 1;
+            }
+        }
+
+        let maps = Arc::new(Mutex::new(0));
+        for shared_memory in [false, true] {
+            let name = format!("mock_shared_memory_{shared_memory}");
+            register_static(
+                CString::new(name.clone()).unwrap(),
+                MockVfs::new(Box::new(H { shared_memory, maps: maps.clone() })),
+                RegisterOpts { make_default: false },
+            )
+            .map_err(|_| "failed to register vfs")?;
+            let open = || {
+                Connection::open_with_flags_and_vfs(
+                    "shared_memory.db",
+                    OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                    name.as_str(),
+                )
+            };
+            let writer = open()?;
+            let reader = open()?;
+            let mode: String = writer.query_row("pragma journal_mode=wal", [], |row| row.get(0))?;
+            if !shared_memory {
+                // without shared memory WAL needs locking_mode=exclusive
+                assert_eq!(mode, "delete");
+                assert_eq!(*maps.lock(), 0);
+                continue;
+            }
+            assert_eq!(mode, "wal");
+            writer.execute("create table t (val int)", [])?;
+            writer.execute("insert into t (val) values (1), (2)", [])?;
+            // the reader finds the writes in the wal through the shared wal-index
+            let n: i64 = reader.query_row("select sum(val) from t", [], |row| row.get(0))?;
+            assert_eq!(n, 3);
+            assert!(*maps.lock() > 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sleep_and_current_time() -> Result<(), Box<dyn std::error::Error>> {
+        // 2000-01-01 00:00:00 UTC
+        const JULIAN_MS: i64 = 2_451_544_500 * 86_400;
+
+        struct H {
+            slept: Arc<Mutex<Vec<Duration>>>,
+        }
+        impl Hooks for H {
+            fn sleep(&mut self, duration: Duration) -> Option<Duration> {
+                self.slept.lock().push(duration);
+                Some(duration)
+            }
+            fn current_time_ms(&mut self) -> Option<i64> {
+                Some(JULIAN_MS)
+            }
+        }
+
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let name = CString::new("mock_sleep_and_current_time").unwrap();
+        register_static(
+            name.clone(),
+            MockVfs::new(Box::new(H { slept: slept.clone() })),
+            RegisterOpts { make_default: false },
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags_and_vfs(
+            "sleep_and_current_time.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            "mock_sleep_and_current_time",
+        )?;
+        let now: String = conn.query_row("select datetime('now')", [], |row| row.get(0))?;
+        assert_eq!(now, "2000-01-01 00:00:00");
+
+        let p_vfs = unsafe { rusqlite::ffi::sqlite3_vfs_find(name.as_ptr()) };
+        let x_sleep = unsafe { (*p_vfs).xSleep }.unwrap();
+        assert_eq!(unsafe { x_sleep(p_vfs, 1500) }, 1500);
+        assert_eq!(*slept.lock(), [Duration::from_micros(1500)]);
+        Ok(())
+    }
+
+    #[test]
+    fn persistent_journal() -> Result<(), Box<dyn std::error::Error>> {
+        // TRUNCATE and PERSIST leave the journal behind between transactions, so every
+        // transaction asks whether it is hot with xCheckReservedLock
+        struct H {}
+        impl Hooks for H {}
+
+        register_static(
+            CString::new("mock_persistent_journal").unwrap(),
+            MockVfs::new(Box::new(H {})),
+            RegisterOpts { make_default: false },
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        for mode in ["truncate", "persist"] {
+            let conn = Connection::open_with_flags_and_vfs(
+                format!("{mode}.db"),
+                OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+                "mock_persistent_journal",
+            )?;
+            conn.query_row(&format!("pragma journal_mode={mode}"), [], |_| Ok(()))?;
+            conn.execute("create table t (val int)", [])?;
+            conn.execute("insert into t (val) values (1)", [])?;
+            conn.execute("insert into t (val) values (2)", [])?;
+            let n: i64 = conn.query_row("select sum(val) from t", [], |row| row.get(0))?;
+            assert_eq!(n, 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uri_params() -> Result<(), Box<dyn std::error::Error>> {
+        struct H {
+            params: Arc<Mutex<Vec<(String, String)>>>,
+        }
+        impl Hooks for H {
+            fn open_params(&mut self, path: &Option<&str>, params: &[(String, String)]) {
+                assert_eq!(*path, Some("params.db"));
+                *self.params.lock() = params.to_vec();
+            }
+        }
+
+        let params = Arc::new(Mutex::new(Vec::new()));
+        register_static(
+            CString::new("mock_uri_params").unwrap(),
+            MockVfs::new(Box::new(H { params: params.clone() })),
+            RegisterOpts { make_default: false },
+        )
+        .map_err(|_| "failed to register vfs")?;
+
+        let conn = Connection::open_with_flags(
+            "file:params.db?scan=true&mode=rwc&vfs=mock_uri_params",
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        conn.execute("create table t (val int)", [])?;
+        let params = params.lock().clone();
+        assert!(
+            params.contains(&("scan".to_string(), "true".to_string())),
+            "{params:?}"
+        );
+        assert!(
+            params.contains(&("mode".to_string(), "rwc".to_string())),
+            "{params:?}"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,114 @@
+//! Diagnostic bundles for stalled writes.
+//!
+//! "It was slow once" can't be acted on. With `STALL_THRESHOLD_MS` set, every write,
+//! atomic commit and sync is timed, and one that takes longer writes a bundle to
+//! `stalls/` in the state directory: the stalled operation with the span it ran in and
+//! its trace ID, the last `RECENT` timed operations, the open handles and the counters of
+//! `stats`. With the chrome trace on, see `diagnostics`, it's flushed first and the bundle
+//! names it, so the span tree of the stall can be opened as a flamegraph. A stall also
+//! counts in `stalls` and is logged.
+//!
+//! A run of stalls writes one bundle every `BUNDLE_INTERVAL` at most, and only the newest
+//! `STALL_BUNDLES_KEEP` are kept.
+
+use crate::health::json_string;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Timed operations kept for the next bundle.
+pub const RECENT: usize = 64;
+
+pub const BUNDLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// One timed write, commit or sync.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub op: &'static str,
+    pub path: String,
+    pub elapsed: Duration,
+    /// When it finished, in Unix milliseconds
+    pub at_ms: u64,
+}
+
+impl Timing {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"op\":\"{}\",\"path\":{},\"elapsed_ms\":{:.3},\"at_ms\":{}}}",
+            self.op,
+            json_string(&self.path),
+            self.elapsed.as_secs_f64() * 1000.0,
+            self.at_ms
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Stalls {
+    pub threshold: Duration,
+    recent: Mutex<VecDeque<Timing>>,
+    last_bundle: Mutex<Option<Instant>>,
+}
+
+impl Stalls {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT)),
+            last_bundle: Mutex::new(None),
+        }
+    }
+
+    /// Record `timing`, returning whether it stalled.
+    pub fn record(&self, timing: Timing) -> bool {
+        let stalled = timing.elapsed >= self.threshold;
+        let mut recent = self.recent.lock();
+        if recent.len() == RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(timing);
+        stalled
+    }
+
+    /// Whether a stall at `now` gets a bundle, counting one as written if so.
+    pub fn take_bundle_due(&self, now: Instant) -> bool {
+        let mut last_bundle = self.last_bundle.lock();
+        if last_bundle.is_some_and(|last| now.saturating_duration_since(last) < BUNDLE_INTERVAL) {
+            return false;
+        }
+        *last_bundle = Some(now);
+        true
+    }
+
+    /// The recent timings as a JSON array, oldest first.
+    pub fn recent_json(&self) -> String {
+        let recent: Vec<String> = self.recent.lock().iter().map(Timing::to_json).collect();
+        format!("[{}]", recent.join(","))
+    }
+}
+
+/// Write `bundle` for a stall at `at_ms` to a new file in `dir`, then delete all but the
+/// newest `keep`.
+pub fn write_bundle(dir: &Path, bundle: &str, keep: usize, at_ms: u64) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    // zero padded so the names sort oldest first
+    let path = dir.join(format!("stall-{at_ms:015}.json"));
+    std::fs::write(&path, bundle)?;
+
+    let mut bundles: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("stall-"))
+        })
+        .collect();
+    bundles.sort();
+    for old in &bundles[..bundles.len().saturating_sub(keep.max(1))] {
+        if let Err(e) = std::fs::remove_file(old) {
+            log::warn!("failed to remove old stall bundle {}: {e}", old.display());
+        }
+    }
+    Ok(path)
+}
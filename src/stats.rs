@@ -0,0 +1,168 @@
+//! Process-wide counters, reported as JSON by `PRAGMA s3qlite_stats`.
+//!
+//! Embedded deployments rarely have a metrics pipeline, so with
+//! `STATS_DUMP_INTERVAL_SECS` set the counters are also written to `stats/` in the state
+//! directory on a fixed interval, as CSV or JSON (`STATS_DUMP_FORMAT`). Only the newest
+//! `STATS_DUMP_KEEP` files are kept, so a postmortem finds the recent history without
+//! the directory growing forever. `PRAGMA s3qlite_dump_stats` writes one straight away.
+//! Both also carry the runtime's scheduler gauges, see `runtime_metrics`, and the
+//! request window, see `throttle`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static DUMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Csv,
+    Json,
+}
+
+impl DumpFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    /// Cached pages re-fetched from the store by the cache verifier
+    pub cache_verified_pages: AtomicU64,
+    /// Verified pages whose cached copy differed from the store, and were evicted
+    pub cache_verify_mismatches: AtomicU64,
+    /// Transaction writes and commits refused with `SQLITE_FULL` past
+    /// `PENDING_WRITES_MAX_BYTES`
+    pub pending_writes_full: AtomicU64,
+    /// Writes and truncates refused with `SQLITE_FULL` past a database's size limit
+    pub size_limit_full: AtomicU64,
+    /// Commits that waited for the in-flight commit budget
+    pub commit_budget_waits: AtomicU64,
+    /// Bytes of committed transactions' writes that were spilled to disk, see
+    /// `pending_writes`
+    pub spilled_bytes: AtomicU64,
+    /// New files stored before their first sync because they outgrew memory
+    pub fresh_files_flushed_early: AtomicU64,
+    /// Pages that differed from their shadow copy, see `shadow`
+    pub shadow_mismatches: AtomicU64,
+    /// `access` calls answered from the journals known not to exist
+    pub absent_journal_hits: AtomicU64,
+    /// Pages missing from the store that were served from a snapshot, see `read_repair`
+    pub pages_repaired: AtomicU64,
+    /// Pages missing from the store that no snapshot had either
+    pub page_repairs_failed: AtomicU64,
+    /// Writes, commits and syncs slower than `STALL_THRESHOLD_MS`, see `stall`
+    pub stalls: AtomicU64,
+    /// Cache manifests written because their hot set changed, see `cache_manifest`
+    pub cache_manifests_published: AtomicU64,
+    pub runtime: crate::runtime_metrics::RuntimeMetrics,
+    pub throttle: crate::throttle::Throttle,
+    pub memory: crate::memory_budget::MemoryBudget,
+}
+
+impl Stats {
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        let mut counters: Vec<(&'static str, u64)> = [
+            ("cache_verified_pages", &self.cache_verified_pages),
+            ("cache_verify_mismatches", &self.cache_verify_mismatches),
+            ("pending_writes_full", &self.pending_writes_full),
+            ("size_limit_full", &self.size_limit_full),
+            ("commit_budget_waits", &self.commit_budget_waits),
+            ("spilled_bytes", &self.spilled_bytes),
+            ("fresh_files_flushed_early", &self.fresh_files_flushed_early),
+            ("shadow_mismatches", &self.shadow_mismatches),
+            ("absent_journal_hits", &self.absent_journal_hits),
+            ("pages_repaired", &self.pages_repaired),
+            ("page_repairs_failed", &self.page_repairs_failed),
+            ("stalls", &self.stalls),
+            ("cache_manifests_published", &self.cache_manifests_published),
+        ]
+        .map(|(name, value)| (name, value.load(Ordering::Relaxed)))
+        .into();
+        counters.extend(self.runtime.gauges());
+        counters.extend(self.throttle.gauges());
+        counters.extend(self.memory.gauges());
+        counters
+    }
+
+    pub fn to_json(&self) -> String {
+        let fields: Vec<String> = self
+            .counters()
+            .iter()
+            .map(|(name, value)| format!("\"{name}\":{value}"))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// A header line and one line of values, led by the time of the snapshot.
+    pub fn to_csv(&self, timestamp_ms: u128) -> String {
+        let counters = self.counters();
+        let names: Vec<&str> = counters.iter().map(|(name, _)| *name).collect();
+        let values: Vec<String> = counters
+            .iter()
+            .map(|(_, value)| value.to_string())
+            .collect();
+        format!(
+            "timestamp_ms,{}\n{timestamp_ms},{}\n",
+            names.join(","),
+            values.join(",")
+        )
+    }
+
+    /// Write a snapshot taken at `now` to a new file in `dir`, then delete all but the
+    /// newest `keep`.
+    pub fn dump(
+        &self,
+        dir: &Path,
+        format: DumpFormat,
+        keep: usize,
+        now: std::time::SystemTime,
+    ) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let timestamp_ms = now
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let contents = match format {
+            DumpFormat::Csv => self.to_csv(timestamp_ms),
+            DumpFormat::Json => format!(
+                "{{\"timestamp_ms\":{timestamp_ms},{}\n",
+                &self.to_json()[1..]
+            ),
+        };
+        // zero padded so the names sort oldest first
+        let path = dir.join(format!(
+            "stats-{timestamp_ms:015}-{:06}.{}",
+            DUMP_COUNTER.fetch_add(1, Ordering::Relaxed) % 1_000_000,
+            format.extension()
+        ));
+        std::fs::write(&path, contents)?;
+
+        let mut dumps: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("stats-"))
+            })
+            .collect();
+        dumps.sort();
+        for old in &dumps[..dumps.len().saturating_sub(keep.max(1))] {
+            if let Err(e) = std::fs::remove_file(old) {
+                log::warn!("failed to remove old stats dump {}: {e}", old.display());
+            }
+        }
+        Ok(path)
+    }
+}
@@ -0,0 +1,316 @@
+use crate::endpoints::RoundRobinStore;
+use slatedb::object_store::aws::{AmazonS3Builder, AmazonS3ConfigKey, AwsCredentialProvider};
+use slatedb::object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+use slatedb::object_store::prefix::PrefixStore;
+use slatedb::object_store::{ObjectStore, local::LocalFileSystem, memory::InMemory};
+use std::sync::Arc;
+
+/// Where `gs://` buckets are reached, through GCS's S3-compatible XML API.
+const GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
+/// Build the object store backing the VFS from a URL.
+///
+/// - unset or `memory://`: in-process store, lost on exit, for tests
+/// - `file:///some/dir`: the same layout on the local filesystem, for development
+///   without any S3 dependency
+/// - `s3://bucket/optional/prefix`: S3, configured from the usual `AWS_*` variables and
+///   then from the URL's query, which takes object_store's S3 settings by name:
+///   `s3://bucket/prefix?region=eu-west-1&endpoint=http://localhost:9000&allow_http=true`
+///   for MinIO, say. `endpoint` can list several endpoints of one cluster separated by
+///   commas, see `endpoints`. `credentials` overrides the credentials object_store would otherwise
+///   find itself, see `CREDENTIALS_SOURCE`.
+/// - `gs://bucket/optional/prefix` (or `gcs://`): Google Cloud Storage through its S3
+///   interoperability, with an HMAC key in `AWS_ACCESS_KEY_ID` and
+///   `AWS_SECRET_ACCESS_KEY`. The query takes the same settings as `s3://`.
+/// - `azure://container/optional/prefix`: Azure Blob Storage, configured from the
+///   `AZURE_*` variables and then from the query, which takes object_store's Azure
+///   settings by name, e.g. `azure://container?account_name=acct&use_emulator=true`.
+pub fn object_store_from_url(
+    url: Option<&str>,
+    credentials: Option<AwsCredentialProvider>,
+) -> Result<Arc<dyn ObjectStore>, String> {
+    let Some(url) = url else {
+        log::warn!("OBJECT_STORE_URL isn't set, databases are kept in memory and lost on exit");
+        return Ok(Arc::new(InMemory::new()));
+    };
+    if url == "memory://" {
+        return Ok(Arc::new(InMemory::new()));
+    }
+    if let Some(dir) = url.strip_prefix("file://") {
+        if dir.is_empty() {
+            return Err(format!("object store url {url} has no directory"));
+        }
+        std::fs::create_dir_all(dir).map_err(|e| format!("failed to create {dir}: {e}"))?;
+        let store = LocalFileSystem::new_with_prefix(dir)
+            .map_err(|e| format!("failed to open {dir}: {e}"))?;
+        return Ok(Arc::new(store));
+    }
+    if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, prefix, query) = split_location(url, rest)?;
+        let builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        return s3_store(url, builder, prefix, query, credentials);
+    }
+    if let Some(rest) = url
+        .strip_prefix("gs://")
+        .or_else(|| url.strip_prefix("gcs://"))
+    {
+        let (bucket, prefix, query) = split_location(url, rest)?;
+        let builder = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .with_endpoint(GCS_ENDPOINT)
+            .with_region("auto");
+        return s3_store(url, builder, prefix, query, credentials);
+    }
+    if let Some(rest) = url.strip_prefix("azure://") {
+        let (container, prefix, query) = split_location(url, rest)?;
+        let mut builder = MicrosoftAzureBuilder::from_env().with_container_name(container);
+        for (key, value) in settings(query) {
+            let key: AzureConfigKey = key
+                .parse()
+                .map_err(|_| format!("unknown azure setting {key} in object store url"))?;
+            builder = builder.with_config(key, value?);
+        }
+        let store = builder
+            .build()
+            .map_err(|e| format!("failed to configure azure store {url}: {e}"))?;
+        return Ok(with_prefix(store, prefix));
+    }
+    Err(format!("unsupported object store url: {url}"))
+}
+
+/// The name of the database a URL names, e.g. `app` for `s3://bucket/prefix/app` when
+/// `store_url` is `s3://bucket/prefix`. `None` for a plain path. A URL can only name a
+/// database in the store the VFS was configured with, unset meaning `memory://`.
+///
+/// One in another bucket or store is refused rather than opened. Databases in the
+/// configured store are kept apart by their path, which prefixes every key, but the VFS
+/// runs a single SlateDB instance there: snapshots are checkpoints of it, `reader` opens
+/// it, and the memory budget and stats count one writer. Databases in other stores would
+/// need a SlateDB instance per store, opened with the first handle and closed with the
+/// last, and those to follow.
+pub fn database_name<'a>(
+    name: &'a str,
+    store_url: Option<&str>,
+) -> Result<Option<&'a str>, String> {
+    if !name.contains("://") {
+        return Ok(None);
+    }
+    let store_url = store_url.unwrap_or("memory://");
+    let (store, _) = store_url.split_once('?').unwrap_or((store_url, ""));
+    let store = store.trim_end_matches('/');
+    name.strip_prefix(store)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .map(|rest| rest.trim_start_matches('/'))
+        .filter(|database| !database.is_empty())
+        .map(Some)
+        .ok_or_else(|| {
+            let scheme = store.split("://").next().unwrap_or_default();
+            format!("{name} isn't a database in the configured {scheme} object store")
+        })
+}
+
+/// The bucket, the prefix within it and the query of `rest`, the part of `url` after
+/// its scheme.
+fn split_location<'a>(url: &str, rest: &'a str) -> Result<(&'a str, &'a str, &'a str), String> {
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        return Err(format!("object store url {url} has no bucket"));
+    }
+    Ok((bucket, prefix, query))
+}
+
+/// The `key=value` pairs of a URL query, values decoded.
+fn settings(query: &str) -> impl Iterator<Item = (&str, Result<String, String>)> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (key, percent_decode(value))
+        })
+}
+
+fn s3_store(
+    url: &str,
+    mut builder: AmazonS3Builder,
+    prefix: &str,
+    query: &str,
+    credentials: Option<AwsCredentialProvider>,
+) -> Result<Arc<dyn ObjectStore>, String> {
+    let mut endpoints = Vec::new();
+    for (key, value) in settings(query) {
+        let key: AmazonS3ConfigKey = key
+            .parse()
+            .map_err(|_| format!("unknown s3 setting {key} in object store url"))?;
+        let value = value?;
+        if key == AmazonS3ConfigKey::Endpoint && value.contains(',') {
+            endpoints = value
+                .split(',')
+                .map(str::trim)
+                .map(str::to_string)
+                .collect();
+            if endpoints.iter().any(String::is_empty) {
+                return Err("empty endpoint in object store url".to_string());
+            }
+            continue;
+        }
+        builder = builder.with_config(key, value);
+    }
+    if let Some(credentials) = credentials {
+        builder = builder.with_credentials(credentials);
+    }
+    if endpoints.is_empty() {
+        let store = builder
+            .build()
+            .map_err(|e| format!("failed to configure s3 store {url}: {e}"))?;
+        return Ok(with_prefix(store, prefix));
+    }
+    let stores = endpoints
+        .into_iter()
+        .map(|endpoint| {
+            let store = builder
+                .clone()
+                .with_endpoint(&endpoint)
+                .build()
+                .map_err(|e| format!("failed to configure s3 store {url} at {endpoint}: {e}"))?;
+            Ok((endpoint, Arc::new(store) as Arc<dyn ObjectStore>))
+        })
+        .collect::<Result<_, String>>()?;
+    let store = RoundRobinStore::new(stores, Arc::new(crate::clock::SystemClock));
+    Ok(with_prefix(store, prefix))
+}
+
+fn with_prefix(store: impl ObjectStore, prefix: &str) -> Arc<dyn ObjectStore> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        return Arc::new(store);
+    }
+    Arc::new(PrefixStore::new(store, prefix))
+}
+
+/// Decode the `%XX` escapes in a URL query value.
+fn percent_decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("bad escape in object store url setting {value}"))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("object store url setting {value} isn't UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Reader;
+    use rusqlite::{Connection, OpenFlags};
+    use slatedb::object_store::path::Path;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("s3qlite_{name}_{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn file_urls_keep_objects_as_plain_files() {
+        let dir = temp_dir("file_store");
+        let store =
+            object_store_from_url(Some(&format!("file://{}", dir.display())), None).unwrap();
+        store
+            .put(&Path::from("db/manifest/1"), "contents".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("db/manifest/1")).unwrap(),
+            b"contents"
+        );
+        let stored = store.get(&Path::from("db/manifest/1")).await.unwrap();
+        assert_eq!(&stored.bytes().await.unwrap()[..], b"contents");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(object_store_from_url(Some("file://"), None).is_err());
+    }
+
+    /// Every page of `path`, in offset order.
+    async fn pages(reader: &Reader, path: &str) -> Vec<(usize, Vec<u8>)> {
+        let mut pages = reader.pages(path).await.unwrap();
+        let mut all = Vec::new();
+        while let Some(page) = pages.next().await.unwrap() {
+            all.push((page.offset, page.data.to_vec()));
+        }
+        all.sort();
+        all
+    }
+
+    #[test]
+    fn databases_in_a_file_store_can_be_diffed() {
+        let dir = temp_dir("file_diff");
+        let url = format!("file://{}", dir.display());
+        let mut config = crate::env_config::EnvConfig::new();
+        config.object_store_url = Some(url.clone());
+        let vfs = crate::GrpcVfs::from_config(config).unwrap();
+        sqlite_plugin::vfs::register_static(
+            c"s3qlite_file_store_test".to_owned(),
+            vfs,
+            sqlite_plugin::vfs::RegisterOpts {
+                make_default: false,
+            },
+        )
+        .unwrap();
+        let open = |path: &str| {
+            Connection::open_with_flags_and_vfs(
+                path,
+                OpenFlags::default(),
+                c"s3qlite_file_store_test",
+            )
+            .unwrap()
+        };
+        let (a, b) = (open("a.db"), open("b.db"));
+        for connection in [&a, &b] {
+            connection
+                .execute_batch(
+                    "CREATE TABLE t (id INTEGER PRIMARY KEY, body TEXT);
+                     WITH RECURSIVE s(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM s WHERE x < 200)
+                     INSERT INTO t (body) SELECT printf('row %d', x) FROM s;
+                     PRAGMA s3qlite_flush;",
+                )
+                .unwrap();
+        }
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let diff = || {
+            runtime.block_on(async {
+                // read back from the files, not from the VFS
+                let reader = Reader::open(&url).await.unwrap();
+                let (a, b) = (pages(&reader, "a.db").await, pages(&reader, "b.db").await);
+                reader.close().await.unwrap();
+                assert_eq!(a.len(), b.len());
+                a.into_iter()
+                    .zip(b)
+                    .filter(|(a, b)| a != b)
+                    .map(|((offset, _), _)| offset)
+                    .collect::<Vec<_>>()
+            })
+        };
+        // the same statements store the same pages
+        assert_eq!(diff(), Vec::<usize>::new());
+
+        // the last row's page, and the header's change counter, tell them apart
+        b.execute_batch("UPDATE t SET body = 'changed' WHERE id = 200; PRAGMA s3qlite_flush;")
+            .unwrap();
+        let changed = diff();
+        assert_eq!(changed.len(), 2, "{changed:?}");
+        assert_eq!(changed[0], 0);
+        drop((a, b));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,105 @@
+//! Calling the VFS from inside a tokio runtime.
+//!
+//! SQLite's VFS methods are synchronous, so each one waits for its futures on the VFS's
+//! own runtime, and tokio panics when a thread that's running a runtime starts waiting
+//! on another. A Rust host that links this crate and uses SQLite from async code, under
+//! `#[tokio::main]` say, shares its tokio and would have its first query panic. (A host
+//! linking the C library has a tokio of its own, whose runtimes the VFS doesn't see.)
+//! Instead every wait looks at the runtime the calling thread is in, see `Context`, and
+//! waits the way that runtime allows:
+//!
+//! - none, a C host or a thread of the host's own: on the VFS's runtime.
+//! - a multi-thread runtime, the host's or the VFS's own from a callback: the same, in
+//!   `block_in_place` so the worker's other tasks move to other workers meanwhile.
+//! - a current-thread runtime, `#[tokio::test]` say: blocking in place isn't possible
+//!   there, so the future is polled on the calling thread, with the VFS's runtime
+//!   entered to drive its I/O and timers. None of the host's other tasks run until the
+//!   call returns, which is logged once: such a host is better off making its SQLite
+//!   calls in `spawn_blocking`.
+//!
+//! Building the VFS waits on its runtime too, and a runtime can't be dropped inside
+//! another, so the VFS is built on a thread of its own when the first call comes from
+//! inside a runtime. `initialize` is the async way in: it registers the VFS and builds it
+//! on the host's blocking pool, so the first open doesn't pay for it.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+
+// the first wait inside a current-thread runtime has been logged
+static CURRENT_THREAD_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// The runtime a thread calling into the VFS is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    None,
+    MultiThread,
+    CurrentThread,
+}
+
+impl Context {
+    pub fn current() -> Self {
+        match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+            Err(_) => Context::None,
+            Ok(RuntimeFlavor::CurrentThread) => Context::CurrentThread,
+            // MultiThreadAlt, unstable, can block in place as well
+            Ok(_) => Context::MultiThread,
+        }
+    }
+}
+
+/// Wait for `future` on `runtime` from a synchronous VFS call, in whatever runtime the
+/// calling thread is in.
+pub fn block_on<F: Future>(runtime: &Runtime, future: F) -> F::Output {
+    match Context::current() {
+        Context::None => runtime.block_on(future),
+        Context::MultiThread => tokio::task::block_in_place(|| runtime.block_on(future)),
+        Context::CurrentThread => {
+            if !CURRENT_THREAD_LOGGED.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "s3qlite called from a current-thread tokio runtime, its other tasks wait \
+                     for each call, make SQLite calls in spawn_blocking instead"
+                );
+            }
+            let _entered = runtime.enter();
+            futures::executor::block_on(future)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Context;
+    use rusqlite::Connection;
+
+    // A host's first queries, through the VFS `initialize` registered as the default.
+    async fn open_write_read(path: &str) {
+        crate::initialize().await.unwrap();
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT); \
+                 INSERT INTO t (v) VALUES ('a'), ('b');",
+            )
+            .unwrap();
+        drop(connection);
+
+        let connection = Connection::open(path).unwrap();
+        let values: String = connection
+            .query_row("SELECT group_concat(v) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(values, "a,b");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn from_a_current_thread_runtime() {
+        assert_eq!(Context::current(), Context::CurrentThread);
+        open_write_read("threading_current_thread.db").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn from_a_multi_thread_runtime() {
+        assert_eq!(Context::current(), Context::MultiThread);
+        open_write_read("threading_multi_thread.db").await;
+    }
+}
@@ -0,0 +1,307 @@
+//! Backing off when the object store throttles.
+//!
+//! S3 answers a bucket prefix that's asked too much with `503 SlowDown`. object_store
+//! retries those requests itself, so retrying harder at the VFS level only makes it
+//! worse. Instead every request goes through `ThrottledStore`, and the VFS sizes its
+//! fan-outs (pages loaded per commit, fetched per scan range and prefetched after a
+//! miss) by a window kept the AIMD way: a request still throttled after object_store's
+//! retries halves it, at most once per `CUT_COOLDOWN`, and a window's worth of
+//! successful requests grows it by one, up to `THROTTLE_MAX_WINDOW`.
+//!
+//! The window and the number of throttled requests are in `PRAGMA s3qlite_stats`, a
+//! window that stays small means the store is throttling for good.
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use parking_lot::Mutex;
+use slatedb::bytes::Bytes;
+use slatedb::object_store::path::Path;
+use slatedb::object_store::{
+    self, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOptions, PutOptions, PutPayload, PutResult,
+};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Throttled requests in a burst usually come from the same fan-out, so the window is
+/// only cut once per this long.
+const CUT_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Whether `e` means the store is throttling, by the status or S3 error code in it.
+/// object_store doesn't expose the status of a failed request other than in its message.
+pub fn is_throttle(e: &object_store::Error) -> bool {
+    let mut source: Option<&dyn std::error::Error> = Some(e);
+    while let Some(e) = source {
+        let message = e.to_string();
+        if message.contains("503 Service Unavailable")
+            || message.contains("429 Too Many Requests")
+            || message.contains("SlowDown")
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[derive(Debug, Default)]
+pub struct Throttle {
+    // 0 until configured, then the window never grows past it
+    max: AtomicUsize,
+    window: AtomicUsize,
+    // successful requests since the window last changed
+    successes: AtomicUsize,
+    last_cut: Mutex<Option<Instant>>,
+    clock: OnceLock<Arc<dyn crate::clock::Clock>>,
+    throttled: AtomicU64,
+}
+
+impl Throttle {
+    /// Start the window at `max`, 0 leaves fan-outs as configured.
+    pub fn configure(&self, max: usize, clock: Arc<dyn crate::clock::Clock>) {
+        let _ = self.clock.set(clock);
+        self.max.store(max, Ordering::Relaxed);
+        self.window.store(max, Ordering::Relaxed);
+    }
+
+    /// `wanted` concurrent requests, or fewer while the store is throttling.
+    pub fn limit(&self, wanted: usize) -> usize {
+        match self.window.load(Ordering::Relaxed) {
+            0 => wanted,
+            window => wanted.min(window),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let window = self.window.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        if window >= max {
+            return;
+        }
+        if self.successes.fetch_add(1, Ordering::Relaxed) + 1 >= window {
+            self.successes.store(0, Ordering::Relaxed);
+            let _ = self.window.compare_exchange(
+                window,
+                window + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    pub fn record_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        if self.max.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut last_cut = self.last_cut.lock();
+        let now = self
+            .clock
+            .get()
+            .map_or_else(Instant::now, |clock| clock.now());
+        if last_cut.is_some_and(|at| now.saturating_duration_since(at) < CUT_COOLDOWN) {
+            return;
+        }
+        *last_cut = Some(now);
+        let window = (self.window.load(Ordering::Relaxed) / 2).max(1);
+        self.window.store(window, Ordering::Relaxed);
+        self.successes.store(0, Ordering::Relaxed);
+        log::warn!("object store is throttling, request window cut to {window}");
+    }
+
+    pub fn gauges(&self) -> [(&'static str, u64); 2] {
+        [
+            ("throttled_requests", self.throttled.load(Ordering::Relaxed)),
+            (
+                "throttle_window",
+                self.window.load(Ordering::Relaxed) as u64,
+            ),
+        ]
+    }
+}
+
+/// An object store that reports how its requests went to the VFS's `Throttle`.
+pub struct ThrottledStore {
+    inner: Arc<dyn ObjectStore>,
+    stats: Arc<crate::stats::Stats>,
+}
+
+impl ThrottledStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, stats: Arc<crate::stats::Stats>) -> Self {
+        Self { inner, stats }
+    }
+
+    fn observe<T>(&self, result: object_store::Result<T>) -> object_store::Result<T> {
+        match &result {
+            Ok(_) => self.stats.throttle.record_success(),
+            Err(e) if is_throttle(e) => self.stats.throttle.record_throttled(),
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+impl std::fmt::Debug for ThrottledStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThrottledStore")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Display for ThrottledStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ThrottledStore({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for ThrottledStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> object_store::Result<PutResult> {
+        self.observe(self.inner.put_opts(location, payload, opts).await)
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> object_store::Result<Box<dyn MultipartUpload>> {
+        self.observe(self.inner.put_multipart_opts(location, opts).await)
+    }
+
+    async fn get_opts(
+        &self,
+        location: &Path,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        self.observe(self.inner.get_opts(location, options).await)
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<u64>) -> object_store::Result<Bytes> {
+        self.observe(self.inner.get_range(location, range).await)
+    }
+
+    async fn get_ranges(
+        &self,
+        location: &Path,
+        ranges: &[Range<u64>],
+    ) -> object_store::Result<Vec<Bytes>> {
+        self.observe(self.inner.get_ranges(location, ranges).await)
+    }
+
+    async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+        self.observe(self.inner.head(location).await)
+    }
+
+    async fn delete(&self, location: &Path) -> object_store::Result<()> {
+        self.observe(self.inner.delete(location).await)
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, object_store::Result<ObjectMeta>> {
+        let stats = self.stats.clone();
+        self.inner
+            .list(prefix)
+            .inspect(move |result| {
+                if let Err(e) = result
+                    && is_throttle(e)
+                {
+                    stats.throttle.record_throttled();
+                }
+            })
+            .boxed()
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        self.observe(self.inner.list_with_delimiter(prefix).await)
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.observe(self.inner.copy(from, to).await)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.observe(self.inner.rename(from, to).await)
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.observe(self.inner.copy_if_not_exists(from, to).await)
+    }
+
+    async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.observe(self.inner.rename_if_not_exists(from, to).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn window(throttle: &Throttle) -> u64 {
+        throttle.gauges()[1].1
+    }
+
+    #[test]
+    fn throttling_halves_the_window_and_successes_grow_it_back() {
+        let clock = Arc::new(ManualClock::new());
+        let throttle = Throttle::default();
+        throttle.configure(16, clock.clone());
+        assert_eq!(throttle.limit(64), 16);
+
+        // a burst of throttled requests cuts the window once per cooldown
+        throttle.record_throttled();
+        throttle.record_throttled();
+        assert_eq!(window(&throttle), 8);
+        clock.advance(CUT_COOLDOWN);
+        throttle.record_throttled();
+        assert_eq!(window(&throttle), 4);
+        assert_eq!(throttle.limit(64), 4);
+        assert_eq!(throttle.limit(2), 2);
+
+        // a window's worth of successes grows it by one
+        for _ in 0..3 {
+            throttle.record_success();
+        }
+        assert_eq!(window(&throttle), 4);
+        throttle.record_success();
+        assert_eq!(window(&throttle), 5);
+        for _ in 0..5 {
+            throttle.record_success();
+        }
+        assert_eq!(window(&throttle), 6);
+
+        // never past the configured maximum
+        for _ in 0..1000 {
+            throttle.record_success();
+        }
+        assert_eq!(window(&throttle), 16);
+
+        // and never below one
+        for _ in 0..10 {
+            clock.advance(CUT_COOLDOWN);
+            throttle.record_throttled();
+        }
+        assert_eq!(window(&throttle), 1);
+        assert_eq!(throttle.gauges()[0], ("throttled_requests", 13));
+    }
+
+    #[test]
+    fn unconfigured_counts_without_limiting() {
+        let throttle = Throttle::default();
+        throttle.record_throttled();
+        throttle.record_success();
+        assert_eq!(throttle.limit(64), 64);
+        assert_eq!(
+            throttle.gauges(),
+            [("throttled_requests", 1), ("throttle_window", 0)]
+        );
+    }
+}
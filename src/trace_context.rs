@@ -0,0 +1,30 @@
+//! Correlation IDs set with `PRAGMA s3qlite_trace_id`. While a VFS call runs for a
+//! handle with an ID, the ID is kept in a thread local so every log line emitted on that
+//! thread can carry it. Spans pick it up from the handle directly.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous trace ID when dropped.
+pub struct TraceScope {
+    previous: Option<String>,
+}
+
+pub fn enter(trace_id: Option<&str>) -> TraceScope {
+    let previous = CURRENT.with(|c| c.replace(trace_id.map(str::to_string)));
+    TraceScope { previous }
+}
+
+impl Drop for TraceScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+pub fn current() -> Option<String> {
+    CURRENT.with(|c| c.borrow().clone())
+}
@@ -0,0 +1,43 @@
+//! Ephemeral databases.
+//!
+//! Scratch databases made per test run or per session are rarely deleted by whoever
+//! made them, and a shared bucket grows without bound. `PRAGMA s3qlite_ttl='<secs>'`
+//! marks a database to expire that many seconds from now (`'0'` unmarks it), recorded
+//! under `s3qlite:expires:{path}` so every process sees it and one scan finds them all.
+//!
+//! Every `EXPIRE_INTERVAL_SECS` the VFS deletes the expired databases no handle in this
+//! process has open, with their pages, key-value entries, snapshots and markers.
+//! `PRAGMA s3qlite_expire` does the same straight away and lists what it deleted.
+//! Another process may still have an expired database open, so a TTL should outlast
+//! its use. Journals are left to SQLite, which deletes them with the transaction.
+//!
+//! Deadlines are taken from the VFS clock, see `clock`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const EXPIRES_PREFIX: &str = "s3qlite:expires:";
+
+pub fn expires_key(path: &str) -> String {
+    format!("{EXPIRES_PREFIX}{path}")
+}
+
+/// A stored deadline, milliseconds since the Unix epoch.
+pub fn parse(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+pub fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// The expiry of a database as JSON, `now` and `expires_at` in Unix milliseconds.
+pub fn to_json(expires_at: Option<u64>, now: u64) -> String {
+    match expires_at {
+        Some(expires_at) => format!(
+            "{{\"expires_at_ms\":{expires_at},\"remaining_ms\":{}}}",
+            expires_at.saturating_sub(now)
+        ),
+        None => "{\"expires_at_ms\":null}".to_string(),
+    }
+}
@@ -0,0 +1,78 @@
+//! Following commits to a database from the host application, instead of polling it
+//! with queries.
+//!
+//! `watch_generation("app.db")` hands back a receiver of the database's commit
+//! generation, see `generation`. It changes when a write transaction commits, in this
+//! process at once and elsewhere within `GENERATION_POLL_MS`, after the pages cached
+//! from before the commit are dropped. A cache layer can invalidate on it, or a push
+//! service notify its clients. The generation only moves forward, and a receiver can
+//! skip values when commits come quickly.
+//!
+//! Paths are the ones SQLite opens, not the stored names.
+
+use tokio::sync::watch::Receiver;
+
+/// Follow the commit generation of the database at `path`, starting the VFS if nothing
+/// has yet.
+pub fn watch_generation(path: &str) -> Result<Receiver<u64>, String> {
+    Ok(crate::get_grpc_vfs()?.watch_generation(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generation;
+    use rusqlite::{Connection, OpenFlags};
+    use std::time::Duration;
+
+    #[test]
+    fn commits_here_and_elsewhere_move_the_receiver() {
+        let mut config = crate::env_config::EnvConfig::new();
+        config.object_store_url = None;
+        config.generation_poll_ms = 20;
+        let vfs = crate::GrpcVfs::from_config(config).unwrap();
+        sqlite_plugin::vfs::register_static(
+            c"s3qlite_watch_test".to_owned(),
+            vfs.clone(),
+            sqlite_plugin::vfs::RegisterOpts {
+                make_default: false,
+            },
+        )
+        .unwrap();
+        let connection = Connection::open_with_flags_and_vfs(
+            "watch.db",
+            OpenFlags::default(),
+            c"s3qlite_watch_test",
+        )
+        .unwrap();
+        connection.execute_batch("CREATE TABLE t (x)").unwrap();
+
+        let mut receiver = vfs.watch_generation("watch.db");
+        let created = *receiver.borrow_and_update();
+        assert!(created > 0);
+
+        // a commit here moves it when the transaction unlocks
+        connection.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        assert!(receiver.has_changed().unwrap());
+        let inserted = *receiver.borrow_and_update();
+        assert!(inserted > created);
+
+        // another process's commit only shows in the stored generation, which the poll
+        // picks up
+        let key = generation::generation_key(&vfs.store_path("watch.db"));
+        let elsewhere = inserted + 5;
+        vfs.block_on(vfs.put(&key, elsewhere.to_string())).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                receiver.wait_for(|&generation| generation == elsewhere),
+            )
+            .await
+            .expect("the poll never saw the commit")
+            .unwrap();
+        });
+    }
+}